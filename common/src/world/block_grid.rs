@@ -0,0 +1,419 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Grids of blocks that can move freely through the world, such as trains and spaceships.
+
+use super::{
+    block_index_to_local, components::RigidBody, storage, BlockID, BlockMassProperties, BlockMassSource, Chunk,
+    ChunkCoordinate, FloatingOrigin, GlobalBlockCoordinate, GlobalBlockCoordinateEXT, GlobalBlockRange, GridWorld,
+    LocalBlockCoordinateExt, PhysicsVector, RegistryBlockMasses,
+};
+use derive_error::Error;
+use legion::{Entity, EntityStore};
+use nalgebra::{Isometry3, Point3};
+use rapier3d::dynamics::{RigidBodyBuilder, RigidBodySet};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Error type for block grids.
+#[derive(Debug, Error)]
+pub enum BlockGridError {
+    /// The entity does not exist, or is not a block grid with a rigid body.
+    NotABlockGrid,
+
+    /// The terrain is not loaded where a block needs to be placed.
+    ChunkNotLoaded,
+
+    /// The terrain already has a block where the grid needs to place one.
+    Obstructed,
+}
+
+/// A block grid error type.
+pub type BlockGridResult<O> = std::result::Result<O, BlockGridError>;
+
+/// A grid of blocks that is not locked to the terrain. It is an ECS component, and should be paired with a
+/// [RigidBody](super::components::RigidBody) that decides where it is in the world.
+/// Block coordinates for a grid are in the grid's own space. Block 0x0x0 of the grid sits at the origin of its rigid body.
+#[derive(Serialize, Deserialize)]
+pub struct BlockGrid<ChunkUserData> {
+    chunks: HashMap<ChunkCoordinate, Chunk<ChunkUserData>>,
+    mass_properties: BlockMassProperties,
+}
+
+impl<ChunkUserData: Default> BlockGrid<ChunkUserData> {
+    /// Create a new grid with no blocks in it.
+    pub fn new() -> BlockGrid<ChunkUserData> {
+        BlockGrid { chunks: HashMap::new(), mass_properties: BlockMassProperties::new() }
+    }
+
+    /// Get a block from the grid, in grid space.
+    pub fn get_block(&self, location: GlobalBlockCoordinate) -> Option<BlockID> {
+        let chunk = self.chunks.get(&location.chunk_index())?;
+        chunk.get_single_block_local(location.to_local_block_coordinate())
+    }
+
+    /// Set a block in the grid, in grid space. Returns the block that was there before.
+    /// The block masses are used to keep the mass properties of the grid up to date.
+    pub fn set_block(
+        &mut self, location: GlobalBlockCoordinate, block: Option<BlockID>, block_masses: &impl BlockMassSource,
+    ) -> Option<BlockID> {
+        let chunk_index = location.chunk_index();
+
+        // There's no need to create a chunk just to put air in it.
+        let chunk = match (self.chunks.get_mut(&chunk_index), block) {
+            (Some(chunk), _) => chunk,
+            (None, Some(_)) => {
+                self.chunks.entry(chunk_index).or_insert_with(|| Chunk::new(chunk_index, ChunkUserData::default()))
+            }
+            (None, None) => return None,
+        };
+
+        let old_block = std::mem::replace(chunk.get_single_block_local_mut(location.to_local_block_coordinate()), block);
+
+        if let Some(old_block) = old_block {
+            self.mass_properties.remove_block(location, block_masses.mass_of(old_block));
+        }
+        if let Some(block) = block {
+            self.mass_properties.add_block(location, block_masses.mass_of(block));
+        }
+
+        old_block
+    }
+
+    /// Get the mass properties of the grid, in grid space.
+    pub fn mass_properties(&self) -> &BlockMassProperties {
+        &self.mass_properties
+    }
+
+    /// Get a chunk of the grid by its index, in grid space.
+    pub fn get_chunk(&self, index: &ChunkCoordinate) -> Option<&Chunk<ChunkUserData>> {
+        self.chunks.get(index)
+    }
+
+    /// Get a chunk of the grid by its index, in grid space.
+    pub fn get_chunk_mut(&mut self, index: &ChunkCoordinate) -> Option<&mut Chunk<ChunkUserData>> {
+        self.chunks.get_mut(index)
+    }
+
+    /// Iterate every block in the grid that isn't air, along with its location in grid space.
+    /// The order is not defined.
+    pub fn iter_blocks(&self) -> impl Iterator<Item = (GlobalBlockCoordinate, BlockID)> + '_ {
+        self.chunks.iter().flat_map(|(chunk_index, chunk)| {
+            (0..storage::CHUNK_DIAMETER * storage::CHUNK_DIAMETER * storage::CHUNK_DIAMETER).filter_map(move |block_index| {
+                let block = chunk.direct_access(block_index).ok()??;
+                let location = block_index_to_local(block_index).to_global_block_coordinate(*chunk_index);
+
+                Some((location, block))
+            })
+        })
+    }
+
+    /// Count the blocks in the grid that aren't air.
+    pub fn num_blocks(&self) -> usize {
+        self.iter_blocks().count()
+    }
+}
+
+impl<ChunkUserData: Default> Default for BlockGrid<ChunkUserData> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The transform between the space of a block grid and world space.
+#[derive(Debug, Clone, Copy)]
+pub struct GridTransform {
+    isometry: Isometry3<f32>,
+}
+
+impl GridTransform {
+    /// Create a transform from the position of the grid's rigid body.
+    pub fn new(isometry: Isometry3<f32>) -> GridTransform {
+        GridTransform { isometry }
+    }
+
+    /// Get the transform of a grid from its rigid body.
+    pub fn from_rigid_body(rigid_body: &RigidBody, rigid_bodies: &RigidBodySet) -> Option<GridTransform> {
+        rigid_bodies.get(rigid_body.handle()).map(|body| GridTransform::new(*body.position()))
+    }
+
+    /// Get the isometry going from grid space to world space.
+    pub fn isometry(&self) -> &Isometry3<f32> {
+        &self.isometry
+    }
+
+    /// Convert a point from grid space to world space.
+    pub fn grid_to_world_point(&self, point: PhysicsVector) -> PhysicsVector {
+        self.isometry.transform_point(&Point3::from(point)).coords
+    }
+
+    /// Convert a point from world space to grid space.
+    pub fn world_to_grid_point(&self, point: PhysicsVector) -> PhysicsVector {
+        self.isometry.inverse_transform_point(&Point3::from(point)).coords
+    }
+
+    /// Find the block of the grid that contains a point in world space.
+    pub fn world_to_grid_block(&self, point: PhysicsVector) -> GlobalBlockCoordinate {
+        self.world_to_grid_point(point).map(|v| v.floor() as i64)
+    }
+
+    /// Find the center of a block of the grid in world space.
+    pub fn grid_block_center_to_world(&self, block: GlobalBlockCoordinate) -> PhysicsVector {
+        self.grid_to_world_point(block.map(|v| v as f32 + 0.5))
+    }
+}
+
+impl<ChunkUserData: Default + Send + Sync + 'static> GridWorld<ChunkUserData> {
+    /// Cut a region out of the terrain and turn it into a new block grid entity with a dynamic rigid body.
+    /// The most down-west-south block of the range becomes block 0x0x0 of the grid, so the grid starts out exactly
+    /// where the blocks were. Only loaded chunks are cut from.
+    pub fn terrain_to_grid(&mut self, range: &GlobalBlockRange) -> Entity {
+        let (near, _far) = range.get_near_and_far();
+        let locations: Vec<GlobalBlockCoordinate> = range.iter_locations_xyz().collect();
+
+        self.cut_grid(near, &locations)
+    }
+
+    /// Cut a set of blocks out of the terrain and turn them into a new block grid entity with a dynamic rigid body.
+    /// The grid is placed so that the blocks start out exactly where they were. Only loaded chunks are cut from.
+    pub fn blocks_to_grid(&mut self, locations: &[GlobalBlockCoordinate]) -> Entity {
+        let near = locations
+            .iter()
+            .fold(None, |near: Option<GlobalBlockCoordinate>, location| match near {
+                Some(near) => Some(near.inf(location)),
+                None => Some(*location),
+            })
+            .unwrap_or_else(GlobalBlockCoordinate::zeros);
+
+        self.cut_grid(near, locations)
+    }
+
+    fn cut_grid(&mut self, near: GlobalBlockCoordinate, locations: &[GlobalBlockCoordinate]) -> Entity {
+        let block_masses = self.block_masses();
+
+        let mut grid = BlockGrid::<ChunkUserData>::new();
+        for location in locations.iter() {
+            if let Some(block) = self.get_block_mut(*location).and_then(|block| block.take()) {
+                grid.set_block(location - near, Some(block), &block_masses);
+            }
+        }
+
+        let near = self.ecs_resources.get::<FloatingOrigin>().expect("Failed to find floating origin.").block_to_physics(near);
+        let mut rigid_body = RigidBodyBuilder::new_dynamic().translation(near.x, near.y, near.z).build();
+        rigid_body.set_mass_properties(grid.mass_properties().to_rapier(), true);
+        let rigid_body = RigidBody::new(self.ecs_resources_mut(), rigid_body);
+
+        self.ecs_world_mut().push((grid, rigid_body))
+    }
+
+    /// Set a block in a grid entity, in grid space, and update the mass of its rigid body to match.
+    /// Returns the block that was there before.
+    pub fn set_grid_block(
+        &mut self, entity: Entity, location: GlobalBlockCoordinate, block: Option<BlockID>,
+    ) -> BlockGridResult<Option<BlockID>> {
+        // Only the old and new block are needed, so there's no point in building the whole table of block masses.
+        let block_masses = RegistryBlockMasses::new(self.chunk_provider.block_registry(), &self.material_registry);
+
+        let mut entry = self.ecs_world.entry_mut(entity).map_err(|_| BlockGridError::NotABlockGrid)?;
+        let handle = entry.get_component::<RigidBody>().map_err(|_| BlockGridError::NotABlockGrid)?.handle();
+        let grid = entry.get_component_mut::<BlockGrid<ChunkUserData>>().map_err(|_| BlockGridError::NotABlockGrid)?;
+
+        let old_block = grid.set_block(location, block, &block_masses);
+
+        let mut rigid_bodies = self.ecs_resources.get_mut::<RigidBodySet>().expect("Failed to find rigid body set.");
+        let rigid_body = rigid_bodies.get_mut(handle).ok_or(BlockGridError::NotABlockGrid)?;
+        rigid_body.set_mass_properties(grid.mass_properties().to_rapier(), true);
+
+        Ok(old_block)
+    }
+
+    /// Place the blocks of a grid back into the terrain, snapping each block to the terrain block its center falls in.
+    /// The grid entity and its rigid body are deleted afterwards. If any block would land in unloaded or occupied terrain,
+    /// nothing is changed and an error is returned.
+    pub fn grid_to_terrain(&mut self, entity: Entity) -> BlockGridResult<()> {
+        let placements: Vec<(GlobalBlockCoordinate, BlockID)> = {
+            let entry = self.ecs_world.entry_ref(entity).map_err(|_| BlockGridError::NotABlockGrid)?;
+            let grid = entry.get_component::<BlockGrid<ChunkUserData>>().map_err(|_| BlockGridError::NotABlockGrid)?;
+            let rigid_body = entry.get_component::<RigidBody>().map_err(|_| BlockGridError::NotABlockGrid)?;
+
+            let rigid_bodies = self.ecs_resources.get::<RigidBodySet>().expect("Failed to find rigid body set.");
+            let transform = GridTransform::from_rigid_body(rigid_body, &rigid_bodies).ok_or(BlockGridError::NotABlockGrid)?;
+
+            let floating_origin = self.ecs_resources.get::<FloatingOrigin>().expect("Failed to find floating origin.");
+
+            grid.iter_blocks()
+                .map(|(location, block)| {
+                    (floating_origin.physics_to_block(transform.grid_block_center_to_world(location)), block)
+                })
+                .collect()
+        };
+
+        for (location, _block) in placements.iter() {
+            match self.get_block(*location) {
+                None => return Err(BlockGridError::ChunkNotLoaded),
+                Some(Some(_)) => return Err(BlockGridError::Obstructed),
+                Some(None) => {}
+            }
+        }
+
+        for (location, block) in placements {
+            if let Some(terrain_block) = self.get_block_mut(location) {
+                *terrain_block = Some(block);
+            }
+        }
+
+        self.remove_entity(entity);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+
+    /// Edits in grid space, including ones in negative chunks.
+    #[test]
+    fn edit_blocks() {
+        let block = BlockID::new(std::num::NonZeroU16::new(1).unwrap());
+        let block_masses = BlockMasses::default();
+        let mut grid: BlockGrid<()> = BlockGrid::new();
+
+        assert_eq!(grid.set_block(GlobalBlockCoordinate::new(0, 0, 0), Some(block), &block_masses), None);
+        assert_eq!(grid.set_block(GlobalBlockCoordinate::new(-40, 2, 100), Some(block), &block_masses), None);
+        assert_eq!(grid.get_block(GlobalBlockCoordinate::new(-40, 2, 100)), Some(block));
+        assert_eq!(grid.get_block(GlobalBlockCoordinate::new(1, 0, 0)), None);
+        assert_eq!(grid.num_blocks(), 2);
+
+        assert_eq!(grid.set_block(GlobalBlockCoordinate::new(0, 0, 0), None, &block_masses), Some(block));
+        assert_eq!(grid.num_blocks(), 1);
+
+        // Setting air where there is no chunk shouldn't create one.
+        grid.set_block(GlobalBlockCoordinate::new(1000, 0, 0), None, &block_masses);
+        assert!(grid.get_chunk(&ChunkCoordinate::new(31, 0, 0)).is_none());
+    }
+
+    /// Points should survive a trip to grid space and back.
+    #[test]
+    fn transforms() {
+        let transform = GridTransform::new(Isometry3::new(
+            PhysicsVector::new(10.0, 0.0, 0.0),
+            PhysicsVector::new(0.0, std::f32::consts::FRAC_PI_2, 0.0),
+        ));
+
+        let point = PhysicsVector::new(1.0, 2.0, 3.0);
+        let round_trip = transform.world_to_grid_point(transform.grid_to_world_point(point));
+        assert!((round_trip - point).norm() < 0.0001);
+
+        // Rotated a quarter turn around Y, the grid's X axis points along the world's negative Z axis.
+        let center = transform.grid_block_center_to_world(GlobalBlockCoordinate::new(0, 0, 0));
+        assert!((center - PhysicsVector::new(10.5, 0.5, -0.5)).norm() < 0.0001);
+        assert_eq!(transform.world_to_grid_block(center), GlobalBlockCoordinate::new(0, 0, 0));
+    }
+
+    /// Cut a piece out of the terrain and put it back somewhere else.
+    #[test]
+    fn terrain_round_trip() {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-1, -1, -1), ChunkCoordinate::new(1, 1, 1)));
+        let abstract_block = *world.block_registry().get_block_id_from_name("abstract_block").unwrap();
+
+        let range =
+            GlobalBlockRange::from_end_points(GlobalBlockCoordinate::new(0, -2, 0), GlobalBlockCoordinate::new(2, 0, 2));
+        let entity = world.terrain_to_grid(&range);
+
+        assert_eq!(world.get_block(GlobalBlockCoordinate::new(0, -2, 0)), Some(None));
+        assert_eq!(world.get_block(GlobalBlockCoordinate::new(1, -1, 1)), Some(None));
+        assert_eq!(world.get_block(GlobalBlockCoordinate::new(2, -1, 0)), Some(Some(abstract_block)));
+
+        {
+            let entry = world.ecs_world().entry_ref(entity).unwrap();
+            let grid = entry.get_component::<BlockGrid<()>>().unwrap();
+            assert_eq!(grid.num_blocks(), 8);
+            assert_eq!(grid.get_block(GlobalBlockCoordinate::new(1, 1, 1)), Some(abstract_block));
+
+            let rigid_bodies = world.ecs_resources().get::<RigidBodySet>().unwrap();
+            let transform = GridTransform::from_rigid_body(entry.get_component::<RigidBody>().unwrap(), &rigid_bodies).unwrap();
+            assert_eq!(transform.world_to_grid_block(PhysicsVector::new(0.5, -1.5, 0.5)), GlobalBlockCoordinate::new(0, 0, 0));
+        }
+
+        // Putting it back into the ground should fail since something is already there.
+        {
+            let entry = world.ecs_world().entry_ref(entity).unwrap();
+            let handle = entry.get_component::<RigidBody>().unwrap().handle();
+            let mut rigid_bodies = world.ecs_resources_mut().get_mut::<RigidBodySet>().unwrap();
+            rigid_bodies.get_mut(handle).unwrap().set_position(Isometry3::translation(4.0, -2.0, 0.0), false);
+        }
+        assert!(matches!(world.grid_to_terrain(entity), Err(BlockGridError::Obstructed)));
+
+        // Lift it up into the air and it should go right in.
+        {
+            let entry = world.ecs_world().entry_ref(entity).unwrap();
+            let handle = entry.get_component::<RigidBody>().unwrap().handle();
+            let mut rigid_bodies = world.ecs_resources_mut().get_mut::<RigidBodySet>().unwrap();
+            rigid_bodies.get_mut(handle).unwrap().set_position(Isometry3::translation(4.0, 3.0, 0.0), false);
+        }
+        world.grid_to_terrain(entity).unwrap();
+
+        assert_eq!(world.get_block(GlobalBlockCoordinate::new(4, 3, 0)), Some(Some(abstract_block)));
+        assert_eq!(world.get_block(GlobalBlockCoordinate::new(5, 4, 1)), Some(Some(abstract_block)));
+        assert_eq!(world.get_block(GlobalBlockCoordinate::new(6, 3, 0)), Some(None));
+        assert!(world.ecs_world().entry_ref(entity).is_err());
+        assert!(world.ecs_resources().get::<RigidBodySet>().unwrap().is_empty());
+    }
+
+    /// Two identical ships, one of steel and one of aluminum, should not handle the same.
+    #[test]
+    fn material_mass() {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-1, -1, -1), ChunkCoordinate::new(1, 1, 1)));
+
+        let steel = world.material_registry_mut().register_material(String::from("steel"), 7850, 250000);
+        let aluminum = world.material_registry_mut().register_material(String::from("aluminum"), 2700, 80000);
+
+        let registry = world.block_registry_mut();
+        registry.add_block(String::from("steel_block"), String::from("Steel Block")).unwrap();
+        registry.add_block(String::from("aluminum_block"), String::from("Aluminum Block")).unwrap();
+        registry.set_block_material("steel_block", steel).unwrap();
+        registry.set_block_material("aluminum_block", aluminum).unwrap();
+        let steel_block = *registry.get_block_id_from_name("steel_block").unwrap();
+        let aluminum_block = *registry.get_block_id_from_name("aluminum_block").unwrap();
+
+        // Cutting out a piece of sky gets us an empty grid.
+        let empty_range =
+            GlobalBlockRange::from_end_points(GlobalBlockCoordinate::new(0, 10, 0), GlobalBlockCoordinate::new(1, 11, 1));
+        let steel_ship = world.terrain_to_grid(&empty_range);
+        let aluminum_ship = world.terrain_to_grid(&empty_range);
+
+        for x in 0..4 {
+            world.set_grid_block(steel_ship, GlobalBlockCoordinate::new(x, 0, 0), Some(steel_block)).unwrap();
+            world.set_grid_block(aluminum_ship, GlobalBlockCoordinate::new(x, 0, 0), Some(aluminum_block)).unwrap();
+        }
+
+        // Knock a block off the end and the center of mass should shift.
+        world.set_grid_block(steel_ship, GlobalBlockCoordinate::new(3, 0, 0), None).unwrap();
+
+        let handle_of = |world: &GridWorld<()>, entity| {
+            world.ecs_world().entry_ref(entity).unwrap().get_component::<RigidBody>().unwrap().handle()
+        };
+        let steel_handle = handle_of(&world, steel_ship);
+        let aluminum_handle = handle_of(&world, aluminum_ship);
+
+        let mut rigid_bodies = world.ecs_resources_mut().get_mut::<RigidBodySet>().unwrap();
+
+        let steel_body = rigid_bodies.get_mut(steel_handle).unwrap();
+        assert!((steel_body.mass() - 7850.0 * 3.0).abs() < 0.1);
+        assert!((steel_body.mass_properties().local_com - Point3::new(1.5, 0.5, 0.5)).norm() < 0.001);
+        steel_body.apply_impulse(PhysicsVector::new(0.0, 0.0, 10000.0), true);
+        let steel_speed = steel_body.linvel().norm();
+
+        let aluminum_body = rigid_bodies.get_mut(aluminum_handle).unwrap();
+        assert!((aluminum_body.mass() - 2700.0 * 4.0).abs() < 0.1);
+        assert!((aluminum_body.mass_properties().local_com - Point3::new(2.0, 0.5, 0.5)).norm() < 0.001);
+        aluminum_body.apply_impulse(PhysicsVector::new(0.0, 0.0, 10000.0), true);
+        let aluminum_speed = aluminum_body.linvel().norm();
+
+        assert!(aluminum_speed > steel_speed);
+    }
+}
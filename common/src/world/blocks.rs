@@ -3,6 +3,7 @@
 
 //! Stuff relating to terrain blocks.
 
+use super::inventory::MaterialID;
 use derive_error::Error;
 use serde::{Deserialize, Serialize};
 use std::{
@@ -27,6 +28,9 @@ pub enum RegistryError {
     /// This error happens if you attempt to add an item to the registry with the same key as an item
     /// already in the registry.
     KeyAlreadyExists,
+
+    /// This error happens if you attempt to modify an item that isn't in the registry.
+    KeyNotFound,
 }
 
 /// Meta data used to describe a block.
@@ -36,6 +40,29 @@ pub struct BlockData {
     name: String,
     id: BlockID,
     display_text: String, // TODO grab this from a translation table?
+    material: Option<MaterialID>,
+}
+
+impl BlockData {
+    /// Get the name of the block.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the ID of the block.
+    pub fn id(&self) -> BlockID {
+        self.id
+    }
+
+    /// Get the name players see.
+    pub fn display_text(&self) -> &str {
+        &self.display_text
+    }
+
+    /// Get the material the block is made of. Blocks that aren't made of a material have no mass.
+    pub fn material(&self) -> Option<MaterialID> {
+        self.material
+    }
 }
 
 impl fmt::Display for BlockData {
@@ -47,6 +74,12 @@ impl fmt::Display for BlockData {
 
 type RegistryResult<O> = std::result::Result<O, RegistryError>;
 
+impl Default for BlockRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BlockRegistry {
     /// Construct a new block registry.
     pub fn new() -> BlockRegistry {
@@ -60,7 +93,7 @@ impl BlockRegistry {
             let id = BlockID::new(NonZeroU16::new((self.block_data.len() + 1) as u16).expect("Generated invalid block ID."));
 
             self.block_ids.insert(name.clone(), id);
-            self.block_data.push(BlockData { name, id, display_text });
+            self.block_data.push(BlockData { name, id, display_text, material: None });
 
             Ok(())
         } else {
@@ -68,11 +101,19 @@ impl BlockRegistry {
         }
     }
 
+    /// Set the material a block is made of.
+    pub fn set_block_material(&mut self, name: &str, material: MaterialID) -> RegistryResult<()> {
+        let id = *self.block_ids.get(name).ok_or(RegistryError::KeyNotFound)?;
+        self.block_data[id.index()].material = Some(material);
+
+        Ok(())
+    }
+
     /// Get a block's data from its ID.
     #[inline]
     pub fn get_block_data_from_id(&self, id: BlockID) -> Option<&BlockData> {
         // We subtract one because that fits into our block data range.
-        self.block_data.get((id.id.get() - 1) as usize)
+        self.block_data.get(id.index())
     }
 
     /// Get the ID of a block from its name.
@@ -88,6 +129,11 @@ impl BlockRegistry {
         self.get_block_data_from_id(*id)
     }
 
+    /// Iterate over the data of every block, in order of their IDs.
+    pub fn iter_blocks(&self) -> impl Iterator<Item = &BlockData> {
+        self.block_data.iter()
+    }
+
     /// Get the number of different types of blocks.
     #[inline]
     pub fn num_block_types(&self) -> u16 {
@@ -96,7 +142,7 @@ impl BlockRegistry {
 }
 
 /// Represents the ID of a single block in a terrain chunk.
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord, Hash)]
 pub struct BlockID {
     id: NonZeroU16,
 }
@@ -108,6 +154,11 @@ impl BlockID {
     pub fn new(id: NonZeroU16) -> BlockID {
         BlockID { id }
     }
+
+    /// The index of the block's data in the registry.
+    pub(crate) fn index(&self) -> usize {
+        (self.id.get() - 1) as usize
+    }
 }
 
 #[cfg(test)]
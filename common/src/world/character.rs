@@ -0,0 +1,759 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! A kinematic character controller, for players and anything else that walks around on the terrain.
+//! Characters are boxes that are moved one axis at a time, so they slide along walls instead of sticking to them.
+//! Everything is computed from the fixed step and the character's input, so a client and server that agree on the
+//! inputs agree on where the character ends up.
+
+use super::{
+    components::{RigidBody, Transform},
+    GlobalBlockCoordinate, GridWorld, PhysicsGlobalConstants, PhysicsVector,
+};
+use legion::{system, world::SubWorld, Entity, IntoQuery, Query};
+use nalgebra::{Isometry3, Vector2, Vector3};
+use rapier3d::{
+    dynamics::{RigidBodyBuilder, RigidBodyHandle, RigidBodySet},
+    geometry::{Collider, ColliderBuilder, ColliderHandle, ColliderSet, InteractionGroups, SharedShape},
+    pipeline::QueryPipeline,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// How far characters keep from whatever they're touching. Without it, a character standing on the ground would be
+/// touching it, and couldn't tell that from running into it.
+const SKIN: f32 = 0.005;
+
+/// Slop for deciding if a character and a block are side by side or overlapping.
+const EPSILON: f32 = 1.0e-4;
+
+/// How a character moves.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CharacterSettings {
+    /// Half of the width of the character, in meters. Characters are square from above.
+    pub radius: f32,
+
+    /// How tall the character is when standing.
+    pub standing_height: f32,
+
+    /// How tall the character is when crouching.
+    pub crouching_height: f32,
+
+    /// How fast the character walks, in meters per second.
+    pub walk_speed: f32,
+
+    /// How fast the character sprints.
+    pub sprint_speed: f32,
+
+    /// How fast the character moves while crouching.
+    pub crouch_speed: f32,
+
+    /// How fast the character is moving upward when it jumps.
+    pub jump_speed: f32,
+
+    /// How quickly the character speeds up as it falls, in meters per second squared.
+    pub gravity: f32,
+
+    /// The fastest the character can fall.
+    pub max_fall_speed: f32,
+
+    /// How quickly the character gets up to speed or stops on the ground, in meters per second squared.
+    pub ground_acceleration: f32,
+
+    /// How quickly the character can change direction in the air.
+    pub air_acceleration: f32,
+
+    /// The tallest ledge the character can walk up without jumping.
+    pub step_height: f32,
+}
+
+impl Default for CharacterSettings {
+    fn default() -> Self {
+        // Enough to step onto a single block and jump onto one, but not two.
+        CharacterSettings {
+            radius: 0.3,
+            standing_height: 1.8,
+            crouching_height: 1.5,
+            walk_speed: 4.3,
+            sprint_speed: 5.6,
+            crouch_speed: 1.3,
+            jump_speed: 7.0,
+            gravity: 20.0,
+            max_fall_speed: 50.0,
+            ground_acceleration: 50.0,
+            air_acceleration: 10.0,
+            step_height: 1.05,
+        }
+    }
+}
+
+/// What a character is trying to do. Whatever controls the character, such as a player's controls or an AI, fills
+/// this in, and the character controller takes care of the rest during the next step.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct CharacterInput {
+    /// Which way to move, relative to where the character is facing. X is to the right and Y is forward. Anything
+    /// longer than one is shortened to one.
+    pub movement: Vector2<f32>,
+
+    /// Which way the character is facing, in radians around the up axis. Zero faces toward negative Z.
+    pub yaw: f32,
+
+    /// Jump, if standing on something.
+    pub jump: bool,
+
+    /// Crouch, or keep crouching.
+    pub crouch: bool,
+
+    /// Sprint. Has no effect while crouching.
+    pub sprint: bool,
+}
+
+/// Moves an entity around like a character, from its [CharacterInput]. The entity needs a kinematic [RigidBody], whose
+/// position is the bottom center of the character. Use [GridWorld::spawn_character] to set all of that up.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CharacterController {
+    settings: CharacterSettings,
+    velocity: PhysicsVector,
+    grounded: bool,
+    crouching: bool,
+    collider: Option<ColliderHandle>,
+}
+
+impl CharacterController {
+    /// Create a character controller for a standing character.
+    pub fn new(settings: CharacterSettings) -> CharacterController {
+        CharacterController { settings, velocity: PhysicsVector::zeros(), grounded: false, crouching: false, collider: None }
+    }
+
+    /// How the character moves.
+    pub fn settings(&self) -> &CharacterSettings {
+        &self.settings
+    }
+
+    /// Change how the character moves.
+    pub fn settings_mut(&mut self) -> &mut CharacterSettings {
+        &mut self.settings
+    }
+
+    /// How fast the character is moving, in meters per second.
+    pub fn velocity(&self) -> PhysicsVector {
+        self.velocity
+    }
+
+    /// Check if the character is standing on something.
+    pub fn is_grounded(&self) -> bool {
+        self.grounded
+    }
+
+    /// Check if the character is crouching.
+    pub fn is_crouching(&self) -> bool {
+        self.crouching
+    }
+
+    /// How tall the character is right now.
+    pub fn height(&self) -> f32 {
+        if self.crouching {
+            self.settings.crouching_height
+        } else {
+            self.settings.standing_height
+        }
+    }
+
+    /// Half of the size of the character's box.
+    fn half_extents(&self, height: f32) -> PhysicsVector {
+        PhysicsVector::new(self.settings.radius, height / 2.0, self.settings.radius)
+    }
+}
+
+/// The unit vector for an axis.
+fn axis_vector(axis: usize) -> PhysicsVector {
+    let mut vector = PhysicsVector::zeros();
+    vector[axis] = 1.0;
+
+    vector
+}
+
+/// How far beyond a character's box [CharacterTerrain] looks, at most. Anything further than this counts as solid, so
+/// a character going faster than that gets stopped short instead of passing through the terrain.
+const MAX_TERRAIN_REACH: f32 = 16.0;
+
+/// The solid cells of physics space around each character. The world gathers them from its terrain at the start of
+/// every step, so that [ecs_move_characters] can move the characters without needing the world's chunks.
+#[derive(Debug, Default)]
+pub struct CharacterTerrain {
+    solid: HashSet<Vector3<i64>>,
+    gathered: Vec<(Vector3<i64>, Vector3<i64>)>,
+}
+
+impl CharacterTerrain {
+    /// Check if a cell of physics space is solid. Cells that weren't gathered count as solid, the same as terrain that
+    /// isn't loaded.
+    fn is_solid(&self, cell: &Vector3<i64>) -> bool {
+        self.solid.contains(cell)
+            || !self.gathered.iter().any(|(low, high)| (0..3).all(|axis| cell[axis] >= low[axis] && cell[axis] <= high[axis]))
+    }
+
+    /// Find the cells within a box that have solid blocks in them.
+    fn solid_cells(&self, mins: PhysicsVector, maxs: PhysicsVector) -> Vec<Vector3<i64>> {
+        let low = mins.map(|v| v.floor() as i64);
+        let high = maxs.map(|v| v.floor() as i64);
+
+        let mut cells = Vec::new();
+        for x in low.x..=high.x {
+            for y in low.y..=high.y {
+                for z in low.z..=high.z {
+                    let cell = Vector3::new(x, y, z);
+                    if self.is_solid(&cell) {
+                        cells.push(cell);
+                    }
+                }
+            }
+        }
+
+        cells
+    }
+}
+
+impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
+    /// Spawn a character with its feet at a point in physics space. It gets a kinematic rigid body with a box collider,
+    /// a [Transform], a [CharacterController] and a [CharacterInput].
+    pub fn spawn_character(&mut self, feet: PhysicsVector, settings: CharacterSettings) -> Entity {
+        let mut controller = CharacterController::new(settings);
+        let half_extents = controller.half_extents(controller.height());
+
+        let rigid_body = RigidBody::new(
+            self.ecs_resources_mut(),
+            RigidBodyBuilder::new_kinematic().translation(feet.x, feet.y, feet.z).build(),
+        );
+        controller.collider = Some(
+            rigid_body.add_collider(
+                ColliderBuilder::cuboid(half_extents.x, half_extents.y, half_extents.z)
+                    .translation(0.0, half_extents.y, 0.0)
+                    .build(),
+                self.ecs_resources_mut(),
+            ),
+        );
+
+        let position = Isometry3::translation(feet.x, feet.y, feet.z);
+        self.ecs_world.push((rigid_body, Transform::new(position), controller, CharacterInput::default()))
+    }
+
+    /// Gather the terrain every character could run into this step into the [CharacterTerrain]. This is done at the
+    /// start of every step, before the systems run.
+    pub(super) fn gather_character_terrain(&mut self) {
+        let dt = self.simulation_settings.step_duration().as_secs_f32();
+        let physics_origin = self.physics_origin();
+        let mut terrain = CharacterTerrain::default();
+
+        {
+            let rigid_bodies = self.ecs_resources.get::<RigidBodySet>().expect("Failed to find rigid body set.");
+            for (rigid_body, controller) in <(&RigidBody, &CharacterController)>::query().iter(&self.ecs_world) {
+                let feet = match rigid_bodies.get(rigid_body.handle()) {
+                    Some(body) => body.position().translation.vector,
+                    None => continue,
+                };
+
+                // As far as the character could possibly get this step, stepping up included.
+                let settings = &controller.settings;
+                let speed = controller.velocity.norm()
+                    + settings.sprint_speed.max(settings.walk_speed).max(settings.crouch_speed)
+                    + settings.jump_speed
+                    + (settings.ground_acceleration.max(settings.air_acceleration) + settings.gravity) * dt;
+                let reach = (speed * dt + settings.step_height + SKIN * 2.0).min(MAX_TERRAIN_REACH);
+                let reach = if reach.is_finite() { reach.max(0.0) } else { 0.0 };
+
+                let height = settings.standing_height.max(settings.crouching_height);
+                let mins = feet - PhysicsVector::new(settings.radius, 0.0, settings.radius).add_scalar(reach);
+                let maxs = feet + PhysicsVector::new(settings.radius, height, settings.radius).add_scalar(reach);
+                let low = mins.map(|v| v.floor() as i64);
+                let high = maxs.map(|v| v.floor() as i64);
+                if (0..3).any(|axis| high[axis] - low[axis] > (MAX_TERRAIN_REACH as i64 + 1) * 4) {
+                    // The character itself is absurdly big. Everything around it counts as solid.
+                    continue;
+                }
+
+                for x in low.x..=high.x {
+                    for y in low.y..=high.y {
+                        for z in low.z..=high.z {
+                            let cell = Vector3::new(x, y, z);
+                            let location: GlobalBlockCoordinate = cell + physics_origin;
+
+                            if !matches!(self.get_block(location), Some(None)) {
+                                terrain.solid.insert(cell);
+                            }
+                        }
+                    }
+                }
+                terrain.gathered.push((low, high));
+            }
+        }
+
+        *self.ecs_resources.get_mut::<CharacterTerrain>().expect("Failed to find character terrain.") = terrain;
+    }
+}
+
+/// Moves every character according to its input, before physics.
+#[system]
+pub(super) fn ecs_move_characters(
+    world: &mut SubWorld, characters: &mut Query<(&RigidBody, &mut CharacterController, &CharacterInput)>,
+    #[resource] terrain: &CharacterTerrain, #[resource] constants: &PhysicsGlobalConstants,
+    #[resource] query_pipeline: &QueryPipeline, #[resource] rigid_bodies: &mut RigidBodySet,
+    #[resource] colliders: &mut ColliderSet,
+) {
+    let dt = constants.integration_parameters.dt;
+
+    characters.for_each_mut(world, |(rigid_body, controller, input)| {
+        let handle = rigid_body.handle();
+        let feet = match rigid_bodies.get(handle) {
+            Some(body) => body.position().translation.vector,
+            None => return,
+        };
+
+        let was_crouching = controller.crouching;
+        let surroundings = Surroundings { terrain, query_pipeline, colliders: &*colliders, body: handle };
+        let feet = surroundings.move_character(feet, controller, input, dt);
+
+        if let Some(body) = rigid_bodies.get_mut(handle) {
+            body.set_next_kinematic_position(Isometry3::translation(feet.x, feet.y, feet.z));
+        }
+
+        if controller.crouching != was_crouching {
+            if let Some(collider) = controller.collider.and_then(|collider| colliders.get_mut(collider)) {
+                let half_extents = controller.half_extents(controller.height());
+                collider.set_shape(SharedShape::cuboid(half_extents.x, half_extents.y, half_extents.z));
+                collider.set_position_wrt_parent(Isometry3::translation(0.0, half_extents.y, 0.0));
+            }
+        }
+    });
+}
+
+/// Everything a character can run into: the terrain, and the colliders of every body but its own.
+struct Surroundings<'a> {
+    terrain: &'a CharacterTerrain,
+    query_pipeline: &'a QueryPipeline,
+    colliders: &'a ColliderSet,
+    body: RigidBodyHandle,
+}
+
+impl<'a> Surroundings<'a> {
+    /// Work out where a character goes this step, and update its velocity and state along the way.
+    fn move_character(
+        &self, mut feet: PhysicsVector, controller: &mut CharacterController, input: &CharacterInput, dt: f32,
+    ) -> PhysicsVector {
+        let settings = controller.settings;
+
+        // Standing back up needs room overhead.
+        if input.crouch {
+            controller.crouching = true;
+        } else if controller.crouching && !self.character_obstructed(feet, controller.half_extents(settings.standing_height)) {
+            controller.crouching = false;
+        }
+
+        let speed = if controller.crouching {
+            settings.crouch_speed
+        } else if input.sprint {
+            settings.sprint_speed
+        } else {
+            settings.walk_speed
+        };
+
+        let movement = if input.movement.x.is_finite() && input.movement.y.is_finite() {
+            input.movement.cap_magnitude(1.0)
+        } else {
+            Vector2::zeros()
+        };
+        let yaw = if input.yaw.is_finite() { input.yaw } else { 0.0 };
+        let (sin, cos) = yaw.sin_cos();
+        let forward = PhysicsVector::new(-sin, 0.0, -cos);
+        let right = PhysicsVector::new(cos, 0.0, -sin);
+        let target = (right * movement.x + forward * movement.y) * speed;
+
+        let acceleration = if controller.grounded { settings.ground_acceleration } else { settings.air_acceleration };
+        let horizontal = PhysicsVector::new(controller.velocity.x, 0.0, controller.velocity.z);
+        let horizontal = horizontal + (target - horizontal).cap_magnitude(acceleration * dt);
+
+        let mut vertical = controller.velocity.y;
+        if controller.grounded && input.jump {
+            vertical = settings.jump_speed;
+            controller.grounded = false;
+        }
+        vertical = (vertical - settings.gravity * dt).max(-settings.max_fall_speed);
+
+        let mut velocity = PhysicsVector::new(horizontal.x, vertical, horizontal.z);
+        let motion = velocity * dt;
+        let half_extents = controller.half_extents(controller.height());
+
+        // Walk, then see if stepping up onto something would get further.
+        let (mut walked, mut blocked) = self.slide_character(feet, half_extents, motion);
+        if controller.grounded && blocked.iter().any(|blocked| *blocked) {
+            let up = self.sweep_character(feet, half_extents, 1, settings.step_height);
+            let (stepped, step_blocked) = self.slide_character(feet + PhysicsVector::new(0.0, up, 0.0), half_extents, motion);
+            let down = self.sweep_character(stepped, half_extents, 1, -up);
+
+            let progress = |to: PhysicsVector| Vector2::new(to.x - feet.x, to.z - feet.z).norm();
+            if progress(stepped) > progress(walked) + EPSILON {
+                walked = stepped + PhysicsVector::new(0.0, down, 0.0);
+                blocked = step_blocked;
+            }
+        }
+        feet = walked;
+
+        // Running into a wall stops movement into it.
+        if blocked[0] {
+            velocity.x = 0.0;
+        }
+        if blocked[1] {
+            velocity.z = 0.0;
+        }
+
+        let fallen = self.sweep_character(feet, half_extents, 1, motion.y);
+        feet.y += fallen;
+        if (fallen - motion.y).abs() > EPSILON {
+            // Hit the ground or the ceiling.
+            controller.grounded = motion.y < 0.0;
+            velocity.y = 0.0;
+        } else {
+            controller.grounded = false;
+        }
+
+        controller.velocity = velocity;
+        feet
+    }
+
+    /// Move a character horizontally, one axis at a time so that it slides along whatever it runs into. Returns where
+    /// it ended up, and whether it was stopped along X and Z.
+    fn slide_character(
+        &self, mut feet: PhysicsVector, half_extents: PhysicsVector, motion: PhysicsVector,
+    ) -> (PhysicsVector, [bool; 2]) {
+        let mut blocked = [false; 2];
+
+        for (index, axis) in [0, 2].iter().enumerate() {
+            let moved = self.sweep_character(feet, half_extents, *axis, motion[*axis]);
+            blocked[index] = (moved - motion[*axis]).abs() > EPSILON;
+            feet[*axis] += moved;
+        }
+
+        (feet, blocked)
+    }
+
+    /// Move a character's box along a single axis as far as it can go, up to `amount`, which can be negative.
+    /// Returns how far it got. The character ends up [SKIN] away from whatever stopped it.
+    fn sweep_character(&self, feet: PhysicsVector, half_extents: PhysicsVector, axis: usize, amount: f32) -> f32 {
+        if amount == 0.0 {
+            return 0.0;
+        }
+
+        let center = feet + PhysicsVector::new(0.0, half_extents.y, 0.0);
+        let mins = center - half_extents;
+        let maxs = center + half_extents;
+        let direction = amount.signum();
+        let mut allowed = amount.abs();
+
+        // Look a little further than the character is going, to keep its distance from what's just out of reach.
+        let mut swept_mins = mins;
+        let mut swept_maxs = maxs;
+        if direction > 0.0 {
+            swept_maxs[axis] += allowed + SKIN;
+        } else {
+            swept_mins[axis] -= allowed + SKIN;
+        }
+
+        for cell in self.terrain.solid_cells(swept_mins, swept_maxs) {
+            let block_mins = cell.map(|v| v as f32);
+            let block_maxs = block_mins.add_scalar(1.0);
+
+            let beside = (0..3)
+                .filter(|other| *other != axis)
+                .all(|other| mins[other] < block_maxs[other] - EPSILON && maxs[other] > block_mins[other] + EPSILON);
+            if !beside {
+                continue;
+            }
+
+            let gap = if direction > 0.0 { block_mins[axis] - maxs[axis] } else { mins[axis] - block_maxs[axis] };
+
+            // Blocks the character is already stuck in don't stop it, so that it can get back out.
+            if gap >= -EPSILON {
+                allowed = allowed.min((gap - SKIN).max(0.0));
+            }
+        }
+
+        let shape = SharedShape::cuboid(half_extents.x, half_extents.y, half_extents.z);
+        let position = Isometry3::translation(center.x, center.y, center.z);
+        let hit = self.query_pipeline.cast_shape(
+            self.colliders,
+            &position,
+            &(axis_vector(axis) * direction),
+            &*shape,
+            allowed,
+            InteractionGroups::all(),
+            Some(&|_handle, collider: &Collider| self.can_hit(collider)),
+        );
+        if let Some((_collider, toi)) = hit {
+            allowed = allowed.min((toi.toi - SKIN).max(0.0));
+        }
+
+        allowed * direction
+    }
+
+    /// Check if a character's box would overlap the terrain or another body.
+    fn character_obstructed(&self, feet: PhysicsVector, half_extents: PhysicsVector) -> bool {
+        let center = feet + PhysicsVector::new(0.0, half_extents.y, 0.0);
+        let mins = center - half_extents;
+        let maxs = center + half_extents;
+
+        let terrain = self.terrain.solid_cells(mins, maxs).into_iter().any(|cell| {
+            let block_mins = cell.map(|v| v as f32);
+            (0..3).all(|axis| mins[axis] < block_mins[axis] + 1.0 - EPSILON && maxs[axis] > block_mins[axis] + EPSILON)
+        });
+
+        let shape = SharedShape::cuboid(half_extents.x, half_extents.y, half_extents.z);
+        let position = Isometry3::translation(center.x, center.y, center.z);
+        let mut bodies = false;
+        self.query_pipeline.intersections_with_shape(
+            self.colliders,
+            &position,
+            &*shape,
+            InteractionGroups::all(),
+            Some(&|_handle, collider: &Collider| self.can_hit(collider)),
+            |_handle, _collider| {
+                bodies = true;
+                false
+            },
+        );
+
+        terrain || bodies
+    }
+
+    /// Check if a collider gets in the character's way. Sensors and the character's own collider don't.
+    fn can_hit(&self, collider: &Collider) -> bool {
+        !collider.is_sensor() && collider.parent() != self.body
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use legion::EntityStore;
+    use std::{hash::Hasher, time::Duration};
+
+    /// Put a block of the same type as the ground somewhere.
+    fn place_block(world: &mut GridWorld<()>, location: GlobalBlockCoordinate) {
+        let ground = world.get_block(GlobalBlockCoordinate::new(0, -1, 0)).unwrap();
+        *world.get_block_mut(location).unwrap() = ground;
+    }
+
+    /// Set what a character is trying to do.
+    fn set_input(world: &mut GridWorld<()>, character: Entity, input: CharacterInput) {
+        *world.ecs_world_mut().entry(character).unwrap().get_component_mut::<CharacterInput>().unwrap() = input;
+    }
+
+    /// Where a character's feet are, and its controller.
+    fn character_state(world: &GridWorld<()>, character: Entity) -> (PhysicsVector, CharacterController) {
+        let entry = world.ecs_world().entry_ref(character).unwrap();
+        let rigid_body = entry.get_component::<RigidBody>().unwrap();
+        let rigid_bodies = world.ecs_resources().get::<RigidBodySet>().unwrap();
+
+        (
+            rigid_bodies.get(rigid_body.handle()).unwrap().position().translation.vector,
+            *entry.get_component::<CharacterController>().unwrap(),
+        )
+    }
+
+    /// Run the world for a while.
+    fn run(world: &mut GridWorld<()>, steps: u32) {
+        for _ in 0..steps {
+            world.update(Duration::from_millis(10));
+        }
+    }
+
+    /// Walking forward.
+    fn forward() -> CharacterInput {
+        CharacterInput { movement: Vector2::new(0.0, 1.0), ..CharacterInput::default() }
+    }
+
+    /// A character dropped onto the ground lands on it, and walks across it at walking speed.
+    #[test]
+    fn walk() {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-2, -1, -2), ChunkCoordinate::new(2, 1, 2)));
+        let character = world.spawn_character(PhysicsVector::new(0.5, 2.0, 0.5), CharacterSettings::default());
+
+        run(&mut world, 100);
+        let (feet, controller) = character_state(&world, character);
+        assert!(controller.is_grounded());
+        assert!(feet.y > 0.0 && feet.y < 0.01);
+
+        set_input(&mut world, character, forward());
+        run(&mut world, 100);
+        let (feet, controller) = character_state(&world, character);
+        assert!(controller.is_grounded());
+        assert!((controller.velocity().z + 4.3).abs() < 1.0e-3);
+        assert!(feet.z < -3.0 && feet.z > -4.3);
+        assert!((feet.x - 0.5).abs() < 1.0e-4);
+    }
+
+    /// Walking into a wall at an angle slides along it.
+    #[test]
+    fn wall_slide() {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-2, -1, -2), ChunkCoordinate::new(2, 1, 2)));
+        for x in -8..8 {
+            place_block(&mut world, GlobalBlockCoordinate::new(x, 0, -2));
+            place_block(&mut world, GlobalBlockCoordinate::new(x, 1, -2));
+        }
+
+        let character = world.spawn_character(PhysicsVector::new(0.5, 0.01, 0.5), CharacterSettings::default());
+        set_input(&mut world, character, CharacterInput { movement: Vector2::new(1.0, 1.0), ..CharacterInput::default() });
+        run(&mut world, 100);
+
+        let (feet, controller) = character_state(&world, character);
+        // The wall's face is at z = -1.
+        assert!((feet.z - (-1.0 + 0.3 + SKIN)).abs() < 1.0e-3);
+        assert!(feet.x > 2.0);
+        assert_eq!(controller.velocity().z, 0.0);
+    }
+
+    /// Characters walk up single blocks, but not two stacked up.
+    #[test]
+    fn step_up() {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-2, -1, -2), ChunkCoordinate::new(2, 1, 2)));
+        place_block(&mut world, GlobalBlockCoordinate::new(0, 0, -2));
+        place_block(&mut world, GlobalBlockCoordinate::new(3, 0, -2));
+        place_block(&mut world, GlobalBlockCoordinate::new(3, 1, -2));
+
+        let low = world.spawn_character(PhysicsVector::new(0.5, 0.01, 0.5), CharacterSettings::default());
+        let high = world.spawn_character(PhysicsVector::new(3.5, 0.01, 0.5), CharacterSettings::default());
+        set_input(&mut world, low, forward());
+        set_input(&mut world, high, forward());
+        run(&mut world, 50);
+
+        let (feet, _) = character_state(&world, low);
+        assert!(feet.z < -1.0);
+        assert!((feet.y - (1.0 + SKIN)).abs() < 1.0e-4);
+
+        let (feet, _) = character_state(&world, high);
+        assert!((feet.z - (-1.0 + 0.3 + SKIN)).abs() < 1.0e-3);
+        assert!(feet.y < 0.01);
+    }
+
+    /// Jumping goes up high enough to get onto a block, and comes back down.
+    #[test]
+    fn jump() {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-2, -1, -2), ChunkCoordinate::new(2, 1, 2)));
+        let character = world.spawn_character(PhysicsVector::new(0.5, 0.01, 0.5), CharacterSettings::default());
+        run(&mut world, 5);
+
+        set_input(&mut world, character, CharacterInput { jump: true, ..CharacterInput::default() });
+        let mut highest: f32 = 0.0;
+        for _ in 0..40 {
+            run(&mut world, 1);
+            set_input(&mut world, character, CharacterInput::default());
+            highest = highest.max(character_state(&world, character).0.y);
+        }
+        run(&mut world, 100);
+
+        assert!(highest > 1.0 && highest < 1.5);
+        let (feet, controller) = character_state(&world, character);
+        assert!(controller.is_grounded());
+        assert!(feet.y < 0.01);
+    }
+
+    /// Sprinting is faster than walking, and crouching is slower. Characters can't stand up under a low ceiling.
+    #[test]
+    fn sprint_and_crouch() {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-2, -1, -2), ChunkCoordinate::new(2, 1, 2)));
+        let walker = world.spawn_character(PhysicsVector::new(0.5, 0.01, 0.5), CharacterSettings::default());
+        let sprinter = world.spawn_character(PhysicsVector::new(3.5, 0.01, 0.5), CharacterSettings::default());
+        // Short enough when crouched to fit under a single block.
+        let croucher = world.spawn_character(
+            PhysicsVector::new(6.5, 0.01, 0.5),
+            CharacterSettings { crouching_height: 0.9, ..CharacterSettings::default() },
+        );
+        set_input(&mut world, walker, forward());
+        set_input(&mut world, sprinter, CharacterInput { sprint: true, ..forward() });
+        set_input(&mut world, croucher, CharacterInput { crouch: true, ..forward() });
+        run(&mut world, 100);
+
+        let walked = character_state(&world, walker).0.z;
+        let sprinted = character_state(&world, sprinter).0.z;
+        let (crouched, controller) = character_state(&world, croucher);
+        assert!(sprinted < walked && walked < crouched.z);
+        assert!(controller.is_crouching());
+        assert_eq!(controller.height(), 0.9);
+
+        // Crouch under a ceiling that's too low to stand under, then try to stand up.
+        place_block(&mut world, GlobalBlockCoordinate::new(6, 1, -1));
+        place_block(&mut world, GlobalBlockCoordinate::new(6, 1, -2));
+        set_input(&mut world, croucher, CharacterInput::default());
+        run(&mut world, 10);
+        assert!(character_state(&world, croucher).1.is_crouching());
+
+        *world.get_block_mut(GlobalBlockCoordinate::new(6, 1, -1)).unwrap() = None;
+        *world.get_block_mut(GlobalBlockCoordinate::new(6, 1, -2)).unwrap() = None;
+        run(&mut world, 1);
+        assert!(!character_state(&world, croucher).1.is_crouching());
+    }
+
+    /// Characters run into other bodies instead of going through them.
+    #[test]
+    fn bodies() {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-2, -1, -2), ChunkCoordinate::new(2, 1, 2)));
+
+        // Too tall to step onto.
+        let pillar =
+            RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_static().translation(0.5, 1.0, -2.5).build());
+        pillar.add_collider(ColliderBuilder::cuboid(0.5, 1.0, 0.5).build(), world.ecs_resources_mut());
+        world.ecs_world_mut().push((pillar,));
+
+        let character = world.spawn_character(PhysicsVector::new(0.5, 0.01, 0.5), CharacterSettings::default());
+        set_input(&mut world, character, forward());
+        run(&mut world, 100);
+
+        let (feet, _) = character_state(&world, character);
+        assert!((feet.z - (-2.0 + 0.3 + SKIN)).abs() < 1.0e-3);
+    }
+
+    /// Hash where a character is and how it's moving, bit for bit, with FNV-1a.
+    fn state_hash(feet: PhysicsVector, controller: &CharacterController) -> u64 {
+        let velocity = controller.velocity();
+        let values = feet.iter().chain(velocity.iter()).map(|value| value.to_bits());
+        let flags = [controller.is_grounded() as u32, controller.is_crouching() as u32];
+
+        let mut hasher = Fnv1a::new();
+        for value in values.chain(flags.iter().copied()) {
+            hasher.write(&value.to_le_bytes());
+        }
+
+        hasher.finish()
+    }
+
+    /// A character given the same inputs always ends up in exactly the same place, the one it ended up in when this
+    /// test was written. A change to the hash means characters move differently than before, which breaks replays.
+    #[test]
+    fn deterministic() {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-2, -1, -2), ChunkCoordinate::new(2, 1, 2)));
+        place_block(&mut world, GlobalBlockCoordinate::new(1, 0, -3));
+        let character = world.spawn_character(PhysicsVector::new(0.5, 1.0, 0.5), CharacterSettings::default());
+
+        for step in 0..400u32 {
+            let input = CharacterInput {
+                movement: Vector2::new(((step / 40) as f32).sin(), 1.0),
+                yaw: step as f32 * 0.01,
+                jump: step % 90 == 45,
+                crouch: (200..240).contains(&step),
+                sprint: step > 300,
+            };
+            set_input(&mut world, character, input);
+            run(&mut world, 1);
+        }
+
+        let (feet, controller) = character_state(&world, character);
+        assert_eq!(format!("{:016x}", state_hash(feet, &controller)), "3d1cd7683648f20f");
+    }
+}
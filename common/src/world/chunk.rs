@@ -4,10 +4,12 @@
 //! All stuff relating to chunks, the big hunks of the world full of terrain.
 
 use super::{
+    chunk_providers::GenerationStage,
     coordinates::{ChunkCoordinate, LocalBlockCoordinate, LocalBlockCoordinateExt},
-    storage, BlockID, LocalBlockIterator, LocalBlockIteratorMut, LocalBlockRange,
+    storage, BlockID, ChunkTickSchedule, LocalBlockIterator, LocalBlockIteratorMut, LocalBlockRange,
 };
 use derive_error::Error;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::num::NonZeroU16;
 
 /// Error type for chunks.
@@ -20,16 +22,38 @@ pub enum ChunkError {
 /// A chunk error type.
 pub type ChunkResult<O> = std::result::Result<O, ChunkError>;
 
+/// Convert an index used for direct access of a chunk into the local coordinate of the block.
+pub(crate) fn block_index_to_local(index: usize) -> LocalBlockCoordinate {
+    LocalBlockCoordinate::new(
+        (index % storage::CHUNK_DIAMETER) as u8,
+        (index / storage::CHUNK_DIAMETER % storage::CHUNK_DIAMETER) as u8,
+        (index / (storage::CHUNK_DIAMETER * storage::CHUNK_DIAMETER)) as u8,
+    )
+}
+
 /// A chunk of the world's terrain.
 pub struct Chunk<UserData> {
     storage: Box<storage::ChunkData>,
+    scheduled_ticks: ChunkTickSchedule,
+    generation_stage: GenerationStage,
     user_data: UserData,
 }
 
 impl<UserData> Chunk<UserData> {
     /// Create a new, blank chunk.
     pub fn new(location: ChunkCoordinate, user_data: UserData) -> Chunk<UserData> {
-        Chunk { storage: storage::ChunkData::create(location), user_data }
+        Chunk {
+            storage: storage::ChunkData::create(location),
+            scheduled_ticks: ChunkTickSchedule::default(),
+            generation_stage: GenerationStage::Empty,
+            user_data,
+        }
+    }
+
+    /// Create a chunk from block data, such as a chunk loaded from a world save. The chunk is assumed to be fully
+    /// generated.
+    pub fn from_data(storage: Box<storage::ChunkData>, user_data: UserData) -> Chunk<UserData> {
+        Chunk { storage, scheduled_ticks: ChunkTickSchedule::default(), generation_stage: GenerationStage::COMPLETE, user_data }
     }
 
     /// Get the index of the chunk.
@@ -37,6 +61,11 @@ impl<UserData> Chunk<UserData> {
         self.storage.get_index()
     }
 
+    /// Get the raw block data of the chunk, such as to save it.
+    pub fn data(&self) -> &storage::ChunkData {
+        &self.storage
+    }
+
     /// Get a single block from the chunk.
     /// Do NOT use this to iterate. Use the proper iterators to do so.
     /// This will chop off out of range bits for coordinates extending beyond chunk bounds.
@@ -71,7 +100,7 @@ impl<UserData> Chunk<UserData> {
     pub fn direct_access(&self, index: usize) -> ChunkResult<Option<BlockID>> {
         let block_id = self.storage.get_data().get(index).ok_or(ChunkError::OutOfRange)?;
 
-        Ok(if let Some(block_id) = NonZeroU16::new(*block_id) { Some(BlockID::new(block_id)) } else { None })
+        Ok(NonZeroU16::new(*block_id).map(BlockID::new))
     }
 
     /// Used internally efficiently iterate the content of the chunk.
@@ -82,14 +111,14 @@ impl<UserData> Chunk<UserData> {
 
         // We have to transmute this to keep it a reference. It should be safe since an Option<BlockID>
         // is just a normal u16 where 0 represents none.
-        Ok(unsafe { std::mem::transmute(block_id) })
+        Ok(unsafe { std::mem::transmute::<&mut u16, &mut Option<BlockID>>(block_id) })
     }
 
     /// An ideal iterator for the chunk. This iterates in what is currently the most efficient way to iterate this chunk.
     /// The order in which blocks are iterated is subject to change at random, and may even be different each time you
     /// call this function.
     #[inline]
-    pub fn iter_ideal(&self, range: LocalBlockRange) -> LocalBlockIterator<UserData> {
+    pub fn iter_ideal(&self, range: LocalBlockRange) -> LocalBlockIterator<'_, UserData> {
         range.iter_xyz(self)
     }
 
@@ -97,7 +126,7 @@ impl<UserData> Chunk<UserData> {
     /// The order in which blocks are iterated is subject to change at random, and may even be different each time you
     /// call this function.
     #[inline]
-    pub fn iter_ideal_mut(&mut self, range: LocalBlockRange) -> LocalBlockIteratorMut<UserData> {
+    pub fn iter_ideal_mut(&mut self, range: LocalBlockRange) -> LocalBlockIteratorMut<'_, UserData> {
         range.iter_xyz_mut(self)
     }
 
@@ -105,7 +134,9 @@ impl<UserData> Chunk<UserData> {
     /// This is just nice for making code more readable.
     #[inline]
     pub fn range_all_blocks() -> LocalBlockRange {
-        LocalBlockRange::from_end_points(
+        // We can't use the end points here, since the far end point is just out of the range of the chunk and would be
+        // clipped by validation.
+        LocalBlockRange::from_root_and_size(
             LocalBlockCoordinate::new(0, 0, 0),
             LocalBlockCoordinate::new(
                 storage::CHUNK_DIAMETER as u8,
@@ -115,6 +146,30 @@ impl<UserData> Chunk<UserData> {
         )
     }
 
+    /// Get the ticks scheduled for blocks in this chunk.
+    #[inline]
+    pub fn scheduled_ticks(&self) -> &ChunkTickSchedule {
+        &self.scheduled_ticks
+    }
+
+    /// Get the ticks scheduled for blocks in this chunk mutably.
+    #[inline]
+    pub fn scheduled_ticks_mut(&mut self) -> &mut ChunkTickSchedule {
+        &mut self.scheduled_ticks
+    }
+
+    /// How far along the chunk is in being generated.
+    #[inline]
+    pub fn generation_stage(&self) -> GenerationStage {
+        self.generation_stage
+    }
+
+    /// Set how far along the chunk is in being generated. Used by chunk providers, and when loading a saved chunk.
+    #[inline]
+    pub fn set_generation_stage(&mut self, stage: GenerationStage) {
+        self.generation_stage = stage;
+    }
+
     /// Get a reference to the user data associated with this chunk.
     #[inline]
     pub fn user_data(&self) -> &UserData {
@@ -127,3 +182,56 @@ impl<UserData> Chunk<UserData> {
         &mut self.user_data
     }
 }
+
+/// What a chunk looks like when it's serialized. Blocks are kept as their raw IDs, the same way they're laid out in
+/// memory.
+#[derive(Serialize)]
+struct SerializedChunkRef<'a, UserData> {
+    index: ChunkCoordinate,
+    blocks: &'a [u16],
+    scheduled_ticks: &'a ChunkTickSchedule,
+    generation_stage: GenerationStage,
+    user_data: &'a UserData,
+}
+
+/// The owned version of [SerializedChunkRef], for deserializing.
+#[derive(Deserialize)]
+struct SerializedChunk<UserData> {
+    index: ChunkCoordinate,
+    blocks: Vec<u16>,
+    scheduled_ticks: ChunkTickSchedule,
+    generation_stage: GenerationStage,
+    user_data: UserData,
+}
+
+impl<UserData: Serialize> Serialize for Chunk<UserData> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedChunkRef {
+            index: self.index(),
+            blocks: self.storage.get_data(),
+            scheduled_ticks: &self.scheduled_ticks,
+            generation_stage: self.generation_stage,
+            user_data: &self.user_data,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, UserData: Deserialize<'de>> Deserialize<'de> for Chunk<UserData> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let serialized = SerializedChunk::deserialize(deserializer)?;
+
+        let mut storage = storage::ChunkData::create(serialized.index);
+        if serialized.blocks.len() != storage.get_data().len() {
+            return Err(de::Error::invalid_length(serialized.blocks.len(), &"a full chunk of blocks"));
+        }
+        storage.get_data_mut().copy_from_slice(&serialized.blocks);
+
+        Ok(Chunk {
+            storage,
+            scheduled_ticks: serialized.scheduled_ticks,
+            generation_stage: serialized.generation_stage,
+            user_data: serialized.user_data,
+        })
+    }
+}
@@ -0,0 +1,429 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Biomes, and the climate that decides where they go.
+//! Climate changes over thousands of blocks, so a single biome can be big enough that it's worth building a railway
+//! to get across it.
+
+use super::noise::{derive_seed, hash_2d, FractalNoise};
+use crate::world::GlobalBlockCoordinate;
+
+/// A key for looking up a biome in a [BiomeMap].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BiomeID(u16);
+
+impl BiomeID {
+    /// Where the biome is in the map's list of biomes.
+    pub(super) fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// The climate of a column of the world. Every field goes roughly from -1 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Climate {
+    /// Cold to hot.
+    pub temperature: f64,
+
+    /// Dry to wet.
+    pub humidity: f64,
+
+    /// Lowlands to highlands.
+    pub elevation: f64,
+}
+
+impl Climate {
+    fn distance_squared(&self, other: &Climate) -> f64 {
+        let temperature = self.temperature - other.temperature;
+        let humidity = self.humidity - other.humidity;
+        let elevation = self.elevation - other.elevation;
+
+        temperature * temperature + humidity * humidity + elevation * elevation
+    }
+}
+
+/// An enemy that can spawn in a biome.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnemySpawn {
+    name: String,
+    weight: u32,
+}
+
+impl EnemySpawn {
+    /// The name of the enemy.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How likely this enemy is to spawn compared to the others in the biome.
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+}
+
+/// Everything about a biome: where it goes, what it looks like, and what lives there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Biome {
+    name: String,
+    climate: Climate,
+    surface_block: String,
+    soil_block: String,
+    height_offset: f64,
+    roughness: f64,
+    soil_depth: i64,
+    enemies: Vec<EnemySpawn>,
+}
+
+impl Biome {
+    /// Create a biome. The surface and soil blocks are given by name, and will be added to the block registry by the
+    /// terrain generator if they don't exist yet.
+    pub fn new(name: &str, surface_block: &str, soil_block: &str) -> Biome {
+        Biome {
+            name: String::from(name),
+            climate: Climate { temperature: 0.0, humidity: 0.0, elevation: 0.0 },
+            surface_block: String::from(surface_block),
+            soil_block: String::from(soil_block),
+            height_offset: 0.0,
+            roughness: 1.0,
+            soil_depth: 4,
+            enemies: Vec::new(),
+        }
+    }
+
+    /// Set the climate this biome is found in.
+    pub fn with_climate(mut self, temperature: f64, humidity: f64, elevation: f64) -> Biome {
+        self.climate = Climate { temperature, humidity, elevation };
+        self
+    }
+
+    /// Set how the biome shapes the terrain. The height offset raises or lowers the ground, roughness scales the hills,
+    /// and the soil depth is how much soil is between the surface and the stone.
+    pub fn with_terrain(mut self, height_offset: f64, roughness: f64, soil_depth: i64) -> Biome {
+        self.height_offset = height_offset;
+        self.roughness = roughness;
+        self.soil_depth = soil_depth;
+        self
+    }
+
+    /// Add an enemy that can spawn in this biome.
+    pub fn with_enemy(mut self, name: &str, weight: u32) -> Biome {
+        self.enemies.push(EnemySpawn { name: String::from(name), weight });
+        self
+    }
+
+    /// The name of the biome.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The climate this biome is found in.
+    pub fn climate(&self) -> Climate {
+        self.climate
+    }
+
+    /// The name of the block on the surface.
+    pub fn surface_block(&self) -> &str {
+        &self.surface_block
+    }
+
+    /// The name of the block between the surface and the stone.
+    pub fn soil_block(&self) -> &str {
+        &self.soil_block
+    }
+
+    /// How far the biome raises or lowers the ground.
+    pub fn height_offset(&self) -> f64 {
+        self.height_offset
+    }
+
+    /// How much the biome scales the hills.
+    pub fn roughness(&self) -> f64 {
+        self.roughness
+    }
+
+    /// How many blocks of soil are between the surface and the stone.
+    pub fn soil_depth(&self) -> i64 {
+        self.soil_depth
+    }
+
+    /// The enemies that can spawn in this biome.
+    pub fn enemy_table(&self) -> &[EnemySpawn] {
+        &self.enemies
+    }
+
+    /// Pick an enemy from the table, using a random number. Returns None if nothing spawns here.
+    pub fn pick_enemy(&self, random: u64) -> Option<&EnemySpawn> {
+        let total_weight: u64 = self.enemies.iter().map(|enemy| enemy.weight as u64).sum();
+        if total_weight == 0 {
+            return None;
+        }
+
+        let mut roll = random % total_weight;
+        for enemy in self.enemies.iter() {
+            if roll < enemy.weight as u64 {
+                return Some(enemy);
+            }
+            roll -= enemy.weight as u64;
+        }
+
+        None
+    }
+}
+
+/// The terrain parameters of the biomes at a column, blended together near borders.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlendedTerrain {
+    /// How far the ground is raised or lowered.
+    pub height_offset: f64,
+
+    /// How much the hills are scaled.
+    pub roughness: f64,
+
+    /// How many blocks of soil are between the surface and the stone.
+    pub soil_depth: i64,
+}
+
+/// Decides what biome every column of the world is in.
+/// Temperature, humidity and elevation are each a field of very low frequency noise. Each column goes to the biome
+/// with the closest climate, and columns near a border get a blend of the biomes on either side.
+pub struct BiomeMap {
+    seed: u64,
+    temperature: FractalNoise,
+    humidity: FractalNoise,
+    elevation: FractalNoise,
+    biomes: Vec<Biome>,
+}
+
+impl BiomeMap {
+    /// How sharp the borders are. Higher numbers give narrower blending between biomes.
+    const BORDER_SHARPNESS: u32 = 4;
+
+    /// Climate differences are clamped to this so that a column right on a biome's ideal climate doesn't divide by zero.
+    const MIN_DISTANCE_SQUARED: f64 = 1.0e-6;
+
+    /// Create a biome map with no biomes. The climate changes over about `scale` blocks.
+    pub fn new(seed: u64, scale: f64) -> BiomeMap {
+        BiomeMap {
+            seed,
+            temperature: FractalNoise::new(derive_seed(seed, 0x7E4D), scale, 3),
+            humidity: FractalNoise::new(derive_seed(seed, 0x4A1D), scale, 3),
+            elevation: FractalNoise::new(derive_seed(seed, 0xE1E7), scale / 2.0, 4),
+            biomes: Vec::new(),
+        }
+    }
+
+    /// Create a biome map with a few basic biomes.
+    pub fn with_default_biomes(seed: u64) -> BiomeMap {
+        let mut map = BiomeMap::new(seed, 8192.0);
+
+        map.register_biome(Biome::new("plains", "grass", "soil").with_climate(0.0, 0.0, -0.2).with_terrain(0.0, 0.5, 4));
+        map.register_biome(
+            Biome::new("forest", "grass", "soil")
+                .with_climate(0.1, 0.5, 0.0)
+                .with_terrain(4.0, 1.0, 5)
+                .with_enemy("spider", 3)
+                .with_enemy("wolf", 1),
+        );
+        map.register_biome(
+            Biome::new("desert", "sand", "sand")
+                .with_climate(0.6, -0.6, -0.1)
+                .with_terrain(-4.0, 0.6, 8)
+                .with_enemy("scorpion", 1),
+        );
+        map.register_biome(Biome::new("tundra", "snow", "soil").with_climate(-0.6, 0.0, 0.0).with_terrain(2.0, 0.8, 2));
+        map.register_biome(
+            Biome::new("mountains", "stone", "stone")
+                .with_climate(-0.2, 0.0, 0.6)
+                .with_terrain(48.0, 2.5, 0)
+                .with_enemy("golem", 1),
+        );
+
+        map
+    }
+
+    /// Add a biome to the map.
+    pub fn register_biome(&mut self, biome: Biome) -> BiomeID {
+        let id = BiomeID(self.biomes.len() as u16);
+        self.biomes.push(biome);
+
+        id
+    }
+
+    /// The seed the climate is generated from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Get a biome from its ID.
+    pub fn get_biome(&self, id: BiomeID) -> Option<&Biome> {
+        self.biomes.get(id.0 as usize)
+    }
+
+    /// Get the ID of a biome from its name.
+    pub fn get_biome_id_from_name(&self, name: &str) -> Option<BiomeID> {
+        self.biomes.iter().position(|biome| biome.name == name).map(|index| BiomeID(index as u16))
+    }
+
+    /// Iterate over all the biomes.
+    pub fn iter_biomes(&self) -> impl Iterator<Item = (BiomeID, &Biome)> {
+        self.biomes.iter().enumerate().map(|(index, biome)| (BiomeID(index as u16), biome))
+    }
+
+    /// The number of biomes.
+    pub fn num_biomes(&self) -> usize {
+        self.biomes.len()
+    }
+
+    /// Get the climate of a column.
+    pub fn climate(&self, x: i64, z: i64) -> Climate {
+        let (x, z) = (x as f64, z as f64);
+
+        Climate {
+            temperature: self.temperature.sample_2d(x, z),
+            humidity: self.humidity.sample_2d(x, z),
+            elevation: self.elevation.sample_2d(x, z),
+        }
+    }
+
+    /// Get how much each biome contributes to a column. The weights add up to one.
+    pub fn biome_weights(&self, x: i64, z: i64) -> Vec<(BiomeID, f64)> {
+        let climate = self.climate(x, z);
+
+        let mut weights: Vec<(BiomeID, f64)> = self
+            .iter_biomes()
+            .map(|(id, biome)| {
+                let distance_squared = climate.distance_squared(&biome.climate).max(Self::MIN_DISTANCE_SQUARED);
+                // Multiplied out by hand, since powi isn't guaranteed to give the same answer on every platform.
+                let falloff = (0..Self::BORDER_SHARPNESS).fold(1.0, |falloff, _| falloff * distance_squared);
+                (id, 1.0 / falloff)
+            })
+            .collect();
+
+        let total: f64 = weights.iter().map(|(_id, weight)| weight).sum();
+        for (_id, weight) in weights.iter_mut() {
+            *weight /= total;
+        }
+
+        weights
+    }
+
+    /// Get the biome a block is in. Returns None if there are no biomes.
+    pub fn biome_at(&self, location: GlobalBlockCoordinate) -> Option<BiomeID> {
+        self.dominant_biome(location.x, location.z)
+    }
+
+    /// Get the biome that contributes the most to a column.
+    pub fn dominant_biome(&self, x: i64, z: i64) -> Option<BiomeID> {
+        self.biome_weights(x, z)
+            .into_iter()
+            .fold(None, |best: Option<(BiomeID, f64)>, (id, weight)| match best {
+                Some((_best_id, best_weight)) if best_weight >= weight => best,
+                _ => Some((id, weight)),
+            })
+            .map(|(id, _weight)| id)
+    }
+
+    /// Pick the biome whose blocks cover a column. Near borders the biomes are scattered together, in proportion to
+    /// how much they contribute, so that there's no hard line between them.
+    pub fn surface_biome(&self, x: i64, z: i64) -> Option<BiomeID> {
+        let mut roll = (hash_2d(derive_seed(self.seed, 0x5CA7), x, z) >> 11) as f64 / (1u64 << 53) as f64;
+
+        let weights = self.biome_weights(x, z);
+        for (id, weight) in weights.iter() {
+            if roll < *weight {
+                return Some(*id);
+            }
+            roll -= weight;
+        }
+
+        // Rounding errors can leave us just past the end.
+        weights.last().map(|(id, _weight)| *id)
+    }
+
+    /// Get the terrain parameters of a column, blended between the biomes around it.
+    pub fn blended_terrain(&self, x: i64, z: i64) -> BlendedTerrain {
+        let mut height_offset = 0.0;
+        let mut roughness = 0.0;
+        let mut soil_depth = 0.0;
+
+        for (id, weight) in self.biome_weights(x, z) {
+            let biome = &self.biomes[id.0 as usize];
+            height_offset += biome.height_offset * weight;
+            roughness += biome.roughness * weight;
+            soil_depth += biome.soil_depth as f64 * weight;
+        }
+
+        BlendedTerrain { height_offset, roughness, soil_depth: soil_depth.round() as i64 }
+    }
+
+    /// The most any biome raises or lowers the ground, and the most any biome scales the hills.
+    pub(super) fn terrain_bounds(&self) -> (f64, f64) {
+        self.biomes.iter().fold((0.0f64, 0.0f64), |(offset, roughness), biome| {
+            (offset.max(biome.height_offset.abs()), roughness.max(biome.roughness.abs()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Weights always add up to one, and the dominant biome has the most weight.
+    #[test]
+    fn weights() {
+        let map = BiomeMap::with_default_biomes(3);
+
+        for x in 0..20 {
+            let (x, z) = (x * 1000, x * -700);
+            let weights = map.biome_weights(x, z);
+
+            let total: f64 = weights.iter().map(|(_id, weight)| weight).sum();
+            assert!((total - 1.0).abs() < 1.0e-9);
+
+            let dominant = map.dominant_biome(x, z).unwrap();
+            let dominant_weight = weights.iter().find(|(id, _weight)| *id == dominant).unwrap().1;
+            assert!(weights.iter().all(|(_id, weight)| *weight <= dominant_weight));
+        }
+    }
+
+    /// Biomes are big. Neighboring blocks are almost always in the same biome, but far away places are in others.
+    #[test]
+    fn size() {
+        let map = BiomeMap::with_default_biomes(3);
+
+        let changes = (0..1000)
+            .filter(|x| {
+                map.biome_at(GlobalBlockCoordinate::new(x * 8, 0, 0))
+                    != map.biome_at(GlobalBlockCoordinate::new(x * 8 + 1, 0, 0))
+            })
+            .count();
+        assert!(changes <= 2);
+
+        let mut seen: Vec<BiomeID> = (0..200).filter_map(|x| map.dominant_biome(x * 2000, x * 1500)).collect();
+        seen.sort_by_key(|id| id.0);
+        seen.dedup();
+        assert!(seen.len() >= 3, "Only found {} biomes.", seen.len());
+    }
+
+    /// Blended terrain changes smoothly from one block to the next.
+    #[test]
+    fn smooth_borders() {
+        let map = BiomeMap::with_default_biomes(11);
+
+        for x in 0..2000 {
+            let a = map.blended_terrain(x * 16, 0);
+            let b = map.blended_terrain(x * 16 + 1, 0);
+            assert!((a.height_offset - b.height_offset).abs() < 1.0);
+        }
+    }
+
+    /// Enemies are picked in proportion to their weight.
+    #[test]
+    fn enemy_table() {
+        let biome = Biome::new("test", "grass", "soil").with_enemy("common", 3).with_enemy("rare", 1);
+        let picks: Vec<&str> = (0..4).map(|roll| biome.pick_enemy(roll).unwrap().name()).collect();
+        assert_eq!(picks, vec!["common", "common", "common", "rare"]);
+
+        assert!(Biome::new("empty", "grass", "soil").pick_enemy(5).is_none());
+    }
+}
@@ -0,0 +1,565 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Carves caves, ravines and tunnels out of terrain that has already been generated.
+
+use super::{
+    noise::{derive_seed, gradient_noise_2d, hash_3d, mix, unit, FractalNoise},
+    TerrainGenerator, TerrainGeneratorResult, TerrainGeneratorSuccessType,
+};
+use crate::world::{
+    storage, BlockID, BlockRegistry, Chunk, ChunkCoordinate, ChunkCoordinateEXT, GlobalBlockCoordinate,
+    GlobalBlockCoordinateEXT, LocalBlockCoordinate,
+};
+use antidote::Mutex;
+use anyhow::Result;
+use nalgebra::Vector3;
+use std::{collections::HashMap, sync::Arc};
+
+/// The kinds of tunnels a worm can dig.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WormKind {
+    /// A round tunnel that wanders in every direction.
+    Tunnel,
+
+    /// A narrow, tall crack that stays mostly level.
+    Ravine,
+}
+
+/// A sphere, or a stretched sphere, carved out by a worm.
+#[derive(Debug, Clone)]
+struct Carving {
+    center: Vector3<f64>,
+    radius: f64,
+    height: f64,
+}
+
+impl Carving {
+    /// The corners of the box of blocks this carving could touch.
+    fn bounds(&self) -> (GlobalBlockCoordinate, GlobalBlockCoordinate) {
+        let extent = Vector3::new(self.radius, self.height, self.radius);
+        let near = (self.center - extent).map(|v| v.floor() as i64);
+        let far = (self.center + extent).map(|v| v.floor() as i64);
+
+        (near, far)
+    }
+
+    /// True if the center of a block is inside this carving.
+    fn contains(&self, location: GlobalBlockCoordinate) -> bool {
+        let offset = location.map(|v| v as f64 + 0.5) - self.center;
+        let x = offset.x / self.radius;
+        let y = offset.y / self.height;
+        let z = offset.z / self.radius;
+
+        x * x + y * y + z * z <= 1.0
+    }
+}
+
+/// Carves underground spaces into terrain generated by the generators before it. Only blocks listed as carvable are
+/// removed, so it won't eat into anything that isn't natural rock.
+///
+/// There are three kinds of caves. Caverns are large open spaces, grown with a cellular automaton from random noise
+/// wherever 3D noise is high enough. Every block's fate only depends on the blocks around it, so caverns continue across
+/// chunk boundaries. Tunnels and ravines are dug by worms, which start at random places in a grid of regions and wander
+/// with noise. A chunk is carved by every worm that could reach it, no matter which region it started in, so tunnels
+/// continue across chunk boundaries too.
+pub struct CaveCarver {
+    seed: u64,
+    carvable_names: Vec<String>,
+    carvable_blocks: Vec<BlockID>,
+    cavern_noise: FractalNoise,
+    cavern_threshold: f64,
+    cavern_ceiling: i64,
+    tunnel_range: (i64, i64),
+    ravine_range: (i64, i64),
+    tunnels_per_region: u32,
+    ravine_chance: f64,
+    region_carvings: Mutex<HashMap<GlobalBlockCoordinate, Arc<RegionCarvings>>>,
+}
+
+/// Everything the worms of a region carve, sorted by the chunks they carve into.
+type RegionCarvings = HashMap<ChunkCoordinate, Vec<Carving>>;
+
+impl CaveCarver {
+    /// The size of the regions that worms start in, in blocks.
+    const REGION_SIZE: i64 = 64;
+
+    /// The most steps, one block each, that a worm takes.
+    const MAX_WORM_LENGTH: i64 = 160;
+
+    /// The farthest from its center a worm carves.
+    const MAX_WORM_RADIUS: f64 = 16.0;
+
+    /// The number of times the cellular automaton smooths out the caverns.
+    const CAVERN_STEPS: i64 = 4;
+
+    /// The chance a block starts out open, where caverns can be.
+    const CAVERN_FILL: f64 = 0.6;
+
+    /// How many regions of worms are remembered before they have to be dug again.
+    const MAX_CACHED_REGIONS: usize = 1024;
+
+    /// Create a cave carver that carves stone and soil.
+    pub fn new(seed: u64) -> Box<CaveCarver> {
+        Self::with_carvable_blocks(seed, &["stone", "soil"])
+    }
+
+    /// Create a cave carver that carves the blocks with the given names.
+    pub fn with_carvable_blocks(seed: u64, carvable: &[&str]) -> Box<CaveCarver> {
+        Box::new(CaveCarver {
+            seed,
+            carvable_names: carvable.iter().map(|name| String::from(*name)).collect(),
+            carvable_blocks: Vec::new(),
+            cavern_noise: FractalNoise::new(derive_seed(seed, 0xCA7E), 48.0, 2),
+            cavern_threshold: 0.4,
+            cavern_ceiling: -32,
+            tunnel_range: (-256, -8),
+            ravine_range: (-48, -4),
+            tunnels_per_region: 2,
+            ravine_chance: 0.05,
+            region_carvings: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Set how much of the underground is open caverns. Caverns grow where noise is above the threshold, which goes
+    /// from -1 for everything to 1 for nothing.
+    pub fn set_cavern_threshold(&mut self, cavern_threshold: f64) {
+        self.cavern_threshold = cavern_threshold;
+    }
+
+    /// Set the highest block caverns can be carved at.
+    pub fn set_cavern_ceiling(&mut self, cavern_ceiling: i64) {
+        self.cavern_ceiling = cavern_ceiling;
+    }
+
+    /// Set the lowest and highest height tunnels can start at.
+    pub fn set_tunnel_range(&mut self, lowest: i64, highest: i64) {
+        self.tunnel_range = (lowest, highest);
+    }
+
+    /// Set the lowest and highest height ravines can start at.
+    pub fn set_ravine_range(&mut self, lowest: i64, highest: i64) {
+        self.ravine_range = (lowest, highest);
+    }
+
+    /// Set the most tunnels that can start in each region.
+    pub fn set_tunnels_per_region(&mut self, tunnels_per_region: u32) {
+        self.tunnels_per_region = tunnels_per_region;
+    }
+
+    /// Set the chance, from 0 to 1, that a ravine starts in a region.
+    pub fn set_ravine_chance(&mut self, ravine_chance: f64) {
+        self.ravine_chance = ravine_chance;
+    }
+
+    /// True if a cavern can grow at a block.
+    fn in_cavern(&self, location: GlobalBlockCoordinate) -> bool {
+        if location.y > self.cavern_ceiling {
+            return false;
+        }
+
+        // Squash the caverns vertically so they're wider than they are tall.
+        let value = self.cavern_noise.sample_3d(location.x as f64, location.y as f64 * 2.0, location.z as f64);
+        value > self.cavern_threshold
+    }
+
+    /// Grow the caverns for a chunk, and find which of its blocks are open. Blocks are indexed by x, then y, then z.
+    /// None is returned if there are no caverns anywhere near the chunk.
+    ///
+    /// Blocks where caverns can grow start out randomly open or solid, and then every step each block becomes whatever
+    /// most of the 27 blocks around it, itself included, were. That smooths the noise out into open caves with pillars
+    /// and rough walls. A block can only be affected by blocks as far away as there are steps, so we start with a
+    /// margin that wide around the chunk and get the same answer any neighboring chunk would.
+    fn grow_caverns(&self, origin: GlobalBlockCoordinate) -> Option<Vec<bool>> {
+        let margin = Self::CAVERN_STEPS;
+        if origin.y - margin > self.cavern_ceiling {
+            return None;
+        }
+
+        let size = storage::CHUNK_DIAMETER as i64 + margin * 2;
+        let start = origin.add_scalar(-margin);
+        let index = |x: i64, y: i64, z: i64| ((z * size + y) * size + x) as usize;
+        let fill_seed = derive_seed(self.seed, 0xF111);
+
+        let mut open = vec![false; (size * size * size) as usize];
+        let mut any_open = false;
+        for z in 0..size {
+            for y in 0..size {
+                for x in 0..size {
+                    let location = start + GlobalBlockCoordinate::new(x, y, z);
+                    if self.in_cavern(location) {
+                        let is_open = unit(hash_3d(fill_seed, location.x, location.y, location.z)) < Self::CAVERN_FILL;
+                        open[index(x, y, z)] = is_open;
+                        any_open |= is_open;
+                    }
+                }
+            }
+        }
+
+        if !any_open {
+            return None;
+        }
+
+        // Counting the open blocks in a 3x3x3 box is done one axis at a time. Anything past the edge counts as solid,
+        // which is wrong, but the error only creeps in one block per step and never reaches the chunk.
+        let mut counts = vec![0u8; open.len()];
+        let mut scratch = vec![0u8; open.len()];
+        for _ in 0..Self::CAVERN_STEPS {
+            for (count, open) in counts.iter_mut().zip(open.iter()) {
+                *count = *open as u8;
+            }
+
+            for (axis, stride) in [(0, 1), (1, size), (2, size * size)].iter() {
+                for z in 0..size {
+                    for y in 0..size {
+                        for x in 0..size {
+                            let position = [x, y, z][*axis];
+                            let here = index(x, y, z) as i64;
+                            let mut sum = counts[here as usize];
+                            if position > 0 {
+                                sum += counts[(here - stride) as usize];
+                            }
+                            if position < size - 1 {
+                                sum += counts[(here + stride) as usize];
+                            }
+                            scratch[here as usize] = sum;
+                        }
+                    }
+                }
+                std::mem::swap(&mut counts, &mut scratch);
+            }
+
+            for z in 0..size {
+                for y in 0..size {
+                    for x in 0..size {
+                        // Caverns never grow up past their ceiling, no matter what's below.
+                        let i = index(x, y, z);
+                        open[i] = counts[i] >= 14 && start.y + y <= self.cavern_ceiling;
+                    }
+                }
+            }
+        }
+
+        let diameter = storage::CHUNK_DIAMETER as i64;
+        let mut chunk_open = Vec::with_capacity(storage::CHUNK_DIAMETER.pow(3));
+        for z in 0..diameter {
+            for y in 0..diameter {
+                for x in 0..diameter {
+                    chunk_open.push(open[index(x + margin, y + margin, z + margin)]);
+                }
+            }
+        }
+
+        Some(chunk_open)
+    }
+
+    /// Find every worm that starts in a region.
+    fn region_worms(&self, region: GlobalBlockCoordinate) -> Vec<(WormKind, u64)> {
+        let region_hash = hash_3d(self.seed, region.x, region.y, region.z);
+        let mut worms = Vec::new();
+
+        for index in 0..self.tunnels_per_region {
+            let worm_seed = mix(region_hash ^ index as u64);
+            if unit(worm_seed) < 0.5 {
+                worms.push((WormKind::Tunnel, worm_seed));
+            }
+        }
+
+        let ravine_seed = mix(region_hash ^ 0x7A1E);
+        if unit(ravine_seed) < self.ravine_chance {
+            worms.push((WormKind::Ravine, ravine_seed));
+        }
+
+        worms
+    }
+
+    /// Follow a worm from start to end, and sort every carving it makes into the chunks it touches.
+    fn dig_worm(&self, region: GlobalBlockCoordinate, kind: WormKind, worm_seed: u64, carvings: &mut RegionCarvings) {
+        let (lowest, highest) = match kind {
+            WormKind::Tunnel => self.tunnel_range,
+            WormKind::Ravine => self.ravine_range,
+        };
+
+        let region_origin = region.map(|v| (v * Self::REGION_SIZE) as f64);
+        let start = Vector3::new(
+            region_origin.x + unit(mix(worm_seed ^ 1)) * Self::REGION_SIZE as f64,
+            region_origin.y + unit(mix(worm_seed ^ 2)) * Self::REGION_SIZE as f64,
+            region_origin.z + unit(mix(worm_seed ^ 3)) * Self::REGION_SIZE as f64,
+        );
+
+        if start.y < lowest as f64 || start.y > highest as f64 {
+            return;
+        }
+
+        let length = Self::MAX_WORM_LENGTH / 2 + (mix(worm_seed ^ 4) % (Self::MAX_WORM_LENGTH as u64 / 2)) as i64;
+        let (direction_x, direction_y, direction_z, size) =
+            (derive_seed(worm_seed, 5), derive_seed(worm_seed, 6), derive_seed(worm_seed, 7), derive_seed(worm_seed, 8));
+
+        let mut position = start;
+        for step in 0..length {
+            let t = step as f64 / 24.0;
+
+            // Ravines mostly stay level, while tunnels are free to go up and down, just not as much as sideways.
+            let vertical_scale = match kind {
+                WormKind::Tunnel => 0.4,
+                WormKind::Ravine => 0.05,
+            };
+            let direction = Vector3::new(
+                gradient_noise_2d(direction_x, t, 0.5),
+                gradient_noise_2d(direction_y, t, 0.5) * vertical_scale,
+                gradient_noise_2d(direction_z, t, 0.5),
+            );
+            let direction = if direction.norm_squared() > 1.0e-6 { direction.normalize() } else { Vector3::x() };
+            position += direction;
+
+            // The worm is thickest in the middle and tapers off at the ends.
+            let taper = 1.0 - (step as f64 / length as f64 * 2.0 - 1.0).abs();
+            let wobble = gradient_noise_2d(size, t, 0.5) * 0.5 + 1.0;
+            let (radius, height) = match kind {
+                WormKind::Tunnel => {
+                    let radius = 1.5 + 2.5 * taper * wobble;
+                    (radius, radius)
+                }
+                WormKind::Ravine => (1.0 + 2.0 * taper * wobble, 4.0 + 12.0 * taper),
+            };
+
+            let carving = Carving { center: position, radius, height };
+            let (near, far) = carving.bounds();
+            let (near, far) = (near.chunk_index(), far.chunk_index());
+            for z in near.z..=far.z {
+                for y in near.y..=far.y {
+                    for x in near.x..=far.x {
+                        carvings.entry(ChunkCoordinate::new(x, y, z)).or_default().push(carving.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Get everything the worms of a region carve. Neighboring chunks share most of their worms, so each region is only
+    /// dug once and then remembered for a while.
+    fn region_carvings(&self, region: GlobalBlockCoordinate) -> Arc<RegionCarvings> {
+        if let Some(carvings) = self.region_carvings.lock().get(&region) {
+            return carvings.clone();
+        }
+
+        let mut carvings = RegionCarvings::new();
+        for (kind, worm_seed) in self.region_worms(region) {
+            self.dig_worm(region, kind, worm_seed, &mut carvings);
+        }
+        let carvings = Arc::new(carvings);
+
+        let mut cache = self.region_carvings.lock();
+        if cache.len() >= Self::MAX_CACHED_REGIONS {
+            cache.clear();
+        }
+        cache.insert(region, carvings.clone());
+
+        carvings
+    }
+}
+
+impl<ChunkUserData: Default> TerrainGenerator<ChunkUserData> for CaveCarver {
+    fn initialize_block_ids(&mut self, registry: &BlockRegistry) -> Result<()> {
+        // We don't add anything. If a carvable block isn't there, there's nothing to carve.
+        self.carvable_blocks =
+            self.carvable_names.iter().filter_map(|name| registry.get_block_id_from_name(name).cloned()).collect();
+
+        Ok(())
+    }
+
+    fn populate_chunk(&self, chunk: &mut Chunk<ChunkUserData>) -> TerrainGeneratorResult {
+        let origin: GlobalBlockCoordinate = chunk.index().to_block_coordinate();
+        let diameter = storage::CHUNK_DIAMETER as i64;
+        let index = |location: GlobalBlockCoordinate| {
+            let local = location - origin;
+            ((local.z * diameter + local.y) * diameter + local.x) as usize
+        };
+
+        let mut carved = self.grow_caverns(origin).unwrap_or_else(|| vec![false; storage::CHUNK_DIAMETER.pow(3)]);
+
+        // Worms can reach us from any region close enough, and each of those regions already knows what it carves here.
+        let region_reach = (Self::MAX_WORM_LENGTH as f64 + Self::MAX_WORM_RADIUS) / Self::REGION_SIZE as f64;
+        let region_reach = region_reach.ceil() as i64;
+        let chunk_region = origin.map(|v| v.div_euclid(Self::REGION_SIZE));
+        let chunk_far = origin.add_scalar(diameter - 1);
+        for region_z in -region_reach..=region_reach {
+            for region_y in -region_reach..=region_reach {
+                for region_x in -region_reach..=region_reach {
+                    let region = chunk_region + GlobalBlockCoordinate::new(region_x, region_y, region_z);
+                    let region_carvings = self.region_carvings(region);
+
+                    for carving in region_carvings.get(&chunk.index()).into_iter().flatten() {
+                        let (near, far) = carving.bounds();
+                        let (near, far) = (near.sup(&origin), far.inf(&chunk_far));
+                        for z in near.z..=far.z {
+                            for y in near.y..=far.y {
+                                for x in near.x..=far.x {
+                                    let location = GlobalBlockCoordinate::new(x, y, z);
+                                    if carving.contains(location) {
+                                        carved[index(location)] = true;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for local_z in 0..storage::CHUNK_DIAMETER as u8 {
+            for local_y in 0..storage::CHUNK_DIAMETER as u8 {
+                for local_x in 0..storage::CHUNK_DIAMETER as u8 {
+                    let local = LocalBlockCoordinate::new(local_x, local_y, local_z);
+                    let block = chunk.get_single_block_local_mut(local);
+
+                    match block {
+                        Some(id) if self.carvable_blocks.contains(id) => {}
+                        _ => continue,
+                    }
+
+                    if carved[index(origin + local.map(|v| v as i64))] {
+                        *block = None;
+                    }
+                }
+            }
+        }
+
+        Ok(TerrainGeneratorSuccessType::Continue)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::test::{hash_chunk, registry_with},
+        *,
+    };
+    use crate::world::{
+        chunk_providers::{HeightmapWorld, RAMWorld},
+        ChunkCoordinate, ChunkProvider,
+    };
+    use std::collections::HashMap;
+
+    fn create_provider(seed: u64) -> Box<RAMWorld<()>> {
+        let mut provider = RAMWorld::new(registry_with(HeightmapWorld::BLOCKS));
+        // A flat world of stone, with the surface at zero.
+        provider.add_generator(HeightmapWorld::with_layers(seed, &[])).unwrap();
+        provider.add_generator(CaveCarver::new(seed)).unwrap();
+
+        provider
+    }
+
+    fn generate(provider: &dyn ChunkProvider<()>, location: ChunkCoordinate) -> Chunk<()> {
+        let mut chunk = Chunk::new(location, ());
+        provider.provide_chunk(&mut chunk).unwrap();
+
+        chunk
+    }
+
+    fn count_empty(chunk: &Chunk<()>) -> usize {
+        chunk.iter_ideal(Chunk::<()>::range_all_blocks()).filter(|block| block.is_none()).count()
+    }
+
+    /// Caves are carved deep underground, but nothing is carved out of the sky or near the surface.
+    #[test]
+    fn carves_underground() {
+        let provider = create_provider(9);
+
+        let carved: usize = (0..4).map(|x| count_empty(&generate(provider.as_ref(), ChunkCoordinate::new(x, -3, 0)))).sum();
+        assert!(carved > 0);
+
+        // Only the grass at the very bottom of this chunk is left, and grass isn't carvable.
+        assert_eq!(count_empty(&generate(provider.as_ref(), ChunkCoordinate::new(0, 0, 0))), 32 * 32 * 31);
+    }
+
+    /// Blocks that aren't carvable are left alone.
+    #[test]
+    fn only_carvable() {
+        let mut provider = RAMWorld::new(registry_with(HeightmapWorld::BLOCKS));
+        provider.add_generator(HeightmapWorld::with_layers(9, &[])).unwrap();
+        provider.add_generator(CaveCarver::with_carvable_blocks(9, &["soil"])).unwrap();
+
+        assert_eq!(count_empty(&generate(provider.as_ref(), ChunkCoordinate::new(0, -3, 0))), 0);
+    }
+
+    /// Caves carry on across chunk boundaries. Where a block is carved on one side of a boundary, the block across from
+    /// it is almost always carved too.
+    #[test]
+    fn continuous() {
+        let provider = create_provider(21);
+
+        let mut chunks = HashMap::new();
+        for x in 0..4 {
+            for z in 0..2 {
+                let location = ChunkCoordinate::new(x, -4, z);
+                chunks.insert(location, generate(provider.as_ref(), location));
+            }
+        }
+
+        let (mut carved, mut matched) = (0, 0);
+        for x in 0..3 {
+            for z in 0..2 {
+                let left = &chunks[&ChunkCoordinate::new(x, -4, z)];
+                let right = &chunks[&ChunkCoordinate::new(x + 1, -4, z)];
+
+                for y in 0..32 {
+                    for local_z in 0..32 {
+                        let left_block = left.get_single_block_local(LocalBlockCoordinate::new(31, y, local_z));
+                        let right_block = right.get_single_block_local(LocalBlockCoordinate::new(0, y, local_z));
+
+                        if left_block.is_none() {
+                            carved += 1;
+                            if right_block.is_none() {
+                                matched += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        assert!(carved > 0);
+        assert!(matched * 10 >= carved * 7, "Only {} of {} carved blocks continued across the boundary.", matched, carved);
+    }
+
+    /// Caverns come out the same no matter which chunk they're grown for, so they line up across chunk boundaries.
+    #[test]
+    fn cavern_boundaries() {
+        let mut carver = CaveCarver::new(5);
+        carver.set_cavern_threshold(0.0);
+
+        let left_origin = GlobalBlockCoordinate::new(0, -96, 0);
+        let right_origin = GlobalBlockCoordinate::new(16, -96, 0);
+        let left = carver.grow_caverns(left_origin).unwrap();
+        let right = carver.grow_caverns(right_origin).unwrap();
+
+        let index = |x: i64, y: i64, z: i64| ((z * 32 + y) * 32 + x) as usize;
+        let mut open = 0;
+        for z in 0..32 {
+            for y in 0..32 {
+                for x in 16..32 {
+                    assert_eq!(left[index(x, y, z)], right[index(x - 16, y, z)]);
+                    open += left[index(x, y, z)] as usize;
+                }
+            }
+        }
+
+        // Smoothing should leave real open spaces, not nothing and not everything.
+        assert!(open > 0 && open < 16 * 32 * 32);
+    }
+
+    /// Carving happens after the heightmap, and the result never changes for a seed.
+    #[test]
+    fn golden_hashes() {
+        let mut provider = RAMWorld::new(registry_with(HeightmapWorld::BLOCKS));
+        provider.add_generator(HeightmapWorld::new(0xDEADBEEF)).unwrap();
+        provider.add_generator(CaveCarver::new(0xDEADBEEF)).unwrap();
+
+        let hashes: Vec<u64> = [(0, -1, 0), (0, -3, 0), (5, -5, -5)]
+            .iter()
+            .map(|(x, y, z)| hash_chunk(&generate(provider.as_ref(), ChunkCoordinate::new(*x, *y, *z))))
+            .collect();
+        assert_eq!(hashes, vec![0x6EF49FC05A0018A4, 0x4860C90D2DDDF495, 0x58437B841950D71C]);
+    }
+}
@@ -0,0 +1,378 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Rolling terrain built from layers of noise.
+
+use super::{
+    noise::{derive_seed, FractalNoise},
+    require_block, BiomeMap, TerrainGenerator, TerrainGeneratorResult, TerrainGeneratorSuccessType,
+};
+use crate::world::{storage, BlockID, BlockRegistry, Chunk, ChunkCoordinateEXT, GlobalBlockCoordinate, LocalBlockCoordinate};
+use anyhow::Result;
+use std::sync::Arc;
+
+/// One layer of a heightmap. The layers of a heightmap are added together to get the height of the ground.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeightmapLayer {
+    scale: f64,
+    amplitude: f64,
+    octaves: u32,
+}
+
+impl HeightmapLayer {
+    /// Create a layer with features about `scale` blocks across that raise and lower the ground by up to `amplitude`
+    /// blocks.
+    pub fn new(scale: f64, amplitude: f64, octaves: u32) -> HeightmapLayer {
+        HeightmapLayer { scale, amplitude, octaves }
+    }
+
+    /// How far this layer can raise or lower the ground.
+    pub fn amplitude(&self) -> f64 {
+        self.amplitude
+    }
+}
+
+/// What a single column of terrain is made of.
+struct Column {
+    surface: i64,
+    soil_depth: i64,
+    surface_block: Option<BlockID>,
+    soil_block: Option<BlockID>,
+}
+
+/// Generates terrain from a heightmap. Everything below the ground is stone, with a few blocks of soil on top,
+/// covered by a single block of surface.
+/// With biomes, the biomes decide what the surface and soil are made of, and how high and rough the ground is.
+/// A given seed will always generate the same terrain, no matter what order chunks are generated in.
+pub struct HeightmapWorld {
+    seed: u64,
+    base_height: i64,
+    soil_depth: i64,
+    layers: Vec<(FractalNoise, f64)>,
+    biomes: Option<Arc<BiomeMap>>,
+    biome_blocks: Vec<(Option<BlockID>, Option<BlockID>)>,
+    stone_block: Option<BlockID>,
+    soil_block: Option<BlockID>,
+    surface_block: Option<BlockID>,
+}
+
+impl HeightmapWorld {
+    /// The blocks this generator needs, which must be registered before it's added. With biomes, each biome's surface
+    /// and soil blocks are needed too.
+    pub const BLOCKS: &'static [(&'static str, &'static str)] = &[("stone", "Stone"), ("soil", "Soil"), ("grass", "Grass")];
+
+    /// Create a heightmap terrain generator with rolling hills on top of broad continents.
+    pub fn new(seed: u64) -> Box<HeightmapWorld> {
+        Self::with_layers(
+            seed,
+            &[HeightmapLayer::new(2048.0, 96.0, 4), HeightmapLayer::new(256.0, 24.0, 4), HeightmapLayer::new(32.0, 3.0, 2)],
+        )
+    }
+
+    /// Create a heightmap terrain generator with rolling hills on top of broad continents, shaped by biomes.
+    pub fn with_biomes(seed: u64, biomes: Arc<BiomeMap>) -> Box<HeightmapWorld> {
+        let mut generator = Self::new(seed);
+        generator.set_biomes(biomes);
+
+        generator
+    }
+
+    /// Create a heightmap terrain generator from custom layers.
+    pub fn with_layers(seed: u64, layers: &[HeightmapLayer]) -> Box<HeightmapWorld> {
+        let layers = layers
+            .iter()
+            .enumerate()
+            .map(|(index, layer)| {
+                (FractalNoise::new(derive_seed(seed, index as u64), layer.scale, layer.octaves), layer.amplitude)
+            })
+            .collect();
+
+        Box::new(HeightmapWorld {
+            seed,
+            base_height: 0,
+            soil_depth: 4,
+            layers,
+            biomes: None,
+            biome_blocks: Vec::new(),
+            stone_block: None,
+            soil_block: None,
+            surface_block: None,
+        })
+    }
+
+    /// The seed this terrain is generated from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The biomes shaping the terrain, if there are any.
+    pub fn biomes(&self) -> Option<&Arc<BiomeMap>> {
+        self.biomes.as_ref()
+    }
+
+    /// Shape the terrain with biomes. This must be done before the generator is added to a chunk provider.
+    pub fn set_biomes(&mut self, biomes: Arc<BiomeMap>) {
+        self.biomes = Some(biomes);
+    }
+
+    /// Set the height the layers of the heightmap raise and lower the ground from.
+    pub fn set_base_height(&mut self, base_height: i64) {
+        self.base_height = base_height;
+    }
+
+    /// Set how many blocks of soil there are between the surface and the stone below.
+    pub fn set_soil_depth(&mut self, soil_depth: i64) {
+        self.soil_depth = soil_depth;
+    }
+
+    /// Find the height of the surface block at a column of the world.
+    pub fn surface_height(&self, x: i64, z: i64) -> i64 {
+        self.column(x, z).surface
+    }
+
+    fn column(&self, x: i64, z: i64) -> Column {
+        let layer_heights = self.layers.iter().map(|(noise, amplitude)| noise.sample_2d(x as f64, z as f64) * amplitude);
+
+        match &self.biomes {
+            Some(biomes) => {
+                // The broadest layer is left alone so that continents line up across biomes. Only the hills on top of
+                // them are shaped by the biomes.
+                let terrain = biomes.blended_terrain(x, z);
+                let height: f64 = layer_heights
+                    .enumerate()
+                    .map(|(index, height)| if index == 0 { height } else { height * terrain.roughness })
+                    .sum::<f64>()
+                    + terrain.height_offset;
+
+                let (surface_block, soil_block) = biomes
+                    .surface_biome(x, z)
+                    .and_then(|biome| self.biome_blocks.get(biome.index()).copied())
+                    .unwrap_or((self.surface_block, self.soil_block));
+
+                Column {
+                    surface: self.base_height + height.floor() as i64,
+                    soil_depth: terrain.soil_depth,
+                    surface_block,
+                    soil_block,
+                }
+            }
+            None => {
+                let height: f64 = layer_heights.sum();
+
+                Column {
+                    surface: self.base_height + height.floor() as i64,
+                    soil_depth: self.soil_depth,
+                    surface_block: self.surface_block,
+                    soil_block: self.soil_block,
+                }
+            }
+        }
+    }
+
+    /// The lowest and highest the surface could possibly be, and the deepest the soil could be.
+    fn height_bounds(&self) -> (i64, i64, i64) {
+        let (offset, roughness, soil_depth) = match &self.biomes {
+            Some(biomes) => {
+                let (offset, roughness) = biomes.terrain_bounds();
+                let soil_depth = biomes.iter_biomes().map(|(_id, biome)| biome.soil_depth()).max().unwrap_or(0);
+                (offset, roughness.max(1.0), soil_depth)
+            }
+            None => (0.0, 1.0, self.soil_depth),
+        };
+
+        let total_amplitude: f64 =
+            self.layers.iter().map(|(_noise, amplitude)| amplitude.abs()).sum::<f64>() * roughness + offset;
+        let total_amplitude = total_amplitude.ceil() as i64 + 1;
+
+        (self.base_height - total_amplitude, self.base_height + total_amplitude, soil_depth)
+    }
+}
+
+impl<ChunkUserData: Default> TerrainGenerator<ChunkUserData> for HeightmapWorld {
+    fn initialize_block_ids(&mut self, registry: &BlockRegistry) -> Result<()> {
+        self.stone_block = Some(require_block(registry, "stone")?);
+        self.soil_block = Some(require_block(registry, "soil")?);
+        self.surface_block = Some(require_block(registry, "grass")?);
+
+        if let Some(biomes) = &self.biomes {
+            self.biome_blocks = biomes
+                .iter_biomes()
+                .map(|(_id, biome)| {
+                    Ok((
+                        Some(require_block(registry, biome.surface_block())?),
+                        Some(require_block(registry, biome.soil_block())?),
+                    ))
+                })
+                .collect::<Result<_>>()?;
+        }
+
+        Ok(())
+    }
+
+    fn populate_chunk(&self, chunk: &mut Chunk<ChunkUserData>) -> TerrainGeneratorResult {
+        let origin: GlobalBlockCoordinate = chunk.index().to_block_coordinate();
+        let (lowest, highest, soil_depth) = self.height_bounds();
+
+        // Nothing to do if the chunk is entirely above the ground.
+        if origin.y > highest {
+            return Ok(TerrainGeneratorSuccessType::Continue);
+        }
+
+        // Entirely below the ground is just stone.
+        if origin.y + (storage::CHUNK_DIAMETER as i64) < lowest - soil_depth {
+            chunk.iter_ideal_mut(Chunk::<ChunkUserData>::range_all_blocks()).for_each(|block| *block = self.stone_block);
+            return Ok(TerrainGeneratorSuccessType::Continue);
+        }
+
+        for local_z in 0..storage::CHUNK_DIAMETER as u8 {
+            for local_x in 0..storage::CHUNK_DIAMETER as u8 {
+                let column = self.column(origin.x + local_x as i64, origin.z + local_z as i64);
+
+                for local_y in 0..storage::CHUNK_DIAMETER as u8 {
+                    let depth = column.surface - (origin.y + local_y as i64);
+
+                    let block = if depth < 0 {
+                        continue;
+                    } else if depth == 0 {
+                        column.surface_block
+                    } else if depth <= column.soil_depth {
+                        column.soil_block
+                    } else {
+                        self.stone_block
+                    };
+
+                    *chunk.get_single_block_local_mut(LocalBlockCoordinate::new(local_x, local_y, local_z)) = block;
+                }
+            }
+        }
+
+        Ok(TerrainGeneratorSuccessType::Continue)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::test::{hash_chunk, registry_with},
+        *,
+    };
+    use crate::world::{ChunkCoordinate, GlobalBlockCoordinateEXT};
+
+    /// Generate a chunk and hash its content.
+    fn hash_generated(generator: &dyn TerrainGenerator<()>, location: ChunkCoordinate) -> u64 {
+        let mut chunk = Chunk::new(location, ());
+        generator.populate_chunk(&mut chunk).unwrap();
+
+        hash_chunk(&chunk)
+    }
+
+    fn create_generator(seed: u64) -> Box<HeightmapWorld> {
+        let mut generator = HeightmapWorld::new(seed);
+        TerrainGenerator::<()>::initialize_block_ids(generator.as_mut(), &registry_with(HeightmapWorld::BLOCKS)).unwrap();
+
+        generator
+    }
+
+    /// Chunks that the surface passes through for seed 0xDEADBEEF, so that every layer ends up in the hashes.
+    const CHUNKS: [(i32, i32, i32); 5] = [(0, 0, 0), (0, -1, 0), (3, 0, -7), (-100, -1, 250), (1_000_000, 0, -1_000_000)];
+
+    /// The terrain for a seed should never change, or existing worlds will get seams in them.
+    /// If you change the generator on purpose, these hashes will need to be updated.
+    #[test]
+    fn golden_hashes() {
+        let generator = create_generator(0xDEADBEEF);
+
+        // A chunk that's all air or all stone would hardly test anything.
+        for (x, y, z) in CHUNKS.iter() {
+            let mut chunk = Chunk::new(ChunkCoordinate::new(*x, *y, *z), ());
+            TerrainGenerator::<()>::populate_chunk(generator.as_ref(), &mut chunk).unwrap();
+
+            let blocks: Vec<Option<BlockID>> = chunk.iter_ideal(Chunk::<()>::range_all_blocks()).collect();
+            assert!(blocks.contains(&None), "Chunk {:?} has no air.", (x, y, z));
+            assert!(blocks.iter().any(Option::is_some), "Chunk {:?} has no ground.", (x, y, z));
+        }
+
+        let hashes: Vec<u64> =
+            CHUNKS.iter().map(|(x, y, z)| hash_generated(generator.as_ref(), ChunkCoordinate::new(*x, *y, *z))).collect();
+        assert_eq!(
+            hashes,
+            vec![0x13335077123380F5, 0xF35FC710B56BF20D, 0x662343B1369EEA7D, 0xB056B8F84CA9D124, 0x2A15E97FB54069ED]
+        );
+    }
+
+    /// Generating chunks in a different order, or with a fresh generator, should give the same terrain.
+    #[test]
+    fn order_independent() {
+        let forward: Vec<u64> = {
+            let generator = create_generator(42);
+            CHUNKS.iter().map(|(x, y, z)| hash_generated(generator.as_ref(), ChunkCoordinate::new(*x, *y, *z))).collect()
+        };
+
+        let mut backward: Vec<u64> = {
+            let generator = create_generator(42);
+            CHUNKS.iter().rev().map(|(x, y, z)| hash_generated(generator.as_ref(), ChunkCoordinate::new(*x, *y, *z))).collect()
+        };
+        backward.reverse();
+
+        assert_eq!(forward, backward);
+    }
+
+    /// Different seeds should give different terrain.
+    #[test]
+    fn seeds() {
+        let location = ChunkCoordinate::new(0, -1, 0);
+        assert_ne!(
+            hash_generated(create_generator(1).as_ref(), location),
+            hash_generated(create_generator(2).as_ref(), location)
+        );
+    }
+
+    fn block_at(generator: &HeightmapWorld, location: GlobalBlockCoordinate) -> Option<BlockID> {
+        let mut chunk = Chunk::new(location.chunk_index(), ());
+        TerrainGenerator::<()>::populate_chunk(generator, &mut chunk).unwrap();
+
+        chunk.get_single_block_local(location.to_local_block_coordinate())
+    }
+
+    /// Columns are made of the right layers, even where they cross between chunks.
+    #[test]
+    fn layers() {
+        let generator = create_generator(7);
+
+        for x in 0..64 {
+            let surface = GlobalBlockCoordinate::new(x, generator.surface_height(x, 5), 5);
+            let down = GlobalBlockCoordinate::new(0, 1, 0);
+
+            assert_eq!(block_at(&generator, surface + down), None);
+            assert_eq!(block_at(&generator, surface), generator.surface_block);
+            assert_eq!(block_at(&generator, surface - down), generator.soil_block);
+            assert_eq!(block_at(&generator, surface - down * 4), generator.soil_block);
+            assert_eq!(block_at(&generator, surface - down * 5), generator.stone_block);
+        }
+    }
+
+    /// The surface is made of whatever biome the column is in, and biome terrain is as deterministic as the rest.
+    #[test]
+    fn biomes() {
+        let biomes = Arc::new(BiomeMap::with_default_biomes(5));
+        let registry = registry_with(&[HeightmapWorld::BLOCKS, &[("sand", "Sand"), ("snow", "Snow")]].concat());
+        let mut generator = HeightmapWorld::with_biomes(5, biomes.clone());
+        TerrainGenerator::<()>::initialize_block_ids(generator.as_mut(), &registry).unwrap();
+
+        for x in 0..200 {
+            let (x, z) = (x * 977, x * -1231);
+            let surface = GlobalBlockCoordinate::new(x, generator.surface_height(x, z), z);
+
+            let biome = biomes.get_biome(biomes.surface_biome(x, z).unwrap()).unwrap();
+            let surface_block = registry.get_block_id_from_name(biome.surface_block()).cloned();
+            assert_eq!(block_at(&generator, surface), surface_block);
+        }
+
+        let hashes: Vec<u64> =
+            CHUNKS.iter().map(|(x, y, z)| hash_generated(generator.as_ref(), ChunkCoordinate::new(*x, *y, *z))).collect();
+        assert_eq!(
+            hashes,
+            vec![0x160C9E9B880B8D9E, 0xACAC2A58F08DFEFF, 0xEB05052EA5B62325, 0x66DEEE2BEEAC2325, 0xAFB66674963F1F57]
+        );
+    }
+}
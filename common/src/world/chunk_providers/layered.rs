@@ -0,0 +1,108 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Perfectly flat worlds made of layers of blocks.
+
+use super::{require_block, TerrainGenerator, TerrainGeneratorResult, TerrainGeneratorSuccessType};
+use crate::world::{storage, BlockID, BlockRegistry, Chunk, ChunkCoordinateEXT, LocalBlockCoordinate};
+use anyhow::Result;
+
+/// A flat world made of layers of blocks stacked on top of each other, like a cake.
+/// Layers are listed from the top down, with the top of the first layer at the surface height. Everything below the
+/// last layer is left empty.
+pub struct LayeredWorld {
+    surface_height: i64,
+    layers: Vec<(String, u32)>,
+    layer_blocks: Vec<Option<BlockID>>,
+}
+
+impl LayeredWorld {
+    /// Create a layered world from the names of blocks and how many blocks thick each layer is.
+    pub fn new(surface_height: i64, layers: &[(&str, u32)]) -> Box<LayeredWorld> {
+        Box::new(LayeredWorld {
+            surface_height,
+            layers: layers.iter().map(|(block, thickness)| (String::from(*block), *thickness)).collect(),
+            layer_blocks: Vec::new(),
+        })
+    }
+
+    /// Find which layer a height falls in, if any.
+    fn layer_at(&self, y: i64) -> Option<usize> {
+        let mut top = self.surface_height;
+        for (index, (_block, thickness)) in self.layers.iter().enumerate() {
+            let bottom = top - *thickness as i64;
+            if y <= top && y > bottom {
+                return Some(index);
+            }
+            top = bottom;
+        }
+
+        None
+    }
+}
+
+impl<ChunkUserData: Default> TerrainGenerator<ChunkUserData> for LayeredWorld {
+    fn initialize_block_ids(&mut self, registry: &BlockRegistry) -> Result<()> {
+        self.layer_blocks =
+            self.layers.iter().map(|(block, _thickness)| Ok(Some(require_block(registry, block)?))).collect::<Result<_>>()?;
+
+        Ok(())
+    }
+
+    fn populate_chunk(&self, chunk: &mut Chunk<ChunkUserData>) -> TerrainGeneratorResult {
+        let origin = chunk.index().to_block_coordinate();
+
+        for local_y in 0..storage::CHUNK_DIAMETER as u8 {
+            let block = match self.layer_at(origin.y + local_y as i64) {
+                Some(layer) => self.layer_blocks[layer],
+                None => continue,
+            };
+
+            for local_z in 0..storage::CHUNK_DIAMETER as u8 {
+                for local_x in 0..storage::CHUNK_DIAMETER as u8 {
+                    *chunk.get_single_block_local_mut(LocalBlockCoordinate::new(local_x, local_y, local_z)) = block;
+                }
+            }
+        }
+
+        Ok(TerrainGeneratorSuccessType::Continue)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{super::test::registry_with, *};
+    use crate::world::{chunk_providers::RAMWorld, ChunkCoordinate, ChunkProvider};
+
+    /// Layers land at the right heights, even where they cross between chunks.
+    #[test]
+    fn layers() {
+        let registry = registry_with(&[("grass", "Grass"), ("soil", "Soil"), ("stone", "Stone"), ("bedrock", "Bedrock")]);
+        let mut provider = RAMWorld::new(registry);
+        provider.add_generator(LayeredWorld::new(2, &[("grass", 1), ("soil", 3), ("stone", 60), ("bedrock", 1)])).unwrap();
+
+        let registry = provider.block_registry();
+        let [grass, soil, stone, bedrock] =
+            ["grass", "soil", "stone", "bedrock"].map(|name| registry.get_block_id_from_name(name).cloned());
+
+        let column = |provider: &RAMWorld<()>, y: i32| {
+            let mut chunk = Chunk::new(ChunkCoordinate::new(5, y, -9), ());
+            provider.provide_chunk(&mut chunk).unwrap();
+            (0..storage::CHUNK_DIAMETER as u8)
+                .map(|local_y| chunk.get_single_block_local(LocalBlockCoordinate::new(3, local_y, 7)))
+                .collect::<Vec<_>>()
+        };
+
+        let heights: Vec<Option<BlockID>> = (-3..1).flat_map(|y| column(&provider, y)).collect();
+        let expected: Vec<Option<BlockID>> = (-96..32)
+            .map(|y| match y {
+                2 => grass,
+                -1..=1 => soil,
+                -61..=-2 => stone,
+                -62 => bedrock,
+                _ => None,
+            })
+            .collect();
+        assert_eq!(heights, expected);
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Chunk providers to fill your world with land and honey.
+
+use super::{BlockID, BlockRegistry, Chunk, ChunkProvider};
+use anyhow::{Context, Result};
+
+mod stages;
+pub use stages::*;
+
+pub mod noise;
+
+mod heightmap;
+pub use heightmap::*;
+
+mod biomes;
+pub use biomes::*;
+
+mod caves;
+pub use caves::*;
+mod vents;
+pub use vents::*;
+mod ores;
+pub use ores::*;
+mod structures;
+pub use structures::*;
+
+mod layered;
+pub use layered::*;
+
+mod presets;
+pub use presets::*;
+
+/// Used by the terrain generator to indicate if the chunk has been fully generated or should be passed to the next generator
+/// function to continue filling.
+pub enum TerrainGeneratorSuccessType {
+    /// Terrain generation is finished. Do not pass the chunk to the next generator, or on to any later stages.
+    Finished,
+
+    /// Terrain generation is unfinished. Please pass the chunk to the next generator.
+    Continue,
+}
+
+/// A result indicating the success or failure of a generated chunk.
+pub type TerrainGeneratorResult = anyhow::Result<TerrainGeneratorSuccessType>;
+
+/// An object that provides the terrain for chunks.
+pub trait TerrainGenerator<ChunkUserData: Default> {
+    /// Load all the block IDs this generator needs to populate chunks. Fails if a block it needs is unavailable.
+    fn initialize_block_ids(&mut self, registry: &BlockRegistry) -> Result<()>;
+
+    /// Populates the provided chunk with terrain. Assumes the chunk is initially empty.
+    fn populate_chunk(&self, chunk: &mut Chunk<ChunkUserData>) -> TerrainGeneratorResult;
+
+    /// Populates the provided chunk with terrain, with the chunks around it there to look at. This is what chunk
+    /// providers actually call, but most generators only care about their own chunk, so by default it just calls
+    /// [TerrainGenerator::populate_chunk].
+    fn populate_chunk_with_neighbors(
+        &self, chunk: &mut Chunk<ChunkUserData>, _neighbors: &ChunkNeighborhood<ChunkUserData>,
+    ) -> TerrainGeneratorResult {
+        self.populate_chunk(chunk)
+    }
+}
+
+/// A chunk provider that fills its chunks using terrain generators, which can be added to it.
+pub trait GeneratorHost<ChunkUserData: Default>: ChunkProvider<ChunkUserData> {
+    /// Add a terrain generator to run in a specific stage of generation. Fails if the generator can't get the blocks it
+    /// needs.
+    fn add_stage_generator(
+        &mut self, stage: GenerationStage, generator: Box<dyn TerrainGenerator<ChunkUserData>>,
+    ) -> Result<()>;
+}
+
+/// Get the ID of a block a generator needs. Generators never add blocks themselves, so the block must already be
+/// registered, by the world preset's blocks or by a plugin.
+pub fn require_block(registry: &BlockRegistry, name: &str) -> Result<BlockID> {
+    registry.get_block_id_from_name(name).cloned().with_context(|| format!("Block \"{}\" is not registered.", name))
+}
+
+/// Register blocks as pairs of names and display text. Fails if any of them is already registered.
+pub fn register_blocks(registry: &mut BlockRegistry, blocks: &[(&str, &str)]) -> Result<()> {
+    for (name, display_text) in blocks.iter() {
+        registry
+            .add_block(String::from(*name), String::from(*display_text))
+            .with_context(|| format!("Failed to register block \"{}\".", name))?;
+    }
+
+    Ok(())
+}
+
+/// Just a flat world of abstract blocks.
+pub struct AbstractFlatWorld {
+    abstract_block: Option<BlockID>,
+}
+
+impl AbstractFlatWorld {
+    /// The blocks this generator needs, which must be registered before it's added.
+    pub const BLOCKS: &'static [(&'static str, &'static str)] = &[("abstract_block", "Abstract Block")];
+
+    /// Create a new abstract flat world terrain provider.
+    pub fn new() -> Box<AbstractFlatWorld> {
+        Box::new(AbstractFlatWorld { abstract_block: None })
+    }
+}
+
+impl<ChunkUserData: Default> TerrainGenerator<ChunkUserData> for AbstractFlatWorld {
+    fn initialize_block_ids(&mut self, registry: &BlockRegistry) -> Result<()> {
+        self.abstract_block = Some(require_block(registry, "abstract_block")?);
+
+        Ok(())
+    }
+
+    fn populate_chunk(&self, chunk: &mut Chunk<ChunkUserData>) -> TerrainGeneratorResult {
+        // We just generate a flat world.
+        let chunk_location = chunk.index();
+        if chunk_location.y < 0 {
+            chunk.iter_ideal_mut(Chunk::<ChunkUserData>::range_all_blocks()).for_each(|block| *block = self.abstract_block);
+        }
+
+        Ok(TerrainGeneratorSuccessType::Finished)
+    }
+}
+
+/// A world that just exists in memory. It cannot be saved or backed up.
+/// It's ideal for testing!
+pub struct RAMWorld<ChunkUserData> {
+    block_registry: BlockRegistry,
+    generators: Vec<(GenerationStage, Box<dyn TerrainGenerator<ChunkUserData>>)>,
+}
+
+impl<ChunkUserData: Default> RAMWorld<ChunkUserData> {
+    /// Construct a new RAM world.
+    pub fn new(block_registry: BlockRegistry) -> Box<RAMWorld<ChunkUserData>> {
+        let generators = Vec::new();
+
+        Box::new(RAMWorld { block_registry, generators })
+    }
+
+    // TODO this should definitely go into a factory.
+    /// Add a terrain generator to provide terrain for this chunk. Terrain generators will be called in the order
+    /// they have been added. If the generator fails, the chunk fails along with it, and no more generators are called.
+    /// If the generator returns "continue" then the next generator will be called. If  the generator returns "finished" then the next
+    /// terrain generator will not be called. In the case that there is no next terrain generator, then this function will return.
+    /// The generator is run in the base terrain stage. Fails if the generator can't get the blocks it needs.
+    pub fn add_generator(&mut self, generator: Box<dyn TerrainGenerator<ChunkUserData>>) -> Result<()> {
+        self.add_stage_generator(GenerationStage::BaseTerrain, generator)
+    }
+
+    /// Add a terrain generator to run in a specific stage of generation. Generators in the same stage are called in the
+    /// order they have been added, just like [RAMWorld::add_generator]. Every stage but the base terrain gets to see the
+    /// chunks around the one being generated.
+    pub fn add_stage_generator(
+        &mut self, stage: GenerationStage, mut generator: Box<dyn TerrainGenerator<ChunkUserData>>,
+    ) -> Result<()> {
+        generator.initialize_block_ids(&self.block_registry).context("Failed to initialize terrain generator.")?;
+        self.generators.push((stage, generator));
+
+        Ok(())
+    }
+}
+
+impl<ChunkUserData: Default> GeneratorHost<ChunkUserData> for RAMWorld<ChunkUserData> {
+    fn add_stage_generator(
+        &mut self, stage: GenerationStage, generator: Box<dyn TerrainGenerator<ChunkUserData>>,
+    ) -> Result<()> {
+        RAMWorld::add_stage_generator(self, stage, generator)
+    }
+}
+
+impl<ChunkUserData: Default> ChunkProvider<ChunkUserData> for RAMWorld<ChunkUserData> {
+    fn provide_chunk(&self, chunk: &mut Chunk<ChunkUserData>) -> Result<()> {
+        // Generated on its own, so there are no neighbors to look at.
+        while !chunk.generation_stage().is_complete() {
+            self.provide_stage(chunk, &ChunkNeighborhood::empty(chunk.index()))?;
+        }
+
+        Ok(())
+    }
+    fn provide_stage(&self, chunk: &mut Chunk<ChunkUserData>, neighbors: &ChunkNeighborhood<ChunkUserData>) -> Result<()> {
+        let stage = match chunk.generation_stage().next() {
+            Some(stage) => stage,
+            None => return Ok(()),
+        };
+
+        for (_stage, generator) in self.generators.iter().filter(|(generator_stage, _generator)| *generator_stage == stage) {
+            let success_type = generator
+                .populate_chunk_with_neighbors(chunk, neighbors)
+                .with_context(|| format!("Failed to populate chunk {:?}.", chunk.index()))?;
+
+            match success_type {
+                TerrainGeneratorSuccessType::Continue => continue,
+                TerrainGeneratorSuccessType::Finished => {
+                    chunk.set_generation_stage(GenerationStage::COMPLETE);
+                    return Ok(());
+                }
+            }
+        }
+
+        chunk.set_generation_stage(stage);
+
+        Ok(())
+    }
+    fn stage_needs_neighbors(&self, stage: GenerationStage) -> bool {
+        stage > GenerationStage::BaseTerrain
+            && self.generators.iter().any(|(generator_stage, _generator)| *generator_stage == stage)
+    }
+    fn block_registry(&self) -> &BlockRegistry {
+        &self.block_registry
+    }
+    fn block_registry_mut(&mut self) -> &mut BlockRegistry {
+        &mut self.block_registry
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::world::{storage, Fnv1a};
+    use std::hash::Hasher;
+
+    /// Hash the content of a chunk, with a hash that is the same on every platform.
+    /// Used to make sure generators keep generating the same terrain for the same seed.
+    pub(super) fn hash_chunk(chunk: &Chunk<()>) -> u64 {
+        let mut hasher = Fnv1a::new();
+        for index in 0..storage::CHUNK_DIAMETER * storage::CHUNK_DIAMETER * storage::CHUNK_DIAMETER {
+            let block = chunk.direct_access(index).unwrap().map(|block| block.index() as u16 + 1).unwrap_or(0);
+            hasher.write(&block.to_le_bytes());
+        }
+
+        hasher.finish()
+    }
+
+    /// A block registry with the given blocks registered in order, so their IDs don't change between runs.
+    pub(super) fn registry_with(blocks: &[(&str, &str)]) -> BlockRegistry {
+        let mut registry = BlockRegistry::new();
+        register_blocks(&mut registry, blocks).unwrap();
+
+        registry
+    }
+
+    /// Generators only look up the blocks they need, and never add them to the registry.
+    #[test]
+    fn unregistered_blocks() {
+        let mut provider = RAMWorld::<()>::new(BlockRegistry::new());
+        assert!(provider.add_generator(AbstractFlatWorld::new()).is_err());
+        assert!(provider.block_registry().get_block_id_from_name("abstract_block").is_none());
+
+        let mut provider = RAMWorld::<()>::new(registry_with(AbstractFlatWorld::BLOCKS));
+        provider.add_generator(AbstractFlatWorld::new()).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Seeded noise for terrain generation.
+//! Everything here is worked out from the seed and the position alone, with no tables or state, so it doesn't matter
+//! what order the world is generated in. Only addition, subtraction, multiplication, division and floor are used on
+//! floats. IEEE 754 requires those to be correctly rounded, so they give the same results on every platform.
+
+/// Mix a value into a well distributed hash. This is the finalizer from splitmix64.
+pub fn mix(value: u64) -> u64 {
+    let mut value = value.wrapping_add(0x9E3779B97F4A7C15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D049BB133111EB);
+    value ^ (value >> 31)
+}
+
+/// Hash a seed and a 2D position together.
+pub fn hash_2d(seed: u64, x: i64, z: i64) -> u64 {
+    mix(mix(seed ^ x as u64) ^ z as u64)
+}
+
+/// Hash a seed and a 3D position together.
+pub fn hash_3d(seed: u64, x: i64, y: i64, z: i64) -> u64 {
+    mix(mix(mix(seed ^ x as u64) ^ y as u64) ^ z as u64)
+}
+
+/// Turn a hash into a number from 0 to 1, never quite reaching 1.
+pub fn unit(hash: u64) -> f64 {
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Derive a new seed from a seed, so that different uses of the same world seed don't line up with each other.
+pub fn derive_seed(seed: u64, salt: u64) -> u64 {
+    mix(seed ^ mix(salt))
+}
+
+/// Smooths the interpolation between lattice points so that the noise has no visible creases.
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+/// Pick one of eight gradients for a lattice point, and find its influence on an offset from that point.
+fn gradient_2d(hash: u64, x: f64, z: f64) -> f64 {
+    match hash >> 61 {
+        0 => x + z,
+        1 => x - z,
+        2 => -x + z,
+        3 => -x - z,
+        4 => x,
+        5 => -x,
+        6 => z,
+        _ => -z,
+    }
+}
+
+/// Pick one of twelve gradients for a lattice point, and find its influence on an offset from that point.
+fn gradient_3d(hash: u64, x: f64, y: f64, z: f64) -> f64 {
+    match (hash >> 32) % 12 {
+        0 => x + y,
+        1 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
+
+/// Gradient noise in two dimensions, with features about one unit apart. Gives values roughly from -1 to 1.
+pub fn gradient_noise_2d(seed: u64, x: f64, z: f64) -> f64 {
+    let x0 = x.floor();
+    let z0 = z.floor();
+    let (xi, zi) = (x0 as i64, z0 as i64);
+    let (dx, dz) = (x - x0, z - z0);
+
+    let n00 = gradient_2d(hash_2d(seed, xi, zi), dx, dz);
+    let n10 = gradient_2d(hash_2d(seed, xi + 1, zi), dx - 1.0, dz);
+    let n01 = gradient_2d(hash_2d(seed, xi, zi + 1), dx, dz - 1.0);
+    let n11 = gradient_2d(hash_2d(seed, xi + 1, zi + 1), dx - 1.0, dz - 1.0);
+
+    let (u, v) = (fade(dx), fade(dz));
+    lerp(lerp(n00, n10, u), lerp(n01, n11, u), v)
+}
+
+/// Gradient noise in three dimensions, with features about one unit apart. Gives values roughly from -1 to 1.
+pub fn gradient_noise_3d(seed: u64, x: f64, y: f64, z: f64) -> f64 {
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let z0 = z.floor();
+    let (xi, yi, zi) = (x0 as i64, y0 as i64, z0 as i64);
+    let (dx, dy, dz) = (x - x0, y - y0, z - z0);
+
+    let corner = |cx: i64, cy: i64, cz: i64| {
+        gradient_3d(hash_3d(seed, xi + cx, yi + cy, zi + cz), dx - cx as f64, dy - cy as f64, dz - cz as f64)
+    };
+
+    let (u, v, w) = (fade(dx), fade(dy), fade(dz));
+    lerp(
+        lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), u), lerp(corner(0, 1, 0), corner(1, 1, 0), u), v),
+        lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), u), lerp(corner(0, 1, 1), corner(1, 1, 1), u), v),
+        w,
+    )
+}
+
+/// Several octaves of gradient noise stacked on top of each other, each with twice the detail and half the strength
+/// of the last.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FractalNoise {
+    seed: u64,
+    scale: f64,
+    octaves: u32,
+}
+
+impl FractalNoise {
+    /// Create fractal noise where the largest features are about `scale` blocks across.
+    pub fn new(seed: u64, scale: f64, octaves: u32) -> FractalNoise {
+        FractalNoise { seed, scale, octaves: octaves.max(1) }
+    }
+
+    /// Sample the noise in two dimensions. Gives values roughly from -1 to 1.
+    pub fn sample_2d(&self, x: f64, z: f64) -> f64 {
+        self.sum_octaves(|seed, frequency| gradient_noise_2d(seed, x * frequency, z * frequency))
+    }
+
+    /// Sample the noise in three dimensions. Gives values roughly from -1 to 1.
+    pub fn sample_3d(&self, x: f64, y: f64, z: f64) -> f64 {
+        self.sum_octaves(|seed, frequency| gradient_noise_3d(seed, x * frequency, y * frequency, z * frequency))
+    }
+
+    fn sum_octaves(&self, sample: impl Fn(u64, f64) -> f64) -> f64 {
+        let mut total = 0.0;
+        let mut total_amplitude = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = 1.0 / self.scale;
+
+        for octave in 0..self.octaves {
+            total += sample(derive_seed(self.seed, octave as u64), frequency) * amplitude;
+            total_amplitude += amplitude;
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+
+        total / total_amplitude
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Noise is zero on lattice points and stays within its range between them.
+    #[test]
+    fn range() {
+        for x in -50..50 {
+            for z in -50..50 {
+                assert_eq!(gradient_noise_2d(7, x as f64, z as f64), 0.0);
+
+                let value = gradient_noise_2d(7, x as f64 * 0.37, z as f64 * 0.41);
+                assert!((-1.0..=1.0).contains(&value), "{} is out of range", value);
+
+                let value = gradient_noise_3d(7, x as f64 * 0.37, 0.5, z as f64 * 0.41);
+                assert!((-1.0..=1.0).contains(&value), "{} is out of range", value);
+            }
+        }
+    }
+
+    /// Noise with different seeds should look different.
+    #[test]
+    fn seeds() {
+        let noise_a = FractalNoise::new(1, 64.0, 4);
+        let noise_b = FractalNoise::new(2, 64.0, 4);
+
+        let differences =
+            (0..100).filter(|x| noise_a.sample_2d(*x as f64 * 3.3, 0.0) != noise_b.sample_2d(*x as f64 * 3.3, 0.0)).count();
+        assert!(differences > 90);
+    }
+
+    /// Hashes turn into numbers from 0 up to, but never including, 1.
+    #[test]
+    fn unit_range() {
+        assert_eq!(unit(0), 0.0);
+        assert!(unit(u64::MAX) < 1.0);
+        assert!((0..1000).map(|x| unit(mix(x))).all(|value| (0.0..1.0).contains(&value)));
+    }
+}
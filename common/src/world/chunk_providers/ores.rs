@@ -0,0 +1,318 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Scatters ore veins through the rock, and builds the vents from a [VentField] into the world.
+
+use super::{
+    noise::{hash_3d, mix, unit},
+    require_block, BiomeMap, TerrainGenerator, TerrainGeneratorResult, TerrainGeneratorSuccessType, VentField,
+};
+use crate::world::{
+    storage, BlockID, BlockRegistry, Chunk, ChunkCoordinate, ChunkCoordinateEXT, GlobalBlockCoordinate, LocalBlockCoordinate,
+};
+use anyhow::Result;
+use std::sync::Arc;
+
+/// A kind of ore and where it can be found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OreVein {
+    block: String,
+    depth_range: (i64, i64),
+    veins_per_chunk: f64,
+    size: u32,
+    biomes: Vec<String>,
+}
+
+impl OreVein {
+    /// Create a kind of ore found between two heights. On average, there are `veins_per_chunk` veins in every chunk in
+    /// that range, each made of up to `size` blocks.
+    pub fn new(block: &str, lowest: i64, highest: i64, veins_per_chunk: f64, size: u32) -> OreVein {
+        OreVein { block: String::from(block), depth_range: (lowest, highest), veins_per_chunk, size, biomes: Vec::new() }
+    }
+
+    /// Only generate this ore in the biomes with the given names. Ignored if the generator has no biomes.
+    pub fn in_biomes(mut self, biomes: &[&str]) -> OreVein {
+        self.biomes = biomes.iter().map(|name| String::from(*name)).collect();
+        self
+    }
+
+    /// The name of the ore block.
+    pub fn block(&self) -> &str {
+        &self.block
+    }
+}
+
+/// Generates ore veins and vents. Ore only replaces stone, but vents are built wherever they are, even if they end up
+/// hanging in a cave.
+pub struct OreGenerator {
+    seed: u64,
+    ores: Vec<OreVein>,
+    ore_blocks: Vec<Option<BlockID>>,
+    stone_block: Option<BlockID>,
+    biomes: Option<Arc<BiomeMap>>,
+    vents: Arc<VentField>,
+    vent_blocks: Vec<Option<BlockID>>,
+    vent_shell_block: Option<BlockID>,
+}
+
+impl OreGenerator {
+    /// How far the shell of rock around a vent reaches from its center.
+    pub const VENT_RADIUS: i64 = 3;
+
+    /// The blocks this generator needs, which must be registered before it's added. The blocks of its ores and vents
+    /// are needed too.
+    pub const BLOCKS: &'static [(&'static str, &'static str)] = &[("stone", "Stone"), ("basalt", "Basalt")];
+
+    /// Create an ore generator with some basic ores.
+    pub fn new(seed: u64, vents: Arc<VentField>) -> Box<OreGenerator> {
+        Self::with_ores(
+            seed,
+            vents,
+            vec![
+                OreVein::new("coal_ore", -128, 0, 4.0, 12),
+                OreVein::new("iron_ore", -256, -16, 2.5, 8),
+                OreVein::new("copper_ore", -192, -8, 2.0, 8).in_biomes(&["desert", "mountains"]),
+            ],
+        )
+    }
+
+    /// Create an ore generator with custom ores.
+    pub fn with_ores(seed: u64, vents: Arc<VentField>, ores: Vec<OreVein>) -> Box<OreGenerator> {
+        Box::new(OreGenerator {
+            seed,
+            ores,
+            ore_blocks: Vec::new(),
+            stone_block: None,
+            biomes: None,
+            vents,
+            vent_blocks: Vec::new(),
+            vent_shell_block: None,
+        })
+    }
+
+    /// Use biomes to decide which ores go where. This must be done before the generator is added to a chunk provider.
+    pub fn set_biomes(&mut self, biomes: Arc<BiomeMap>) {
+        self.biomes = Some(biomes);
+    }
+
+    /// The vents this generator builds.
+    pub fn vents(&self) -> &Arc<VentField> {
+        &self.vents
+    }
+
+    /// Find every block of every vein that starts in a chunk.
+    fn veins_from_chunk(&self, source: ChunkCoordinate, mut place: impl FnMut(usize, GlobalBlockCoordinate)) {
+        let origin = source.to_block_coordinate();
+        let diameter = storage::CHUNK_DIAMETER as u64;
+
+        for (ore_index, ore) in self.ores.iter().enumerate() {
+            let (lowest, highest) = ore.depth_range;
+            let size = ore.size as i64;
+            if origin.y + diameter as i64 + size < lowest || origin.y - size > highest {
+                continue;
+            }
+
+            let chunk_hash = mix(hash_3d(self.seed, origin.x, origin.y, origin.z) ^ ore_index as u64);
+            let num_veins = ore.veins_per_chunk.floor() as u64 + (unit(chunk_hash) < ore.veins_per_chunk.fract()) as u64;
+
+            for vein in 0..num_veins {
+                let vein_hash = mix(chunk_hash ^ (vein + 1));
+                let mut position = origin
+                    + GlobalBlockCoordinate::new(
+                        (mix(vein_hash ^ 1) % diameter) as i64,
+                        (mix(vein_hash ^ 2) % diameter) as i64,
+                        (mix(vein_hash ^ 3) % diameter) as i64,
+                    );
+
+                if position.y < lowest || position.y > highest {
+                    continue;
+                }
+
+                if let Some(biomes) = &self.biomes {
+                    if !ore.biomes.is_empty() {
+                        let biome = biomes.biome_at(position).and_then(|biome| biomes.get_biome(biome));
+                        if !biome.map(|biome| ore.biomes.iter().any(|name| name == biome.name())).unwrap_or(false) {
+                            continue;
+                        }
+                    }
+                }
+
+                // Veins wander one block at a time in random directions.
+                for step in 0..ore.size as u64 {
+                    place(ore_index, position);
+
+                    let direction = mix(vein_hash ^ (step + 4)) % 6;
+                    let axis = (direction / 2) as usize;
+                    position[axis] += [1, -1][(direction % 2) as usize];
+                }
+            }
+        }
+    }
+}
+
+impl<ChunkUserData: Default> TerrainGenerator<ChunkUserData> for OreGenerator {
+    fn initialize_block_ids(&mut self, registry: &BlockRegistry) -> Result<()> {
+        self.stone_block = Some(require_block(registry, "stone")?);
+        self.vent_shell_block = Some(require_block(registry, "basalt")?);
+
+        self.ore_blocks = self.ores.iter().map(|ore| Ok(Some(require_block(registry, &ore.block)?))).collect::<Result<_>>()?;
+
+        self.vent_blocks = self
+            .vents
+            .types()
+            .iter()
+            .map(|vent_type| Ok(Some(require_block(registry, vent_type.block())?)))
+            .collect::<Result<_>>()?;
+
+        Ok(())
+    }
+
+    fn populate_chunk(&self, chunk: &mut Chunk<ChunkUserData>) -> TerrainGeneratorResult {
+        let location = chunk.index();
+        let origin = location.to_block_coordinate();
+        let far = origin.add_scalar(storage::CHUNK_DIAMETER as i64 - 1);
+        let in_chunk =
+            |position: GlobalBlockCoordinate| (0..3).all(|axis| position[axis] >= origin[axis] && position[axis] <= far[axis]);
+        let local = |position: GlobalBlockCoordinate| (position - origin).map(|v| v as u8) as LocalBlockCoordinate;
+
+        // Veins can wander out of the chunk they started in, so our neighbors' veins need to be checked too.
+        for offset_z in -1..=1 {
+            for offset_y in -1..=1 {
+                for offset_x in -1..=1 {
+                    let source = location + ChunkCoordinate::new(offset_x, offset_y, offset_z);
+                    self.veins_from_chunk(source, |ore_index, position| {
+                        if in_chunk(position) {
+                            let block = chunk.get_single_block_local_mut(local(position));
+                            if block.is_some() && *block == self.stone_block {
+                                *block = self.ore_blocks[ore_index];
+                            }
+                        }
+                    });
+                }
+            }
+        }
+
+        let radius = GlobalBlockCoordinate::new(Self::VENT_RADIUS, Self::VENT_RADIUS, Self::VENT_RADIUS);
+        for vent in self.vents.vents_in_box(origin - radius, far + radius) {
+            let center = vent.position();
+            for offset_z in -Self::VENT_RADIUS..=Self::VENT_RADIUS {
+                for offset_y in -Self::VENT_RADIUS..=Self::VENT_RADIUS {
+                    for offset_x in -Self::VENT_RADIUS..=Self::VENT_RADIUS {
+                        let offset = GlobalBlockCoordinate::new(offset_x, offset_y, offset_z);
+                        let position = center + offset;
+                        if offset.map(|v| v * v).sum() > Self::VENT_RADIUS * Self::VENT_RADIUS || !in_chunk(position) {
+                            continue;
+                        }
+
+                        *chunk.get_single_block_local_mut(local(position)) =
+                            if position == center { self.vent_blocks[vent.type_index()] } else { self.vent_shell_block };
+                    }
+                }
+            }
+        }
+
+        Ok(TerrainGeneratorSuccessType::Continue)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::test::{hash_chunk, registry_with},
+        *,
+    };
+    use crate::world::{
+        chunk_providers::{HeightmapWorld, RAMWorld},
+        ChunkProvider, GlobalBlockCoordinateEXT,
+    };
+
+    fn create_provider(seed: u64, vents: Arc<VentField>) -> Box<RAMWorld<()>> {
+        let blocks = [
+            ("basalt", "Basalt"),
+            ("coal_ore", "Coal Ore"),
+            ("iron_ore", "Iron Ore"),
+            ("copper_ore", "Copper Ore"),
+            ("iron_vent", "Iron Vent"),
+            ("copper_vent", "Copper Vent"),
+            ("gold_vent", "Gold Vent"),
+        ];
+        let mut provider = RAMWorld::new(registry_with(&[HeightmapWorld::BLOCKS, &blocks].concat()));
+        provider.add_generator(HeightmapWorld::with_layers(seed, &[])).unwrap();
+        provider.add_generator(OreGenerator::new(seed, vents)).unwrap();
+
+        provider
+    }
+
+    fn generate(provider: &dyn ChunkProvider<()>, location: ChunkCoordinate) -> Chunk<()> {
+        let mut chunk = Chunk::new(location, ());
+        provider.provide_chunk(&mut chunk).unwrap();
+
+        chunk
+    }
+
+    fn count(provider: &dyn ChunkProvider<()>, chunk: &Chunk<()>, name: &str) -> usize {
+        let id = provider.block_registry().get_block_id_from_name(name).cloned();
+        chunk.iter_ideal(Chunk::<()>::range_all_blocks()).filter(|block| *block == id).count()
+    }
+
+    /// Ores are only found at the right depths, give or take how far their veins can wander.
+    #[test]
+    fn ore_depths() {
+        let provider = create_provider(3, Arc::new(VentField::new(3)));
+        let registry = provider.block_registry();
+        let coal = registry.get_block_id_from_name("coal_ore").cloned();
+        let iron = registry.get_block_id_from_name("iron_ore").cloned();
+        let copper = registry.get_block_id_from_name("copper_ore").cloned();
+
+        let mut found = [0; 3];
+        for y in -9..1 {
+            for x in 0..4 {
+                let chunk = generate(provider.as_ref(), ChunkCoordinate::new(x, y, 0));
+                for (index, block) in chunk.iter_ideal(Chunk::<()>::range_all_blocks()).enumerate() {
+                    let height = y as i64 * storage::CHUNK_DIAMETER as i64
+                        + (index / storage::CHUNK_DIAMETER) as i64 % storage::CHUNK_DIAMETER as i64;
+                    if block == coal {
+                        assert!((-128 - 12..=0).contains(&height), "Coal at {}.", height);
+                        found[0] += 1;
+                    } else if block == iron {
+                        assert!((-256 - 8..=-16 + 8).contains(&height), "Iron at {}.", height);
+                        found[1] += 1;
+                    } else if block == copper {
+                        assert!((-192 - 8..=-8 + 8).contains(&height), "Copper at {}.", height);
+                        found[2] += 1;
+                    }
+                }
+            }
+        }
+
+        // Without biomes, biome restrictions are ignored, so copper is found too.
+        assert!(found.iter().all(|count| *count > 0), "Found {:?}.", found);
+    }
+
+    /// Vents the locator finds really are in the world.
+    #[test]
+    fn vents() {
+        let vents = Arc::new(VentField::new(3));
+        let provider = create_provider(3, vents.clone());
+
+        let vent = vents.nearest_vent(GlobalBlockCoordinate::new(0, 0, 0), "iron", 10_000).unwrap();
+        let chunk = generate(provider.as_ref(), vent.position().chunk_index());
+
+        let vent_block = provider.block_registry().get_block_id_from_name("iron_vent").cloned();
+        assert!(vent_block.is_some());
+        assert_eq!(chunk.get_single_block_local(vent.position().to_local_block_coordinate()), vent_block);
+        assert_eq!(count(provider.as_ref(), &chunk, "iron_vent"), 1);
+    }
+
+    /// The result never changes for a seed.
+    #[test]
+    fn golden_hashes() {
+        let provider = create_provider(0xDEADBEEF, Arc::new(VentField::new(0xDEADBEEF)));
+
+        let hashes: Vec<u64> = [(0, -1, 0), (0, -4, 0), (-3, -7, 2)]
+            .iter()
+            .map(|(x, y, z)| hash_chunk(&generate(provider.as_ref(), ChunkCoordinate::new(*x, *y, *z))))
+            .collect();
+        assert_eq!(hashes, vec![0xE5B50ED65A862175, 0x94FEBE7213A4DA08, 0x7C39C22C0800F59D]);
+    }
+}
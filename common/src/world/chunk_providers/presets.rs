@@ -0,0 +1,905 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! World presets, which describe how a world is generated in a data file instead of in code.
+//! Presets are written in RON. They list the blocks the world uses, an optional table of biomes, and the generators
+//! to run in each stage of generation, along with their settings. A preset is saved with the world, so that the
+//! world keeps generating the same way even if the defaults change.
+
+use super::{
+    BiomeMap, CaveCarver, GenerationStage, GeneratorHost, HeightmapLayer, HeightmapWorld, LayeredWorld, OreGenerator, OreVein,
+    StructureGenerator, StructureMap, StructureTemplate, TerrainGenerator, VentField, VentType,
+};
+use crate::world::GlobalBlockCoordinate;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt, sync::Arc};
+
+/// A problem with a field of a preset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresetError {
+    field: String,
+    problem: String,
+}
+
+impl PresetError {
+    fn new(field: String, problem: &str) -> PresetError {
+        PresetError { field, problem: String::from(problem) }
+    }
+
+    /// The path to the bad field, such as `stages[2].generator.layers[0].block`.
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// What is wrong with the field.
+    pub fn problem(&self) -> &str {
+        &self.problem
+    }
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}: {}", self.field, self.problem)
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+/// A block the world uses.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockPreset {
+    /// The name generators refer to the block by.
+    pub name: String,
+
+    /// The name players see.
+    pub display_text: String,
+}
+
+/// A biome, as described in a preset. See [super::Biome] for what the fields do.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BiomePreset {
+    /// The name of the biome.
+    pub name: String,
+
+    /// The block covering the ground.
+    pub surface_block: String,
+
+    /// The block between the surface and the stone.
+    pub soil_block: String,
+
+    /// The ideal temperature, from -1 to 1.
+    #[serde(default)]
+    pub temperature: f64,
+
+    /// The ideal humidity, from -1 to 1.
+    #[serde(default)]
+    pub humidity: f64,
+
+    /// The ideal elevation, from -1 to 1.
+    #[serde(default)]
+    pub elevation: f64,
+
+    /// How far the biome raises or lowers the ground.
+    #[serde(default)]
+    pub height_offset: f64,
+
+    /// How much the biome exaggerates the hills.
+    #[serde(default = "default_roughness")]
+    pub roughness: f64,
+
+    /// How many blocks of soil are under the surface.
+    #[serde(default = "default_soil_depth")]
+    pub soil_depth: i64,
+
+    /// The names of the enemies that spawn in the biome, and how common each is.
+    #[serde(default)]
+    pub enemies: Vec<(String, u32)>,
+}
+
+fn default_roughness() -> f64 {
+    1.0
+}
+
+fn default_soil_depth() -> i64 {
+    4
+}
+
+/// The biomes of a world.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BiomeTablePreset {
+    /// How many blocks the climate takes to change.
+    pub scale: f64,
+
+    /// The biomes to choose from.
+    pub biomes: Vec<BiomePreset>,
+}
+
+/// One layer of a [LayeredWorld].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerPreset {
+    /// The block the layer is made of.
+    pub block: String,
+
+    /// How many blocks thick the layer is.
+    pub thickness: u32,
+}
+
+/// One layer of a [HeightmapWorld].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeightmapLayerPreset {
+    /// About how many blocks across the features of the layer are.
+    pub scale: f64,
+
+    /// How far the layer raises and lowers the ground.
+    pub amplitude: f64,
+
+    /// How many octaves of detail the layer has. No more than [HeightmapLayerPreset::MAX_OCTAVES].
+    pub octaves: u32,
+}
+
+impl HeightmapLayerPreset {
+    /// The most octaves a layer can have. Each one is another noise sample for every column, and past this they're too
+    /// small to see anyway.
+    pub const MAX_OCTAVES: u32 = 16;
+}
+
+/// A kind of ore, as described in a preset. See [OreVein] for what the fields do.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrePreset {
+    /// The ore block.
+    pub block: String,
+
+    /// The lowest the ore is found.
+    pub lowest: i64,
+
+    /// The highest the ore is found.
+    pub highest: i64,
+
+    /// How many veins there are in each chunk, on average.
+    pub veins_per_chunk: f64,
+
+    /// How many blocks are in each vein.
+    pub size: u32,
+
+    /// The biomes the ore is found in. Empty means everywhere.
+    #[serde(default)]
+    pub biomes: Vec<String>,
+}
+
+/// A kind of vent, as described in a preset. See [VentType] for what the fields do.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VentPreset {
+    /// The material the vent puts out.
+    pub material: String,
+
+    /// The block marking the vent.
+    pub block: String,
+
+    /// How many kilograms per second the vent puts out.
+    pub base_output_rate: f32,
+
+    /// How common the vent is compared to the others.
+    pub weight: u32,
+}
+
+/// A box of blocks that makes up part of a structure.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StructurePiecePreset {
+    /// One corner of the box, relative to the structure's origin.
+    pub near: (i64, i64, i64),
+
+    /// The other corner of the box, relative to the structure's origin.
+    pub far: (i64, i64, i64),
+
+    /// The block to fill the box with. None clears it out.
+    pub block: Option<String>,
+}
+
+/// A structure template, as described in a preset. See [StructureTemplate] for what the fields do.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StructurePreset {
+    /// The name of the structure.
+    pub name: String,
+
+    /// How common the structure is compared to the others.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+
+    /// How far the structure is moved up or down from the surface.
+    #[serde(default)]
+    pub vertical_offset: i64,
+
+    /// The chance for each solid block to be missing.
+    #[serde(default)]
+    pub decay: f64,
+
+    /// The boxes the structure is built from, in order.
+    pub pieces: Vec<StructurePiecePreset>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// A terrain generator and its settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum GeneratorPreset {
+    /// A flat world made of layers. See [LayeredWorld].
+    Layered {
+        /// The height of the top block of the first layer.
+        surface_height: i64,
+
+        /// The layers, from the top down.
+        layers: Vec<LayerPreset>,
+    },
+
+    /// Rolling terrain. See [HeightmapWorld].
+    Heightmap {
+        /// The layers of noise added together to get the height of the ground.
+        layers: Vec<HeightmapLayerPreset>,
+
+        /// The height the layers raise and lower the ground from.
+        #[serde(default)]
+        base_height: i64,
+
+        /// How many blocks of soil are under the surface, without biomes.
+        #[serde(default = "default_soil_depth")]
+        soil_depth: i64,
+
+        /// Shape the terrain with the preset's biome table.
+        #[serde(default)]
+        use_biomes: bool,
+    },
+
+    /// Caves, tunnels and ravines. See [CaveCarver].
+    Caves {
+        /// The blocks that can be carved away.
+        carvable_blocks: Vec<String>,
+    },
+
+    /// Ore veins and resource vents. See [OreGenerator].
+    Ores {
+        /// The kinds of ore.
+        ores: Vec<OrePreset>,
+
+        /// The kinds of vent.
+        #[serde(default)]
+        vents: Vec<VentPreset>,
+
+        /// The size of the grid columns vents are placed in.
+        #[serde(default = "default_vent_cell_size")]
+        vent_cell_size: i64,
+
+        /// The chance for each grid column to have a vent.
+        #[serde(default = "default_vent_chance")]
+        vent_chance: f64,
+
+        /// The lowest and highest vents can be.
+        #[serde(default = "default_vent_depth")]
+        vent_depth: (i64, i64),
+    },
+
+    /// Ruins, fortresses and the like. See [StructureGenerator]. Structures sit on the terrain of the first heightmap in
+    /// the preset, or at a height of zero without one.
+    Structures {
+        /// The size of the grid columns structures are placed in.
+        #[serde(default = "default_structure_cell_size")]
+        cell_size: i64,
+
+        /// The chance for each grid column to have a structure.
+        #[serde(default = "default_structure_chance")]
+        chance: f64,
+
+        /// The structures to choose from.
+        templates: Vec<StructurePreset>,
+    },
+}
+
+fn default_vent_cell_size() -> i64 {
+    512
+}
+
+fn default_vent_chance() -> f64 {
+    0.5
+}
+
+fn default_vent_depth() -> (i64, i64) {
+    (-768, -256)
+}
+
+fn default_structure_cell_size() -> i64 {
+    256
+}
+
+fn default_structure_chance() -> f64 {
+    0.25
+}
+
+/// A generator to run in a stage of generation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StagePreset {
+    /// The stage to run the generator in.
+    pub stage: GenerationStage,
+
+    /// The generator and its settings.
+    pub generator: GeneratorPreset,
+}
+
+/// Everything needed to generate a world.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorldPreset {
+    /// The name of the preset.
+    pub name: String,
+
+    /// The seed everything is generated from.
+    pub seed: u64,
+
+    /// The blocks the world uses. Every block a generator uses must be listed here, including the ones heightmaps and
+    /// ore generators always use.
+    #[serde(default)]
+    pub blocks: Vec<BlockPreset>,
+
+    /// The biomes of the world, if it has any.
+    #[serde(default)]
+    pub biomes: Option<BiomeTablePreset>,
+
+    /// The generators, in the order they run. Stages must be in order.
+    pub stages: Vec<StagePreset>,
+}
+
+impl WorldPreset {
+    /// The preset for a normal world, with hills, biomes, caves, ores and ruins.
+    pub fn default_world(seed: u64) -> WorldPreset {
+        let mut preset = Self::from_ron(include_str!("presets/default.ron")).expect("Built in preset is broken.");
+        preset.seed = seed;
+
+        preset
+    }
+
+    /// The preset for a perfectly flat world made of layers.
+    pub fn superflat(seed: u64) -> WorldPreset {
+        let mut preset = Self::from_ron(include_str!("presets/superflat.ron")).expect("Built in preset is broken.");
+        preset.seed = seed;
+
+        preset
+    }
+
+    /// Read a preset from RON, and make sure it makes sense.
+    pub fn from_ron(text: &str) -> Result<WorldPreset> {
+        let preset: WorldPreset = ron::de::from_str(text).context("Failed to parse world preset.")?;
+        preset.validate()?;
+
+        Ok(preset)
+    }
+
+    /// Write the preset as RON.
+    pub fn to_ron(&self) -> Result<String> {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).context("Failed to write world preset.")
+    }
+
+    /// Check that the preset makes sense. The error points at the first bad field found.
+    pub fn validate(&self) -> std::result::Result<(), PresetError> {
+        let mut blocks = HashSet::new();
+        for (index, block) in self.blocks.iter().enumerate() {
+            if !blocks.insert(block.name.as_str()) {
+                return Err(PresetError::new(format!("blocks[{}].name", index), "block is listed more than once"));
+            }
+        }
+
+        let check_block = |field: String, name: &str| {
+            if blocks.contains(name) {
+                Ok(())
+            } else {
+                Err(PresetError::new(field, &format!("block \"{}\" is not listed in blocks", name)))
+            }
+        };
+
+        let mut biomes = HashSet::new();
+        if let Some(table) = &self.biomes {
+            if table.scale.is_nan() || table.scale <= 0.0 {
+                return Err(PresetError::new(String::from("biomes.scale"), "must be more than zero"));
+            }
+            if table.biomes.is_empty() {
+                return Err(PresetError::new(String::from("biomes.biomes"), "there must be at least one biome"));
+            }
+
+            for (index, biome) in table.biomes.iter().enumerate() {
+                let field = format!("biomes.biomes[{}]", index);
+                if !biomes.insert(biome.name.as_str()) {
+                    return Err(PresetError::new(format!("{}.name", field), "biome is listed more than once"));
+                }
+                check_block(format!("{}.surface_block", field), &biome.surface_block)?;
+                check_block(format!("{}.soil_block", field), &biome.soil_block)?;
+            }
+        }
+
+        let mut previous_stage = GenerationStage::Empty;
+        for (stage_index, stage) in self.stages.iter().enumerate() {
+            let field = format!("stages[{}]", stage_index);
+            if stage.stage == GenerationStage::Empty {
+                return Err(PresetError::new(format!("{}.stage", field), "nothing can be generated in the empty stage"));
+            }
+            if stage.stage < previous_stage {
+                return Err(PresetError::new(format!("{}.stage", field), "stages must be in order"));
+            }
+            previous_stage = stage.stage;
+
+            let field = format!("{}.generator", field);
+            match &stage.generator {
+                GeneratorPreset::Layered { layers, .. } => {
+                    for (index, layer) in layers.iter().enumerate() {
+                        check_block(format!("{}.layers[{}].block", field, index), &layer.block)?;
+                        if layer.thickness == 0 {
+                            return Err(PresetError::new(
+                                format!("{}.layers[{}].thickness", field, index),
+                                "must be more than zero",
+                            ));
+                        }
+                    }
+                }
+                GeneratorPreset::Heightmap { layers, soil_depth, use_biomes, .. } => {
+                    for (name, _display_text) in HeightmapWorld::BLOCKS.iter() {
+                        check_block(field.clone(), name)?;
+                    }
+                    for (index, layer) in layers.iter().enumerate() {
+                        if layer.scale.is_nan() || layer.scale <= 0.0 {
+                            return Err(PresetError::new(
+                                format!("{}.layers[{}].scale", field, index),
+                                "must be more than zero",
+                            ));
+                        }
+                        if layer.octaves > HeightmapLayerPreset::MAX_OCTAVES {
+                            return Err(PresetError::new(
+                                format!("{}.layers[{}].octaves", field, index),
+                                &format!("can't be more than {}", HeightmapLayerPreset::MAX_OCTAVES),
+                            ));
+                        }
+                    }
+                    if *soil_depth < 0 {
+                        return Err(PresetError::new(format!("{}.soil_depth", field), "can't be negative"));
+                    }
+                    if *use_biomes && self.biomes.is_none() {
+                        return Err(PresetError::new(format!("{}.use_biomes", field), "the preset has no biome table"));
+                    }
+                }
+                GeneratorPreset::Caves { carvable_blocks } => {
+                    for (index, block) in carvable_blocks.iter().enumerate() {
+                        check_block(format!("{}.carvable_blocks[{}]", field, index), block)?;
+                    }
+                }
+                GeneratorPreset::Ores { ores, vents, vent_cell_size, vent_chance, vent_depth } => {
+                    for (name, _display_text) in OreGenerator::BLOCKS.iter() {
+                        check_block(field.clone(), name)?;
+                    }
+                    for (index, ore) in ores.iter().enumerate() {
+                        let field = format!("{}.ores[{}]", field, index);
+                        check_block(format!("{}.block", field), &ore.block)?;
+                        if ore.lowest > ore.highest {
+                            return Err(PresetError::new(format!("{}.lowest", field), "must not be above highest"));
+                        }
+                        if ore.veins_per_chunk.is_nan() || ore.veins_per_chunk < 0.0 {
+                            return Err(PresetError::new(format!("{}.veins_per_chunk", field), "can't be negative"));
+                        }
+                        for (biome_index, biome) in ore.biomes.iter().enumerate() {
+                            if !biomes.contains(biome.as_str()) {
+                                return Err(PresetError::new(
+                                    format!("{}.biomes[{}]", field, biome_index),
+                                    &format!("biome \"{}\" is not in the biome table", biome),
+                                ));
+                            }
+                        }
+                    }
+                    for (index, vent) in vents.iter().enumerate() {
+                        let field = format!("{}.vents[{}]", field, index);
+                        if vent.material.is_empty() {
+                            return Err(PresetError::new(format!("{}.material", field), "must name a material"));
+                        }
+                        check_block(format!("{}.block", field), &vent.block)?;
+                    }
+                    if *vent_cell_size <= 0 {
+                        return Err(PresetError::new(format!("{}.vent_cell_size", field), "must be more than zero"));
+                    }
+                    if !(0.0..=1.0).contains(vent_chance) {
+                        return Err(PresetError::new(format!("{}.vent_chance", field), "must be from 0 to 1"));
+                    }
+                    if vent_depth.0 > vent_depth.1 {
+                        return Err(PresetError::new(format!("{}.vent_depth", field), "lowest must not be above highest"));
+                    }
+                }
+                GeneratorPreset::Structures { cell_size, chance, templates } => {
+                    if *cell_size <= 0 {
+                        return Err(PresetError::new(format!("{}.cell_size", field), "must be more than zero"));
+                    }
+                    if !(0.0..=1.0).contains(chance) {
+                        return Err(PresetError::new(format!("{}.chance", field), "must be from 0 to 1"));
+                    }
+                    for (index, template) in templates.iter().enumerate() {
+                        let field = format!("{}.templates[{}]", field, index);
+                        if !(0.0..=1.0).contains(&template.decay) {
+                            return Err(PresetError::new(format!("{}.decay", field), "must be from 0 to 1"));
+                        }
+                        for (piece_index, piece) in template.pieces.iter().enumerate() {
+                            if let Some(block) = &piece.block {
+                                check_block(format!("{}.pieces[{}].block", field, piece_index), block)?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set up a chunk provider to generate the world this preset describes. The blocks are registered, and then the
+    /// generators are added in order. Fails if one of the preset's blocks is already registered, since the world's
+    /// block IDs would depend on whoever registered it.
+    pub fn apply<ChunkUserData: Default>(&self, host: &mut dyn GeneratorHost<ChunkUserData>) -> Result<()> {
+        self.validate()?;
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            super::register_blocks(host.block_registry_mut(), &[(&block.name, &block.display_text)])
+                .with_context(|| format!("blocks[{}]", index))?;
+        }
+
+        let biomes = self.biome_map();
+        let ground = self.build_ground(biomes.as_ref())?;
+
+        for (index, stage) in self.stages.iter().enumerate() {
+            let generator: Box<dyn TerrainGenerator<ChunkUserData>> = match &stage.generator {
+                GeneratorPreset::Layered { surface_height, layers } => {
+                    let layers: Vec<(&str, u32)> = layers.iter().map(|layer| (layer.block.as_str(), layer.thickness)).collect();
+                    LayeredWorld::new(*surface_height, &layers)
+                }
+                GeneratorPreset::Heightmap { .. } => self.build_heightmap(&stage.generator, biomes.as_ref())?,
+                GeneratorPreset::Caves { carvable_blocks } => {
+                    let carvable: Vec<&str> = carvable_blocks.iter().map(String::as_str).collect();
+                    CaveCarver::with_carvable_blocks(self.seed, &carvable)
+                }
+                GeneratorPreset::Ores { ores, .. } => {
+                    let vent_field = self.build_vent_field(&stage.generator)?;
+
+                    let ores = ores
+                        .iter()
+                        .map(|ore| {
+                            let biomes: Vec<&str> = ore.biomes.iter().map(String::as_str).collect();
+                            OreVein::new(&ore.block, ore.lowest, ore.highest, ore.veins_per_chunk, ore.size).in_biomes(&biomes)
+                        })
+                        .collect();
+
+                    let mut generator = OreGenerator::with_ores(self.seed, vent_field, ores);
+                    if let Some(biomes) = &biomes {
+                        generator.set_biomes(biomes.clone());
+                    }
+
+                    generator
+                }
+                GeneratorPreset::Structures { .. } => {
+                    StructureGenerator::new(self.build_structure_map(&stage.generator, ground.as_ref())?)
+                }
+            };
+
+            host.add_stage_generator(stage.stage, generator).with_context(|| format!("stages[{}].generator", index))?;
+        }
+
+        Ok(())
+    }
+
+    /// The biomes of the world this preset describes, if it has any.
+    pub fn biome_map(&self) -> Option<Arc<BiomeMap>> {
+        self.biomes.as_ref().map(|table| Arc::new(self.build_biomes(table)))
+    }
+
+    /// The vents placed by each ore generator in the preset, in order. Useful for finding vents without generating
+    /// any terrain.
+    pub fn vent_fields(&self) -> Result<Vec<Arc<VentField>>> {
+        self.stages
+            .iter()
+            .filter(|stage| matches!(stage.generator, GeneratorPreset::Ores { .. }))
+            .map(|stage| self.build_vent_field(&stage.generator))
+            .collect()
+    }
+
+    /// The structures placed by each structure generator in the preset, in order. Useful for finding structures
+    /// without generating any terrain.
+    pub fn structure_maps(&self) -> Result<Vec<Arc<StructureMap>>> {
+        let ground = self.build_ground(self.biome_map().as_ref())?;
+
+        self.stages
+            .iter()
+            .filter(|stage| matches!(stage.generator, GeneratorPreset::Structures { .. }))
+            .map(|stage| self.build_structure_map(&stage.generator, ground.as_ref()))
+            .collect()
+    }
+
+    fn build_biomes(&self, table: &BiomeTablePreset) -> BiomeMap {
+        let mut map = BiomeMap::new(self.seed, table.scale);
+        for biome in table.biomes.iter() {
+            let built = biome.enemies.iter().fold(
+                super::Biome::new(&biome.name, &biome.surface_block, &biome.soil_block)
+                    .with_climate(biome.temperature, biome.humidity, biome.elevation)
+                    .with_terrain(biome.height_offset, biome.roughness, biome.soil_depth),
+                |built, (enemy, weight)| built.with_enemy(enemy, *weight),
+            );
+            map.register_biome(built);
+        }
+
+        map
+    }
+
+    /// Structures need to know where the ground is, so they get their own copy of the first heightmap's terrain.
+    fn build_ground(&self, biomes: Option<&Arc<BiomeMap>>) -> Result<Option<Arc<HeightmapWorld>>> {
+        self.stages
+            .iter()
+            .find(|stage| matches!(stage.generator, GeneratorPreset::Heightmap { .. }))
+            .map(|stage| Ok(Arc::from(self.build_heightmap(&stage.generator, biomes)?)))
+            .transpose()
+    }
+
+    fn build_vent_field(&self, generator: &GeneratorPreset) -> Result<Arc<VentField>> {
+        match generator {
+            GeneratorPreset::Ores { vents, vent_cell_size, vent_chance, vent_depth, .. } => {
+                let mut vent_field = VentField::with_types(
+                    self.seed,
+                    vents
+                        .iter()
+                        .map(|vent| VentType::new(&vent.material, &vent.block, vent.base_output_rate, vent.weight))
+                        .collect(),
+                );
+                vent_field.set_spacing(*vent_cell_size, *vent_chance);
+                vent_field.set_depth_range(vent_depth.0, vent_depth.1);
+
+                Ok(Arc::new(vent_field))
+            }
+            _ => bail!("Only ore generators have vents."),
+        }
+    }
+
+    fn build_structure_map(
+        &self, generator: &GeneratorPreset, ground: Option<&Arc<HeightmapWorld>>,
+    ) -> Result<Arc<StructureMap>> {
+        match generator {
+            GeneratorPreset::Structures { cell_size, chance, templates } => {
+                let mut structures = StructureMap::new(self.seed);
+                structures.set_spacing(*cell_size, *chance);
+                if let Some(ground) = ground {
+                    structures.set_ground(ground.clone());
+                }
+
+                for template in templates.iter() {
+                    let to_coordinate = |(x, y, z): (i64, i64, i64)| GlobalBlockCoordinate::new(x, y, z);
+                    let built = template.pieces.iter().fold(
+                        StructureTemplate::new(&template.name)
+                            .with_weight(template.weight)
+                            .with_vertical_offset(template.vertical_offset)
+                            .with_decay(template.decay),
+                        |built, piece| {
+                            built.with_box(to_coordinate(piece.near), to_coordinate(piece.far), piece.block.as_deref())
+                        },
+                    );
+                    structures.register_template(built);
+                }
+
+                Ok(Arc::new(structures))
+            }
+            _ => bail!("Only structure generators have structures."),
+        }
+    }
+
+    fn build_heightmap(&self, generator: &GeneratorPreset, biomes: Option<&Arc<BiomeMap>>) -> Result<Box<HeightmapWorld>> {
+        match generator {
+            GeneratorPreset::Heightmap { layers, base_height, soil_depth, use_biomes } => {
+                let layers: Vec<HeightmapLayer> =
+                    layers.iter().map(|layer| HeightmapLayer::new(layer.scale, layer.amplitude, layer.octaves)).collect();
+                let mut heightmap = HeightmapWorld::with_layers(self.seed, &layers);
+                heightmap.set_base_height(*base_height);
+                heightmap.set_soil_depth(*soil_depth);
+
+                if let (true, Some(biomes)) = (*use_biomes, biomes) {
+                    heightmap.set_biomes(biomes.clone());
+                }
+
+                Ok(heightmap)
+            }
+            _ => bail!("Only heightmaps can be built into heightmaps."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::test::{hash_chunk, registry_with},
+        *,
+    };
+    use crate::world::{chunk_providers::RAMWorld, BlockRegistry, Chunk, ChunkCoordinate, ChunkProvider};
+
+    fn generate(preset: &WorldPreset, location: ChunkCoordinate) -> Chunk<()> {
+        let mut provider = RAMWorld::new(BlockRegistry::new());
+        preset.apply(provider.as_mut()).unwrap();
+
+        let mut chunk = Chunk::new(location, ());
+        provider.provide_chunk(&mut chunk).unwrap();
+
+        chunk
+    }
+
+    /// The built in presets are valid, and survive being written out and read back in.
+    #[test]
+    fn built_in_presets() {
+        for preset in [WorldPreset::default_world(12), WorldPreset::superflat(12)].iter() {
+            let text = preset.to_ron().unwrap();
+            assert_eq!(&WorldPreset::from_ron(&text).unwrap(), preset);
+        }
+    }
+
+    /// A preset generates the same world as setting up the same generators by hand.
+    #[test]
+    fn matches_code() {
+        let preset = WorldPreset::from_ron(
+            r#"
+            WorldPreset(
+                name: "Caves",
+                seed: 77,
+                blocks: [
+                    (name: "stone", display_text: "Stone"),
+                    (name: "soil", display_text: "Soil"),
+                    (name: "grass", display_text: "Grass"),
+                ],
+                stages: [
+                    (stage: BaseTerrain, generator: Heightmap(layers: [(scale: 256.0, amplitude: 24.0, octaves: 4)])),
+                    (stage: Carving, generator: Caves(carvable_blocks: ["stone", "soil"])),
+                ],
+            )
+            "#,
+        )
+        .unwrap();
+
+        let mut provider = RAMWorld::new(registry_with(HeightmapWorld::BLOCKS));
+        provider.add_generator(HeightmapWorld::with_layers(77, &[HeightmapLayer::new(256.0, 24.0, 4)])).unwrap();
+        provider.add_stage_generator(GenerationStage::Carving, CaveCarver::new(77)).unwrap();
+
+        for location in [ChunkCoordinate::new(0, -1, 0), ChunkCoordinate::new(-4, -2, 9)].iter() {
+            let mut chunk = Chunk::new(*location, ());
+            provider.provide_chunk(&mut chunk).unwrap();
+            assert_eq!(hash_chunk(&generate(&preset, *location)), hash_chunk(&chunk));
+        }
+    }
+
+    /// A preset owns the block IDs of its world, so it won't share blocks that were registered before it.
+    #[test]
+    fn registered_blocks() {
+        let mut registry = BlockRegistry::new();
+        registry.add_block(String::from("stone"), String::from("Stone")).unwrap();
+        let mut provider = RAMWorld::<()>::new(registry);
+
+        let error = WorldPreset::superflat(0).apply(provider.as_mut()).unwrap_err();
+        assert!(format!("{:?}", error).contains("blocks[2]"), "{:?}", error);
+    }
+
+    /// Superflat worlds are flat.
+    #[test]
+    fn superflat() {
+        let preset = WorldPreset::superflat(0);
+        let surface = generate(&preset, ChunkCoordinate::new(3, 0, -3));
+        let underground = generate(&preset, ChunkCoordinate::new(3, -1, -3));
+
+        let empty = Chunk::<()>::range_all_blocks();
+        assert!(surface.iter_ideal(empty).all(|block| block.is_none()));
+        assert!(underground.iter_ideal(Chunk::<()>::range_all_blocks()).all(|block| block.is_some()));
+    }
+
+    /// Bad presets say which field is wrong.
+    #[test]
+    fn validation() {
+        let field_of =
+            |text: &str| WorldPreset::from_ron(text).unwrap_err().downcast::<PresetError>().unwrap().field().to_string();
+
+        assert_eq!(
+            field_of(
+                r#"WorldPreset(name: "", seed: 0, blocks: [(name: "stone", display_text: "Stone")], stages: [
+                    (stage: BaseTerrain, generator: Layered(surface_height: 0, layers: [
+                        (block: "stone", thickness: 3),
+                        (block: "bedrok", thickness: 1),
+                    ])),
+                ])"#
+            ),
+            "stages[0].generator.layers[1].block"
+        );
+
+        assert_eq!(
+            field_of(
+                r#"WorldPreset(name: "", seed: 0, stages: [
+                    (stage: Carving, generator: Caves(carvable_blocks: [])),
+                    (stage: BaseTerrain, generator: Heightmap(layers: [])),
+                ])"#
+            ),
+            "stages[1].stage"
+        );
+
+        assert_eq!(
+            field_of(
+                r#"WorldPreset(name: "", seed: 0, blocks: [
+                    (name: "stone", display_text: "Stone"),
+                    (name: "soil", display_text: "Soil"),
+                    (name: "grass", display_text: "Grass"),
+                ], stages: [
+                    (stage: BaseTerrain, generator: Heightmap(layers: [], use_biomes: true)),
+                ])"#
+            ),
+            "stages[0].generator.use_biomes"
+        );
+
+        assert_eq!(
+            field_of(
+                r#"WorldPreset(name: "", seed: 0, blocks: [
+                    (name: "stone", display_text: "Stone"),
+                    (name: "soil", display_text: "Soil"),
+                    (name: "grass", display_text: "Grass"),
+                ], stages: [
+                    (stage: BaseTerrain, generator: Heightmap(layers: [
+                        (scale: 256.0, amplitude: 24.0, octaves: 4),
+                        (scale: 16.0, amplitude: 2.0, octaves: 4294967295),
+                    ])),
+                ])"#
+            ),
+            "stages[0].generator.layers[1].octaves"
+        );
+
+        // Heightmaps always need their own blocks, even though the preset never names them.
+        let error = WorldPreset::from_ron(
+            r#"WorldPreset(name: "", seed: 0, blocks: [(name: "stone", display_text: "Stone")], stages: [
+                (stage: BaseTerrain, generator: Heightmap(layers: [])),
+            ])"#,
+        )
+        .unwrap_err()
+        .downcast::<PresetError>()
+        .unwrap();
+        assert_eq!(error.field(), "stages[0].generator");
+        assert!(error.problem().contains("\"soil\""), "{}", error);
+
+        assert_eq!(
+            field_of(
+                r#"WorldPreset(name: "", seed: 0, blocks: [(name: "stone", display_text: "Stone")], stages: [
+                    (stage: Features, generator: Ores(ores: [])),
+                ])"#
+            ),
+            "stages[0].generator"
+        );
+
+        assert_eq!(
+            field_of(
+                r#"WorldPreset(name: "", seed: 0, blocks: [
+                    (name: "stone", display_text: "Stone"),
+                    (name: "basalt", display_text: "Basalt"),
+                    (name: "iron_vent", display_text: "Iron Vent"),
+                ], stages: [
+                    (stage: Features, generator: Ores(ores: [], vents: [
+                        (material: "", block: "iron_vent", base_output_rate: 1.0, weight: 1),
+                    ])),
+                ])"#
+            ),
+            "stages[0].generator.vents[0].material"
+        );
+
+        assert_eq!(
+            field_of(
+                r#"WorldPreset(name: "", seed: 0, blocks: [
+                    (name: "stone", display_text: "Stone"),
+                    (name: "basalt", display_text: "Basalt"),
+                    (name: "coal", display_text: "Coal"),
+                ], stages: [
+                    (stage: Features, generator: Ores(ores: [
+                        (block: "coal", lowest: -10, highest: 0, veins_per_chunk: 1.0, size: 4, biomes: ["swamp"]),
+                    ])),
+                ])"#
+            ),
+            "stages[0].generator.ores[0].biomes[0]"
+        );
+
+        // Parse errors point at where in the file they are.
+        let error = WorldPreset::from_ron("WorldPreset(name: \"\",\n seed: \"zero\", stages: [])").unwrap_err();
+        assert!(format!("{:?}", error).contains("2:"), "{:?}", error);
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Chunks are generated in stages, so that later stages can count on the earlier stages of the chunks around them
+//! being done. A tree hanging over the edge of a chunk, for example, needs to know where the ground is on both sides.
+
+use crate::world::{BlockID, Chunk, ChunkCoordinate, GlobalBlockCoordinate, GlobalBlockCoordinateEXT};
+use serde::{Deserialize, Serialize};
+
+/// How far along a chunk is in being generated. Stages always happen in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+pub enum GenerationStage {
+    /// Nothing has been generated yet.
+    #[default]
+    Empty,
+
+    /// The ground and everything under it.
+    BaseTerrain,
+
+    /// Caves, ravines, and anything else cut out of the base terrain.
+    Carving,
+
+    /// Ores, vents, and other things set into the ground.
+    Features,
+
+    /// Trees, structures, and anything else built on top of the world. Once this stage is done, the chunk is complete.
+    Decoration,
+}
+
+impl GenerationStage {
+    /// The stage a chunk is in once it has been completely generated.
+    pub const COMPLETE: GenerationStage = GenerationStage::Decoration;
+
+    /// Every stage, in order.
+    pub const ALL: [GenerationStage; 5] = [
+        GenerationStage::Empty,
+        GenerationStage::BaseTerrain,
+        GenerationStage::Carving,
+        GenerationStage::Features,
+        GenerationStage::Decoration,
+    ];
+
+    /// The stage after this one. Complete chunks have no next stage.
+    pub fn next(self) -> Option<GenerationStage> {
+        Self::ALL.get(self as usize + 1).copied()
+    }
+
+    /// The stage before this one. Empty chunks have no previous stage.
+    pub fn previous(self) -> Option<GenerationStage> {
+        (self as usize).checked_sub(1).map(|index| Self::ALL[index])
+    }
+
+    /// Check if a chunk in this stage has been completely generated.
+    pub fn is_complete(self) -> bool {
+        self == Self::COMPLETE
+    }
+}
+
+/// The indexes of the 26 chunks around a chunk. Chunks past the edge of the world don't exist, so they're left out.
+pub fn chunk_neighbors(center: ChunkCoordinate) -> impl Iterator<Item = ChunkCoordinate> {
+    (0..27).filter(|index| *index != 13).filter_map(move |index| neighbor_index(center, neighbor_offset(index)))
+}
+
+/// The offset of a chunk from the center of a neighborhood, by its place in the neighborhood.
+fn neighbor_offset(index: usize) -> ChunkCoordinate {
+    ChunkCoordinate::new(index as i32 % 3 - 1, index as i32 / 3 % 3 - 1, index as i32 / 9 - 1)
+}
+
+/// The index of a chunk offset from another, unless it's past the edge of the world.
+fn neighbor_index(center: ChunkCoordinate, offset: ChunkCoordinate) -> Option<ChunkCoordinate> {
+    Some(ChunkCoordinate::new(
+        center.x.checked_add(offset.x)?,
+        center.y.checked_add(offset.y)?,
+        center.z.checked_add(offset.z)?,
+    ))
+}
+
+/// Read only access to the chunks around a chunk that is being generated.
+/// Neighbors are only provided for stages the chunk provider asked for them in, and are only guaranteed to have
+/// reached the stage before the one being generated. They may have gone further.
+pub struct ChunkNeighborhood<'a, ChunkUserData> {
+    center: ChunkCoordinate,
+    chunks: [Option<&'a Chunk<ChunkUserData>>; 27],
+}
+
+impl<'a, ChunkUserData> ChunkNeighborhood<'a, ChunkUserData> {
+    /// Gather the 26 chunks around a chunk. The center chunk itself is never included, and neither are chunks past the
+    /// edge of the world.
+    pub fn new(
+        center: ChunkCoordinate, mut lookup: impl FnMut(ChunkCoordinate) -> Option<&'a Chunk<ChunkUserData>>,
+    ) -> ChunkNeighborhood<'a, ChunkUserData> {
+        let mut neighborhood = Self::empty(center);
+        for (index, chunk) in neighborhood.chunks.iter_mut().enumerate() {
+            if index != 13 {
+                *chunk = neighbor_index(center, neighbor_offset(index)).and_then(&mut lookup);
+            }
+        }
+
+        neighborhood
+    }
+
+    /// A neighborhood with no chunks in it, for when a chunk is generated on its own.
+    pub fn empty(center: ChunkCoordinate) -> ChunkNeighborhood<'a, ChunkUserData> {
+        ChunkNeighborhood { center, chunks: [None; 27] }
+    }
+
+    /// The chunk being generated.
+    pub fn center(&self) -> ChunkCoordinate {
+        self.center
+    }
+
+    /// Get a neighboring chunk. Returns None for chunks that aren't next to the center, and for the center itself.
+    pub fn get_chunk(&self, index: ChunkCoordinate) -> Option<&'a Chunk<ChunkUserData>> {
+        let offset = ChunkCoordinate::new(
+            index.x.checked_sub(self.center.x)?,
+            index.y.checked_sub(self.center.y)?,
+            index.z.checked_sub(self.center.z)?,
+        );
+        if offset.iter().all(|axis| (-1..=1).contains(axis)) {
+            self.chunks[(offset.x + 1 + (offset.y + 1) * 3 + (offset.z + 1) * 9) as usize]
+        } else {
+            None
+        }
+    }
+
+    /// Get a block from a neighboring chunk. Returns None if the block isn't in one of the neighbors.
+    pub fn get_block(&self, location: GlobalBlockCoordinate) -> Option<Option<BlockID>> {
+        self.get_chunk(location.chunk_index()).map(|chunk| chunk.get_single_block_local(location.to_local_block_coordinate()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::world::{
+        chunk_providers::{
+            register_blocks, require_block, RAMWorld, TerrainGenerator, TerrainGeneratorResult, TerrainGeneratorSuccessType,
+        },
+        storage::ChunkDiskStorage,
+        BlockRegistry, ChunkCoordinateEXT, GridWorld, LocalBlockCoordinate,
+    };
+
+    /// Fills everything below zero.
+    struct Ground(Option<BlockID>);
+
+    impl TerrainGenerator<()> for Ground {
+        fn initialize_block_ids(&mut self, registry: &BlockRegistry) -> anyhow::Result<()> {
+            self.0 = Some(require_block(registry, "ground")?);
+
+            Ok(())
+        }
+
+        fn populate_chunk(&self, chunk: &mut Chunk<()>) -> TerrainGeneratorResult {
+            if chunk.index().y < 0 {
+                chunk.iter_ideal_mut(Chunk::<()>::range_all_blocks()).for_each(|block| *block = self.0);
+            }
+
+            Ok(TerrainGeneratorSuccessType::Continue)
+        }
+    }
+
+    /// Puts a marker on the surface of every chunk that sits on the ground, which can only be known by looking at the
+    /// chunk below.
+    struct Marker(Option<BlockID>);
+
+    impl TerrainGenerator<()> for Marker {
+        fn initialize_block_ids(&mut self, registry: &BlockRegistry) -> anyhow::Result<()> {
+            self.0 = Some(require_block(registry, "marker")?);
+
+            Ok(())
+        }
+
+        fn populate_chunk(&self, _chunk: &mut Chunk<()>) -> TerrainGeneratorResult {
+            unreachable!("The marker needs its neighbors.")
+        }
+
+        fn populate_chunk_with_neighbors(
+            &self, chunk: &mut Chunk<()>, neighbors: &ChunkNeighborhood<()>,
+        ) -> TerrainGeneratorResult {
+            // Every neighbor must be at least at the stage before this one.
+            for neighbor in chunk_neighbors(chunk.index()) {
+                let neighbor = neighbors.get_chunk(neighbor).expect("Neighbor is missing.");
+                assert!(neighbor.generation_stage() >= GenerationStage::Features);
+            }
+
+            // There's nothing below the bottom of the world.
+            let below = chunk.index().to_block_coordinate() - GlobalBlockCoordinate::new(0, 1, 0);
+            let surface = LocalBlockCoordinate::new(0, 0, 0);
+            if chunk.get_single_block_local(surface).is_none() && neighbors.get_block(below).flatten().is_some() {
+                *chunk.get_single_block_local_mut(surface) = self.0;
+            }
+
+            Ok(TerrainGeneratorSuccessType::Continue)
+        }
+    }
+
+    fn create_world() -> GridWorld<()> {
+        let mut registry = BlockRegistry::new();
+        register_blocks(&mut registry, &[("ground", "Ground"), ("marker", "Marker")]).unwrap();
+        let mut provider = RAMWorld::new(registry);
+        provider.add_generator(Box::new(Ground(None))).unwrap();
+        provider.add_stage_generator(GenerationStage::Decoration, Box::new(Marker(None))).unwrap();
+
+        GridWorld::new(provider)
+    }
+
+    /// Stages go in order.
+    #[test]
+    fn stage_order() {
+        assert_eq!(GenerationStage::Empty.next(), Some(GenerationStage::BaseTerrain));
+        assert_eq!(GenerationStage::COMPLETE.next(), None);
+        assert_eq!(GenerationStage::BaseTerrain.previous(), Some(GenerationStage::Empty));
+        assert_eq!(GenerationStage::Empty.previous(), None);
+        assert!(GenerationStage::ALL.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    /// A chunk only gets decorated once its neighbors exist, and those half generated neighbors are kept away from the
+    /// rest of the world until they are done.
+    #[test]
+    fn neighbors_first() {
+        let mut world = create_world();
+        let marker = world.block_registry().get_block_id_from_name("marker").cloned();
+
+        let chunk = world.load_chunk(ChunkCoordinate::new(0, 0, 0));
+        assert!(chunk.generation_stage().is_complete());
+        assert_eq!(chunk.get_single_block_local(LocalBlockCoordinate::new(0, 0, 0)), marker);
+
+        // The neighbors were generated, but aren't done yet.
+        assert!(world.get_chunk(&ChunkCoordinate::new(1, 0, 0)).is_none());
+        assert!(world.get_chunk(&ChunkCoordinate::new(0, -1, 0)).is_none());
+
+        // Finishing them off gives the same result as generating them from nothing.
+        let neighbor = world.load_chunk(ChunkCoordinate::new(1, 0, 0));
+        assert!(neighbor.generation_stage().is_complete());
+        assert_eq!(neighbor.get_single_block_local(LocalBlockCoordinate::new(0, 0, 0)), marker);
+
+        let underground = world.load_chunk(ChunkCoordinate::new(0, -1, 0));
+        assert!(underground.generation_stage().is_complete());
+        assert_ne!(underground.get_single_block_local(LocalBlockCoordinate::new(0, 0, 0)), marker);
+
+        let up_high = world.load_chunk(ChunkCoordinate::new(0, 1, 0));
+        assert_eq!(up_high.get_single_block_local(LocalBlockCoordinate::new(0, 0, 0)), None);
+    }
+
+    /// Chunks at the very edge of the world can be generated, even though some of their neighbors can't exist.
+    #[test]
+    fn world_edge() {
+        let mut world = create_world();
+
+        for index in [ChunkCoordinate::new(i32::MAX, 0, 0), ChunkCoordinate::new(i32::MIN, i32::MIN, i32::MAX)].iter() {
+            assert!(world.load_chunk(*index).generation_stage().is_complete());
+        }
+
+        assert_eq!(chunk_neighbors(ChunkCoordinate::new(i32::MAX, 0, 0)).count(), 17);
+        assert_eq!(chunk_neighbors(ChunkCoordinate::new(i32::MIN, i32::MIN, i32::MAX)).count(), 7);
+    }
+
+    /// Half generated chunks are let go once nothing loaded is next to them, and are saved if they can be.
+    #[test]
+    fn staged_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut world = create_world();
+        world.set_chunk_storage(Some(ChunkDiskStorage::initialize(dir.path(), 9)));
+        let marker = world.block_registry().get_block_id_from_name("marker").cloned();
+
+        world.load_chunk(ChunkCoordinate::new(0, 0, 0));
+        assert_eq!(world.staged_chunks.len(), 26);
+
+        world.unload_chunk(ChunkCoordinate::new(0, 0, 0)).unwrap();
+        assert!(world.staged_chunks.is_empty());
+
+        // The neighbors were saved part way through, and pick up from there.
+        let storage = world.chunk_storage().unwrap();
+        let saved = storage.load_terrain_chunk::<()>(ChunkCoordinate::new(1, 0, 0)).unwrap().unwrap();
+        assert_eq!(saved.generation_stage(), GenerationStage::Features);
+
+        let neighbor = world.load_chunk(ChunkCoordinate::new(1, 0, 0));
+        assert!(neighbor.generation_stage().is_complete());
+        assert_eq!(neighbor.get_single_block_local(LocalBlockCoordinate::new(0, 0, 0)), marker);
+    }
+}
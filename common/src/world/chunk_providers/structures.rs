@@ -0,0 +1,469 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Structures like ruins and fortresses that can be bigger than a chunk.
+//! Where structures go is worked out from the seed alone, so every chunk a structure touches can find it and build its
+//! own slice of it, no matter what order the chunks are generated in.
+
+use super::{
+    noise::{derive_seed, hash_2d, hash_3d, mix, unit},
+    require_block, HeightmapWorld, TerrainGenerator, TerrainGeneratorResult, TerrainGeneratorSuccessType,
+};
+use crate::world::{
+    storage, BlockID, BlockRegistry, Chunk, ChunkCoordinate, ChunkCoordinateEXT, GlobalBlockCoordinate, LocalBlockCoordinate,
+};
+use anyhow::Result;
+use std::sync::Arc;
+
+/// A key for looking up a structure template in a [StructureMap].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StructureID(u16);
+
+/// A box of blocks that makes up part of a structure.
+#[derive(Debug, Clone, PartialEq)]
+struct StructurePiece {
+    near: GlobalBlockCoordinate,
+    far: GlobalBlockCoordinate,
+    block: Option<String>,
+}
+
+/// The plan for a structure, made out of boxes of blocks. Boxes are built in the order they are added, so later boxes
+/// replace earlier ones where they overlap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructureTemplate {
+    name: String,
+    weight: u32,
+    vertical_offset: i64,
+    decay: f64,
+    pieces: Vec<StructurePiece>,
+}
+
+impl StructureTemplate {
+    /// Create an empty structure template.
+    pub fn new(name: &str) -> StructureTemplate {
+        StructureTemplate { name: String::from(name), weight: 1, vertical_offset: 0, decay: 0.0, pieces: Vec::new() }
+    }
+
+    /// Fill a box between two corners, relative to the structure's origin, inclusive. A block of `None` clears the box
+    /// out.
+    pub fn with_box(
+        mut self, near: GlobalBlockCoordinate, far: GlobalBlockCoordinate, block: Option<&str>,
+    ) -> StructureTemplate {
+        let (near, far) = (near.inf(&far), near.sup(&far));
+        self.pieces.push(StructurePiece { near, far, block: block.map(String::from) });
+        self
+    }
+
+    /// Place a single block, relative to the structure's origin.
+    pub fn with_block(self, position: GlobalBlockCoordinate, block: Option<&str>) -> StructureTemplate {
+        self.with_box(position, position, block)
+    }
+
+    /// Set how common this structure is compared to the others.
+    pub fn with_weight(mut self, weight: u32) -> StructureTemplate {
+        self.weight = weight;
+        self
+    }
+
+    /// Move the structure up or down from the surface. Negative numbers bury it.
+    pub fn with_vertical_offset(mut self, offset: i64) -> StructureTemplate {
+        self.vertical_offset = offset;
+        self
+    }
+
+    /// Set the chance, from 0 to 1, for each solid block to be missing. Good for making ruins look ruined.
+    pub fn with_decay(mut self, decay: f64) -> StructureTemplate {
+        self.decay = decay;
+        self
+    }
+
+    /// The name of the structure.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How common this structure is compared to the others.
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// The corners of the box that holds the whole structure, relative to its origin. Empty templates have a box that
+    /// holds only the origin.
+    pub fn bounding_box(&self) -> (GlobalBlockCoordinate, GlobalBlockCoordinate) {
+        let mut pieces = self.pieces.iter();
+        match pieces.next() {
+            Some(first) => {
+                pieces.fold((first.near, first.far), |(near, far), piece| (near.inf(&piece.near), far.sup(&piece.far)))
+            }
+            None => (GlobalBlockCoordinate::zeros(), GlobalBlockCoordinate::zeros()),
+        }
+    }
+
+    /// Names of all the blocks the structure is built from.
+    pub fn blocks(&self) -> impl Iterator<Item = &str> {
+        self.pieces.iter().filter_map(|piece| piece.block.as_deref())
+    }
+}
+
+/// A structure that has been placed in the world.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlacedStructure {
+    template: StructureID,
+    origin: GlobalBlockCoordinate,
+    near: GlobalBlockCoordinate,
+    far: GlobalBlockCoordinate,
+}
+
+impl PlacedStructure {
+    /// The template the structure is built from.
+    pub fn template(&self) -> StructureID {
+        self.template
+    }
+
+    /// Where the structure's origin is in the world.
+    pub fn origin(&self) -> GlobalBlockCoordinate {
+        self.origin
+    }
+
+    /// The corners of the box that holds the whole structure, in world coordinates, inclusive.
+    pub fn bounding_box(&self) -> (GlobalBlockCoordinate, GlobalBlockCoordinate) {
+        (self.near, self.far)
+    }
+
+    /// Check if the structure overlaps the box between two corners, inclusive.
+    pub fn overlaps(&self, near: GlobalBlockCoordinate, far: GlobalBlockCoordinate) -> bool {
+        (0..3).all(|axis| self.near[axis] <= far[axis] && self.far[axis] >= near[axis])
+    }
+}
+
+/// Decides where all the structures in the world go.
+/// The world is split into a grid of columns, and each column has a chance of holding one structure, which sits on the
+/// ground somewhere inside it. A structure can reach far outside of its column.
+pub struct StructureMap {
+    seed: u64,
+    cell_size: i64,
+    chance: f64,
+    ground: Option<Arc<HeightmapWorld>>,
+    templates: Vec<StructureTemplate>,
+}
+
+impl StructureMap {
+    /// Create a structure map with no templates.
+    pub fn new(seed: u64) -> StructureMap {
+        StructureMap { seed: derive_seed(seed, 0x5747), cell_size: 256, chance: 0.25, ground: None, templates: Vec::new() }
+    }
+
+    /// Create a structure map with some basic ruins and fortresses.
+    pub fn with_default_templates(seed: u64) -> StructureMap {
+        let mut map = StructureMap::new(seed);
+
+        map.register_template(
+            StructureTemplate::new("ruin")
+                .with_weight(4)
+                .with_decay(0.3)
+                .with_box(GlobalBlockCoordinate::new(0, -1, 0), GlobalBlockCoordinate::new(8, -1, 8), Some("cobblestone"))
+                .with_box(GlobalBlockCoordinate::new(0, 0, 0), GlobalBlockCoordinate::new(8, 4, 8), Some("cobblestone"))
+                .with_box(GlobalBlockCoordinate::new(1, 0, 1), GlobalBlockCoordinate::new(7, 4, 7), None),
+        );
+        map.register_template(
+            StructureTemplate::new("fortress")
+                .with_weight(1)
+                .with_decay(0.05)
+                .with_box(GlobalBlockCoordinate::new(-24, -4, -24), GlobalBlockCoordinate::new(24, -1, 24), Some("cobblestone"))
+                .with_box(GlobalBlockCoordinate::new(-24, 0, -24), GlobalBlockCoordinate::new(24, 12, 24), Some("cobblestone"))
+                .with_box(GlobalBlockCoordinate::new(-23, 0, -23), GlobalBlockCoordinate::new(23, 12, 23), None)
+                .with_box(GlobalBlockCoordinate::new(-4, 0, -4), GlobalBlockCoordinate::new(4, 24, 4), Some("cobblestone"))
+                .with_box(GlobalBlockCoordinate::new(-3, 0, -3), GlobalBlockCoordinate::new(3, 23, 3), None)
+                .with_box(GlobalBlockCoordinate::new(-1, 0, -24), GlobalBlockCoordinate::new(1, 3, -24), None),
+        );
+
+        map
+    }
+
+    /// Set how big the grid columns are, and the chance of each having a structure.
+    pub fn set_spacing(&mut self, cell_size: i64, chance: f64) {
+        self.cell_size = cell_size.max(1);
+        self.chance = chance;
+    }
+
+    /// Set the terrain structures are placed on top of. Without it, structures are placed at a height of zero.
+    /// This should be a generator with the same seed and layers as the one that builds the terrain.
+    pub fn set_ground(&mut self, ground: Arc<HeightmapWorld>) {
+        self.ground = Some(ground);
+    }
+
+    /// Add a template to the map.
+    pub fn register_template(&mut self, template: StructureTemplate) -> StructureID {
+        let id = StructureID(self.templates.len() as u16);
+        self.templates.push(template);
+
+        id
+    }
+
+    /// Get a template from its ID.
+    pub fn get_template(&self, id: StructureID) -> Option<&StructureTemplate> {
+        self.templates.get(id.0 as usize)
+    }
+
+    /// Get the ID of a template from its name.
+    pub fn get_template_id_from_name(&self, name: &str) -> Option<StructureID> {
+        self.templates.iter().position(|template| template.name == name).map(|index| StructureID(index as u16))
+    }
+
+    /// Iterate over all the templates.
+    pub fn iter_templates(&self) -> impl Iterator<Item = (StructureID, &StructureTemplate)> {
+        self.templates.iter().enumerate().map(|(index, template)| (StructureID(index as u16), template))
+    }
+
+    /// The size of the grid columns structures are placed in.
+    pub fn cell_size(&self) -> i64 {
+        self.cell_size
+    }
+
+    /// Get the structure in a grid column, if it has one.
+    pub fn structure_in_cell(&self, cell_x: i64, cell_z: i64) -> Option<PlacedStructure> {
+        let hash = hash_2d(self.seed, cell_x, cell_z);
+        if unit(hash) >= self.chance {
+            return None;
+        }
+
+        let total_weight: u64 = self.templates.iter().map(|template| template.weight as u64).sum();
+        if total_weight == 0 {
+            return None;
+        }
+
+        let mut roll = mix(hash ^ 1) % total_weight;
+        let index = self.templates.iter().position(|template| {
+            if roll < template.weight as u64 {
+                true
+            } else {
+                roll -= template.weight as u64;
+                false
+            }
+        })?;
+        let template = &self.templates[index];
+
+        let x = cell_x * self.cell_size + (mix(hash ^ 2) % self.cell_size as u64) as i64;
+        let z = cell_z * self.cell_size + (mix(hash ^ 3) % self.cell_size as u64) as i64;
+        let ground = self.ground.as_ref().map(|ground| ground.surface_height(x, z) + 1).unwrap_or(0);
+        let origin = GlobalBlockCoordinate::new(x, ground + template.vertical_offset, z);
+
+        let (near, far) = template.bounding_box();
+        Some(PlacedStructure { template: StructureID(index as u16), origin, near: origin + near, far: origin + far })
+    }
+
+    /// Get every structure that overlaps the box between two corners, inclusive.
+    pub fn structures_overlapping(&self, near: GlobalBlockCoordinate, far: GlobalBlockCoordinate) -> Vec<PlacedStructure> {
+        // Find how far any structure can reach from its origin, so we know which columns could hold one that reaches
+        // into the box.
+        let (reach_near, reach_far) =
+            self.templates.iter().map(|template| template.bounding_box()).fold(
+                (GlobalBlockCoordinate::zeros(), GlobalBlockCoordinate::zeros()),
+                |(reach_near, reach_far), (near, far)| (reach_near.inf(&near), reach_far.sup(&far)),
+            );
+
+        let mut structures = Vec::new();
+        for cell_z in (near.z - reach_far.z).div_euclid(self.cell_size)..=(far.z - reach_near.z).div_euclid(self.cell_size) {
+            for cell_x in (near.x - reach_far.x).div_euclid(self.cell_size)..=(far.x - reach_near.x).div_euclid(self.cell_size)
+            {
+                if let Some(structure) = self.structure_in_cell(cell_x, cell_z) {
+                    if structure.overlaps(near, far) {
+                        structures.push(structure);
+                    }
+                }
+            }
+        }
+
+        structures
+    }
+
+    /// Get every structure that overlaps a chunk.
+    pub fn structures_in_chunk(&self, chunk: ChunkCoordinate) -> Vec<PlacedStructure> {
+        let near = chunk.to_block_coordinate();
+        self.structures_overlapping(near, near.add_scalar(storage::CHUNK_DIAMETER as i64 - 1))
+    }
+
+    /// Check if a solid block of a structure has decayed away.
+    fn is_decayed(&self, template: &StructureTemplate, position: GlobalBlockCoordinate) -> bool {
+        template.decay > 0.0 && unit(hash_3d(self.seed, position.x, position.y, position.z)) < template.decay
+    }
+}
+
+/// Builds the structures from a [StructureMap] into the world.
+pub struct StructureGenerator {
+    structures: Arc<StructureMap>,
+    piece_blocks: Vec<Vec<Option<BlockID>>>,
+}
+
+impl StructureGenerator {
+    /// Create a generator that builds the structures from a structure map.
+    pub fn new(structures: Arc<StructureMap>) -> Box<StructureGenerator> {
+        Box::new(StructureGenerator { structures, piece_blocks: Vec::new() })
+    }
+
+    /// The structures this generator builds.
+    pub fn structures(&self) -> &Arc<StructureMap> {
+        &self.structures
+    }
+}
+
+impl<ChunkUserData: Default> TerrainGenerator<ChunkUserData> for StructureGenerator {
+    fn initialize_block_ids(&mut self, registry: &BlockRegistry) -> Result<()> {
+        self.piece_blocks = self
+            .structures
+            .templates
+            .iter()
+            .map(|template| {
+                template
+                    .pieces
+                    .iter()
+                    .map(|piece| piece.block.as_ref().map(|block| require_block(registry, block)).transpose())
+                    .collect()
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(())
+    }
+
+    fn populate_chunk(&self, chunk: &mut Chunk<ChunkUserData>) -> TerrainGeneratorResult {
+        let chunk_near = chunk.index().to_block_coordinate();
+        let chunk_far = chunk_near.add_scalar(storage::CHUNK_DIAMETER as i64 - 1);
+
+        for structure in self.structures.structures_overlapping(chunk_near, chunk_far) {
+            let template = &self.structures.templates[structure.template.0 as usize];
+            let blocks = &self.piece_blocks[structure.template.0 as usize];
+
+            for (piece, block) in template.pieces.iter().zip(blocks.iter()) {
+                // Only the part of the piece inside this chunk.
+                let near = (structure.origin + piece.near).sup(&chunk_near);
+                let far = (structure.origin + piece.far).inf(&chunk_far);
+
+                for z in near.z..=far.z {
+                    for y in near.y..=far.y {
+                        for x in near.x..=far.x {
+                            let position = GlobalBlockCoordinate::new(x, y, z);
+                            if block.is_some() && self.structures.is_decayed(template, position) {
+                                continue;
+                            }
+
+                            let local = (position - chunk_near).map(|v| v as u8) as LocalBlockCoordinate;
+                            *chunk.get_single_block_local_mut(local) = *block;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(TerrainGeneratorSuccessType::Continue)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::test::{hash_chunk, registry_with},
+        *,
+    };
+    use crate::world::{chunk_providers::RAMWorld, ChunkProvider, GlobalBlockCoordinateEXT};
+
+    fn create_provider(structures: Arc<StructureMap>) -> Box<RAMWorld<()>> {
+        let registry = registry_with(&[HeightmapWorld::BLOCKS, &[("cobblestone", "Cobblestone")]].concat());
+        let mut provider = RAMWorld::new(registry);
+        provider.add_generator(HeightmapWorld::with_layers(9, &[])).unwrap();
+        provider.add_generator(StructureGenerator::new(structures)).unwrap();
+
+        provider
+    }
+
+    fn generate(provider: &dyn ChunkProvider<()>, location: ChunkCoordinate) -> Chunk<()> {
+        let mut chunk = Chunk::new(location, ());
+        provider.provide_chunk(&mut chunk).unwrap();
+
+        chunk
+    }
+
+    /// A structure spanning several chunks is built the same no matter what order its chunks are generated in, and
+    /// every one of those chunks can find it.
+    #[test]
+    fn spans_chunks() {
+        let mut structures = StructureMap::new(9);
+        structures.set_spacing(1024, 1.0);
+        let wall = structures.register_template(StructureTemplate::new("wall").with_decay(0.2).with_box(
+            GlobalBlockCoordinate::new(-40, 0, 0),
+            GlobalBlockCoordinate::new(40, 3, 1),
+            Some("cobblestone"),
+        ));
+        let structures = Arc::new(structures);
+
+        let structure = structures.structure_in_cell(0, 0).unwrap();
+        assert_eq!(structure.template(), wall);
+        assert_eq!(structure.origin().y, 0);
+
+        let (near, far) = structure.bounding_box();
+        let chunks: Vec<ChunkCoordinate> = (near.chunk_index().x..=far.chunk_index().x)
+            .flat_map(|x| (near.chunk_index().z..=far.chunk_index().z).map(move |z| ChunkCoordinate::new(x, 0, z)))
+            .collect();
+        assert!(chunks.len() >= 3);
+
+        for chunk in chunks.iter() {
+            assert_eq!(structures.structures_in_chunk(*chunk), vec![structure]);
+        }
+
+        let forward: Vec<u64> =
+            chunks.iter().map(|chunk| hash_chunk(&generate(create_provider(structures.clone()).as_ref(), *chunk))).collect();
+        let provider = create_provider(structures.clone());
+        let mut backward: Vec<u64> =
+            chunks.iter().rev().map(|chunk| hash_chunk(&generate(provider.as_ref(), *chunk))).collect();
+        backward.reverse();
+        assert_eq!(forward, backward);
+
+        // Some of the wall has decayed, but most of it is there.
+        let cobblestone = provider.block_registry().get_block_id_from_name("cobblestone").cloned();
+        let count: usize = chunks
+            .iter()
+            .map(|chunk| {
+                generate(provider.as_ref(), *chunk)
+                    .iter_ideal(Chunk::<()>::range_all_blocks())
+                    .filter(|block| *block == cobblestone)
+                    .count()
+            })
+            .sum();
+        assert!(count > 81 * 4 * 2 / 2 && count < 81 * 4 * 2, "Found {} blocks.", count);
+    }
+
+    /// Structures land on the ground.
+    #[test]
+    fn on_the_ground() {
+        let ground: Arc<HeightmapWorld> = Arc::from(HeightmapWorld::new(4));
+        let mut structures = StructureMap::with_default_templates(4);
+        structures.set_ground(ground.clone());
+
+        let mut found = 0;
+        for cell_z in -4..4 {
+            for cell_x in -4..4 {
+                if let Some(structure) = structures.structure_in_cell(cell_x, cell_z) {
+                    let origin = structure.origin();
+                    assert_eq!(origin.y, ground.surface_height(origin.x, origin.z) + 1);
+                    found += 1;
+                }
+            }
+        }
+
+        // 64 cells with a one in four chance each.
+        assert!(found > 4 && found < 32, "Found {} structures.", found);
+    }
+
+    /// The result never changes for a seed.
+    #[test]
+    fn golden_hashes() {
+        let mut structures = StructureMap::with_default_templates(0xDEADBEEF);
+        structures.set_spacing(64, 1.0);
+        let provider = create_provider(Arc::new(structures));
+
+        let hashes: Vec<u64> = [(0, 0, 0), (1, 0, -1), (-3, -1, 2)]
+            .iter()
+            .map(|(x, y, z)| hash_chunk(&generate(provider.as_ref(), ChunkCoordinate::new(*x, *y, *z))))
+            .collect();
+        assert_eq!(hashes, vec![0xEFCB23C6F2467325, 0x5C302F205F2125D2, 0x4EA70D57B9C0D953]);
+    }
+}
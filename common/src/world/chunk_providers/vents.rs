@@ -0,0 +1,266 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Deep molten vents, the main source of resources.
+//! Where vents are is worked out from the seed alone, so any vent in the world can be found, even in chunks that have
+//! never been generated.
+
+use super::noise::{derive_seed, hash_2d, mix, unit};
+use crate::world::GlobalBlockCoordinate;
+
+/// A kind of vent that can be generated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VentType {
+    material: String,
+    block: String,
+    base_output_rate: f32,
+    weight: u32,
+}
+
+impl VentType {
+    /// Create a kind of vent that puts out a material, at a base rate in kilograms per second. It's marked in the world
+    /// with a block of the given name. Weight is how common it is compared to the other kinds.
+    pub fn new(material: &str, block: &str, base_output_rate: f32, weight: u32) -> VentType {
+        VentType { material: String::from(material), block: String::from(block), base_output_rate, weight }
+    }
+
+    /// The name of the material the vent puts out.
+    pub fn material(&self) -> &str {
+        &self.material
+    }
+
+    /// The name of the block marking the vent.
+    pub fn block(&self) -> &str {
+        &self.block
+    }
+}
+
+/// A single vent in the world.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vent {
+    material: String,
+    base_output_rate: f32,
+    position: GlobalBlockCoordinate,
+    type_index: usize,
+}
+
+impl Vent {
+    /// The name of the material the vent puts out. It can be looked up in the world's material registry.
+    pub fn material(&self) -> &str {
+        &self.material
+    }
+
+    /// How much the vent puts out, in kilograms per second, before anything is done to improve it.
+    pub fn base_output_rate(&self) -> f32 {
+        self.base_output_rate
+    }
+
+    /// The block at the center of the vent.
+    pub fn position(&self) -> GlobalBlockCoordinate {
+        self.position
+    }
+
+    /// Which of the field's vent types this is.
+    pub(super) fn type_index(&self) -> usize {
+        self.type_index
+    }
+}
+
+/// Where all the vents in the world are.
+/// The world is split into a grid of columns, and each column has a chance of holding a single vent somewhere inside it,
+/// deep underground.
+pub struct VentField {
+    seed: u64,
+    cell_size: i64,
+    chance: f64,
+    depth_range: (i64, i64),
+    types: Vec<VentType>,
+}
+
+impl VentField {
+    /// Create a vent field with a few basic kinds of vent.
+    pub fn new(seed: u64) -> VentField {
+        Self::with_types(
+            seed,
+            vec![
+                VentType::new("iron", "iron_vent", 10.0, 3),
+                VentType::new("copper", "copper_vent", 8.0, 2),
+                VentType::new("gold", "gold_vent", 2.0, 1),
+            ],
+        )
+    }
+
+    /// Create a vent field with custom kinds of vent.
+    pub fn with_types(seed: u64, types: Vec<VentType>) -> VentField {
+        VentField { seed: derive_seed(seed, 0x7E47), cell_size: 512, chance: 0.5, depth_range: (-768, -256), types }
+    }
+
+    /// Set how big the grid columns are, and the chance of each having a vent.
+    pub fn set_spacing(&mut self, cell_size: i64, chance: f64) {
+        self.cell_size = cell_size.max(1);
+        self.chance = chance;
+    }
+
+    /// Set the lowest and highest vents can be.
+    pub fn set_depth_range(&mut self, lowest: i64, highest: i64) {
+        self.depth_range = (lowest, highest);
+    }
+
+    /// The kinds of vents that can be generated.
+    pub fn types(&self) -> &[VentType] {
+        &self.types
+    }
+
+    /// The size of the grid columns vents are placed in.
+    pub fn cell_size(&self) -> i64 {
+        self.cell_size
+    }
+
+    /// Get the vent in a grid column, if it has one.
+    pub fn vent_in_cell(&self, cell_x: i64, cell_z: i64) -> Option<Vent> {
+        let hash = hash_2d(self.seed, cell_x, cell_z);
+        if unit(hash) >= self.chance {
+            return None;
+        }
+
+        let total_weight: u64 = self.types.iter().map(|vent_type| vent_type.weight as u64).sum();
+        if total_weight == 0 {
+            return None;
+        }
+
+        let mut roll = mix(hash ^ 1) % total_weight;
+        let type_index = self.types.iter().position(|vent_type| {
+            if roll < vent_type.weight as u64 {
+                true
+            } else {
+                roll -= vent_type.weight as u64;
+                false
+            }
+        })?;
+        let vent_type = &self.types[type_index];
+
+        let (lowest, highest) = self.depth_range;
+        let position = GlobalBlockCoordinate::new(
+            cell_x * self.cell_size + (mix(hash ^ 2) % self.cell_size as u64) as i64,
+            lowest + (mix(hash ^ 3) % (highest - lowest + 1).max(1) as u64) as i64,
+            cell_z * self.cell_size + (mix(hash ^ 4) % self.cell_size as u64) as i64,
+        );
+
+        Some(Vent { material: vent_type.material.clone(), base_output_rate: vent_type.base_output_rate, position, type_index })
+    }
+
+    /// Get every vent whose center is within the box between two corners, inclusive.
+    pub fn vents_in_box(&self, near: GlobalBlockCoordinate, far: GlobalBlockCoordinate) -> Vec<Vent> {
+        let (lowest, highest) = self.depth_range;
+        if far.y < lowest || near.y > highest {
+            return Vec::new();
+        }
+
+        let mut vents = Vec::new();
+        for cell_z in near.z.div_euclid(self.cell_size)..=far.z.div_euclid(self.cell_size) {
+            for cell_x in near.x.div_euclid(self.cell_size)..=far.x.div_euclid(self.cell_size) {
+                if let Some(vent) = self.vent_in_cell(cell_x, cell_z) {
+                    let position = vent.position;
+                    if (0..3).all(|axis| position[axis] >= near[axis] && position[axis] <= far[axis]) {
+                        vents.push(vent);
+                    }
+                }
+            }
+        }
+
+        vents
+    }
+
+    /// Find the nearest vent of a material, no more than `max_distance` blocks away.
+    pub fn nearest_vent(&self, from: GlobalBlockCoordinate, material: &str, max_distance: i64) -> Option<Vent> {
+        let center_x = from.x.div_euclid(self.cell_size);
+        let center_z = from.z.div_euclid(self.cell_size);
+        let max_rings = max_distance / self.cell_size + 1;
+
+        let mut best: Option<(i64, Vent)> = None;
+        for ring in 0..=max_rings {
+            // Everything in this ring and beyond is at least this far away, so there's no point looking further.
+            let ring_distance = (ring - 1).max(0) * self.cell_size;
+            if let Some((best_distance, _vent)) = &best {
+                if ring_distance * ring_distance > *best_distance {
+                    break;
+                }
+            }
+
+            for cell_z in center_z - ring..=center_z + ring {
+                for cell_x in center_x - ring..=center_x + ring {
+                    // Only the outside edge of the ring. The inside has already been searched.
+                    if (cell_x - center_x).abs() != ring && (cell_z - center_z).abs() != ring {
+                        continue;
+                    }
+
+                    if let Some(vent) = self.vent_in_cell(cell_x, cell_z) {
+                        if vent.material != material {
+                            continue;
+                        }
+
+                        let distance = (vent.position - from).map(|v| v * v).sum();
+                        if distance <= max_distance * max_distance
+                            && best.as_ref().map(|(best_distance, _vent)| distance < *best_distance).unwrap_or(true)
+                        {
+                            best = Some((distance, vent));
+                        }
+                    }
+                }
+            }
+        }
+
+        best.map(|(_distance, vent)| vent)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Vents are sparse, deep, and always in the same place for a seed.
+    #[test]
+    fn placement() {
+        let field = VentField::new(5);
+        let vents =
+            field.vents_in_box(GlobalBlockCoordinate::new(-4096, -1000, -4096), GlobalBlockCoordinate::new(4095, 0, 4095));
+
+        // 256 cells with a one in two chance each.
+        assert!(vents.len() > 90 && vents.len() < 170, "Found {} vents.", vents.len());
+        assert!(vents.iter().all(|vent| vent.position().y >= -768 && vent.position().y <= -256));
+
+        let again = VentField::new(5)
+            .vents_in_box(GlobalBlockCoordinate::new(-4096, -1000, -4096), GlobalBlockCoordinate::new(4095, 0, 4095));
+        assert_eq!(vents, again);
+    }
+
+    /// The locator finds the same vent as checking every vent by hand.
+    #[test]
+    fn nearest() {
+        let field = VentField::new(8);
+
+        for (index, from) in [
+            GlobalBlockCoordinate::new(0, 0, 0),
+            GlobalBlockCoordinate::new(10_000, -500, -3_333),
+            GlobalBlockCoordinate::new(-77_777, 20, 123),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let material = field.types()[index].material();
+            let found = field.nearest_vent(*from, material, 4000).unwrap();
+
+            let reach = GlobalBlockCoordinate::new(4000, 4000, 4000);
+            let expected = field
+                .vents_in_box(from - reach, from + reach)
+                .into_iter()
+                .filter(|vent| vent.material() == material)
+                .min_by_key(|vent| (vent.position() - from).map(|v| v * v).sum())
+                .unwrap();
+
+            assert_eq!(found, expected);
+        }
+
+        assert!(field.nearest_vent(GlobalBlockCoordinate::new(0, 0, 0), "unobtainium", 4000).is_none());
+    }
+}
@@ -0,0 +1,395 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Collision and intersection events from the physics engine, translated into terms of entities so that gameplay
+//! systems can react to things touching.
+
+use super::{
+    components::{RigidBody, Transform},
+    GridWorld,
+};
+use antidote::Mutex;
+use legion::{system, world::SubWorld, Entity, Query};
+use nalgebra::Isometry3;
+use rapier3d::{
+    dynamics::RigidBodyBuilder,
+    geometry::{ColliderBuilder, ColliderHandle, ColliderSet, ContactEvent, IntersectionEvent, SharedShape},
+    pipeline::EventHandler,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, ops::Deref, sync::Arc};
+
+/// How two colliders are touching.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CollisionKind {
+    /// Two solid colliders are in contact and pushing on each other.
+    Contact,
+
+    /// At least one of the colliders is a sensor, and they overlap. Nothing pushes back.
+    Intersection,
+}
+
+/// Two entities started or stopped touching.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CollisionEvent {
+    /// How the entities are touching.
+    pub kind: CollisionKind,
+
+    /// True if they started touching, false if they stopped.
+    pub started: bool,
+
+    /// The first entity.
+    pub entity1: Entity,
+
+    /// The second entity.
+    pub entity2: Entity,
+
+    /// The collider of the first entity that is touching.
+    pub collider1: ColliderHandle,
+
+    /// The collider of the second entity that is touching.
+    pub collider2: ColliderHandle,
+}
+
+impl CollisionEvent {
+    /// Check if an entity is one of the two touching.
+    pub fn involves(&self, entity: Entity) -> bool {
+        self.entity1 == entity || self.entity2 == entity
+    }
+
+    /// Get the entity touching the one given, or None if the one given isn't part of this event.
+    pub fn other(&self, entity: Entity) -> Option<Entity> {
+        if self.entity1 == entity {
+            Some(self.entity2)
+        } else if self.entity2 == entity {
+            Some(self.entity1)
+        } else {
+            None
+        }
+    }
+}
+
+/// The collisions that started or stopped during the last step. This is a resource of the world's ECS, so any system
+/// that runs after physics can read it. The events are replaced every step.
+/// Only colliders attached to a [RigidBody] component are reported.
+#[derive(Default, Serialize, Deserialize)]
+pub struct CollisionEvents {
+    events: Vec<CollisionEvent>,
+    touching: HashMap<(ColliderHandle, ColliderHandle), CollisionEvent>,
+}
+
+impl CollisionEvents {
+    /// Create an empty set of events.
+    pub fn new() -> CollisionEvents {
+        CollisionEvents::default()
+    }
+
+    /// Iterate the events from the last step, in the order they happened.
+    pub fn iter(&self) -> impl Iterator<Item = &CollisionEvent> {
+        self.events.iter()
+    }
+
+    /// Iterate the events from the last step that an entity was part of.
+    pub fn involving(&self, entity: Entity) -> impl Iterator<Item = &CollisionEvent> {
+        self.events.iter().filter(move |event| event.involves(entity))
+    }
+
+    /// The number of events from the last step.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Check if nothing started or stopped touching during the last step.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// What's touching, in the order of the colliders' handles rather than the order of the map, so that anything
+    /// going by it happens the same way every run.
+    fn touching_in_order(&self) -> Vec<&CollisionEvent> {
+        let mut touching: Vec<&CollisionEvent> = self.touching.values().collect();
+        touching.sort_by_key(|touching| collider_pair_order(touching.collider1, touching.collider2));
+
+        touching
+    }
+
+    /// Record an event, and keep track of what's touching so that it can be stopped later.
+    fn push(&mut self, event: CollisionEvent) {
+        let key = (event.collider1, event.collider2);
+        if event.started {
+            self.touching.insert(key, event);
+        } else {
+            self.touching.remove(&key);
+        }
+
+        self.events.push(event);
+    }
+}
+
+/// A key to sort pairs of colliders by, since handles can't be compared themselves.
+fn collider_pair_order(collider1: ColliderHandle, collider2: ColliderHandle) -> ((usize, u64), (usize, u64)) {
+    (collider1.into_raw_parts(), collider2.into_raw_parts())
+}
+
+/// Collects events from the physics engine while it steps. The physics engine may report from several threads at once.
+/// It's shared between the physics system and [ecs_publish_collision_events] rather than being a resource, since the
+/// physics system already has as many resources as a system can take.
+pub(super) struct PhysicsEventCollector {
+    contacts: Mutex<Vec<ContactEvent>>,
+    intersections: Mutex<Vec<IntersectionEvent>>,
+}
+
+impl PhysicsEventCollector {
+    /// Create a collector with nothing collected.
+    pub(super) fn new() -> Arc<PhysicsEventCollector> {
+        Arc::new(PhysicsEventCollector { contacts: Mutex::new(Vec::new()), intersections: Mutex::new(Vec::new()) })
+    }
+}
+
+impl EventHandler for PhysicsEventCollector {
+    fn handle_intersection_event(&self, event: IntersectionEvent) {
+        self.intersections.lock().push(event);
+    }
+
+    fn handle_contact_event(&self, event: ContactEvent) {
+        self.contacts.lock().push(event);
+    }
+}
+
+/// After the physics step, turn what the physics engine reported into [CollisionEvents].
+#[system]
+pub(super) fn ecs_publish_collision_events(
+    world: &mut SubWorld, query: &mut Query<(Entity, &RigidBody)>, #[state] collector: &Arc<PhysicsEventCollector>,
+    #[resource] colliders: &ColliderSet, #[resource] collision_events: &mut CollisionEvents,
+) {
+    collision_events.events.clear();
+
+    // When the physics engine runs in parallel, events come in whatever order the threads finish in. A pair of colliders
+    // only has one event a step, so sorting by the pair puts them back in the same order every run.
+    let mut contacts = std::mem::take(&mut *collector.contacts.lock());
+    contacts.sort_by_key(|event| match *event {
+        ContactEvent::Started(collider1, collider2) | ContactEvent::Stopped(collider1, collider2) => {
+            collider_pair_order(collider1, collider2)
+        }
+    });
+    let mut intersections = std::mem::take(&mut *collector.intersections.lock());
+    intersections.sort_by_key(|event| collider_pair_order(event.collider1, event.collider2));
+
+    if !contacts.is_empty() || !intersections.is_empty() {
+        let body_entities: HashMap<_, _> =
+            query.iter(world).map(|(entity, rigid_body)| (rigid_body.handle(), *entity)).collect();
+        let entity_of = |collider: ColliderHandle| {
+            colliders.get(collider).and_then(|collider| body_entities.get(&collider.parent())).copied()
+        };
+
+        let raw_events = contacts
+            .into_iter()
+            .map(|event| match event {
+                ContactEvent::Started(collider1, collider2) => (CollisionKind::Contact, true, collider1, collider2),
+                ContactEvent::Stopped(collider1, collider2) => (CollisionKind::Contact, false, collider1, collider2),
+            })
+            .chain(
+                intersections
+                    .into_iter()
+                    .map(|event| (CollisionKind::Intersection, event.intersecting, event.collider1, event.collider2)),
+            );
+
+        for (kind, started, collider1, collider2) in raw_events {
+            let event = if started {
+                match (entity_of(collider1), entity_of(collider2)) {
+                    (Some(entity1), Some(entity2)) => {
+                        Some(CollisionEvent { kind, started, entity1, entity2, collider1, collider2 })
+                    }
+                    _ => None,
+                }
+            } else {
+                // The colliders might already be gone, so go by who was touching.
+                collision_events
+                    .touching
+                    .get(&(collider1, collider2))
+                    .map(|touching| CollisionEvent { started: false, ..*touching })
+            };
+
+            if let Some(event) = event {
+                collision_events.push(event);
+            }
+        }
+    }
+
+    // The physics engine doesn't say anything when a collider is removed while touching something, so do it for it.
+    let removed: Vec<CollisionEvent> = collision_events
+        .touching_in_order()
+        .into_iter()
+        .filter(|touching| colliders.get(touching.collider1).is_none() || colliders.get(touching.collider2).is_none())
+        .map(|touching| CollisionEvent { started: false, ..*touching })
+        .collect();
+    for event in removed {
+        collision_events.push(event);
+    }
+}
+
+impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
+    /// Get the collisions that started or stopped during the last step.
+    pub fn collision_events(&self) -> impl Deref<Target = CollisionEvents> + '_ {
+        self.ecs_resources.get::<CollisionEvents>().expect("Failed to find collision events.")
+    }
+
+    /// Spawn a trigger volume: a sensor that doesn't block anything, but reports an [CollisionKind::Intersection]
+    /// whenever something moving enters or leaves it. Good for things like pressure plates and item pickups.
+    pub fn spawn_trigger_volume(&mut self, shape: SharedShape, position: Isometry3<f32>) -> Entity {
+        let rigid_body = RigidBody::new(self.ecs_resources_mut(), RigidBodyBuilder::new_static().position(position).build());
+        rigid_body.add_sensor(ColliderBuilder::new(shape).build(), self.ecs_resources_mut());
+
+        self.ecs_world.push((rigid_body, Transform::new(position)))
+    }
+
+    /// Check which entities are in a trigger volume, or any other entity with a sensor, right now.
+    pub fn entities_in_trigger(&self, trigger: Entity) -> Vec<Entity> {
+        let collision_events = self.collision_events();
+
+        // An entity with several colliders in the trigger only counts once.
+        let mut entities = Vec::new();
+        for touching in
+            collision_events.touching_in_order().into_iter().filter(|touching| touching.kind == CollisionKind::Intersection)
+        {
+            if let Some(entity) = touching.other(trigger) {
+                if !entities.contains(&entity) {
+                    entities.push(entity);
+                }
+            }
+        }
+
+        entities
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use rapier3d::geometry::ColliderBuilder;
+    use std::time::Duration;
+
+    /// Create an empty world with a static floor at the origin.
+    fn world_with_floor() -> (GridWorld<()>, Entity) {
+        let block_registry = BlockRegistry::new();
+        let chunk_provider = chunk_providers::RAMWorld::new(block_registry);
+        let mut world: GridWorld<()> = GridWorld::new(chunk_provider);
+
+        let floor = RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_static().build());
+        floor.add_collider(ColliderBuilder::cuboid(10.0, 0.5, 10.0).build(), world.ecs_resources_mut());
+        let floor = world.ecs_world_mut().push((floor,));
+
+        (world, floor)
+    }
+
+    /// Spawn a ball that falls from a height.
+    fn drop_ball(world: &mut GridWorld<()>, height: f32) -> Entity {
+        let ball =
+            RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_dynamic().translation(0.0, height, 0.0).build());
+        ball.add_collider(ColliderBuilder::ball(0.5).build(), world.ecs_resources_mut());
+
+        world.ecs_world_mut().push((ball,))
+    }
+
+    /// Step until an event shows up, or give up after a few seconds.
+    fn step_until_event(world: &mut GridWorld<()>) -> Vec<CollisionEvent> {
+        for _ in 0..300 {
+            world.update(Duration::from_millis(10));
+
+            let events: Vec<CollisionEvent> = world.collision_events().iter().copied().collect();
+            if !events.is_empty() {
+                return events;
+            }
+        }
+
+        panic!("Nothing happened.");
+    }
+
+    /// A ball falls onto the floor, and the contact is reported with both entities.
+    #[test]
+    fn contact() {
+        let (mut world, floor) = world_with_floor();
+        let ball = drop_ball(&mut world, 2.0);
+
+        let events = step_until_event(&mut world);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, CollisionKind::Contact);
+        assert!(events[0].started);
+        assert_eq!(events[0].other(ball), Some(floor));
+        assert_eq!(world.collision_events().involving(floor).count(), 1);
+
+        // Events only last a step.
+        world.update(Duration::from_millis(10));
+        assert!(world.collision_events().is_empty());
+
+        // Taking the ball away ends the contact, even though the physics engine never says so.
+        world.remove_entity(ball);
+        world.update(Duration::from_millis(10));
+        let events: Vec<CollisionEvent> = world.collision_events().iter().copied().collect();
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].started);
+        assert_eq!(events[0].other(floor), Some(ball));
+    }
+
+    /// A ball falls through a trigger volume, which reports it entering and leaving without getting in its way.
+    #[test]
+    fn trigger_volume() {
+        let (mut world, floor) = world_with_floor();
+        let trigger = world.spawn_trigger_volume(SharedShape::cuboid(1.0, 1.0, 1.0), Isometry3::translation(0.0, 5.0, 0.0));
+        let ball = drop_ball(&mut world, 8.0);
+
+        let events = step_until_event(&mut world);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, CollisionKind::Intersection);
+        assert!(events[0].started);
+        assert_eq!(events[0].other(trigger), Some(ball));
+        assert_eq!(world.entities_in_trigger(trigger), vec![ball]);
+
+        let events = step_until_event(&mut world);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, CollisionKind::Intersection);
+        assert!(!events[0].started);
+        assert!(world.entities_in_trigger(trigger).is_empty());
+
+        // It fell straight through to the floor.
+        let events = step_until_event(&mut world);
+        assert_eq!(events[0].kind, CollisionKind::Contact);
+        assert_eq!(events[0].other(floor), Some(ball));
+    }
+
+    /// Whatever is in a trigger, and whatever stops touching because it was removed, comes out in the order of the
+    /// colliders' handles rather than the order of a hash map.
+    #[test]
+    fn touching_order() {
+        let (mut world, floor) = world_with_floor();
+        let trigger = world.spawn_trigger_volume(SharedShape::cuboid(8.0, 2.0, 8.0), Isometry3::translation(0.0, 1.0, 0.0));
+        let balls: Vec<Entity> = (0..8)
+            .map(|index| {
+                let ball = RigidBody::new(
+                    world.ecs_resources_mut(),
+                    RigidBodyBuilder::new_dynamic().translation(index as f32 * 1.5 - 6.0, 1.0, 0.0).build(),
+                );
+                ball.add_collider(ColliderBuilder::ball(0.5).build(), world.ecs_resources_mut());
+                world.ecs_world_mut().push((ball,))
+            })
+            .collect();
+
+        for _ in 0..10 {
+            world.update(Duration::from_millis(10));
+        }
+        assert_eq!(world.entities_in_trigger(trigger), balls);
+
+        for ball in balls.iter() {
+            world.remove_entity(*ball);
+        }
+        world.update(Duration::from_millis(10));
+        let stopped: Vec<CollisionEvent> = world.collision_events().iter().copied().filter(|event| !event.started).collect();
+        assert_eq!(stopped.iter().filter(|event| event.involves(trigger)).count(), balls.len());
+        assert!(stopped.iter().all(|event| event.involves(trigger) || event.involves(floor)));
+        assert!(stopped.windows(2).all(|pair| {
+            collider_pair_order(pair[0].collider1, pair[0].collider2)
+                < collider_pair_order(pair[1].collider1, pair[1].collider2)
+        }));
+    }
+}
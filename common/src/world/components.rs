@@ -3,15 +3,30 @@
 
 //! Components that can be used within the ECS.
 
-use legion::Resources;
-use nalgebra::Isometry3;
+use super::{GridWorld, PhysicsVector};
+use antidote::Mutex;
+use legion::{
+    query::component,
+    system,
+    world::{Event, EventSender},
+    Entity, EntityStore, IntoQuery, Resources, World,
+};
+use nalgebra::{Isometry3, Translation3};
 use rapier3d::{
-    dynamics::{RigidBodyHandle, RigidBodySet},
+    dynamics::{JointSet, RigidBodyHandle, RigidBodySet},
     geometry::{Collider, ColliderHandle, ColliderSet},
 };
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 /// A rigid body is part of the physics engine. It's a collection of shapes that make up a full object.
-/// This component just references the rigid body within the physics engine.
+/// This component just references the rigid body within the physics engine. The rigid body, along with its colliders
+/// and joints, is removed from the physics engine once the component is gone, either because it was removed or because
+/// its entity was. A component that never makes it onto an entity by the end of the step has its body removed too.
+#[derive(Serialize, Deserialize)]
 pub struct RigidBody {
     handle: RigidBodyHandle,
 }
@@ -20,8 +35,18 @@ impl RigidBody {
     /// Create a new rigid body component.
     pub fn new(resource_set: &mut Resources, rigid_body: rapier3d::dynamics::RigidBody) -> RigidBody {
         let mut rigid_bodies = resource_set.get_mut::<RigidBodySet>().expect("Failed to find rigid body set.");
+        let handle = rigid_bodies.insert(rigid_body);
+
+        let mut owned_bodies = resource_set.get_mut::<OwnedRigidBodies>().expect("Failed to find owned rigid bodies.");
+        owned_bodies.handles.insert(handle);
+        owned_bodies.unclaimed.insert(handle);
+
+        RigidBody { handle }
+    }
 
-        RigidBody { handle: rigid_bodies.insert(rigid_body) }
+    /// Get the handle of the rigid body within the physics engine.
+    pub fn handle(&self) -> RigidBodyHandle {
+        self.handle
     }
 
     /// Add a collider (shape) to the rigid body.
@@ -29,7 +54,278 @@ impl RigidBody {
         let mut rigid_bodies = resource_set.get_mut::<RigidBodySet>().expect("Failed to find rigid body set.");
         let mut colliders = resource_set.get_mut::<ColliderSet>().expect("Failed to find collider set.");
 
-        colliders.insert(collider, self.handle, &mut *rigid_bodies)
+        colliders.insert(collider, self.handle, &mut rigid_bodies)
+    }
+
+    /// Add a sensor to the rigid body. Sensors don't push anything around, they only report what overlaps them as
+    /// [super::CollisionKind::Intersection] events. The collider is made a sensor if it wasn't built as one.
+    pub fn add_sensor(&self, mut collider: Collider, resource_set: &mut Resources) -> ColliderHandle {
+        collider.set_sensor(true);
+        self.add_collider(collider, resource_set)
+    }
+}
+
+/// Keeps track of which rigid bodies belong to [RigidBody] components, so that they can be cleaned up when their
+/// component goes away. Rigid bodies added to the physics engine some other way are left alone.
+#[derive(Default, Serialize, Deserialize)]
+pub struct OwnedRigidBodies {
+    handles: HashSet<RigidBodyHandle>,
+
+    /// The body of every entity with a [RigidBody], and the other way around. Rebuilt when the world is loaded.
+    #[serde(skip)]
+    entities: HashMap<Entity, RigidBodyHandle>,
+    #[serde(skip)]
+    bodies: HashMap<RigidBodyHandle, Entity>,
+
+    /// Bodies whose components are gone, waiting to be removed.
+    #[serde(skip)]
+    orphans: Vec<RigidBodyHandle>,
+
+    /// Bodies whose components haven't been seen on an entity yet.
+    #[serde(skip)]
+    unclaimed: HashSet<RigidBodyHandle>,
+
+    #[serde(skip)]
+    changes: RigidBodyChanges,
+}
+
+/// Hears from the ECS world about entities that gain or lose a [RigidBody], so that orphaned bodies can be found without
+/// going through every entity.
+#[derive(Clone)]
+struct RigidBodyChanges(Arc<Mutex<Vec<Entity>>>);
+
+impl Default for RigidBodyChanges {
+    fn default() -> Self {
+        RigidBodyChanges(Arc::new(Mutex::new(Vec::new())))
+    }
+}
+
+impl EventSender for RigidBodyChanges {
+    fn send(&self, event: Event) -> bool {
+        match event {
+            Event::EntityInserted(entity, _archetype) | Event::EntityRemoved(entity, _archetype) => {
+                self.0.lock().push(entity);
+            }
+            Event::ArchetypeCreated(_archetype) => {}
+        }
+
+        true
+    }
+}
+
+impl OwnedRigidBodies {
+    /// Create an empty set of owned rigid bodies.
+    pub fn new() -> OwnedRigidBodies {
+        OwnedRigidBodies::default()
+    }
+
+    /// Start listening to an ECS world for entities gaining or losing their [RigidBody], and find the ones that
+    /// already have one.
+    fn track(&mut self, ecs_world: &mut World) {
+        ecs_world.subscribe(self.changes.clone(), component::<RigidBody>());
+        self.entities =
+            <(Entity, &RigidBody)>::query().iter(ecs_world).map(|(entity, rigid_body)| (*entity, rigid_body.handle)).collect();
+        self.bodies = self.entities.iter().map(|(entity, handle)| (*handle, *entity)).collect();
+        self.unclaimed = self.handles.iter().filter(|handle| !self.bodies.contains_key(handle)).copied().collect();
+        self.orphans.clear();
+    }
+
+    /// Catch up on the entities that gained or lost a [RigidBody] since last time. Bodies that lost their component are
+    /// set aside to be removed.
+    fn sync(&mut self, ecs_world: &World) {
+        let changed = std::mem::take(&mut *self.changes.0.lock());
+        for entity in changed {
+            let current = ecs_world
+                .entry_ref(entity)
+                .ok()
+                .and_then(|entry| entry.get_component::<RigidBody>().ok().map(|rigid_body| rigid_body.handle));
+            let previous = match current {
+                Some(handle) => {
+                    self.unclaimed.remove(&handle);
+                    self.bodies.insert(handle, entity);
+                    self.entities.insert(entity, handle)
+                }
+                None => self.entities.remove(&entity),
+            };
+
+            if let Some(previous) = previous.filter(|previous| Some(*previous) != current) {
+                self.bodies.remove(&previous);
+                self.orphans.push(previous);
+            }
+        }
+    }
+
+    /// Find the entity whose [RigidBody] component owns a body.
+    pub(super) fn entity_of(&mut self, handle: RigidBodyHandle, ecs_world: &World) -> Option<Entity> {
+        self.sync(ecs_world);
+        self.bodies.get(&handle).copied()
+    }
+
+    /// The number of rigid bodies that belong to components.
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Check if no rigid bodies belong to components.
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+}
+
+/// Marks an entity, such as a player, that the physics simulation should stay centered around.
+/// The entity needs a [RigidBody] for its position to be known.
+#[derive(Serialize, Deserialize)]
+pub struct OriginAnchor;
+
+/// Where an entity with a [RigidBody] is, in physics space. It's updated from the physics engine after every step,
+/// so other systems can find out where things are without going through the physics engine.
+/// A transform can also drive its body instead, for kinematic bodies such as moving platforms. The body is moved to
+/// wherever the transform is set before each step.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Transform {
+    position: Isometry3<f32>,
+    previous_position: Isometry3<f32>,
+    drives_body: bool,
+}
+
+impl Transform {
+    /// Create a transform that follows its rigid body.
+    pub fn new(position: Isometry3<f32>) -> Transform {
+        Transform { position, previous_position: position, drives_body: false }
+    }
+
+    /// Create a transform that its kinematic rigid body follows.
+    pub fn driving(position: Isometry3<f32>) -> Transform {
+        Transform { position, previous_position: position, drives_body: true }
+    }
+
+    /// Where the entity is as of the last step.
+    pub fn position(&self) -> &Isometry3<f32> {
+        &self.position
+    }
+
+    /// Where the entity was the step before last.
+    pub fn previous_position(&self) -> &Isometry3<f32> {
+        &self.previous_position
+    }
+
+    /// Move the entity. If the transform drives its body, the body follows during the next step. Otherwise, this will
+    /// be overwritten by wherever the physics engine puts the body.
+    pub fn set_position(&mut self, position: Isometry3<f32>) {
+        self.position = position;
+    }
+
+    /// Check if the rigid body follows this transform, rather than the other way around.
+    pub fn drives_body(&self) -> bool {
+        self.drives_body
+    }
+
+    /// Blend between the last two steps. An alpha of 0 is the previous step, and 1 is the latest one. Use this with
+    /// [GridWorld::interpolation_alpha] to draw things moving smoothly between steps.
+    pub fn interpolated(&self, alpha: f32) -> Isometry3<f32> {
+        self.previous_position.lerp_slerp(&self.position, alpha)
+    }
+
+    /// Shift the transform without it counting as movement, such as when the physics origin moves.
+    pub(crate) fn shift(&mut self, shift: PhysicsVector) {
+        self.position = Translation3::from(shift) * self.position;
+        self.previous_position = Translation3::from(shift) * self.previous_position;
+    }
+}
+
+/// Before the physics step, move kinematic bodies to where their transforms say they should be.
+#[system(for_each)]
+pub(super) fn ecs_drive_kinematic_bodies(
+    rigid_body: &RigidBody, transform: &Transform, #[resource] rigid_bodies: &mut RigidBodySet,
+) {
+    if transform.drives_body {
+        if let Some(body) = rigid_bodies.get_mut(rigid_body.handle) {
+            if body.is_kinematic() {
+                body.set_next_kinematic_position(transform.position);
+            }
+        }
+    }
+}
+
+/// After the physics step, update transforms from where their bodies ended up.
+#[system(for_each)]
+pub(super) fn ecs_sync_transforms(rigid_body: &RigidBody, transform: &mut Transform, #[resource] rigid_bodies: &RigidBodySet) {
+    if let Some(body) = rigid_bodies.get(rigid_body.handle) {
+        transform.previous_position = transform.position;
+        transform.position = *body.position();
+    }
+}
+
+impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
+    /// Keep track of which entities own which rigid bodies in the physics engine. Needs to be done again whenever the
+    /// ECS world or the owned rigid bodies are replaced.
+    pub(super) fn track_rigid_bodies(&mut self) {
+        let mut owned_bodies = self.ecs_resources.get_mut::<OwnedRigidBodies>().expect("Failed to find owned rigid bodies.");
+        owned_bodies.track(&mut self.ecs_world);
+    }
+
+    /// Spawn an entity with a rigid body made of some colliders, and a [Transform] that follows the body around.
+    pub fn spawn_rigid_body(&mut self, body: rapier3d::dynamics::RigidBody, colliders: Vec<Collider>) -> Entity {
+        let position = *body.position();
+        let rigid_body = RigidBody::new(&mut self.ecs_resources, body);
+        for collider in colliders {
+            rigid_body.add_collider(collider, &mut self.ecs_resources);
+        }
+
+        self.ecs_world.push((rigid_body, Transform::new(position)))
+    }
+
+    /// Remove an entity from the world, along with its rigid body and anything attached to it in the physics engine.
+    /// Returns false if there was no such entity.
+    pub fn remove_entity(&mut self, entity: Entity) -> bool {
+        // Catch up first, so that a body only just put on the entity is known to be its body once the entity is gone.
+        self.ecs_resources.get_mut::<OwnedRigidBodies>().expect("Failed to find owned rigid bodies.").sync(&self.ecs_world);
+        let removed = self.ecs_world.remove(entity);
+        self.remove_orphaned_rigid_bodies();
+
+        removed
+    }
+
+    /// Remove rigid bodies from the physics engine whose [RigidBody] components are gone. This is done before every
+    /// step, but can be done sooner if you need the physics engine cleaned up right away.
+    pub fn remove_orphaned_rigid_bodies(&mut self) {
+        let mut owned_bodies = self.ecs_resources.get_mut::<OwnedRigidBodies>().expect("Failed to find owned rigid bodies.");
+        let owned_bodies = &mut *owned_bodies;
+        owned_bodies.sync(&self.ecs_world);
+
+        let orphans: Vec<RigidBodyHandle> = owned_bodies.orphans.drain(..).collect();
+        self.remove_owned_rigid_bodies(owned_bodies, orphans);
+    }
+
+    /// Remove the bodies of [RigidBody] components that were dropped without ever being added to an entity. Components
+    /// can be made at any time and added to an entity later, even by a command buffer, so this is only done at the end
+    /// of a step, once the schedule has flushed its command buffers.
+    pub(super) fn remove_unclaimed_rigid_bodies(&mut self) {
+        let mut owned_bodies = self.ecs_resources.get_mut::<OwnedRigidBodies>().expect("Failed to find owned rigid bodies.");
+        let owned_bodies = &mut *owned_bodies;
+        owned_bodies.sync(&self.ecs_world);
+
+        // They're removed in a fixed order, since that decides which handles the physics engine hands out next.
+        let mut unclaimed: Vec<RigidBodyHandle> = owned_bodies.unclaimed.drain().collect();
+        unclaimed.sort_by_key(|handle| handle.into_raw_parts());
+        self.remove_owned_rigid_bodies(owned_bodies, unclaimed);
+    }
+
+    /// Remove bodies that belonged to components from the physics engine, along with their colliders and joints.
+    fn remove_owned_rigid_bodies(&self, owned_bodies: &mut OwnedRigidBodies, handles: Vec<RigidBodyHandle>) {
+        if handles.is_empty() {
+            return;
+        }
+
+        let mut rigid_bodies = self.ecs_resources.get_mut::<RigidBodySet>().expect("Failed to find rigid body set.");
+        let mut colliders = self.ecs_resources.get_mut::<ColliderSet>().expect("Failed to find collider set.");
+        let mut joints = self.ecs_resources.get_mut::<JointSet>().expect("Failed to find joint set.");
+
+        for handle in handles {
+            if owned_bodies.handles.remove(&handle) {
+                rigid_bodies.remove(handle, &mut colliders, &mut joints);
+            }
+        }
     }
 }
 
@@ -41,16 +337,18 @@ mod test {
     // Import ourselves.
     use super::*;
 
+    use legion::EntityStore;
     use rapier3d::{dynamics::RigidBodyBuilder, geometry::ColliderBuilder};
 
     /// Create a world. Add a single rigid body. Tick the world once.
     #[test]
     fn rigid_body() {
-        let block_registry = BlockRegistry::new();
+        let mut block_registry = BlockRegistry::new();
+        chunk_providers::register_blocks(&mut block_registry, chunk_providers::AbstractFlatWorld::BLOCKS).unwrap();
         let mut chunk_provider = chunk_providers::RAMWorld::new(block_registry);
 
         let abstract_flat_world = chunk_providers::AbstractFlatWorld::new();
-        chunk_provider.add_generator(abstract_flat_world);
+        chunk_provider.add_generator(abstract_flat_world).unwrap();
 
         let mut world: GridWorld<()> = GridWorld::new(chunk_provider);
 
@@ -63,11 +361,12 @@ mod test {
     /// Create a world. Add a single rigid body. Give it a collider. Tick the world once.
     #[test]
     fn collider() {
-        let block_registry = BlockRegistry::new();
+        let mut block_registry = BlockRegistry::new();
+        chunk_providers::register_blocks(&mut block_registry, chunk_providers::AbstractFlatWorld::BLOCKS).unwrap();
         let mut chunk_provider = chunk_providers::RAMWorld::new(block_registry);
 
         let abstract_flat_world = chunk_providers::AbstractFlatWorld::new();
-        chunk_provider.add_generator(abstract_flat_world);
+        chunk_provider.add_generator(abstract_flat_world).unwrap();
 
         let mut world: GridWorld<()> = GridWorld::new(chunk_provider);
 
@@ -78,4 +377,170 @@ mod test {
 
         world.update(Duration::from_millis(10));
     }
+
+    /// Create a world for the lifecycle tests.
+    fn empty_world() -> GridWorld<()> {
+        let block_registry = BlockRegistry::new();
+        let chunk_provider = chunk_providers::RAMWorld::new(block_registry);
+
+        GridWorld::new(chunk_provider)
+    }
+
+    /// Count what the physics engine is holding on to.
+    fn physics_counts(world: &GridWorld<()>) -> (usize, usize, usize) {
+        let resources = world.ecs_resources();
+        let rigid_bodies = resources.get::<RigidBodySet>().unwrap();
+        let colliders = resources.get::<ColliderSet>().unwrap();
+        let owned_bodies = resources.get::<OwnedRigidBodies>().unwrap();
+
+        (rigid_bodies.len(), colliders.len(), owned_bodies.len())
+    }
+
+    /// Spawn a lot of entities with colliders, then get rid of them every way there is. Nothing should be left behind
+    /// in the physics engine.
+    #[test]
+    fn spawn_and_despawn() {
+        let mut world = empty_world();
+
+        for round in 0..5 {
+            let entities: Vec<Entity> = (0..300)
+                .map(|index| {
+                    let rigid_body = RigidBody::new(
+                        world.ecs_resources_mut(),
+                        RigidBodyBuilder::new_dynamic().translation(index as f32 * 2.0, round as f32 * 2.0, 0.0).build(),
+                    );
+                    rigid_body.add_collider(ColliderBuilder::ball(0.5).build(), world.ecs_resources_mut());
+                    rigid_body.add_collider(ColliderBuilder::cuboid(0.5, 0.5, 0.5).build(), world.ecs_resources_mut());
+
+                    world.ecs_world_mut().push((rigid_body, Transform::new(Isometry3::identity())))
+                })
+                .collect();
+
+            world.step();
+            assert_eq!(physics_counts(&world), (300, 600, 300));
+
+            for (index, entity) in entities.into_iter().enumerate() {
+                match index % 3 {
+                    0 => {
+                        world.ecs_world_mut().remove(entity);
+                    }
+                    1 => {
+                        world.ecs_world_mut().entry(entity).unwrap().remove_component::<RigidBody>();
+                    }
+                    _ => {
+                        assert!(world.remove_entity(entity));
+                    }
+                }
+            }
+
+            world.step();
+            assert_eq!(physics_counts(&world), (0, 0, 0));
+        }
+    }
+
+    /// Moving an entity between archetypes by adding and removing other components doesn't cost it its body.
+    #[test]
+    fn change_archetype() {
+        let mut world = empty_world();
+
+        let rigid_body = RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_dynamic().build());
+        let entity = world.ecs_world_mut().push((rigid_body,));
+        world.step();
+
+        world.ecs_world_mut().entry(entity).unwrap().add_component(Transform::new(Isometry3::identity()));
+        world.step();
+        assert_eq!(physics_counts(&world), (1, 0, 1));
+
+        world.ecs_world_mut().entry(entity).unwrap().remove_component::<Transform>();
+        world.step();
+        assert_eq!(physics_counts(&world), (1, 0, 1));
+
+        assert!(world.remove_entity(entity));
+        assert_eq!(physics_counts(&world), (0, 0, 0));
+    }
+
+    /// A component can be made before its entity is. Removing some other entity in the meantime doesn't take its body,
+    /// but a component that's dropped without being added to an entity loses its body at the end of the step.
+    #[test]
+    fn unclaimed_bodies() {
+        let mut world = empty_world();
+        let other = RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_dynamic().build());
+        let other = world.ecs_world_mut().push((other,));
+        world.step();
+
+        let rigid_body = RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_dynamic().build());
+        let handle = rigid_body.handle();
+        assert!(world.remove_entity(other));
+        world.remove_orphaned_rigid_bodies();
+        let entity = world.ecs_world_mut().push((rigid_body,));
+        world.step();
+        assert_eq!(physics_counts(&world), (1, 0, 1));
+        assert!(world.ecs_resources().get::<RigidBodySet>().unwrap().get(handle).is_some());
+
+        let _dropped = RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_dynamic().build());
+        assert_eq!(physics_counts(&world), (2, 0, 2));
+        world.step();
+        assert_eq!(physics_counts(&world), (1, 0, 1));
+
+        assert!(world.remove_entity(entity));
+        assert_eq!(physics_counts(&world), (0, 0, 0));
+    }
+
+    /// Rigid bodies that weren't made through a component are left alone.
+    #[test]
+    fn unowned_bodies() {
+        let mut world = empty_world();
+
+        let handle =
+            world.ecs_resources_mut().get_mut::<RigidBodySet>().unwrap().insert(RigidBodyBuilder::new_static().build());
+        let rigid_body = RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_dynamic().build());
+        let entity = world.ecs_world_mut().push((rigid_body,));
+
+        world.remove_entity(entity);
+        assert_eq!(physics_counts(&world), (1, 0, 0));
+        assert!(world.ecs_resources().get::<RigidBodySet>().unwrap().get(handle).is_some());
+    }
+
+    /// Transforms follow their bodies as they fall.
+    #[test]
+    fn transform_follows_body() {
+        let mut world = empty_world();
+
+        let rigid_body =
+            RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_dynamic().translation(0.0, 10.0, 0.0).build());
+        rigid_body.add_collider(ColliderBuilder::ball(0.5).build(), world.ecs_resources_mut());
+        let entity = world.ecs_world_mut().push((rigid_body, Transform::new(Isometry3::translation(0.0, 10.0, 0.0))));
+
+        world.step();
+        world.step();
+
+        let transform = *world.ecs_world().entry_ref(entity).unwrap().get_component::<Transform>().unwrap();
+        assert!(transform.position().translation.y < 10.0);
+        assert!(transform.position().translation.y < transform.previous_position().translation.y);
+
+        let halfway = transform.interpolated(0.5).translation.y;
+        assert!(halfway < transform.previous_position().translation.y && halfway > transform.position().translation.y);
+    }
+
+    /// A transform can drive a kinematic body around.
+    #[test]
+    fn transform_drives_kinematic_body() {
+        let mut world = empty_world();
+
+        let rigid_body = RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_kinematic().build());
+        let handle = rigid_body.handle();
+        let entity = world.ecs_world_mut().push((rigid_body, Transform::driving(Isometry3::identity())));
+
+        world
+            .ecs_world_mut()
+            .entry(entity)
+            .unwrap()
+            .get_component_mut::<Transform>()
+            .unwrap()
+            .set_position(Isometry3::translation(3.0, 0.0, 0.0));
+        world.step();
+
+        let rigid_bodies = world.ecs_resources().get::<RigidBodySet>().unwrap();
+        assert!((rigid_bodies.get(handle).unwrap().position().translation.x - 3.0).abs() < 1.0e-5);
+    }
 }
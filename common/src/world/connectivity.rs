@@ -0,0 +1,330 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Working out which blocks are connected to each other, so that structures can split apart when a block is removed
+//! and join together when one is placed.
+
+use super::{BlockGrid, GlobalBlockCoordinate, GridWorld};
+use std::collections::{HashMap, VecDeque};
+
+/// Offsets to the six blocks sharing a face with a block.
+pub const FACE_NEIGHBORS: [GlobalBlockCoordinate; 6] = [
+    GlobalBlockCoordinate::new(1, 0, 0),
+    GlobalBlockCoordinate::new(-1, 0, 0),
+    GlobalBlockCoordinate::new(0, 1, 0),
+    GlobalBlockCoordinate::new(0, -1, 0),
+    GlobalBlockCoordinate::new(0, 0, 1),
+    GlobalBlockCoordinate::new(0, 0, -1),
+];
+
+/// Something made of blocks that we can work out the connectivity of.
+/// Blocks are connected when they share a face.
+pub trait BlockStructure {
+    /// True if there is a block at this location that can connect to its neighbors.
+    fn is_solid(&self, location: GlobalBlockCoordinate) -> bool;
+}
+
+impl<ChunkUserData: Default> BlockStructure for BlockGrid<ChunkUserData> {
+    fn is_solid(&self, location: GlobalBlockCoordinate) -> bool {
+        self.get_block(location).is_some()
+    }
+}
+
+/// The terrain of a world. Unloaded terrain is treated as empty.
+impl<ChunkUserData: Default> BlockStructure for GridWorld<ChunkUserData> {
+    fn is_solid(&self, location: GlobalBlockCoordinate) -> bool {
+        matches!(self.get_block(location), Some(Some(_)))
+    }
+}
+
+/// A group of blocks that are all connected to each other.
+#[derive(Debug)]
+pub struct ConnectedPiece {
+    blocks: Vec<GlobalBlockCoordinate>,
+    complete: bool,
+}
+
+impl ConnectedPiece {
+    /// The blocks of the piece that were found. If the piece is not complete, this is only part of it.
+    pub fn blocks(&self) -> &[GlobalBlockCoordinate] {
+        &self.blocks
+    }
+
+    /// True if every block of the piece was found. Searches stop early once the answer is known or they hit their
+    /// limit, so large pieces, such as the ground itself, are usually not complete.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+}
+
+/// The pieces found around a block by a connectivity search.
+#[derive(Debug)]
+pub struct ConnectivityReport {
+    pieces: Vec<ConnectedPiece>,
+}
+
+impl ConnectivityReport {
+    /// The separate pieces touching the block. There is one entry per piece, not per neighbor.
+    pub fn pieces(&self) -> &[ConnectedPiece] {
+        &self.pieces
+    }
+
+    /// Take the pieces out of the report.
+    pub fn into_pieces(self) -> Vec<ConnectedPiece> {
+        self.pieces
+    }
+
+    /// True if more than one piece touches the block.
+    /// After a removal this means the structure split apart. After a placement it means pieces were joined.
+    pub fn is_divided(&self) -> bool {
+        self.pieces.len() > 1
+    }
+
+    /// The pieces that were fully explored and are not the largest piece. After a removal, these are the bits that
+    /// broke off and should become their own bodies.
+    pub fn detached_pieces(&self) -> impl Iterator<Item = &ConnectedPiece> {
+        // If every piece is complete, the biggest one is considered the main body.
+        let main_body = if self.pieces.iter().all(|piece| piece.complete) {
+            self.pieces.iter().enumerate().max_by_key(|(_index, piece)| piece.blocks.len()).map(|(index, _piece)| index)
+        } else {
+            None
+        };
+
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(move |(index, piece)| piece.complete && Some(*index) != main_body)
+            .map(|(_, piece)| piece)
+    }
+}
+
+/// The state of a flood fill started from one neighbor of the block being looked at.
+struct Search {
+    frontier: VecDeque<GlobalBlockCoordinate>,
+    blocks: Vec<GlobalBlockCoordinate>,
+}
+
+/// Find the separate pieces that touch a block, pretending the block itself is empty.
+/// A flood fill is started from each neighbor and they take turns taking a step. When two fills meet, they're the same
+/// piece and get merged. We stop as soon as only one piece is left unfinished, so the cost is that of the smaller
+/// pieces, not the whole structure. No more than `block_limit` blocks will be visited.
+fn find_pieces_around(
+    structure: &impl BlockStructure, center: GlobalBlockCoordinate, block_limit: usize,
+) -> ConnectivityReport {
+    let mut searches = Vec::new();
+    let mut parents = Vec::new();
+    let mut visited = HashMap::new();
+
+    visited.insert(center, usize::MAX);
+    for offset in FACE_NEIGHBORS.iter() {
+        let neighbor = center + offset;
+        if structure.is_solid(neighbor) {
+            visited.insert(neighbor, searches.len());
+            parents.push(searches.len());
+            searches.push(Search { frontier: VecDeque::from(vec![neighbor]), blocks: vec![neighbor] });
+        }
+    }
+
+    fn find_root(parents: &mut [usize], mut index: usize) -> usize {
+        while parents[index] != index {
+            parents[index] = parents[parents[index]];
+            index = parents[index];
+        }
+
+        index
+    }
+
+    let mut num_visited = searches.len();
+    loop {
+        let roots: Vec<usize> = (0..searches.len()).filter(|index| parents[*index] == *index).collect();
+        let unfinished: Vec<usize> = roots.iter().copied().filter(|index| !searches[*index].frontier.is_empty()).collect();
+
+        // Once there's only one piece that could still be growing, nothing else can join up.
+        if roots.len() <= 1 || unfinished.len() <= 1 || num_visited >= block_limit {
+            break;
+        }
+
+        for search_index in unfinished {
+            // This search may have been merged into another during this round.
+            let search_index = find_root(&mut parents, search_index);
+
+            if let Some(location) = searches[search_index].frontier.pop_front() {
+                for offset in FACE_NEIGHBORS.iter() {
+                    let neighbor = location + offset;
+
+                    match visited.get(&neighbor).copied() {
+                        Some(usize::MAX) => {}
+                        Some(other) => {
+                            let other = find_root(&mut parents, other);
+                            if other != search_index {
+                                // We've met another search, so we're part of the same piece.
+                                parents[other] = search_index;
+                                let other = std::mem::replace(
+                                    &mut searches[other],
+                                    Search { frontier: VecDeque::new(), blocks: Vec::new() },
+                                );
+                                searches[search_index].frontier.extend(other.frontier);
+                                searches[search_index].blocks.extend(other.blocks);
+                            }
+                        }
+                        None => {
+                            if structure.is_solid(neighbor) {
+                                visited.insert(neighbor, search_index);
+                                searches[search_index].frontier.push_back(neighbor);
+                                searches[search_index].blocks.push(neighbor);
+                                num_visited += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let pieces = searches
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _search)| parents[*index] == *index)
+        .map(|(_index, search)| ConnectedPiece { complete: search.frontier.is_empty(), blocks: search.blocks })
+        .collect();
+
+    ConnectivityReport { pieces }
+}
+
+/// Work out what a block was holding together, after it has been removed from a structure.
+/// If the report is divided, the structure has split, and the detached pieces are the parts that broke off.
+pub fn pieces_after_removal(
+    structure: &impl BlockStructure, removed: GlobalBlockCoordinate, block_limit: usize,
+) -> ConnectivityReport {
+    find_pieces_around(structure, removed, block_limit)
+}
+
+/// Work out what a block has joined together, after it has been placed into a structure.
+/// If the report is divided, each piece was separate before the block was placed and they are now one.
+pub fn pieces_joined_by_placement(
+    structure: &impl BlockStructure, placed: GlobalBlockCoordinate, block_limit: usize,
+) -> ConnectivityReport {
+    // The block itself is ignored by the search, so this is the same as if it had been removed again.
+    find_pieces_around(structure, placed, block_limit)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+
+    const LIMIT: usize = 10000;
+
+    fn abstract_block() -> BlockID {
+        BlockID::new(std::num::NonZeroU16::new(1).unwrap())
+    }
+
+    /// Build a grid from a list of blocks.
+    fn build_grid(blocks: &[(i64, i64, i64)]) -> BlockGrid<()> {
+        let mut grid = BlockGrid::new();
+        for (x, y, z) in blocks.iter() {
+            grid.set_block(GlobalBlockCoordinate::new(*x, *y, *z), Some(abstract_block()), &BlockMasses::default());
+        }
+
+        grid
+    }
+
+    fn remove(grid: &mut BlockGrid<()>, x: i64, y: i64, z: i64) -> ConnectivityReport {
+        let location = GlobalBlockCoordinate::new(x, y, z);
+        grid.set_block(location, None, &BlockMasses::default());
+        pieces_after_removal(grid, location, LIMIT)
+    }
+
+    /// Breaking the middle of a bar leaves two halves.
+    #[test]
+    fn split_bar() {
+        let mut grid = build_grid(&[(0, 0, 0), (1, 0, 0), (2, 0, 0), (3, 0, 0), (4, 0, 0)]);
+        let report = remove(&mut grid, 2, 0, 0);
+
+        assert!(report.is_divided());
+        assert_eq!(report.pieces().len(), 2);
+        for piece in report.pieces() {
+            assert!(piece.is_complete());
+            assert_eq!(piece.blocks().len(), 2);
+        }
+    }
+
+    /// Breaking the end of a bar doesn't split anything.
+    #[test]
+    fn trim_bar() {
+        let mut grid = build_grid(&[(0, 0, 0), (1, 0, 0), (2, 0, 0)]);
+        let report = remove(&mut grid, 0, 0, 0);
+
+        assert!(!report.is_divided());
+        assert_eq!(report.detached_pieces().count(), 0);
+    }
+
+    /// Breaking one side of a ring leaves it in one piece.
+    #[test]
+    fn ring() {
+        let mut grid = build_grid(&[(0, 0, 0), (1, 0, 0), (2, 0, 0), (2, 0, 1), (2, 0, 2), (1, 0, 2), (0, 0, 2), (0, 0, 1)]);
+        let report = remove(&mut grid, 1, 0, 0);
+
+        assert!(!report.is_divided());
+    }
+
+    /// Breaking the center of a 3D cross leaves six arms, five of which are detached from the longest.
+    #[test]
+    fn cross() {
+        let mut blocks = vec![(0, 0, 0)];
+        for offset in FACE_NEIGHBORS.iter() {
+            blocks.push((offset.x, offset.y, offset.z));
+        }
+        // Make one arm longer than the others.
+        blocks.push((2, 0, 0));
+
+        let mut grid = build_grid(&blocks);
+        let report = remove(&mut grid, 0, 0, 0);
+
+        assert_eq!(report.pieces().len(), 6);
+        assert_eq!(report.detached_pieces().count(), 5);
+        assert!(report.detached_pieces().all(|piece| piece.blocks().len() == 1));
+    }
+
+    /// Placing a block between two bars joins them.
+    #[test]
+    fn join_bars() {
+        let mut grid = build_grid(&[(0, 0, 0), (1, 0, 0), (3, 0, 0), (4, 0, 0), (2, 1, 0)]);
+        let location = GlobalBlockCoordinate::new(2, 0, 0);
+        grid.set_block(location, Some(abstract_block()), &BlockMasses::default());
+
+        let report = pieces_joined_by_placement(&grid, location, LIMIT);
+        assert_eq!(report.pieces().len(), 3);
+    }
+
+    /// Knock out the base of a tower standing on the ground. The top falls off, and we shouldn't have to search the
+    /// entire ground to find that out.
+    #[test]
+    fn terrain_tower() {
+        let mut block_registry = BlockRegistry::new();
+        chunk_providers::register_blocks(&mut block_registry, chunk_providers::AbstractFlatWorld::BLOCKS).unwrap();
+        let mut chunk_provider = chunk_providers::RAMWorld::new(block_registry);
+        chunk_provider.add_generator(chunk_providers::AbstractFlatWorld::new()).unwrap();
+
+        let mut world: GridWorld<()> = GridWorld::new(chunk_provider);
+        world.load_chunk_range(ChunkRange::from_end_points(ChunkCoordinate::new(-1, -1, -1), ChunkCoordinate::new(1, 1, 1)));
+        let abstract_block = world.block_registry().get_block_id_from_name("abstract_block").cloned();
+
+        for y in 0..4 {
+            *world.get_block_mut(GlobalBlockCoordinate::new(5, y, 5)).unwrap() = abstract_block;
+        }
+
+        let location = GlobalBlockCoordinate::new(5, 1, 5);
+        *world.get_block_mut(location).unwrap() = None;
+
+        let report = pieces_after_removal(&world, location, LIMIT);
+        assert!(report.is_divided());
+
+        let detached: Vec<&ConnectedPiece> = report.detached_pieces().collect();
+        assert_eq!(detached.len(), 1);
+        assert_eq!(detached[0].blocks().len(), 2);
+
+        // The ground was never fully searched.
+        assert_eq!(report.pieces().iter().filter(|piece| !piece.is_complete()).count(), 1);
+    }
+}
@@ -7,7 +7,9 @@ use super::storage;
 use nalgebra::Vector3;
 
 /// Type for a chunk's coordinates in chunk space.
-pub type ChunkCoordinate = Vector3<i16>;
+/// Chunks are addressed with 32 bit integers, which lets the world reach about 68 billion blocks from the origin on
+/// every axis.
+pub type ChunkCoordinate = Vector3<i32>;
 
 /// Type for a block's coordinates in chunk local space.
 pub type LocalBlockCoordinate = Vector3<u8>;
@@ -16,6 +18,8 @@ pub type LocalBlockCoordinate = Vector3<u8>;
 pub type GlobalBlockCoordinate = Vector3<i64>;
 
 /// Type for physics computations.
+/// Physics is done in single precision, relative to the world's floating origin rather than the center of the world,
+/// so that things far from the center of the world still move smoothly. See [FloatingOrigin](super::FloatingOrigin).
 pub type PhysicsVector = Vector3<f32>;
 
 /// Adds the ability to convert the chunk coordinate to a block global space coordinate.
@@ -36,6 +40,7 @@ pub trait GlobalBlockCoordinateEXT {
     fn to_local_block_coordinate(&self) -> LocalBlockCoordinate;
 
     /// Get the index of the chunk this block is in.
+    /// Blocks beyond the range of chunk coordinates wrap around to the other side of the world.
     fn chunk_index(&self) -> ChunkCoordinate;
 }
 
@@ -45,7 +50,7 @@ impl GlobalBlockCoordinateEXT for GlobalBlockCoordinate {
     }
 
     fn chunk_index(&self) -> ChunkCoordinate {
-        self.map(|v| (v >> storage::NUM_BLOCK_ADDRESS_BITS) as i16)
+        self.map(|v| (v >> storage::NUM_BLOCK_ADDRESS_BITS) as i32)
     }
 }
 
@@ -0,0 +1,39 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Hashing that's stable between builds, for comparing simulations.
+
+use std::hash::Hasher;
+
+/// The 64 bit FNV-1a hash. Unlike the standard library's hasher, it's guaranteed to hash the same way in every build,
+/// so its hashes can be saved, compared between runs, and pinned in tests.
+#[derive(Debug, Clone, Copy)]
+pub struct Fnv1a(u64);
+
+impl Fnv1a {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    /// Creates a hasher that hasn't hashed anything yet.
+    pub fn new() -> Fnv1a {
+        Fnv1a(Self::OFFSET_BASIS)
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(Self::PRIME);
+        }
+    }
+}
@@ -8,7 +8,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
 /// A unique ID to identify materials.
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct MaterialID(u32);
 
 impl Hash for MaterialID {
@@ -25,6 +25,7 @@ impl Hash for MaterialID {
 pub struct MaterialInfo {
     name_tag: String,
     density: u64,
+    strength: u64,
     material_id: MaterialID,
 }
 
@@ -34,18 +35,24 @@ impl MaterialInfo {
         &self.name_tag
     }
 
-    /// Get the density of the material.
+    /// Get the density of the material, in kilograms per cubic meter.
+    /// A block is one cubic meter, so this is also the mass of a block made of this material.
     pub fn density(&self) -> u64 {
         self.density
     }
 
+    /// Get the strength of the material, as the most weight in kilograms a block of it can hold up before it breaks.
+    pub fn strength(&self) -> u64 {
+        self.strength
+    }
+
     /// Get the unique key for identifying this material in this registry.
     pub fn id(&self) -> MaterialID {
         self.material_id
     }
 }
 
-impl<'a> Hash for MaterialInfo {
+impl Hash for MaterialInfo {
     fn hash<H>(&self, hasher: &mut H)
     where
         H: std::hash::Hasher,
@@ -67,10 +74,13 @@ impl MaterialRegistry {
     }
 
     /// Register a new material with the registry.
-    pub fn register_material(&mut self, name_tag: String, density: u64) {
-        self.names_to_ids.insert(name_tag.clone(), MaterialID(self.materials.len() as u32));
+    pub fn register_material(&mut self, name_tag: String, density: u64, strength: u64) -> MaterialID {
+        let material_id = MaterialID(self.materials.len() as u32);
+        self.names_to_ids.insert(name_tag.clone(), material_id);
+
+        self.materials.push(MaterialInfo { name_tag, density, strength, material_id });
 
-        self.materials.push(MaterialInfo { name_tag, density, material_id: MaterialID(self.materials.len() as u32) });
+        material_id
     }
 
     /// Get the ID for a material.
@@ -84,6 +94,12 @@ impl MaterialRegistry {
     }
 }
 
+impl Default for MaterialRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// A stack of material.
 #[derive(Serialize, Deserialize)]
 pub struct MaterialStack {
@@ -125,6 +141,21 @@ impl Inventory {
         Inventory { material_stacks: HashSet::new(), mass: 0, mass_limit: None }
     }
 
+    /// The total mass of everything in the inventory.
+    pub fn mass(&self) -> u64 {
+        self.mass
+    }
+
+    /// The most mass the inventory can hold, if it is limited.
+    pub fn mass_limit(&self) -> Option<u64> {
+        self.mass_limit
+    }
+
+    /// Iterate the stacks of material in the inventory.
+    pub fn iter_material_stacks(&self) -> impl Iterator<Item = &MaterialStack> {
+        self.material_stacks.iter()
+    }
+
     /// Add or remove material in the inventory.
     pub fn add_material(&mut self, _material: MaterialID, _quantity: i64) {
         unimplemented!()
@@ -4,7 +4,7 @@
 //! Data structures for representing ranges and iteration of blocks and chunks.
 
 use super::{
-    BlockID, Chunk, ChunkCoordinate, GlobalBlockCoordinate, GlobalBlockCoordinateEXT, GridWorld, LocalBlockCoordinate,
+    storage, BlockID, Chunk, ChunkCoordinate, GlobalBlockCoordinate, GlobalBlockCoordinateEXT, GridWorld, LocalBlockCoordinate,
     LocalBlockCoordinateExt,
 };
 use itertools::{Itertools, Product};
@@ -90,8 +90,8 @@ impl ChunkRange {
 
 /// An iterator for iterating over a range of chunks.
 pub struct ChunkIterator {
-    internal_iterator: Product<Product<Range<i16>, Range<i16>>, Range<i16>>,
-    conversion_function: &'static dyn Fn(i16, i16, i16) -> ChunkCoordinate,
+    internal_iterator: Product<Product<Range<i32>, Range<i32>>, Range<i32>>,
+    conversion_function: &'static dyn Fn(i32, i32, i32) -> ChunkCoordinate,
 }
 
 impl Iterator for ChunkIterator {
@@ -189,6 +189,15 @@ impl LocalBlockRange {
         LocalBlockRange { root_block, size }
     }
 
+    /// Select a range of blocks from its most down-west-south block and its size.
+    /// The range is clipped so that it does not extend beyond the chunk.
+    pub fn from_root_and_size(root_block: LocalBlockCoordinate, size: LocalBlockCoordinate) -> LocalBlockRange {
+        let root_block = root_block.validate();
+        let size = size.zip_map(&root_block, |size, root| size.min(storage::CHUNK_DIAMETER as u8 - root));
+
+        LocalBlockRange { root_block, size }
+    }
+
     /// Get the two chunks most down-west-south and the chunk most up-east-north for this range.
     pub fn get_near_and_far(&self) -> (LocalBlockCoordinate, LocalBlockCoordinate) {
         (self.root_block, self.root_block + self.size)
@@ -400,22 +409,31 @@ impl<'chunk, ChunkUserData: Default> Iterator for GlobalBlockIteratorMut<'chunk,
 }
 
 impl GlobalBlockRange {
-    /// Select a range of chunks using two corner points.
-    pub fn from_end_points(first: ChunkCoordinate, second: ChunkCoordinate) -> ChunkRange {
-        // Use the min values to find the root chunk.
-        let root_chunk = first.inf(&second);
+    /// Select a range of blocks using two corner points.
+    pub fn from_end_points(first: GlobalBlockCoordinate, second: GlobalBlockCoordinate) -> GlobalBlockRange {
+        // Use the min values to find the root block.
+        let root_block = first.inf(&second);
 
         // The size of the selection.
         let size = (first - second).abs();
 
-        ChunkRange { root_chunk, size }
+        GlobalBlockRange { root_block, size }
     }
 
-    /// Get the two chunks most down-west-south and the chunk most up-east-north for this range.
+    /// Get the two blocks most down-west-south and the block most up-east-north for this range.
     pub fn get_near_and_far(&self) -> (GlobalBlockCoordinate, GlobalBlockCoordinate) {
         (self.root_block, self.root_block + self.size)
     }
 
+    /// Iterate the coordinates of the blocks in the range, without looking at any terrain.
+    pub fn iter_locations_xyz(&self) -> impl Iterator<Item = GlobalBlockCoordinate> {
+        let (near, far) = self.get_near_and_far();
+        (near.x..far.x)
+            .cartesian_product(near.y..far.y)
+            .cartesian_product(near.z..far.z)
+            .map(|((x, y), z)| GlobalBlockCoordinate::new(x, y, z))
+    }
+
     /// Get an iterator that iterates over the chunks in a cartesian manner.
     pub fn iter_yxz<'world, ChunkUserData>(
         &self, world: &'world GridWorld<ChunkUserData>,
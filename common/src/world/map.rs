@@ -0,0 +1,563 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Top down maps of the terrain, for previewing terrain generators and for showing players where they are.
+
+use super::{
+    chunk_providers::{noise::mix, BiomeMap, RAMWorld, StructureMap, VentField, WorldPreset},
+    storage::{self, ChunkDiskStorage},
+    BlockRegistry, Chunk, ChunkCoordinate, ChunkCoordinateEXT, ChunkProvider, GlobalBlockCoordinate, LocalBlockCoordinate,
+};
+use anyhow::{anyhow, bail, Result};
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+/// Somewhere to get the chunks a map is drawn from.
+pub trait MapSource {
+    /// The block registry the chunks' blocks come from.
+    fn block_registry(&self) -> &BlockRegistry;
+
+    /// Get a chunk to draw. Returns None if nothing is known about that part of the world.
+    fn get_chunk(&mut self, location: ChunkCoordinate) -> Result<Option<Chunk<()>>>;
+}
+
+/// Generates the chunks of a map from a preset, without keeping a world around.
+pub struct GeneratedMapSource {
+    provider: Box<RAMWorld<()>>,
+}
+
+impl GeneratedMapSource {
+    /// Set up to generate chunks the way a preset describes.
+    pub fn new(preset: &WorldPreset) -> Result<GeneratedMapSource> {
+        let mut provider = RAMWorld::new(BlockRegistry::new());
+        preset.apply(provider.as_mut())?;
+
+        Ok(GeneratedMapSource { provider })
+    }
+}
+
+impl MapSource for GeneratedMapSource {
+    fn block_registry(&self) -> &BlockRegistry {
+        self.provider.block_registry()
+    }
+
+    fn get_chunk(&mut self, location: ChunkCoordinate) -> Result<Option<Chunk<()>>> {
+        let mut chunk = Chunk::new(location, ());
+        self.provider.provide_chunk(&mut chunk)?;
+
+        Ok(Some(chunk))
+    }
+}
+
+/// Reads the chunks of a map from a world save. Chunks that were never saved are left off the map.
+pub struct SavedMapSource {
+    storage: ChunkDiskStorage,
+    preset: WorldPreset,
+    registry: BlockRegistry,
+}
+
+impl SavedMapSource {
+    /// Open a world save. The save must have its block table saved with it, since that's what tells us which blocks
+    /// are which, and its preset, which tells us where the biomes, structures and vents are.
+    pub fn open(root_folder: &Path) -> Result<SavedMapSource> {
+        let storage = ChunkDiskStorage::initialize(root_folder, 9);
+        let registry = storage.load_block_table()?.ok_or_else(|| anyhow!("World save has no block table."))?;
+        let preset = storage.load_world_preset()?.ok_or_else(|| anyhow!("World save has no preset."))?;
+
+        Ok(SavedMapSource { storage, preset, registry })
+    }
+
+    /// The preset the world was generated with.
+    pub fn preset(&self) -> &WorldPreset {
+        &self.preset
+    }
+}
+
+impl MapSource for SavedMapSource {
+    fn block_registry(&self) -> &BlockRegistry {
+        &self.registry
+    }
+
+    fn get_chunk(&mut self, location: ChunkCoordinate) -> Result<Option<Chunk<()>>> {
+        self.storage.load_terrain_chunk(location)
+    }
+}
+
+/// A top down image of the world. Each pixel is one column of blocks, with north at the top.
+pub struct MapImage {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl MapImage {
+    fn new(width: u32, height: u32) -> MapImage {
+        MapImage { width, height, pixels: vec![0; width as usize * height as usize * 4] }
+    }
+
+    /// The width of the image in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// The height of the image in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Get the color of a pixel. Parts of the world that are unknown or have no ground are transparent.
+    pub fn get_pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let index = (x as usize + y as usize * self.width as usize) * 4;
+        [self.pixels[index], self.pixels[index + 1], self.pixels[index + 2], self.pixels[index + 3]]
+    }
+
+    /// The pixels of the image as RGBA bytes, row by row.
+    pub fn as_rgba(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    fn set_pixel(&mut self, x: i64, y: i64, color: [u8; 4]) {
+        // Markers can hang off the edges.
+        if x >= 0 && y >= 0 && x < self.width as i64 && y < self.height as i64 {
+            let index = (x as usize + y as usize * self.width as usize) * 4;
+            self.pixels[index..index + 4].copy_from_slice(&color);
+        }
+    }
+}
+
+/// The colors blocks and biomes are drawn with. Anything without a color gets one made up from its name, so it at least
+/// stays the same from map to map.
+pub struct MapPalette {
+    colors: HashMap<String, [u8; 3]>,
+}
+
+impl Default for MapPalette {
+    fn default() -> Self {
+        let mut palette = MapPalette { colors: HashMap::new() };
+
+        for (name, color) in [
+            ("grass", [86, 148, 52]),
+            ("soil", [121, 85, 58]),
+            ("stone", [128, 128, 128]),
+            ("cobblestone", [100, 100, 100]),
+            ("sand", [219, 205, 148]),
+            ("snow", [240, 245, 250]),
+            ("bedrock", [40, 40, 40]),
+            ("basalt", [60, 58, 64]),
+            ("coal_ore", [50, 50, 50]),
+            ("iron_ore", [170, 130, 110]),
+            ("copper_ore", [190, 110, 60]),
+            ("plains", [140, 200, 90]),
+            ("forest", [30, 110, 40]),
+            ("desert", [240, 200, 90]),
+            ("tundra", [180, 220, 240]),
+            ("mountains", [150, 130, 120]),
+        ]
+        .iter()
+        {
+            palette.set_color(name, *color);
+        }
+
+        palette
+    }
+}
+
+impl MapPalette {
+    /// Set the color of a block or biome.
+    pub fn set_color(&mut self, name: &str, color: [u8; 3]) {
+        self.colors.insert(String::from(name), color);
+    }
+
+    /// Get the color of a block or biome.
+    pub fn color(&self, name: &str) -> [u8; 3] {
+        self.colors.get(name).copied().unwrap_or_else(|| {
+            let hash = name.bytes().fold(0, |hash, byte| mix(hash ^ byte as u64));
+            let channel = |shift: u32| 64 + (hash >> shift) as u8 % 160;
+            [channel(0), channel(8), channel(16)]
+        })
+    }
+}
+
+/// Draws top down maps of the world.
+pub struct MapRenderer {
+    palette: MapPalette,
+    bottom: i32,
+    top: i32,
+    height_shading: bool,
+    biomes: Option<Arc<BiomeMap>>,
+    structures: Vec<Arc<StructureMap>>,
+    vents: Vec<Arc<VentField>>,
+}
+
+impl Default for MapRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MapRenderer {
+    /// The color structures are outlined with.
+    pub const STRUCTURE_COLOR: [u8; 4] = [230, 30, 30, 255];
+
+    /// The color vents are marked with.
+    pub const VENT_COLOR: [u8; 4] = [250, 120, 0, 255];
+
+    /// How far above or below its northern neighbor a column has to be to be fully lit or shaded.
+    const SHADING_RANGE: f64 = 4.0;
+
+    /// How much of the biome's color is mixed into the ground.
+    const BIOME_TINT: f64 = 0.35;
+
+    /// The most pixels a map can have. 8192 by 8192, or 256 by 256 chunks.
+    pub const MAX_PIXELS: i64 = 8192 * 8192;
+
+    /// Create a map renderer that just draws the ground, looking for it between chunk layers -4 and 3.
+    pub fn new() -> MapRenderer {
+        MapRenderer {
+            palette: MapPalette::default(),
+            bottom: -4,
+            top: 3,
+            height_shading: true,
+            biomes: None,
+            structures: Vec::new(),
+            vents: Vec::new(),
+        }
+    }
+
+    /// Create a map renderer that shows the biomes, structures and vents of a preset.
+    pub fn for_preset(preset: &WorldPreset) -> Result<MapRenderer> {
+        let mut renderer = Self::new();
+        renderer.biomes = preset.biome_map();
+        renderer.structures = preset.structure_maps()?;
+        renderer.vents = preset.vent_fields()?;
+
+        Ok(renderer)
+    }
+
+    /// Get the colors blocks and biomes are drawn with.
+    pub fn palette_mut(&mut self) -> &mut MapPalette {
+        &mut self.palette
+    }
+
+    /// Set the layers of chunks to look for the ground in, inclusive. Ground above the top layer is missed, so keep
+    /// this as tight as you can, since every chunk in the range may need to be loaded.
+    pub fn set_vertical_range(&mut self, bottom: i32, top: i32) {
+        self.bottom = bottom.min(top);
+        self.top = bottom.max(top);
+    }
+
+    /// Set if higher ground is lit and lower ground shaded, as if lit from the north.
+    pub fn set_height_shading(&mut self, height_shading: bool) {
+        self.height_shading = height_shading;
+    }
+
+    /// Set the biomes to tint the map with. None to leave the ground its natural color.
+    pub fn set_biomes(&mut self, biomes: Option<Arc<BiomeMap>>) {
+        self.biomes = biomes;
+    }
+
+    /// Outline the structures from a structure map.
+    pub fn add_structures(&mut self, structures: Arc<StructureMap>) {
+        self.structures.push(structures);
+    }
+
+    /// Mark the vents from a vent field.
+    pub fn add_vents(&mut self, vents: Arc<VentField>) {
+        self.vents.push(vents);
+    }
+
+    /// Take away all the structures and vents.
+    pub fn clear_markers(&mut self) {
+        self.structures.clear();
+        self.vents.clear();
+    }
+
+    /// Draw a map of the columns of chunks between two corners, inclusive. Each block is one pixel. Fails if the map
+    /// would have more than [MapRenderer::MAX_PIXELS].
+    pub fn render(&self, source: &mut dyn MapSource, near: (i32, i32), far: (i32, i32)) -> Result<MapImage> {
+        let (near, far) = ((near.0.min(far.0), near.1.min(far.1)), (near.0.max(far.0), near.1.max(far.1)));
+        let diameter = storage::CHUNK_DIAMETER as i64;
+        let width = (far.0 as i64 - near.0 as i64 + 1) * diameter;
+        let height = (far.1 as i64 - near.1 as i64 + 1) * diameter;
+        if width > u32::MAX as i64 || height > u32::MAX as i64 || width * height > Self::MAX_PIXELS {
+            bail!("A map of {} by {} pixels is too big. Maps can have at most {} pixels.", width, height, Self::MAX_PIXELS);
+        }
+        let origin = (near.0 as i64 * diameter, near.1 as i64 * diameter);
+
+        // The height and color of the top block of every column.
+        let mut surface: Vec<Option<(i64, [u8; 3])>> = vec![None; (width * height) as usize];
+        for chunk_z in near.1..=far.1 {
+            for chunk_x in near.0..=far.0 {
+                self.find_surface(source, chunk_x, chunk_z, origin, width, &mut surface)?;
+            }
+        }
+
+        let mut image = MapImage::new(width as u32, height as u32);
+        for y in 0..height {
+            for x in 0..width {
+                if let Some((ground, color)) = surface[(x + y * width) as usize] {
+                    let mut color = [color[0] as f64, color[1] as f64, color[2] as f64];
+
+                    if let Some(biomes) = &self.biomes {
+                        let biome = biomes.dominant_biome(origin.0 + x, origin.1 + y).and_then(|id| biomes.get_biome(id));
+                        if let Some(biome) = biome {
+                            let tint = self.palette.color(biome.name());
+                            for channel in 0..3 {
+                                color[channel] += (tint[channel] as f64 - color[channel]) * Self::BIOME_TINT;
+                            }
+                        }
+                    }
+
+                    if self.height_shading && y > 0 {
+                        if let Some((north, _color)) = surface[(x + (y - 1) * width) as usize] {
+                            let slope = ((ground - north) as f64 / Self::SHADING_RANGE).clamp(-1.0, 1.0);
+                            color.iter_mut().for_each(|channel| *channel *= 1.0 + slope * 0.25);
+                        }
+                    }
+
+                    let channel = |value: f64| value.round().clamp(0.0, 255.0) as u8;
+                    image.set_pixel(x, y, [channel(color[0]), channel(color[1]), channel(color[2]), 255]);
+                }
+            }
+        }
+
+        self.draw_markers(&mut image, origin);
+
+        Ok(image)
+    }
+
+    /// Search a column of chunks from the top down for the highest block in each column of blocks.
+    fn find_surface(
+        &self, source: &mut dyn MapSource, chunk_x: i32, chunk_z: i32, origin: (i64, i64), width: i64,
+        surface: &mut [Option<(i64, [u8; 3])>],
+    ) -> Result<()> {
+        let diameter = storage::CHUNK_DIAMETER;
+        let mut remaining = diameter * diameter;
+
+        for chunk_y in (self.bottom..=self.top).rev() {
+            let chunk = match source.get_chunk(ChunkCoordinate::new(chunk_x, chunk_y, chunk_z))? {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            let chunk_origin = chunk.index().to_block_coordinate();
+
+            for local_z in 0..diameter as u8 {
+                for local_x in 0..diameter as u8 {
+                    let index = (chunk_origin.x - origin.0
+                        + local_x as i64
+                        + (chunk_origin.z - origin.1 + local_z as i64) * width) as usize;
+                    if surface[index].is_some() {
+                        continue;
+                    }
+
+                    let top = (0..diameter as u8).rev().find_map(|local_y| {
+                        chunk
+                            .get_single_block_local(LocalBlockCoordinate::new(local_x, local_y, local_z))
+                            .map(|block| (chunk_origin.y + local_y as i64, block))
+                    });
+
+                    if let Some((ground, block)) = top {
+                        let name = source.block_registry().get_block_data_from_id(block).map(|data| data.name()).unwrap_or("");
+                        surface[index] = Some((ground, self.palette.color(name)));
+                        remaining -= 1;
+                    }
+                }
+            }
+
+            if remaining == 0 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Outline structures and mark vents. Vents are usually deep underground, so they're marked wherever they are.
+    fn draw_markers(&self, image: &mut MapImage, origin: (i64, i64)) {
+        // Markers are drawn no matter how high or low they are.
+        let near = GlobalBlockCoordinate::new(origin.0, -(1 << 40), origin.1);
+        let far = GlobalBlockCoordinate::new(origin.0 + image.width as i64 - 1, 1 << 40, origin.1 + image.height as i64 - 1);
+
+        for structures in self.structures.iter() {
+            for structure in structures.structures_overlapping(near, far) {
+                let (low, high) = structure.bounding_box();
+                let (low, high) = ((low.x - origin.0, low.z - origin.1), (high.x - origin.0, high.z - origin.1));
+                for x in low.0..=high.0 {
+                    image.set_pixel(x, low.1, Self::STRUCTURE_COLOR);
+                    image.set_pixel(x, high.1, Self::STRUCTURE_COLOR);
+                }
+                for y in low.1..=high.1 {
+                    image.set_pixel(low.0, y, Self::STRUCTURE_COLOR);
+                    image.set_pixel(high.0, y, Self::STRUCTURE_COLOR);
+                }
+            }
+        }
+
+        for vents in self.vents.iter() {
+            for vent in vents.vents_in_box(near, far) {
+                let position = vent.position();
+                let (x, y) = (position.x - origin.0, position.z - origin.1);
+                for offset in -2..=2 {
+                    image.set_pixel(x + offset, y, Self::VENT_COLOR);
+                    image.set_pixel(x, y + offset, Self::VENT_COLOR);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::world::{
+        chunk_providers::{GenerationStage, StructureTemplate},
+        GridWorld,
+    };
+
+    /// A world of stairs. Every 8 blocks south, along z, the ground steps up by one.
+    struct Stairs {
+        registry: BlockRegistry,
+    }
+
+    impl MapSource for Stairs {
+        fn block_registry(&self) -> &BlockRegistry {
+            &self.registry
+        }
+
+        fn get_chunk(&mut self, location: ChunkCoordinate) -> Result<Option<Chunk<()>>> {
+            let stone = *self.registry.get_block_id_from_name("stone").unwrap();
+            let mut chunk = Chunk::new(location, ());
+            let origin = location.to_block_coordinate();
+
+            for local_z in 0..storage::CHUNK_DIAMETER as u8 {
+                for local_x in 0..storage::CHUNK_DIAMETER as u8 {
+                    // The stairs climb to the south, so they face the light.
+                    let ground = (origin.z + local_z as i64).div_euclid(8);
+                    for local_y in 0..storage::CHUNK_DIAMETER as u8 {
+                        if origin.y + local_y as i64 <= ground {
+                            *chunk.get_single_block_local_mut(LocalBlockCoordinate::new(local_x, local_y, local_z)) =
+                                Some(stone);
+                        }
+                    }
+                }
+            }
+
+            Ok(Some(chunk))
+        }
+    }
+
+    /// Flat ground is drawn in its block's color, and unknown parts of the world are left transparent.
+    #[test]
+    fn flat() {
+        let preset = WorldPreset::superflat(0);
+        let mut source = GeneratedMapSource::new(&preset).unwrap();
+        let renderer = MapRenderer::new();
+
+        let image = renderer.render(&mut source, (-1, 2), (0, 2)).unwrap();
+        assert_eq!((image.width(), image.height()), (64, 32));
+
+        let grass = renderer.palette.color("grass");
+        let grass = [grass[0], grass[1], grass[2], 255];
+        assert!((0..32).all(|y| (0..64).all(|x| image.get_pixel(x, y) == grass)));
+
+        // Nothing is found if we only look above the ground.
+        let mut renderer = MapRenderer::new();
+        renderer.set_vertical_range(0, 2);
+        let image = renderer.render(&mut source, (0, 0), (0, 0)).unwrap();
+        assert!(image.as_rgba().iter().all(|byte| *byte == 0));
+    }
+
+    /// Maps that are too big are refused instead of being allocated, even across the whole range of chunk coordinates.
+    #[test]
+    fn too_big() {
+        let preset = WorldPreset::superflat(0);
+        let mut source = GeneratedMapSource::new(&preset).unwrap();
+        let renderer = MapRenderer::new();
+
+        assert!(renderer.render(&mut source, (i32::MIN, 0), (i32::MAX, 0)).is_err());
+        assert!(renderer.render(&mut source, (0, 0), (256, 255)).is_err());
+    }
+
+    /// Ground that climbs toward the viewer is lit, and flat ground isn't.
+    #[test]
+    fn height_shading() {
+        let mut registry = BlockRegistry::new();
+        registry.add_block(String::from("stone"), String::from("Stone")).unwrap();
+        let mut source = Stairs { registry };
+
+        let mut renderer = MapRenderer::new();
+        renderer.set_vertical_range(-1, 1);
+        let image = renderer.render(&mut source, (0, 0), (0, 0)).unwrap();
+
+        let stone = renderer.palette.color("stone");
+        assert_eq!(image.get_pixel(5, 9), [stone[0], stone[1], stone[2], 255]);
+        assert!(image.get_pixel(5, 8)[0] > stone[0]);
+
+        renderer.set_height_shading(false);
+        let image = renderer.render(&mut source, (0, 0), (0, 0)).unwrap();
+        assert_eq!(image.get_pixel(5, 8), [stone[0], stone[1], stone[2], 255]);
+    }
+
+    /// Structures are outlined where they're placed, and vents are marked.
+    #[test]
+    fn markers() {
+        let preset = WorldPreset::superflat(0);
+        let mut source = GeneratedMapSource::new(&preset).unwrap();
+
+        let mut structures = StructureMap::new(5);
+        structures.set_spacing(64, 1.0);
+        structures.register_template(StructureTemplate::new("hut").with_box(
+            GlobalBlockCoordinate::new(0, 0, 0),
+            GlobalBlockCoordinate::new(6, 3, 6),
+            Some("stone"),
+        ));
+        let structures = Arc::new(structures);
+
+        let mut vents = VentField::new(5);
+        vents.set_spacing(64, 1.0);
+        let vents = Arc::new(vents);
+
+        let mut renderer = MapRenderer::new();
+        renderer.add_structures(structures.clone());
+        renderer.add_vents(vents.clone());
+        let image = renderer.render(&mut source, (0, 0), (1, 1)).unwrap();
+
+        let structure = structures.structure_in_cell(0, 0).unwrap();
+        let (low, _high) = structure.bounding_box();
+        assert_eq!(image.get_pixel(low.x as u32, low.z as u32), MapRenderer::STRUCTURE_COLOR);
+
+        let vent = vents.vent_in_cell(0, 0).unwrap().position();
+        assert_eq!(image.get_pixel(vent.x as u32, vent.z as u32), MapRenderer::VENT_COLOR);
+    }
+
+    /// A map of a world save matches a map of the same world generated fresh, and unsaved chunks are left out.
+    #[test]
+    fn saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let preset = WorldPreset::superflat(0);
+        let mut world: GridWorld<()> = GridWorld::from_preset(preset.clone()).unwrap();
+        world.set_chunk_storage(Some(ChunkDiskStorage::initialize(dir.path(), 9)));
+        for chunk_y in -4..=3 {
+            let chunk = world.load_chunk(ChunkCoordinate::new(1, chunk_y, 0));
+            assert_eq!(chunk.generation_stage(), GenerationStage::COMPLETE);
+        }
+        world.save_world().unwrap();
+
+        let mut generated = GeneratedMapSource::new(&preset).unwrap();
+
+        let renderer = MapRenderer::for_preset(&preset).unwrap();
+        let mut saved = SavedMapSource::open(dir.path()).unwrap();
+        let from_save = renderer.render(&mut saved, (0, 0), (1, 0)).unwrap();
+        let fresh = renderer.render(&mut generated, (0, 0), (1, 0)).unwrap();
+
+        for y in 0..32 {
+            for x in 0..32 {
+                assert_eq!(from_save.get_pixel(x, y), [0, 0, 0, 0]);
+                assert_eq!(from_save.get_pixel(x + 32, y), fresh.get_pixel(x + 32, y));
+            }
+        }
+
+        // Without a preset, there's no way to know which blocks are which.
+        let empty = tempfile::tempdir().unwrap();
+        assert!(SavedMapSource::open(empty.path()).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Mass, center of mass, and inertia of collections of blocks, worked out from what the blocks are made of.
+
+use super::{inventory::MaterialRegistry, BlockID, BlockRegistry, GlobalBlockCoordinate};
+use nalgebra::{Matrix3, Point3, Vector3};
+use rapier3d::dynamics::MassProperties;
+use serde::{Deserialize, Serialize};
+
+/// Something that knows how heavy blocks are.
+pub trait BlockMassSource {
+    /// Get the mass of a single block, in kilograms.
+    fn mass_of(&self, block: BlockID) -> f32;
+}
+
+/// Look up the mass of a block from the material it is made of.
+/// Blocks are one cubic meter, so a block's mass in kilograms is just the density of its material.
+fn lookup_mass(block_registry: &BlockRegistry, material_registry: &MaterialRegistry, block: BlockID) -> f32 {
+    block_registry
+        .get_block_data_from_id(block)
+        .and_then(|block| block.material())
+        .and_then(|material| material_registry.get_material_info(material))
+        .map(|material| material.density() as f32)
+        .unwrap_or(0.0)
+}
+
+/// The mass of every type of block, looked up from the material each block is made of.
+/// Good for when the masses of a lot of blocks are needed at once.
+#[derive(Debug, Clone, Default)]
+pub struct BlockMasses {
+    masses: Vec<f32>,
+}
+
+impl BlockMasses {
+    /// Build the table of block masses. Blocks with no material, or with a material missing from the material
+    /// registry, have no mass.
+    pub fn new(block_registry: &BlockRegistry, material_registry: &MaterialRegistry) -> BlockMasses {
+        let masses = (1..=block_registry.num_block_types())
+            .map(|id| {
+                let block = BlockID::new(std::num::NonZeroU16::new(id).expect("Generated invalid block ID."));
+                lookup_mass(block_registry, material_registry, block)
+            })
+            .collect();
+
+        BlockMasses { masses }
+    }
+}
+
+impl BlockMassSource for BlockMasses {
+    fn mass_of(&self, block: BlockID) -> f32 {
+        self.masses.get(block.index()).copied().unwrap_or(0.0)
+    }
+}
+
+/// Looks up the mass of blocks straight from the registries, one block at a time.
+/// Much cheaper than building [BlockMasses] when only a block or two is needed.
+pub struct RegistryBlockMasses<'a> {
+    block_registry: &'a BlockRegistry,
+    material_registry: &'a MaterialRegistry,
+}
+
+impl<'a> RegistryBlockMasses<'a> {
+    /// Look up masses from these registries.
+    pub fn new(block_registry: &'a BlockRegistry, material_registry: &'a MaterialRegistry) -> RegistryBlockMasses<'a> {
+        RegistryBlockMasses { block_registry, material_registry }
+    }
+}
+
+impl<'a> BlockMassSource for RegistryBlockMasses<'a> {
+    fn mass_of(&self, block: BlockID) -> f32 {
+        lookup_mass(self.block_registry, self.material_registry, block)
+    }
+}
+
+/// Keeps a running total of the mass properties of a collection of blocks.
+/// Blocks can be added and removed one at a time without having to go over the whole collection again.
+/// Everything is measured in the space the block coordinates are in, and block 0x0x0 spans from the origin to 1x1x1.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BlockMassProperties {
+    // Kept in double precision so that adding and removing many blocks doesn't slowly drift.
+    mass: f64,
+    first_moment: Vector3<f64>,
+    second_moment: Matrix3<f64>,
+}
+
+impl BlockMassProperties {
+    /// Anything lighter than this is treated as having no mass at all, so that rounding errors left over from removing
+    /// every block don't produce a center of mass.
+    const MIN_MASS: f64 = 1.0e-6;
+
+    /// Create the mass properties for an empty collection of blocks.
+    pub fn new() -> BlockMassProperties {
+        Self::default()
+    }
+
+    /// Add a block with the given mass.
+    pub fn add_block(&mut self, location: GlobalBlockCoordinate, mass: f32) {
+        self.accumulate(location, mass as f64);
+    }
+
+    /// Remove a block with the given mass. The mass must match what the block was added with.
+    pub fn remove_block(&mut self, location: GlobalBlockCoordinate, mass: f32) {
+        self.accumulate(location, -(mass as f64));
+    }
+
+    fn accumulate(&mut self, location: GlobalBlockCoordinate, mass: f64) {
+        let center = location.map(|v| v as f64 + 0.5);
+
+        self.mass += mass;
+        self.first_moment += center * mass;
+        self.second_moment += center * center.transpose() * mass;
+    }
+
+    /// The total mass of the blocks, in kilograms.
+    pub fn mass(&self) -> f32 {
+        self.mass as f32
+    }
+
+    /// The center of mass of the blocks. If they have no mass, there is no center of mass.
+    pub fn center_of_mass(&self) -> Option<Point3<f32>> {
+        if self.mass > Self::MIN_MASS {
+            Some(Point3::from((self.first_moment / self.mass).map(|v| v as f32)))
+        } else {
+            None
+        }
+    }
+
+    /// The inertia tensor of the blocks around their center of mass.
+    pub fn inertia_tensor(&self) -> Matrix3<f32> {
+        if self.mass > Self::MIN_MASS {
+            let center = self.first_moment / self.mass;
+
+            // Move the second moment to be around the center of mass.
+            let second_moment = self.second_moment - center * center.transpose() * self.mass;
+
+            // Each block is a point mass at its center plus a solid cube of its own, which for a one meter cube
+            // has an inertia of a sixth of its mass on every axis.
+            let inertia = Matrix3::identity() * (second_moment.trace() + self.mass / 6.0) - second_moment;
+
+            inertia.map(|v| v as f32)
+        } else {
+            Matrix3::zeros()
+        }
+    }
+
+    /// Create the mass properties for a rapier rigid body.
+    pub fn to_rapier(&self) -> MassProperties {
+        match self.center_of_mass() {
+            Some(center_of_mass) => MassProperties::with_inertia_matrix(center_of_mass, self.mass(), self.inertia_tensor()),
+            None => nalgebra::zero(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.001, "{} is not close to {}", a, b);
+    }
+
+    /// A single block should match a solid cube.
+    #[test]
+    fn single_block() {
+        let mut properties = BlockMassProperties::new();
+        properties.add_block(GlobalBlockCoordinate::new(2, 3, 4), 6.0);
+
+        assert_close(properties.mass(), 6.0);
+        assert_eq!(properties.center_of_mass(), Some(Point3::new(2.5, 3.5, 4.5)));
+
+        let inertia = properties.inertia_tensor();
+        assert!((inertia - Matrix3::identity()).norm() < 0.001);
+    }
+
+    /// A bar of blocks along the X axis should be hardest to spin around the Y and Z axis.
+    #[test]
+    fn bar() {
+        let mut properties = BlockMassProperties::new();
+        for x in 0..3 {
+            properties.add_block(GlobalBlockCoordinate::new(x, 0, 0), 1.0);
+        }
+
+        assert_eq!(properties.center_of_mass(), Some(Point3::new(1.5, 0.5, 0.5)));
+
+        // A solid 3x1x1 box has 1/12 m (b² + c²) on each axis.
+        let inertia = properties.inertia_tensor();
+        assert_close(inertia[(0, 0)], 3.0 / 12.0 * 2.0);
+        assert_close(inertia[(1, 1)], 3.0 / 12.0 * 10.0);
+        assert_close(inertia[(2, 2)], 3.0 / 12.0 * 10.0);
+        assert_close(inertia[(0, 1)], 0.0);
+    }
+
+    /// Removing blocks should bring us back to where we were before adding them.
+    #[test]
+    fn add_and_remove() {
+        let mut properties = BlockMassProperties::new();
+        properties.add_block(GlobalBlockCoordinate::new(0, 0, 0), 2.0);
+
+        properties.add_block(GlobalBlockCoordinate::new(10, -5, 7), 100.0);
+        properties.add_block(GlobalBlockCoordinate::new(-3, 1, 1), 50.0);
+        properties.remove_block(GlobalBlockCoordinate::new(10, -5, 7), 100.0);
+        properties.remove_block(GlobalBlockCoordinate::new(-3, 1, 1), 50.0);
+
+        assert_close(properties.mass(), 2.0);
+        assert_eq!(properties.center_of_mass(), Some(Point3::new(0.5, 0.5, 0.5)));
+
+        properties.remove_block(GlobalBlockCoordinate::new(0, 0, 0), 2.0);
+        assert_eq!(properties.center_of_mass(), None);
+    }
+}
@@ -3,13 +3,17 @@
 
 //! Mechanisms and components revolving around what the player sees as a world.
 
+use anyhow::{Context, Result};
+use chunk_providers::{chunk_neighbors, ChunkNeighborhood, GenerationStage};
+use inventory::MaterialRegistry;
 use legion::{system, Resources, Schedule, World};
 use rapier3d::{
     dynamics::{CCDSolver, IntegrationParameters, JointSet, RigidBodySet},
     geometry::{BroadPhase, ColliderSet, NarrowPhase},
-    pipeline::PhysicsPipeline,
+    pipeline::{PhysicsPipeline, QueryPipeline},
 };
-use std::{collections::HashMap, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, rc::Rc, sync::Arc, time::Duration};
 
 mod coordinates;
 mod iteration;
@@ -18,8 +22,13 @@ pub mod storage;
 mod time;
 pub use iteration::*;
 pub use time::*;
+mod hashing;
+pub use hashing::*;
 pub mod chunk_providers;
 pub mod components;
+pub mod connectivity;
+pub mod inventory;
+pub mod map;
 
 mod blocks;
 pub use blocks::*;
@@ -27,6 +36,45 @@ pub use blocks::*;
 mod chunk;
 pub use chunk::*;
 
+mod ticks;
+pub use ticks::*;
+
+mod block_grid;
+pub use block_grid::*;
+
+mod mass;
+pub use mass::*;
+
+mod structural;
+pub use structural::*;
+
+mod origin;
+pub use origin::*;
+
+mod timestep;
+pub use timestep::*;
+
+mod collisions;
+pub use collisions::*;
+
+mod queries;
+pub use queries::*;
+
+mod character;
+pub use character::*;
+
+mod snapshot;
+pub use snapshot::*;
+
+mod replay;
+pub use replay::*;
+
+mod plugins;
+pub use plugins::*;
+
+mod wasm_mods;
+pub use wasm_mods::*;
+
 // Names of files and folders in a world save.
 // const TERRAIN_FOLDER: &str = "terrain";
 
@@ -39,49 +87,183 @@ pub trait ChunkProvider<ChunkUserData> {
     fn block_registry_mut(&mut self) -> &mut BlockRegistry;
 
     /// When a chunk is created, it needs to be filled with blocks. An empty chunk will be provided
-    /// to this method, and this method is to fill it with blocks.
-    fn provide_chunk(&self, chunk: &mut Chunk<ChunkUserData>);
+    /// to this method, and this method is to fill it with blocks. If this fails, the chunk should not be used.
+    fn provide_chunk(&self, chunk: &mut Chunk<ChunkUserData>) -> Result<()>;
+
+    /// Move a chunk on to its next stage of generation. The chunk can skip ahead several stages, but must always move
+    /// forward. If [ChunkProvider::stage_needs_neighbors] asked for them, the neighbors of the chunk will have reached
+    /// at least the stage before the one being generated.
+    /// Providers that don't generate in stages can leave this alone, and will have the whole chunk generated at once.
+    fn provide_stage(&self, chunk: &mut Chunk<ChunkUserData>, _neighbors: &ChunkNeighborhood<ChunkUserData>) -> Result<()> {
+        self.provide_chunk(chunk)?;
+        chunk.set_generation_stage(GenerationStage::COMPLETE);
+
+        Ok(())
+    }
+
+    /// Check if a stage of generation needs the chunks around the one being generated to have reached the stage before.
+    fn stage_needs_neighbors(&self, _stage: GenerationStage) -> bool {
+        false
+    }
+}
+
+/// What to do when a chunk fails to generate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkFailurePolicy {
+    /// Give up and report the error. Nothing is added to the world.
+    Abort,
+
+    /// Try generating the chunk again from scratch, up to this many more times, before giving up.
+    Retry(u32),
+
+    /// Put a chunk completely filled with this block in its place so that the world can carry on. The error is logged.
+    Placeholder(Option<BlockID>),
 }
 
 /// A world full of terrain and entities.
 pub struct GridWorld<ChunkUserData> {
     time: WorldTime,
     terrain_chunks: HashMap<ChunkCoordinate, Chunk<ChunkUserData>>,
+    staged_chunks: HashMap<ChunkCoordinate, Chunk<ChunkUserData>>,
     ecs_world: World,
     ecs_schedule: Schedule,
     ecs_resources: Resources,
     chunk_provider: Box<dyn ChunkProvider<ChunkUserData>>,
+    chunk_failure_policy: ChunkFailurePolicy,
+    chunk_storage: Option<storage::ChunkDiskStorage>,
+    world_preset: Option<chunk_providers::WorldPreset>,
+    material_registry: MaterialRegistry,
+    tick_scheduler: TickScheduler<ChunkUserData>,
+    simulation_settings: SimulationSettings,
+    timestep: FixedTimestep,
+    calendar: GameCalendar,
+    recorder: Option<InputRecorder>,
+    step_listeners: Vec<Rc<dyn StepListener<ChunkUserData>>>,
 }
 
 /// Global constants in the physics engine that we can't just loosely toss into the ECS resources.
+#[derive(Serialize, Deserialize)]
 pub struct PhysicsGlobalConstants {
     gravity: PhysicsVector,
     integration_parameters: IntegrationParameters,
 }
 
 impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
-    /// Create a new world.
+    /// Create a new world with just the core systems and resources. Use a [WorldBuilder] to add plugins.
     pub fn new(chunk_provider: Box<dyn ChunkProvider<ChunkUserData>>) -> GridWorld<ChunkUserData> {
-        let terrain_chunks = HashMap::new();
-        let time = WorldTime::from_ms(0);
+        let mut systems = SystemRegistry::new();
+        let ecs_schedule = Self::register_core_systems(&mut systems)
+            .and_then(|_| systems.build_schedule())
+            .expect("Core systems could not be scheduled.");
+
+        Self::from_parts(chunk_provider, MaterialRegistry::new(), TickScheduler::new(), ecs_schedule, Self::core_resources())
+    }
+
+    /// Create a world that's generated the way a preset describes, with just the core systems and resources. The preset
+    /// is kept with the world, so that it's saved along with it.
+    pub fn from_preset(preset: chunk_providers::WorldPreset) -> Result<GridWorld<ChunkUserData>>
+    where
+        ChunkUserData: 'static,
+    {
+        let mut chunk_provider = chunk_providers::RAMWorld::new(BlockRegistry::new());
+        preset.apply(chunk_provider.as_mut())?;
 
-        let ecs_world = World::default();
-        let ecs_schedule = Schedule::builder().add_system(ecs_physics_system()).build();
+        let mut world = Self::new(chunk_provider);
+        world.world_preset = Some(preset);
+
+        Ok(world)
+    }
+
+    /// Add the systems every world needs, in the order they have to run.
+    fn register_core_systems(systems: &mut SystemRegistry) -> Result<()> {
+        let physics_events = PhysicsEventCollector::new();
+        let after = |system| SystemOrder::new().after(system);
+
+        systems.add_system(core_systems::MOVE_CHARACTERS, ecs_move_characters_system(), SystemOrder::new())?;
+        systems.add_system(
+            core_systems::STRUCTURAL_INTEGRITY,
+            ecs_structural_integrity_system(),
+            after(core_systems::MOVE_CHARACTERS),
+        )?;
+        systems.add_system(
+            core_systems::DRIVE_KINEMATIC_BODIES,
+            components::ecs_drive_kinematic_bodies_system(),
+            after(core_systems::STRUCTURAL_INTEGRITY),
+        )?;
+        systems.add_system(
+            core_systems::PHYSICS,
+            ecs_physics_system(physics_events.clone()),
+            after(core_systems::DRIVE_KINEMATIC_BODIES),
+        )?;
+        systems.add_system(
+            core_systems::SYNC_TRANSFORMS,
+            components::ecs_sync_transforms_system(),
+            after(core_systems::PHYSICS),
+        )?;
+        systems.add_system(
+            core_systems::PUBLISH_COLLISION_EVENTS,
+            ecs_publish_collision_events_system(physics_events),
+            after(core_systems::SYNC_TRANSFORMS),
+        )?;
+        systems.add_system(
+            core_systems::UPDATE_QUERY_PIPELINE,
+            ecs_update_query_pipeline_system(),
+            after(core_systems::PUBLISH_COLLISION_EVENTS),
+        )
+    }
+
+    /// Create the resources every world needs.
+    fn core_resources() -> Resources {
+        let simulation_settings = SimulationSettings::default();
         let mut ecs_resources = Resources::default();
 
         ecs_resources.insert(PhysicsPipeline::new());
-        ecs_resources.insert(PhysicsGlobalConstants {
-            gravity: PhysicsVector::new(0.0, -9.81, 0.0),
-            integration_parameters: IntegrationParameters::default(),
-        });
+        let integration_parameters =
+            IntegrationParameters { dt: simulation_settings.step_duration().as_secs_f32(), ..IntegrationParameters::default() };
+        ecs_resources.insert(PhysicsGlobalConstants { gravity: PhysicsVector::new(0.0, -9.81, 0.0), integration_parameters });
         ecs_resources.insert(BroadPhase::new());
         ecs_resources.insert(NarrowPhase::new());
         ecs_resources.insert(RigidBodySet::new());
         ecs_resources.insert(ColliderSet::new());
         ecs_resources.insert(JointSet::new());
+        ecs_resources.insert(components::OwnedRigidBodies::new());
         ecs_resources.insert(CCDSolver::new());
+        ecs_resources.insert(CollisionEvents::new());
+        ecs_resources.insert(QueryPipeline::new());
+        ecs_resources.insert(StructuralIntegrity::new());
+        ecs_resources.insert(FloatingOrigin::new());
+        ecs_resources.insert(CharacterTerrain::default());
 
-        GridWorld { time, terrain_chunks, ecs_world, ecs_schedule, ecs_resources, chunk_provider }
+        ecs_resources
+    }
+
+    /// Put together a world from everything that was set up for it. The resources must include the core resources.
+    fn from_parts(
+        chunk_provider: Box<dyn ChunkProvider<ChunkUserData>>, material_registry: MaterialRegistry,
+        tick_scheduler: TickScheduler<ChunkUserData>, ecs_schedule: Schedule, ecs_resources: Resources,
+    ) -> GridWorld<ChunkUserData> {
+        let mut world = GridWorld {
+            time: WorldTime::from_ms(0),
+            terrain_chunks: HashMap::new(),
+            staged_chunks: HashMap::new(),
+            ecs_world: World::default(),
+            ecs_schedule,
+            ecs_resources,
+            chunk_provider,
+            chunk_failure_policy: ChunkFailurePolicy::Abort,
+            chunk_storage: None,
+            world_preset: None,
+            material_registry,
+            tick_scheduler,
+            simulation_settings: SimulationSettings::default(),
+            timestep: FixedTimestep::new(),
+            calendar: GameCalendar::default(),
+            recorder: None,
+            step_listeners: Vec::new(),
+        };
+        world.track_rigid_bodies();
+
+        world
     }
 
     /// Get the world block registry.
@@ -90,12 +272,28 @@ impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
         self.chunk_provider.block_registry()
     }
 
-    /// Update the entities of the world.
-    pub fn update(&mut self, time_delta: Duration) {
-        // Update the time.
-        self.time += time_delta;
+    /// Get the world material registry.
+    #[inline]
+    pub fn material_registry(&self) -> &MaterialRegistry {
+        &self.material_registry
+    }
 
-        self.ecs_schedule.execute(&mut self.ecs_world, &mut self.ecs_resources);
+    /// Get the world material registry mutably.
+    #[inline]
+    pub fn material_registry_mut(&mut self) -> &mut MaterialRegistry {
+        &mut self.material_registry
+    }
+
+    /// Get the world block registry mutably.
+    /// Removing or reordering blocks will break any chunks that are already loaded.
+    #[inline]
+    pub fn block_registry_mut(&mut self) -> &mut BlockRegistry {
+        self.chunk_provider.block_registry_mut()
+    }
+
+    /// Work out the mass of every type of block from the material it is made of.
+    pub fn block_masses(&self) -> BlockMasses {
+        BlockMasses::new(self.block_registry(), &self.material_registry)
     }
 
     /// Get the world time.
@@ -104,6 +302,51 @@ impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
         self.time
     }
 
+    /// Get how fast the world's simulation runs.
+    #[inline]
+    pub fn simulation_settings(&self) -> &SimulationSettings {
+        &self.simulation_settings
+    }
+
+    /// Set how fast the world's simulation runs. The physics engine is stepped at the new tick rate from now on.
+    pub fn set_simulation_settings(&mut self, settings: SimulationSettings) {
+        self.simulation_settings = settings;
+
+        let mut constants = self.ecs_resources.get_mut::<PhysicsGlobalConstants>().expect("Physics constants went missing.");
+        constants.integration_parameters.dt = settings.step_duration().as_secs_f32();
+    }
+
+    /// Get the fixed timestep, which keeps track of how many steps the world has taken and how far it is into the next.
+    #[inline]
+    pub fn timestep(&self) -> &FixedTimestep {
+        &self.timestep
+    }
+
+    /// How far between the last step and the next one the world is, from 0 to 1. Renderers can use this to blend
+    /// between the last two steps.
+    #[inline]
+    pub fn interpolation_alpha(&self) -> f32 {
+        self.timestep.interpolation_alpha(&self.simulation_settings)
+    }
+
+    /// Get the calendar, which turns the world time into in-game days and hours.
+    #[inline]
+    pub fn calendar(&self) -> &GameCalendar {
+        &self.calendar
+    }
+
+    /// Set the calendar, such as to change how long days are.
+    #[inline]
+    pub fn set_calendar(&mut self, calendar: GameCalendar) {
+        self.calendar = calendar;
+    }
+
+    /// Get the in-game date and time.
+    #[inline]
+    pub fn date(&self) -> GameDate {
+        self.calendar.date(self.time)
+    }
+
     /// Grab the ECS for manipulating entities.
     #[inline]
     pub fn ecs_world(&self) -> &World {
@@ -152,16 +395,223 @@ impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
         self.terrain_chunks.get_mut(index)
     }
 
+    /// Get a single block from the world.
+    /// Returns None if the chunk containing the block is not loaded.
+    #[inline]
+    pub fn get_block(&self, location: GlobalBlockCoordinate) -> Option<Option<BlockID>> {
+        let chunk = self.get_chunk(&location.chunk_index())?;
+        Some(chunk.get_single_block_local(location.to_local_block_coordinate()))
+    }
+
+    /// Get a single block from the world mutably.
+    /// Returns None if the chunk containing the block is not loaded.
+    #[inline]
+    pub fn get_block_mut(&mut self, location: GlobalBlockCoordinate) -> Option<&mut Option<BlockID>> {
+        let chunk = self.get_chunk_mut(&location.chunk_index())?;
+        Some(chunk.get_single_block_local_mut(location.to_local_block_coordinate()))
+    }
+
+    /// Grab the tick scheduler, which decides how blocks behave over time.
+    #[inline]
+    pub fn tick_scheduler(&self) -> &TickScheduler<ChunkUserData> {
+        &self.tick_scheduler
+    }
+
+    /// Grab the tick scheduler, which decides how blocks behave over time.
+    #[inline]
+    pub fn tick_scheduler_mut(&mut self) -> &mut TickScheduler<ChunkUserData> {
+        &mut self.tick_scheduler
+    }
+
+    /// Schedule a block to be ticked at a specific time. The chunk containing the block must be loaded.
+    /// The tick is stored with the chunk, so it will still happen if the chunk is saved and loaded again.
+    pub fn schedule_tick(&mut self, location: GlobalBlockCoordinate, time: WorldTime) -> TickResult<()> {
+        let chunk = self.get_chunk_mut(&location.chunk_index()).ok_or(TickError::ChunkNotLoaded)?;
+        chunk.scheduled_ticks_mut().schedule(location.to_local_block_coordinate(), time);
+
+        Ok(())
+    }
+
+    /// Get what is done when a chunk fails to generate.
+    #[inline]
+    pub fn chunk_failure_policy(&self) -> ChunkFailurePolicy {
+        self.chunk_failure_policy
+    }
+
+    /// Set what is done when a chunk fails to generate. By default, loading the chunk fails.
+    #[inline]
+    pub fn set_chunk_failure_policy(&mut self, policy: ChunkFailurePolicy) {
+        self.chunk_failure_policy = policy;
+    }
+
+    /// Get where chunks are saved when they're unloaded, if anywhere.
+    #[inline]
+    pub fn chunk_storage(&self) -> Option<&storage::ChunkDiskStorage> {
+        self.chunk_storage.as_ref()
+    }
+
+    /// Set where chunks are saved when they're unloaded, and where they're looked for before being generated.
+    /// Without storage, unloaded chunks are thrown away and generated again the next time they're loaded.
+    #[inline]
+    pub fn set_chunk_storage(&mut self, chunk_storage: Option<storage::ChunkDiskStorage>) {
+        self.chunk_storage = chunk_storage;
+    }
+
+    /// Get the preset the world is generated with, if it was created from one.
+    #[inline]
+    pub fn world_preset(&self) -> Option<&chunk_providers::WorldPreset> {
+        self.world_preset.as_ref()
+    }
+
+    /// Save the whole world to its chunk storage: the preset it's generated with, if it has one, the table of block
+    /// IDs, and every loaded chunk. Fails if the world has no chunk storage.
+    pub fn save_world(&self) -> Result<()> {
+        let chunk_storage = self.chunk_storage.as_ref().context("The world has no chunk storage to save to.")?;
+        chunk_storage.save_block_table(self.block_registry())?;
+        if let Some(preset) = &self.world_preset {
+            chunk_storage.save_world_preset(preset)?;
+        }
+
+        for (index, chunk) in self.terrain_chunks.iter() {
+            chunk_storage.save_terrain_chunk(chunk).with_context(|| format!("Failed to save chunk {:?}.", index))?;
+        }
+
+        Ok(())
+    }
+
+    /// Take a chunk out of the world, saving it first if the world has chunk storage. The ticks scheduled in it are
+    /// saved with it, and will pick up where they left off when it's loaded again. Does nothing if the chunk isn't loaded.
+    pub fn unload_chunk(&mut self, index: ChunkCoordinate) -> Result<()> {
+        if let Some(chunk) = self.terrain_chunks.get(&index) {
+            if let Some(chunk_storage) = &self.chunk_storage {
+                chunk_storage.save_terrain_chunk(chunk).with_context(|| format!("Failed to save chunk {:?}.", index))?;
+            }
+
+            self.terrain_chunks.remove(&index);
+            self.evict_staged_chunks();
+        }
+
+        Ok(())
+    }
+
+    /// Let go of the chunks part way through generating that aren't next to any loaded chunk. They were only generated
+    /// for the sake of their neighbors, and aren't likely to be needed again soon. With chunk storage, they're saved so
+    /// that the work that went into them isn't lost.
+    fn evict_staged_chunks(&mut self) {
+        let terrain_chunks = &self.terrain_chunks;
+        let evicted: Vec<ChunkCoordinate> = self
+            .staged_chunks
+            .keys()
+            .filter(|index| !chunk_neighbors(**index).any(|neighbor| terrain_chunks.contains_key(&neighbor)))
+            .copied()
+            .collect();
+
+        for index in evicted {
+            let chunk = self.staged_chunks.remove(&index).expect("Staged chunk went missing.");
+            if let Some(chunk_storage) = &self.chunk_storage {
+                if let Err(error) = chunk_storage.save_terrain_chunk(&chunk) {
+                    log::warn!("Failed to save partly generated chunk {:?}, it will be generated again: {:?}", index, error);
+                }
+            }
+        }
+    }
+
     /// Get a chunk. If it doesn't exist, it will be loaded or generated. In other words, you're guaranteed to always get a chunk.
+    /// The chunk will always be completely generated, which may mean generating the chunks around it part of the way.
+    /// Panics if the chunk fails to generate and the failure policy doesn't cover it. Use [GridWorld::try_load_chunk]
+    /// if you'd rather handle that yourself.
     #[inline]
     pub fn load_chunk(&mut self, index: ChunkCoordinate) -> &mut Chunk<ChunkUserData> {
-        let chunk_provider = &mut self.chunk_provider;
-        self.terrain_chunks.entry(index).or_insert_with(|| {
-            let mut chunk = Chunk::new(index, ChunkUserData::default());
-            chunk_provider.provide_chunk(&mut chunk);
+        match self.try_load_chunk(index) {
+            Ok(chunk) => chunk,
+            Err(error) => panic!("Failed to load chunk {:?}: {:?}", index, error),
+        }
+    }
+
+    /// Get a chunk. If it doesn't exist, it will be loaded from the chunk storage or generated.
+    /// If generation fails, the chunk failure policy decides if it's tried again, replaced with a placeholder, or if
+    /// the error is returned.
+    pub fn try_load_chunk(&mut self, index: ChunkCoordinate) -> Result<&mut Chunk<ChunkUserData>> {
+        if !self.terrain_chunks.contains_key(&index) {
+            let mut retries = 0;
+            while let Err(error) = self.generate_chunk(index, GenerationStage::COMPLETE) {
+                match self.chunk_failure_policy {
+                    ChunkFailurePolicy::Retry(max_retries) if retries < max_retries => {
+                        log::warn!("Failed to generate chunk {:?}, trying again: {:?}", index, error);
+                        retries += 1;
+                    }
+                    ChunkFailurePolicy::Placeholder(block) => {
+                        log::error!("Failed to generate chunk {:?}, leaving a placeholder: {:?}", index, error);
+
+                        let mut chunk = Chunk::new(index, ChunkUserData::default());
+                        chunk.iter_ideal_mut(Chunk::<ChunkUserData>::range_all_blocks()).for_each(|target| *target = block);
+                        chunk.set_generation_stage(GenerationStage::COMPLETE);
+                        self.staged_chunks.insert(index, chunk);
+                        break;
+                    }
+                    _ => {
+                        self.evict_staged_chunks();
+                        return Err(error);
+                    }
+                }
+            }
+
+            // Only now that it's done can the rest of the world see it.
+            let chunk = self.staged_chunks.remove(&index).expect("Generated chunk went missing.");
+            self.terrain_chunks.insert(index, chunk);
+            self.evict_staged_chunks();
+        }
+
+        Ok(self.terrain_chunks.get_mut(&index).expect("Loaded chunk went missing."))
+    }
 
-            chunk
-        })
+    /// Generate a chunk up to a stage, along with any of its neighbors that stage needs. Chunks that aren't complete yet
+    /// are kept out of the terrain chunks, so that they are never seen by the rest of the world.
+    /// A chunk that fails to generate is thrown away, so that trying again starts over from nothing.
+    fn generate_chunk(&mut self, index: ChunkCoordinate, target: GenerationStage) -> Result<()> {
+        if self.terrain_chunks.contains_key(&index) {
+            return Ok(());
+        }
+
+        loop {
+            if !self.staged_chunks.contains_key(&index) {
+                // A saved chunk is always used over generating a new one.
+                let saved = match &self.chunk_storage {
+                    Some(chunk_storage) => chunk_storage
+                        .load_terrain_chunk(index)
+                        .with_context(|| format!("Failed to load saved chunk {:?}.", index))?,
+                    None => None,
+                };
+                self.staged_chunks.insert(index, saved.unwrap_or_else(|| Chunk::new(index, ChunkUserData::default())));
+            }
+
+            let stage = self.staged_chunks[&index].generation_stage();
+
+            let next = match stage.next() {
+                Some(next) if stage < target => next,
+                _ => break,
+            };
+
+            // The neighbors only need to be one stage behind, so this can't come back around to this chunk.
+            if self.chunk_provider.stage_needs_neighbors(next) {
+                for neighbor in chunk_neighbors(index) {
+                    self.generate_chunk(neighbor, stage)?;
+                }
+            }
+
+            let mut chunk = self.staged_chunks.remove(&index).expect("Staged chunk went missing.");
+            let (terrain_chunks, staged_chunks) = (&self.terrain_chunks, &self.staged_chunks);
+            let neighbors = ChunkNeighborhood::new(index, |neighbor| {
+                terrain_chunks.get(&neighbor).or_else(|| staged_chunks.get(&neighbor))
+            });
+            self.chunk_provider.provide_stage(&mut chunk, &neighbors)?;
+
+            // Make sure we always move forward, even if the provider didn't.
+            chunk.set_generation_stage(chunk.generation_stage().max(next));
+            self.staged_chunks.insert(index, chunk);
+        }
+
+        Ok(())
     }
 
     /// Load many chunks in a range.
@@ -174,15 +624,52 @@ impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
     }
 }
 
+impl<ChunkUserData: Default + Send + Sync + 'static> GridWorld<ChunkUserData> {
+    /// Let time pass in the world. The simulation is stepped at a fixed rate, so this runs as many steps as the time
+    /// that has passed calls for, which may be none at all. Time left over is carried into the next update.
+    pub fn update(&mut self, time_delta: Duration) {
+        let steps = self.timestep.advance(time_delta, &self.simulation_settings);
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    /// Run a single step of the simulation, no matter how much time has passed.
+    pub fn step(&mut self) {
+        self.step_with(|_world| {});
+    }
+
+    /// Run a single step of the simulation, with something done to the world right after the block ticks.
+    fn step_with(&mut self, after_block_ticks: impl FnOnce(&mut Self)) {
+        self.time += self.timestep.step_world_time(&self.simulation_settings);
+
+        self.mark_block_ticks(true);
+        ticks::run_block_ticks(self);
+        self.mark_block_ticks(false);
+        after_block_ticks(self);
+
+        self.remove_orphaned_rigid_bodies();
+        self.recenter_physics_origin();
+        self.gather_character_terrain();
+        self.ecs_schedule.execute(&mut self.ecs_world, &mut self.ecs_resources);
+        self.remove_unclaimed_rigid_bodies();
+
+        self.apply_structural_failures();
+        self.record_step();
+        self.run_step_listeners();
+    }
+}
+
 // Next comes a bunch of systems used in the ECS.
 
 /// The physics system. It will update the world's physics. That's it.
 #[system]
+#[allow(clippy::too_many_arguments)]
 fn ecs_physics(
     #[resource] physics_pipeline: &mut PhysicsPipeline, #[resource] constants: &PhysicsGlobalConstants,
     #[resource] broad_phase: &mut BroadPhase, #[resource] narrow_phase: &mut NarrowPhase,
     #[resource] rigid_bodies: &mut RigidBodySet, #[resource] colliders: &mut ColliderSet, #[resource] joints: &mut JointSet,
-    #[resource] ccd_solver: &mut CCDSolver,
+    #[resource] ccd_solver: &mut CCDSolver, #[state] events: &Arc<PhysicsEventCollector>,
 ) {
     physics_pipeline.step(
         &constants.gravity,
@@ -194,22 +681,81 @@ fn ecs_physics(
         joints,
         ccd_solver,
         &(),
-        &(),
+        events.as_ref(),
     )
 }
 
+/// A world of flat ground whose surface is at y = 0, with the chunks in the range loaded. Shared by the tests of the
+/// world's modules.
+#[cfg(test)]
+pub(crate) fn flat_test_world(chunks: ChunkRange) -> GridWorld<()> {
+    let mut block_registry = BlockRegistry::new();
+    chunk_providers::register_blocks(&mut block_registry, chunk_providers::AbstractFlatWorld::BLOCKS).unwrap();
+    let mut chunk_provider = chunk_providers::RAMWorld::new(block_registry);
+    chunk_provider.add_generator(chunk_providers::AbstractFlatWorld::new()).unwrap();
+
+    let mut world = GridWorld::new(chunk_provider);
+    world.load_chunk_range(chunks);
+
+    world
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use anyhow::{anyhow, Context};
+    use chunk_providers::{TerrainGenerator, TerrainGeneratorResult, TerrainGeneratorSuccessType};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Fails to generate a number of times, and then generates empty chunks.
+    struct FlakyGenerator(AtomicU32);
+
+    impl TerrainGenerator<()> for FlakyGenerator {
+        fn initialize_block_ids(&mut self, _registry: &BlockRegistry) -> Result<()> {
+            Ok(())
+        }
+
+        fn populate_chunk(&self, _chunk: &mut Chunk<()>) -> TerrainGeneratorResult {
+            match self.0.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |failures| failures.checked_sub(1)) {
+                Ok(_failures) => Err(anyhow!("Flaked out.")),
+                Err(_failures) => Ok(TerrainGeneratorSuccessType::Finished),
+            }
+        }
+    }
+
+    /// Needs a block that nobody has added.
+    struct NeedyGenerator;
+
+    impl TerrainGenerator<()> for NeedyGenerator {
+        fn initialize_block_ids(&mut self, registry: &BlockRegistry) -> Result<()> {
+            registry.get_block_id_from_name("unobtainium").context("Nobody added unobtainium.")?;
+            Ok(())
+        }
+
+        fn populate_chunk(&self, _chunk: &mut Chunk<()>) -> TerrainGeneratorResult {
+            Ok(TerrainGeneratorSuccessType::Continue)
+        }
+    }
+
+    fn create_flaky_world(failures: u32, policy: ChunkFailurePolicy) -> GridWorld<()> {
+        let mut chunk_provider = chunk_providers::RAMWorld::new(BlockRegistry::new());
+        chunk_provider.add_generator(Box::new(FlakyGenerator(AtomicU32::new(failures)))).unwrap();
+
+        let mut world = GridWorld::new(chunk_provider);
+        world.set_chunk_failure_policy(policy);
+
+        world
+    }
 
     /// Create an abstract RAM world, just to make sure that works.
     #[test]
     fn new_world_abstract_ram() {
-        let block_registry = BlockRegistry::new();
+        let mut block_registry = BlockRegistry::new();
+        chunk_providers::register_blocks(&mut block_registry, chunk_providers::AbstractFlatWorld::BLOCKS).unwrap();
         let mut chunk_provider = chunk_providers::RAMWorld::new(block_registry);
 
         let abstract_flat_world = chunk_providers::AbstractFlatWorld::new();
-        chunk_provider.add_generator(abstract_flat_world);
+        chunk_provider.add_generator(abstract_flat_world).unwrap();
 
         let _world: GridWorld<()> = GridWorld::new(chunk_provider);
     }
@@ -217,29 +763,150 @@ mod test {
     /// Generate some chunks.
     #[test]
     fn generate_chunks() {
-        let block_registry = BlockRegistry::new();
+        let mut block_registry = BlockRegistry::new();
+        chunk_providers::register_blocks(&mut block_registry, chunk_providers::AbstractFlatWorld::BLOCKS).unwrap();
         let mut chunk_provider = chunk_providers::RAMWorld::new(block_registry);
 
         let abstract_flat_world = chunk_providers::AbstractFlatWorld::new();
-        chunk_provider.add_generator(abstract_flat_world);
+        chunk_provider.add_generator(abstract_flat_world).unwrap();
 
         let mut world: GridWorld<()> = GridWorld::new(chunk_provider);
 
         let abstract_block_id = world.block_registry().get_block_id_from_name("abstract_block").cloned();
         assert!(abstract_block_id.is_some());
 
-        // Being at level 0, it should be filled with abstract blocks.
-        let chunk = world.load_chunk(ChunkCoordinate::new(0, 0, 0));
+        // Being below level 0, it should be filled with abstract blocks.
+        let chunk = world.load_chunk(ChunkCoordinate::new(0, -1, 0));
 
+        assert_eq!(chunk.iter_ideal(Chunk::<()>::range_all_blocks()).count(), storage::CHUNK_DIAMETER.pow(3));
         for block in chunk.iter_ideal(Chunk::<()>::range_all_blocks()) {
             assert_eq!(block, abstract_block_id);
         }
 
-        // Being at level 1, it should be empty.
-        let chunk = world.load_chunk(ChunkCoordinate::new(0, 1, 0));
+        // Being at level 0, it should be empty.
+        let chunk = world.load_chunk(ChunkCoordinate::new(0, 0, 0));
 
         for block in chunk.iter_ideal(Chunk::<()>::range_all_blocks()) {
             assert_eq!(block, None);
         }
     }
+
+    /// A generator that can't get the blocks it needs is never added.
+    #[test]
+    fn generator_initialization_failure() {
+        let mut chunk_provider: Box<chunk_providers::RAMWorld<()>> = chunk_providers::RAMWorld::new(BlockRegistry::new());
+        assert!(chunk_provider.add_generator(Box::new(NeedyGenerator)).is_err());
+
+        // With nothing added, chunks generate empty.
+        let mut chunk = Chunk::new(ChunkCoordinate::new(0, -1, 0), ());
+        chunk_provider.provide_chunk(&mut chunk).unwrap();
+        assert!(chunk.iter_ideal(Chunk::<()>::range_all_blocks()).all(|block| block.is_none()));
+    }
+
+    /// By default, a chunk that fails to generate is reported and left out of the world.
+    #[test]
+    fn chunk_failure_abort() {
+        let mut world = create_flaky_world(1, ChunkFailurePolicy::Abort);
+        assert_eq!(world.chunk_failure_policy(), ChunkFailurePolicy::Abort);
+
+        assert!(world.try_load_chunk(ChunkCoordinate::new(0, 0, 0)).is_err());
+        assert!(world.get_chunk(&ChunkCoordinate::new(0, 0, 0)).is_none());
+
+        // It's not remembered as broken, so the next try can work.
+        assert!(world.try_load_chunk(ChunkCoordinate::new(0, 0, 0)).is_ok());
+        assert!(world.get_chunk(&ChunkCoordinate::new(0, 0, 0)).is_some());
+    }
+
+    /// A failed chunk can be tried again a limited number of times.
+    #[test]
+    fn chunk_failure_retry() {
+        let mut world = create_flaky_world(2, ChunkFailurePolicy::Retry(2));
+        let chunk = world.try_load_chunk(ChunkCoordinate::new(0, 0, 0)).unwrap();
+        assert!(chunk.generation_stage().is_complete());
+
+        let mut world = create_flaky_world(3, ChunkFailurePolicy::Retry(2));
+        assert!(world.try_load_chunk(ChunkCoordinate::new(0, 0, 0)).is_err());
+        assert!(world.get_chunk(&ChunkCoordinate::new(0, 0, 0)).is_none());
+    }
+
+    /// A failed chunk can be replaced with a placeholder.
+    #[test]
+    fn chunk_failure_placeholder() {
+        let mut world = create_flaky_world(1, ChunkFailurePolicy::Abort);
+        world.block_registry_mut().add_block(String::from("placeholder"), String::from("Placeholder")).unwrap();
+        let placeholder = world.block_registry().get_block_id_from_name("placeholder").cloned();
+        world.set_chunk_failure_policy(ChunkFailurePolicy::Placeholder(placeholder));
+
+        let chunk = world.try_load_chunk(ChunkCoordinate::new(0, 0, 0)).unwrap();
+        assert!(chunk.generation_stage().is_complete());
+        assert!(chunk.iter_ideal(Chunk::<()>::range_all_blocks()).all(|block| block == placeholder));
+    }
+
+    /// A falling body ends up in exactly the same place whether the world is updated in small frames or big ones.
+    #[test]
+    fn fixed_timestep() {
+        use rapier3d::{dynamics::RigidBodyBuilder, geometry::ColliderBuilder};
+
+        let simulate = |frames: u32, frame_time: Duration| {
+            let mut world: GridWorld<()> = GridWorld::new(chunk_providers::RAMWorld::new(BlockRegistry::new()));
+            let mut settings = *world.simulation_settings();
+            settings.set_max_steps_per_update(100);
+            world.set_simulation_settings(settings);
+
+            let body = components::RigidBody::new(
+                world.ecs_resources_mut(),
+                RigidBodyBuilder::new_dynamic().translation(0.0, 10.0, 0.0).build(),
+            );
+            body.add_collider(ColliderBuilder::ball(0.5).build(), world.ecs_resources_mut());
+            let handle = body.handle();
+            world.ecs_world_mut().push((body,));
+
+            for _ in 0..frames {
+                world.update(frame_time);
+            }
+
+            let rigid_bodies = world.ecs_resources().get::<RigidBodySet>().unwrap();
+            (world.time(), world.timestep().steps(), rigid_bodies.get(handle).unwrap().position().translation.vector)
+        };
+
+        let smooth = simulate(60, Duration::from_micros(16_667));
+        let choppy = simulate(4, Duration::from_micros(250_005));
+        assert_eq!(smooth, (WorldTime::from_ms(1000), 100, smooth.2));
+        assert_eq!(smooth, choppy);
+        assert!(smooth.2.y < 10.0);
+    }
+
+    /// The physics engine is stepped at the tick rate.
+    #[test]
+    fn tick_rate() {
+        let mut world: GridWorld<()> = GridWorld::new(chunk_providers::RAMWorld::new(BlockRegistry::new()));
+        let mut settings = *world.simulation_settings();
+        settings.set_tick_rate(50);
+        world.set_simulation_settings(settings);
+
+        assert_eq!(world.ecs_resources().get::<PhysicsGlobalConstants>().unwrap().integration_parameters.dt, 0.02);
+
+        world.update(Duration::from_millis(30));
+        assert_eq!(world.time(), WorldTime::from_ms(20));
+        assert!((world.interpolation_alpha() - 0.5).abs() < 1.0e-6);
+    }
+
+    /// The in-game date follows the world time, and stands still while the world is paused.
+    #[test]
+    fn paused_date() {
+        let mut world: GridWorld<()> = GridWorld::new(chunk_providers::RAMWorld::new(BlockRegistry::new()));
+        world.set_calendar(GameCalendar::new(Duration::from_secs(24 * 60), 12));
+        assert_eq!(world.date(), GameDate { day: 0, hour: 12, minute: 0 });
+
+        let mut settings = *world.simulation_settings();
+        settings.set_max_steps_per_update(1000);
+        world.set_simulation_settings(settings);
+        world.update(Duration::from_secs(5));
+        assert_eq!(world.date(), GameDate { day: 0, hour: 12, minute: 5 });
+
+        settings.set_paused(true);
+        world.set_simulation_settings(settings);
+        world.update(Duration::from_secs(5));
+        assert_eq!(world.date(), GameDate { day: 0, hour: 12, minute: 5 });
+    }
 }
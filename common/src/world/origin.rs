@@ -0,0 +1,210 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! A floating origin for physics. Rapier works in single precision, which gets jittery a few kilometers away from
+//! its origin, so the physics simulation is kept centered on wherever the action is.
+
+use super::{
+    components::{OriginAnchor, RigidBody, Transform},
+    GlobalBlockCoordinate, GridWorld, PhysicsVector,
+};
+use legion::IntoQuery;
+use nalgebra::{Translation3, Vector3};
+use rapier3d::dynamics::RigidBodySet;
+use serde::{Deserialize, Serialize};
+
+/// The default distance, in meters, anchors can get from the physics origin before it's moved.
+pub const DEFAULT_RECENTER_DISTANCE: f32 = 1024.0;
+
+/// Where the physics simulation is in the world.
+/// Block coordinates are absolute, but everything in rapier is relative to this origin.
+#[derive(Serialize, Deserialize)]
+pub struct FloatingOrigin {
+    origin: GlobalBlockCoordinate,
+    recenter_distance: f32,
+}
+
+impl FloatingOrigin {
+    /// Create a floating origin at the center of the world.
+    pub fn new() -> FloatingOrigin {
+        FloatingOrigin { origin: GlobalBlockCoordinate::zeros(), recenter_distance: DEFAULT_RECENTER_DISTANCE }
+    }
+
+    /// The block in the world that physics space 0x0x0 is at.
+    pub fn origin(&self) -> GlobalBlockCoordinate {
+        self.origin
+    }
+
+    /// How far anchors can get from the origin before it's moved.
+    pub fn recenter_distance(&self) -> f32 {
+        self.recenter_distance
+    }
+
+    /// Set how far anchors can get from the origin before it's moved.
+    pub fn set_recenter_distance(&mut self, recenter_distance: f32) {
+        self.recenter_distance = recenter_distance;
+    }
+
+    /// Convert the corner of a block to physics space.
+    pub fn block_to_physics(&self, block: GlobalBlockCoordinate) -> PhysicsVector {
+        (block - self.origin).map(|v| v as f32)
+    }
+
+    /// Convert a point in world space, which is measured in double precision, to physics space.
+    pub fn world_to_physics(&self, point: Vector3<f64>) -> PhysicsVector {
+        (point - self.origin.map(|v| v as f64)).map(|v| v as f32)
+    }
+
+    /// Convert a point in physics space to world space, which is measured in double precision.
+    pub fn physics_to_world(&self, point: PhysicsVector) -> Vector3<f64> {
+        point.map(|v| v as f64) + self.origin.map(|v| v as f64)
+    }
+
+    /// Find the block a point in physics space is in.
+    pub fn physics_to_block(&self, point: PhysicsVector) -> GlobalBlockCoordinate {
+        point.map(|v| v.floor() as i64) + self.origin
+    }
+}
+
+impl Default for FloatingOrigin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
+    /// Get the block in the world that physics space 0x0x0 is at.
+    pub fn physics_origin(&self) -> GlobalBlockCoordinate {
+        self.ecs_resources.get::<FloatingOrigin>().expect("Failed to find floating origin.").origin
+    }
+
+    /// Move the physics origin to a new block. Every rigid body and [Transform] is moved the other way, so nothing
+    /// actually moves in the world.
+    pub fn set_physics_origin(&mut self, origin: GlobalBlockCoordinate) {
+        let mut floating_origin = self.ecs_resources.get_mut::<FloatingOrigin>().expect("Failed to find floating origin.");
+        let shift = (floating_origin.origin - origin).map(|v| v as f32);
+        floating_origin.origin = origin;
+
+        let mut rigid_bodies = self.ecs_resources.get_mut::<RigidBodySet>().expect("Failed to find rigid body set.");
+        for (_handle, rigid_body) in rigid_bodies.iter_mut() {
+            let position = Translation3::from(shift) * rigid_body.position();
+            rigid_body.set_position(position, false);
+        }
+
+        for transform in <&mut Transform>::query().iter_mut(&mut self.ecs_world) {
+            transform.shift(shift);
+        }
+    }
+
+    /// If the entities with an [OriginAnchor] have wandered too far from the physics origin, move the origin to
+    /// their average position. With only one origin per world, anchors should be kept reasonably close to each other.
+    pub fn recenter_physics_origin(&mut self) {
+        let average = {
+            let rigid_bodies = self.ecs_resources.get::<RigidBodySet>().expect("Failed to find rigid body set.");
+
+            let mut query = <(&RigidBody, &OriginAnchor)>::query();
+            let (sum, count) = query
+                .iter(&self.ecs_world)
+                .filter_map(|(rigid_body, _anchor)| rigid_bodies.get(rigid_body.handle()))
+                .fold((Vector3::<f64>::zeros(), 0usize), |(sum, count), rigid_body| {
+                    (sum + rigid_body.position().translation.vector.map(|v| v as f64), count + 1)
+                });
+
+            if count == 0 {
+                return;
+            }
+
+            sum / count as f64
+        };
+
+        let (origin, recenter_distance) = {
+            let floating_origin = self.ecs_resources.get::<FloatingOrigin>().expect("Failed to find floating origin.");
+            (floating_origin.origin, floating_origin.recenter_distance)
+        };
+
+        if average.norm() > recenter_distance as f64 {
+            self.set_physics_origin(origin + average.map(|v| v.floor() as i64));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use rapier3d::dynamics::RigidBodyBuilder;
+    use std::time::Duration;
+
+    /// Conversions between world and physics space go both ways.
+    #[test]
+    fn conversions() {
+        let mut floating_origin = FloatingOrigin::new();
+        floating_origin.origin = GlobalBlockCoordinate::new(50_000_000_000, -3, 0);
+
+        let block = GlobalBlockCoordinate::new(50_000_000_010, 7, -2);
+        let point = floating_origin.block_to_physics(block);
+        assert_eq!(point, PhysicsVector::new(10.0, 10.0, -2.0));
+        assert_eq!(floating_origin.physics_to_block(point + PhysicsVector::new(0.5, 0.5, 0.5)), block);
+
+        let world_point = floating_origin.physics_to_world(PhysicsVector::new(0.25, 0.0, 0.0));
+        assert_eq!(world_point, Vector3::new(50_000_000_000.25, -3.0, 0.0));
+        assert_eq!(floating_origin.world_to_physics(world_point), PhysicsVector::new(0.25, 0.0, 0.0));
+    }
+
+    /// An anchor far from the origin drags the origin along with it, and everything else keeps its place in the world.
+    #[test]
+    fn recenter() {
+        let mut world = flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::zeros(), ChunkCoordinate::zeros()));
+
+        let anchor =
+            RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_kinematic().translation(2000.0, 0.5, 0.0).build());
+        let anchor_handle = anchor.handle();
+        world.ecs_world_mut().push((anchor, OriginAnchor));
+
+        let other =
+            RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_static().translation(1990.0, 0.0, 0.0).build());
+        let other_handle = other.handle();
+        world.ecs_world_mut().push((other,));
+
+        world.update(Duration::from_millis(10));
+        assert_eq!(world.physics_origin(), GlobalBlockCoordinate::new(2000, 0, 0));
+
+        let rigid_bodies = world.ecs_resources().get::<RigidBodySet>().unwrap();
+        assert_eq!(rigid_bodies.get(anchor_handle).unwrap().position().translation.vector, PhysicsVector::new(0.0, 0.5, 0.0));
+        assert_eq!(rigid_bodies.get(other_handle).unwrap().position().translation.vector, PhysicsVector::new(-10.0, 0.0, 0.0));
+    }
+
+    /// Nothing moves while anchors stay close to the origin.
+    #[test]
+    fn no_recenter() {
+        let mut world = flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::zeros(), ChunkCoordinate::zeros()));
+
+        let anchor =
+            RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_kinematic().translation(10.0, 0.0, 0.0).build());
+        world.ecs_world_mut().push((anchor, OriginAnchor));
+
+        world.update(Duration::from_millis(10));
+        assert_eq!(world.physics_origin(), GlobalBlockCoordinate::zeros());
+    }
+
+    /// Grids cut from the terrain far from the center of the world go back where they came from.
+    #[test]
+    fn far_terrain_round_trip() {
+        let mut world = flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::zeros(), ChunkCoordinate::zeros()));
+
+        let far_chunk = ChunkCoordinate::new(100_000_000, -1, 0);
+        world.load_chunk(far_chunk);
+        world.set_physics_origin(far_chunk.to_block_coordinate());
+
+        let near = far_chunk.to_block_coordinate() + GlobalBlockCoordinate::new(3, 30, 3);
+        let range = GlobalBlockRange::from_end_points(near, near + GlobalBlockCoordinate::new(2, 2, 2));
+        let block = world.get_block(near).unwrap();
+        assert!(block.is_some());
+
+        let entity = world.terrain_to_grid(&range);
+        assert_eq!(world.get_block(near), Some(None));
+
+        world.grid_to_terrain(entity).unwrap();
+        assert_eq!(world.get_block(near), Some(block));
+    }
+}
@@ -0,0 +1,528 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Plugins are how mods and engine modules add to a world. Each plugin gets a turn to register blocks, materials,
+//! terrain generators, tick handlers, ECS systems, resources and snapshot components while the world is being built.
+//!
+//! Plugins are built in the order they're given to the [WorldBuilder], so a plugin can only depend on plugins that
+//! come before it.
+
+use super::{
+    chunk_providers::{GenerationStage, GeneratorHost, TerrainGenerator},
+    inventory::{MaterialID, MaterialRegistry},
+    BlockID, BlockRegistry, BlockTickHandler, GridWorld, SnapshotRegistry, TickScheduler,
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use legion::{
+    storage::Component,
+    systems::{Executor, ParallelRunnable, Resource, Step},
+    Resources, Schedule,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// The names of the systems every world comes with, so plugin systems can be ordered around them. They run in the
+/// order they're listed here.
+pub mod core_systems {
+    /// Moves characters according to their [CharacterInput](super::CharacterInput).
+    pub const MOVE_CHARACTERS: &str = "move_characters";
+
+    /// Breaks apart structures that can no longer hold themselves up.
+    pub const STRUCTURAL_INTEGRITY: &str = "structural_integrity";
+
+    /// Moves kinematic rigid bodies to where their transforms say they should be.
+    pub const DRIVE_KINEMATIC_BODIES: &str = "drive_kinematic_bodies";
+
+    /// Steps the physics engine.
+    pub const PHYSICS: &str = "physics";
+
+    /// Copies the positions of dynamic rigid bodies back into their transforms.
+    pub const SYNC_TRANSFORMS: &str = "sync_transforms";
+
+    /// Makes the collisions from the physics step available as [CollisionEvents](super::CollisionEvents).
+    pub const PUBLISH_COLLISION_EVENTS: &str = "publish_collision_events";
+
+    /// Brings the query pipeline up to date with the physics step.
+    pub const UPDATE_QUERY_PIPELINE: &str = "update_query_pipeline";
+}
+
+/// Where a system has to run relative to other systems, by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SystemOrder {
+    after: Vec<String>,
+    before: Vec<String>,
+}
+
+impl SystemOrder {
+    /// No constraints. The system runs in the order it was added.
+    pub fn new() -> SystemOrder {
+        SystemOrder::default()
+    }
+
+    /// Run after another system.
+    pub fn after(mut self, system: &str) -> SystemOrder {
+        self.after.push(system.to_string());
+        self
+    }
+
+    /// Run before another system.
+    pub fn before(mut self, system: &str) -> SystemOrder {
+        self.before.push(system.to_string());
+        self
+    }
+}
+
+/// A named system waiting to be put in the schedule.
+struct RegisteredSystem {
+    name: String,
+    system: Box<dyn ParallelRunnable>,
+    order: SystemOrder,
+}
+
+/// Collects ECS systems and puts them in an order that keeps everyone's constraints. Systems without constraints
+/// between them keep the order they were added in.
+#[derive(Default)]
+pub struct SystemRegistry {
+    systems: Vec<RegisteredSystem>,
+}
+
+impl SystemRegistry {
+    /// Create a registry with no systems.
+    pub fn new() -> SystemRegistry {
+        SystemRegistry::default()
+    }
+
+    /// Add a system. Fails if there's already a system with the same name.
+    pub fn add_system<S: ParallelRunnable + 'static>(&mut self, name: &str, system: S, order: SystemOrder) -> Result<()> {
+        self.add_boxed_system(name, Box::new(system), order)
+    }
+
+    /// Add a system that has already been boxed. Fails if there's already a system with the same name.
+    pub fn add_boxed_system(&mut self, name: &str, system: Box<dyn ParallelRunnable>, order: SystemOrder) -> Result<()> {
+        ensure!(!self.contains(name), "System \"{}\" was added more than once.", name);
+        self.systems.push(RegisteredSystem { name: name.to_string(), system, order });
+
+        Ok(())
+    }
+
+    /// Check if a system has been added.
+    pub fn contains(&self, name: &str) -> bool {
+        self.systems.iter().any(|system| system.name == name)
+    }
+
+    /// The names of the systems in the order they'll run. Fails if a constraint names a system that was never added, or
+    /// if the constraints can't all be kept.
+    pub fn ordered_names(&self) -> Result<Vec<&str>> {
+        Ok(self.sorted_indices()?.into_iter().map(|index| self.systems[index].name.as_str()).collect())
+    }
+
+    /// Put the systems in order and build a schedule from them.
+    pub fn build_schedule(mut self) -> Result<Schedule> {
+        let order = self.sorted_indices()?;
+
+        let mut systems: Vec<Option<Box<dyn ParallelRunnable>>> =
+            self.systems.drain(..).map(|registered| Some(registered.system)).collect();
+        let ordered = order.into_iter().filter_map(|index| systems[index].take()).collect();
+
+        Ok(Schedule::from(vec![Step::Systems(Executor::new(ordered)), Step::FlushCmdBuffers]))
+    }
+
+    /// Sort the systems so that every system comes after everything it has to run after. When more than one system is
+    /// free to go next, the one added first goes.
+    fn sorted_indices(&self) -> Result<Vec<usize>> {
+        let indices: HashMap<&str, usize> =
+            self.systems.iter().enumerate().map(|(index, system)| (system.name.as_str(), index)).collect();
+        let find = |system: &RegisteredSystem, other: &str| {
+            indices
+                .get(other)
+                .copied()
+                .ok_or_else(|| anyhow!("System \"{}\" is ordered around \"{}\", which was never added.", system.name, other))
+        };
+
+        // For each system, the systems that have to run before it.
+        let mut waiting_on: Vec<HashSet<usize>> = vec![HashSet::new(); self.systems.len()];
+        for (index, system) in self.systems.iter().enumerate() {
+            for after in system.order.after.iter() {
+                waiting_on[index].insert(find(system, after)?);
+            }
+            for before in system.order.before.iter() {
+                waiting_on[find(system, before)?].insert(index);
+            }
+        }
+
+        let mut sorted = Vec::with_capacity(self.systems.len());
+        let mut placed = vec![false; self.systems.len()];
+        while sorted.len() < self.systems.len() {
+            let next = (0..self.systems.len())
+                .find(|&index| !placed[index] && waiting_on[index].iter().all(|&other| placed[other]))
+                .ok_or_else(|| {
+                    let stuck: Vec<&str> = (0..self.systems.len())
+                        .filter(|&index| !placed[index])
+                        .map(|index| self.systems[index].name.as_str())
+                        .collect();
+                    anyhow!("Systems can't be ordered, because they wait on each other: {}", stuck.join(", "))
+                })?;
+
+            placed[next] = true;
+            sorted.push(next);
+        }
+
+        Ok(sorted)
+    }
+}
+
+/// Something that adds content or behavior to a world.
+pub trait Plugin<ChunkUserData: Default> {
+    /// The name of the plugin. Other plugins use it to depend on this one, so it must be unique.
+    fn name(&self) -> &str;
+
+    /// The names of the plugins this one needs. They must be given to the [WorldBuilder] before this one.
+    fn dependencies(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Register everything the plugin adds to the world.
+    fn build(&self, context: &mut PluginContext<ChunkUserData>) -> Result<()>;
+}
+
+/// What a plugin can register things with while the world is being built.
+pub struct PluginContext<'a, ChunkUserData: Default> {
+    generator_host: &'a mut dyn GeneratorHost<ChunkUserData>,
+    material_registry: &'a mut MaterialRegistry,
+    tick_scheduler: &'a mut TickScheduler<ChunkUserData>,
+    systems: &'a mut SystemRegistry,
+    resources: &'a mut Resources,
+    snapshot_registry: &'a mut SnapshotRegistry<ChunkUserData>,
+}
+
+impl<'a, ChunkUserData: Serialize + DeserializeOwned + Default + Send + Sync + 'static> PluginContext<'a, ChunkUserData> {
+    /// Access the block registry.
+    pub fn block_registry(&self) -> &BlockRegistry {
+        self.generator_host.block_registry()
+    }
+
+    /// Access the block registry mutably.
+    pub fn block_registry_mut(&mut self) -> &mut BlockRegistry {
+        self.generator_host.block_registry_mut()
+    }
+
+    /// Access the material registry.
+    pub fn material_registry(&self) -> &MaterialRegistry {
+        self.material_registry
+    }
+
+    /// Add a new type of block. Fails if there already is a block with the same name. To share a block with other
+    /// plugins, use [require_block](super::chunk_providers::require_block) on the block registry instead.
+    pub fn add_block(&mut self, name: &str, display_text: &str) -> Result<BlockID> {
+        let registry = self.generator_host.block_registry_mut();
+        registry
+            .add_block(name.to_string(), display_text.to_string())
+            .map_err(|_| anyhow!("Block \"{}\" was already added.", name))?;
+
+        registry.get_block_id_from_name(name).cloned().context("Added block is missing from the registry.")
+    }
+
+    /// Add a new material. Fails if there already is a material with the same name.
+    pub fn add_material(&mut self, name: &str, density: u64, strength: u64) -> Result<MaterialID> {
+        ensure!(self.material_registry.get_material_id(name).is_none(), "Material \"{}\" was already added.", name);
+
+        Ok(self.material_registry.register_material(name.to_string(), density, strength))
+    }
+
+    /// Set the material a block is made of.
+    pub fn set_block_material(&mut self, block: &str, material: MaterialID) -> Result<()> {
+        self.generator_host
+            .block_registry_mut()
+            .set_block_material(block, material)
+            .map_err(|_| anyhow!("Block \"{}\" doesn't exist.", block))
+    }
+
+    /// Add a terrain generator to run in a stage of generation. The blocks it needs must already be added, or this
+    /// fails.
+    pub fn add_generator(&mut self, stage: GenerationStage, generator: Box<dyn TerrainGenerator<ChunkUserData>>) -> Result<()> {
+        self.generator_host.add_stage_generator(stage, generator)
+    }
+
+    /// Set the tick handler for a block. Fails if the block doesn't exist.
+    pub fn add_tick_handler(&mut self, block: &str, handler: Box<dyn BlockTickHandler<ChunkUserData>>) -> Result<()> {
+        let block_id = *self
+            .generator_host
+            .block_registry()
+            .get_block_id_from_name(block)
+            .with_context(|| format!("Block \"{}\" doesn't exist.", block))?;
+        self.tick_scheduler.register_handler(block_id, handler);
+
+        Ok(())
+    }
+
+    /// Add an ECS system. Fails if there's already a system with the same name.
+    pub fn add_system<S: ParallelRunnable + 'static>(&mut self, name: &str, system: S, order: SystemOrder) -> Result<()> {
+        self.systems.add_system(name, system, order)
+    }
+
+    /// Add an ECS resource, replacing the old one if there was one.
+    pub fn insert_resource<R: Resource>(&mut self, resource: R) {
+        self.resources.insert(resource);
+    }
+
+    /// Register an ECS component so it can be put in snapshots.
+    pub fn register_component<C: Component + Serialize + DeserializeOwned>(&mut self, name: &str) {
+        self.snapshot_registry.register::<C>(name);
+    }
+}
+
+/// Puts a world together from a chunk provider and a list of plugins.
+pub struct WorldBuilder<ChunkUserData: Default, Provider> {
+    chunk_provider: Box<Provider>,
+    plugins: Vec<Box<dyn Plugin<ChunkUserData>>>,
+}
+
+impl<ChunkUserData, Provider> WorldBuilder<ChunkUserData, Provider>
+where
+    ChunkUserData: Serialize + DeserializeOwned + Default + Send + Sync + 'static,
+    Provider: GeneratorHost<ChunkUserData> + 'static,
+{
+    /// Start building a world with a chunk provider and no plugins.
+    pub fn new(chunk_provider: Box<Provider>) -> WorldBuilder<ChunkUserData, Provider> {
+        WorldBuilder { chunk_provider, plugins: Vec::new() }
+    }
+
+    /// Add a plugin. Plugins are built in the order they're added.
+    pub fn with_plugin<P: Plugin<ChunkUserData> + 'static>(mut self, plugin: P) -> WorldBuilder<ChunkUserData, Provider> {
+        self.add_plugin(Box::new(plugin));
+        self
+    }
+
+    /// Add a plugin that has already been boxed. Plugins are built in the order they're added.
+    pub fn add_plugin(&mut self, plugin: Box<dyn Plugin<ChunkUserData>>) {
+        self.plugins.push(plugin);
+    }
+
+    /// Build every plugin and put the world together. Returns the world along with the registry for taking snapshots
+    /// of it. Fails if the plugins' dependencies aren't met, listing every problem, or if a plugin fails to build.
+    pub fn build(mut self) -> Result<(GridWorld<ChunkUserData>, SnapshotRegistry<ChunkUserData>)> {
+        self.check_dependencies()?;
+
+        let mut material_registry = MaterialRegistry::new();
+        let mut tick_scheduler = TickScheduler::new();
+        let mut systems = SystemRegistry::new();
+        GridWorld::<ChunkUserData>::register_core_systems(&mut systems)?;
+        let mut resources = GridWorld::<ChunkUserData>::core_resources();
+        let mut snapshot_registry = SnapshotRegistry::new();
+
+        for plugin in self.plugins.iter() {
+            let mut context = PluginContext {
+                generator_host: self.chunk_provider.as_mut(),
+                material_registry: &mut material_registry,
+                tick_scheduler: &mut tick_scheduler,
+                systems: &mut systems,
+                resources: &mut resources,
+                snapshot_registry: &mut snapshot_registry,
+            };
+
+            plugin.build(&mut context).with_context(|| format!("Plugin \"{}\" failed to build.", plugin.name()))?;
+        }
+
+        let schedule = systems.build_schedule()?;
+        let world = GridWorld::from_parts(self.chunk_provider, material_registry, tick_scheduler, schedule, resources);
+
+        Ok((world, snapshot_registry))
+    }
+
+    /// Make sure every plugin is unique and comes after the plugins it depends on.
+    fn check_dependencies(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        let mut added = HashSet::new();
+
+        for plugin in self.plugins.iter() {
+            for dependency in plugin.dependencies() {
+                if added.contains(dependency) {
+                    continue;
+                }
+
+                if self.plugins.iter().any(|other| other.name() == *dependency) {
+                    problems.push(format!(
+                        "Plugin \"{}\" depends on \"{}\", which is added after it.",
+                        plugin.name(),
+                        dependency
+                    ));
+                } else {
+                    problems.push(format!("Plugin \"{}\" depends on \"{}\", which is missing.", plugin.name(), dependency));
+                }
+            }
+
+            if !added.insert(plugin.name()) {
+                problems.push(format!("Plugin \"{}\" was added more than once.", plugin.name()));
+            }
+        }
+
+        if !problems.is_empty() {
+            bail!("Plugin dependencies aren't met:\n{}", problems.join("\n"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use legion::system;
+
+    /// Counts how many steps the plugin's system has run for.
+    #[derive(Default)]
+    struct StepCounter(u32);
+
+    /// A component the plugin adds, which needs to survive snapshots.
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, serde::Deserialize)]
+    struct Health(u32);
+
+    #[system]
+    fn count_steps(#[resource] counter: &mut StepCounter) {
+        counter.0 += 1;
+    }
+
+    /// Adds a bit of everything.
+    struct GroundPlugin;
+
+    impl Plugin<()> for GroundPlugin {
+        fn name(&self) -> &str {
+            "ground"
+        }
+
+        fn build(&self, context: &mut PluginContext<()>) -> Result<()> {
+            let stone = context.add_material("stone", 2600, 100)?;
+            context.add_block("core:stone", "Stone")?;
+            context.set_block_material("core:stone", stone)?;
+            context.add_block("abstract_block", "Abstract Block")?;
+            context.add_generator(GenerationStage::BaseTerrain, chunk_providers::AbstractFlatWorld::new())?;
+            context.add_system("count_steps", count_steps_system(), SystemOrder::new().after(core_systems::PHYSICS))?;
+            context.insert_resource(StepCounter::default());
+            context.register_component::<Health>("health");
+
+            Ok(())
+        }
+    }
+
+    /// Tries to add a block that the ground plugin already added.
+    struct StonePlugin;
+
+    impl Plugin<()> for StonePlugin {
+        fn name(&self) -> &str {
+            "more_stone"
+        }
+
+        fn build(&self, context: &mut PluginContext<()>) -> Result<()> {
+            context.add_block("core:stone", "More Stone").map(|_| ())
+        }
+    }
+
+    /// A plugin that only needs other plugins.
+    struct NamedPlugin {
+        name: &'static str,
+        dependencies: &'static [&'static str],
+    }
+
+    impl Plugin<()> for NamedPlugin {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn dependencies(&self) -> &[&str] {
+            self.dependencies
+        }
+
+        fn build(&self, _context: &mut PluginContext<()>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn builder() -> WorldBuilder<(), chunk_providers::RAMWorld<()>> {
+        WorldBuilder::new(chunk_providers::RAMWorld::new(BlockRegistry::new()))
+    }
+
+    /// Everything a plugin registers ends up in the world.
+    #[test]
+    fn build_world() {
+        let (mut world, snapshot_registry) = builder()
+            .with_plugin(GroundPlugin)
+            .with_plugin(NamedPlugin { name: "extras", dependencies: &["ground"] })
+            .build()
+            .unwrap();
+
+        let stone = world.block_registry().get_block_data_from_name("core:stone").unwrap();
+        assert_eq!(stone.material(), world.material_registry().get_material_id("stone"));
+
+        world.load_chunk_range(ChunkRange::from_end_points(ChunkCoordinate::new(-1, -1, -1), ChunkCoordinate::new(1, 1, 1)));
+        assert!(world.get_block(GlobalBlockCoordinate::new(0, -1, 0)).is_some());
+
+        world.update(Duration::from_millis(30));
+        assert_eq!(world.ecs_resources().get::<StepCounter>().unwrap().0, 3);
+
+        world.ecs_world_mut().push((Health(7),));
+        assert!(world.snapshot(&snapshot_registry).is_ok());
+    }
+
+    /// Plugin systems are ordered around the core systems, and bad orders are caught.
+    #[test]
+    fn system_order() {
+        let mut systems = SystemRegistry::new();
+        GridWorld::<()>::register_core_systems(&mut systems).unwrap();
+        systems.add_system("count_steps", count_steps_system(), SystemOrder::new().after(core_systems::PHYSICS)).unwrap();
+        systems.add_system("first", count_steps_system(), SystemOrder::new().before(core_systems::MOVE_CHARACTERS)).unwrap();
+
+        assert_eq!(
+            systems.ordered_names().unwrap(),
+            vec![
+                "first",
+                core_systems::MOVE_CHARACTERS,
+                core_systems::STRUCTURAL_INTEGRITY,
+                core_systems::DRIVE_KINEMATIC_BODIES,
+                core_systems::PHYSICS,
+                core_systems::SYNC_TRANSFORMS,
+                core_systems::PUBLISH_COLLISION_EVENTS,
+                core_systems::UPDATE_QUERY_PIPELINE,
+                "count_steps",
+            ]
+        );
+        assert!(systems.add_system("first", count_steps_system(), SystemOrder::new()).is_err());
+
+        let mut unknown = SystemRegistry::new();
+        unknown.add_system("lonely", count_steps_system(), SystemOrder::new().after("nobody")).unwrap();
+        assert!(unknown.build_schedule().is_err());
+
+        let mut cycle = SystemRegistry::new();
+        cycle.add_system("a", count_steps_system(), SystemOrder::new().after("b")).unwrap();
+        cycle.add_system("b", count_steps_system(), SystemOrder::new().after("a")).unwrap();
+        let error = cycle.ordered_names().unwrap_err().to_string();
+        assert!(error.contains("a, b"), "{}", error);
+    }
+
+    /// Every dependency problem is reported at once.
+    #[test]
+    fn dependency_errors() {
+        let error = builder()
+            .with_plugin(NamedPlugin { name: "early", dependencies: &["late"] })
+            .with_plugin(NamedPlugin { name: "late", dependencies: &["missing"] })
+            .with_plugin(NamedPlugin { name: "late", dependencies: &[] })
+            .build()
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error.contains("\"early\" depends on \"late\", which is added after it"), "{}", error);
+        assert!(error.contains("\"late\" depends on \"missing\", which is missing"), "{}", error);
+        assert!(error.contains("\"late\" was added more than once"), "{}", error);
+    }
+
+    /// A plugin that fails takes the world down with it, and says which plugin it was.
+    #[test]
+    fn plugin_failure() {
+        let error = builder().with_plugin(GroundPlugin).with_plugin(StonePlugin).build().err().unwrap();
+
+        let error = format!("{:#}", error);
+        assert!(error.contains("Plugin \"more_stone\" failed to build"), "{}", error);
+        assert!(error.contains("Block \"core:stone\" was already added"), "{}", error);
+    }
+}
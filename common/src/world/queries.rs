@@ -0,0 +1,597 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Asking the world what's where: raycasts, shape casts and overlap tests against entities and the voxel terrain.
+//! Everything here is in physics space, relative to the [super::FloatingOrigin].
+
+use super::{
+    components::{OwnedRigidBodies, RigidBody},
+    storage, BlockID, ChunkCoordinateEXT, GlobalBlockCoordinate, GridWorld, PhysicsVector,
+};
+use legion::{system, Entity, EntityStore};
+use nalgebra::{Isometry3, Point3, Translation3, Vector3};
+use rapier3d::{
+    dynamics::{RigidBodyHandle, RigidBodySet},
+    geometry::{Collider, ColliderHandle, ColliderSet, InteractionGroups, Ray},
+    parry::{
+        bounding_volume::{BoundingVolume, AABB},
+        query,
+        shape::{Cuboid, Shape},
+    },
+    pipeline::QueryPipeline,
+};
+
+/// Decides what a query can hit.
+#[derive(Debug, Clone)]
+pub struct QueryFilter {
+    groups: InteractionGroups,
+    terrain: bool,
+    sensors: bool,
+    excluded: Vec<Entity>,
+}
+
+impl Default for QueryFilter {
+    fn default() -> Self {
+        QueryFilter { groups: InteractionGroups::all(), terrain: true, sensors: false, excluded: Vec::new() }
+    }
+}
+
+impl QueryFilter {
+    /// Create a filter that hits the terrain and every solid collider.
+    pub fn new() -> QueryFilter {
+        QueryFilter::default()
+    }
+
+    /// Only hit colliders whose collision groups match these.
+    pub fn with_groups(mut self, groups: InteractionGroups) -> QueryFilter {
+        self.groups = groups;
+        self
+    }
+
+    /// Choose whether the voxel terrain can be hit.
+    pub fn with_terrain(mut self, terrain: bool) -> QueryFilter {
+        self.terrain = terrain;
+        self
+    }
+
+    /// Choose whether sensors can be hit. They're skipped by default, since trigger volumes shouldn't block sight.
+    pub fn with_sensors(mut self, sensors: bool) -> QueryFilter {
+        self.sensors = sensors;
+        self
+    }
+
+    /// Never hit this entity, such as the one doing the looking.
+    pub fn excluding(mut self, entity: Entity) -> QueryFilter {
+        self.excluded.push(entity);
+        self
+    }
+
+    /// The collision groups colliders must match.
+    pub fn groups(&self) -> InteractionGroups {
+        self.groups
+    }
+
+    /// Check if the voxel terrain can be hit.
+    pub fn hits_terrain(&self) -> bool {
+        self.terrain
+    }
+
+    /// Check if sensors can be hit.
+    pub fn hits_sensors(&self) -> bool {
+        self.sensors
+    }
+}
+
+/// Something a query hit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueryTarget {
+    /// A collider of an entity with a [RigidBody].
+    Entity {
+        /// The entity that was hit.
+        entity: Entity,
+
+        /// Which of its colliders was hit.
+        collider: ColliderHandle,
+    },
+
+    /// A block of the terrain.
+    Terrain {
+        /// Where the block is in the world.
+        location: GlobalBlockCoordinate,
+
+        /// What type of block it is.
+        block: BlockID,
+    },
+
+    /// A collider that was added to the physics engine without going through a [RigidBody] component.
+    Collider(ColliderHandle),
+}
+
+impl QueryTarget {
+    /// Get the entity that was hit, if it was one.
+    pub fn entity(&self) -> Option<Entity> {
+        match self {
+            QueryTarget::Entity { entity, .. } => Some(*entity),
+            _ => None,
+        }
+    }
+}
+
+/// Where a ray hit something.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RayHit {
+    /// What the ray hit.
+    pub target: QueryTarget,
+
+    /// How far the ray went before it hit.
+    pub distance: f32,
+
+    /// Where the ray hit.
+    pub point: PhysicsVector,
+
+    /// The direction the surface that was hit is facing. If the ray started inside of what it hit, this just points
+    /// back along the ray.
+    pub normal: PhysicsVector,
+}
+
+/// Where a shape cast hit something.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ShapeHit {
+    /// What the shape hit.
+    pub target: QueryTarget,
+
+    /// How far the shape went before it hit.
+    pub distance: f32,
+
+    /// Where the shape was when it hit.
+    pub position: Isometry3<f32>,
+}
+
+/// Keeps the query pipeline up to date with where everything ended up after the physics step.
+#[system]
+pub(super) fn ecs_update_query_pipeline(
+    #[resource] query_pipeline: &mut QueryPipeline, #[resource] rigid_bodies: &RigidBodySet,
+    #[resource] colliders: &ColliderSet,
+) {
+    query_pipeline.update(rigid_bodies, colliders);
+}
+
+/// The shape of a block, centered on the block.
+fn block_shape() -> Cuboid {
+    Cuboid::new(Vector3::repeat(0.5))
+}
+
+impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
+    /// Bring the query pipeline up to date. This is done after every step, so it's only needed to find colliders that
+    /// were added since then. Bodies that were moved by hand are still found where they were until the next step.
+    pub fn update_query_pipeline(&mut self) {
+        let rigid_bodies = self.ecs_resources.get::<RigidBodySet>().expect("Failed to find rigid body set.");
+        let colliders = self.ecs_resources.get::<ColliderSet>().expect("Failed to find collider set.");
+        let mut query_pipeline = self.ecs_resources.get_mut::<QueryPipeline>().expect("Failed to find query pipeline.");
+
+        query_pipeline.update(&rigid_bodies, &colliders);
+    }
+
+    /// Find the first thing a ray hits. The direction doesn't need to be normalized.
+    pub fn cast_ray(
+        &self, origin: PhysicsVector, direction: PhysicsVector, max_distance: f32, filter: &QueryFilter,
+    ) -> Option<RayHit> {
+        let direction = direction.try_normalize(f32::EPSILON)?;
+
+        let entity_hit = self.query_colliders(filter, |query_pipeline, colliders, collider_filter| {
+            let ray = Ray::new(Point3::from(origin), direction);
+            query_pipeline
+                .cast_ray_and_get_normal(colliders, &ray, max_distance, true, filter.groups, Some(collider_filter))
+                .map(|(collider, intersection)| {
+                    let normal = if intersection.toi > 0.0 { intersection.normal } else { -direction };
+                    (collider, intersection.toi, normal)
+                })
+        });
+        let entity_hit = entity_hit.map(|(collider, distance, normal)| RayHit {
+            target: self.collider_target(collider),
+            distance,
+            point: origin + direction * distance,
+            normal,
+        });
+
+        let terrain_hit = if filter.terrain { self.cast_ray_terrain(origin, direction, max_distance) } else { None };
+
+        nearest(entity_hit, terrain_hit, |hit| hit.distance)
+    }
+
+    /// Find the first thing a shape hits as it moves in a straight line, without rotating. The direction doesn't need
+    /// to be normalized. Shape casts against the terrain test every block in the swept volume, so keep them short.
+    pub fn cast_shape(
+        &self, shape: &dyn Shape, position: &Isometry3<f32>, direction: PhysicsVector, max_distance: f32, filter: &QueryFilter,
+    ) -> Option<ShapeHit> {
+        let direction = direction.try_normalize(f32::EPSILON)?;
+
+        let entity_hit = self.query_colliders(filter, |query_pipeline, colliders, collider_filter| {
+            query_pipeline
+                .cast_shape(colliders, position, &direction, shape, max_distance, filter.groups, Some(collider_filter))
+                .map(|(collider, toi)| (collider, toi.toi))
+        });
+        let entity_hit = entity_hit.map(|(collider, distance)| (self.collider_target(collider), distance));
+
+        let terrain_hit = if filter.terrain {
+            let start = shape.compute_aabb(position);
+            let end = shape.compute_aabb(&(Translation3::from(direction * max_distance) * position));
+
+            self.terrain_blocks_in(&start.merged(&end))
+                .into_iter()
+                .filter_map(|(target, block_position)| {
+                    query::time_of_impact(
+                        position,
+                        &direction,
+                        shape,
+                        &block_position,
+                        &Vector3::zeros(),
+                        &block_shape(),
+                        max_distance,
+                    )
+                    .ok()
+                    .flatten()
+                    .map(|toi| (target, toi.toi))
+                })
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        } else {
+            None
+        };
+
+        nearest(entity_hit, terrain_hit, |(_, distance)| *distance).map(|(target, distance)| ShapeHit {
+            target,
+            distance,
+            position: Translation3::from(direction * distance) * position,
+        })
+    }
+
+    /// Find everything a shape overlaps. Entities with several colliders in the shape show up once for each of them.
+    pub fn overlapping(&self, shape: &dyn Shape, position: &Isometry3<f32>, filter: &QueryFilter) -> Vec<QueryTarget> {
+        let mut targets = Vec::new();
+
+        let colliders_hit = self.query_colliders(filter, |query_pipeline, colliders, collider_filter| {
+            let mut hit = Vec::new();
+            query_pipeline.intersections_with_shape(
+                colliders,
+                position,
+                shape,
+                filter.groups,
+                Some(collider_filter),
+                |collider, _| {
+                    hit.push(collider);
+                    true
+                },
+            );
+
+            Some(hit)
+        });
+        targets.extend(colliders_hit.unwrap_or_default().into_iter().map(|collider| self.collider_target(collider)));
+
+        if filter.terrain {
+            targets.extend(
+                self.terrain_blocks_in(&shape.compute_aabb(position))
+                    .into_iter()
+                    .filter(|(_, block_position)| {
+                        query::intersection_test(position, shape, block_position, &block_shape()).unwrap_or(false)
+                    })
+                    .map(|(target, _)| target),
+            );
+        }
+
+        targets
+    }
+
+    /// Run a query against the query pipeline, with the filter turned into something it understands.
+    fn query_colliders<T>(
+        &self, filter: &QueryFilter,
+        query: impl FnOnce(&QueryPipeline, &ColliderSet, &dyn Fn(ColliderHandle, &Collider) -> bool) -> Option<T>,
+    ) -> Option<T> {
+        let excluded: Vec<RigidBodyHandle> = filter
+            .excluded
+            .iter()
+            .filter_map(|entity| self.ecs_world.entry_ref(*entity).ok())
+            .filter_map(|entry| entry.get_component::<RigidBody>().ok().map(|rigid_body| rigid_body.handle()))
+            .collect();
+        let collider_filter = |_handle: ColliderHandle, collider: &Collider| {
+            (filter.sensors || !collider.is_sensor()) && !excluded.contains(&collider.parent())
+        };
+
+        let query_pipeline = self.ecs_resources.get::<QueryPipeline>().expect("Failed to find query pipeline.");
+        let colliders = self.ecs_resources.get::<ColliderSet>().expect("Failed to find collider set.");
+
+        query(&query_pipeline, &colliders, &collider_filter)
+    }
+
+    /// Work out which entity a collider belongs to.
+    fn collider_target(&self, collider: ColliderHandle) -> QueryTarget {
+        let colliders = self.ecs_resources.get::<ColliderSet>().expect("Failed to find collider set.");
+        let mut owned_bodies = self.ecs_resources.get_mut::<OwnedRigidBodies>().expect("Failed to find owned rigid bodies.");
+        let entity = colliders.get(collider).and_then(|found| owned_bodies.entity_of(found.parent(), &self.ecs_world));
+
+        match entity {
+            Some(entity) => QueryTarget::Entity { entity, collider },
+            None => QueryTarget::Collider(collider),
+        }
+    }
+
+    /// Walk a ray through the terrain one block at a time until it finds one that isn't air.
+    /// The direction must be normalized. The walk stops where the ray leaves the loaded chunks, since there's nothing
+    /// to hit past them, so it ends even if the distance is infinite.
+    fn cast_ray_terrain(&self, origin: PhysicsVector, direction: PhysicsVector, max_distance: f32) -> Option<RayHit> {
+        let physics_origin = self.physics_origin();
+        let (low, high) = self.loaded_terrain_bounds()?;
+        let (low, high) = ((low - physics_origin).map(|v| v as f32), (high - physics_origin).map(|v| v as f32 + 1.0));
+
+        // How far the ray goes before it leaves the box around the loaded chunks for good.
+        let exit = (0..3)
+            .map(|axis| {
+                if direction[axis] > 0.0 {
+                    (high[axis] - origin[axis]) / direction[axis]
+                } else if direction[axis] < 0.0 {
+                    (low[axis] - origin[axis]) / direction[axis]
+                } else if origin[axis] >= low[axis] && origin[axis] <= high[axis] {
+                    f32::INFINITY
+                } else {
+                    f32::NEG_INFINITY
+                }
+            })
+            .fold(f32::INFINITY, f32::min);
+        let max_distance = max_distance.min(exit);
+
+        let mut cell = origin.map(|v| v.floor() as i64);
+        let step = direction.map(|v| v.signum() as i64);
+        let delta = direction.map(|v| if v != 0.0 { 1.0 / v.abs() } else { f32::INFINITY });
+        let mut next = Vector3::from_fn(|axis, _| {
+            if direction[axis] > 0.0 {
+                ((cell[axis] + 1) as f32 - origin[axis]) * delta[axis]
+            } else if direction[axis] < 0.0 {
+                (origin[axis] - cell[axis] as f32) * delta[axis]
+            } else {
+                f32::INFINITY
+            }
+        });
+
+        let mut distance = 0.0;
+        let mut normal = -direction;
+
+        while distance <= max_distance {
+            let location = cell + physics_origin;
+            if let Some(Some(block)) = self.get_block(location) {
+                return Some(RayHit {
+                    target: QueryTarget::Terrain { location, block },
+                    distance,
+                    point: origin + direction * distance,
+                    normal,
+                });
+            }
+
+            let axis = next.imin();
+            distance = next[axis];
+            next[axis] += delta[axis];
+            cell[axis] += step[axis];
+
+            normal = PhysicsVector::zeros();
+            normal[axis] = -step[axis] as f32;
+        }
+
+        None
+    }
+
+    /// The lowest and highest blocks of the box around every loaded chunk. None if no chunks are loaded.
+    fn loaded_terrain_bounds(&self) -> Option<(GlobalBlockCoordinate, GlobalBlockCoordinate)> {
+        let mut chunks = self.terrain_chunks.keys();
+        let first = chunks.next()?.to_block_coordinate();
+        let (low, high) = chunks.fold((first, first), |(low, high), chunk| {
+            let corner = chunk.to_block_coordinate();
+            (low.inf(&corner), high.sup(&corner))
+        });
+
+        Some((low, high.add_scalar(storage::CHUNK_DIAMETER as i64 - 1)))
+    }
+
+    /// Find the blocks of terrain that aren't air within a box, along with where they are in physics space.
+    fn terrain_blocks_in(&self, aabb: &AABB) -> Vec<(QueryTarget, Isometry3<f32>)> {
+        let physics_origin = self.physics_origin();
+        let low = aabb.mins.coords.map(|v| v.floor() as i64);
+        let high = aabb.maxs.coords.map(|v| v.floor() as i64);
+
+        let mut blocks = Vec::new();
+        for x in low.x..=high.x {
+            for y in low.y..=high.y {
+                for z in low.z..=high.z {
+                    let cell = GlobalBlockCoordinate::new(x, y, z);
+                    let location = cell + physics_origin;
+
+                    if let Some(Some(block)) = self.get_block(location) {
+                        let center = cell.map(|v| v as f32 + 0.5);
+                        blocks.push((QueryTarget::Terrain { location, block }, Isometry3::from(Translation3::from(center))));
+                    }
+                }
+            }
+        }
+
+        blocks
+    }
+}
+
+/// Pick whichever hit is closer.
+fn nearest<T>(first: Option<T>, second: Option<T>, distance: impl Fn(&T) -> f32) -> Option<T> {
+    match (first, second) {
+        (Some(first), Some(second)) => {
+            if distance(&second) < distance(&first) {
+                Some(second)
+            } else {
+                Some(first)
+            }
+        }
+        (first, second) => first.or(second),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use rapier3d::{
+        dynamics::RigidBodyBuilder,
+        geometry::{ColliderBuilder, SharedShape},
+    };
+    use std::time::Duration;
+
+    /// Create a world with flat terrain whose surface is at y = 0, and a ball floating above it at 0x5x0.
+    fn world_with_ball() -> (GridWorld<()>, Entity) {
+        let mut block_registry = BlockRegistry::new();
+        chunk_providers::register_blocks(&mut block_registry, chunk_providers::AbstractFlatWorld::BLOCKS).unwrap();
+        let mut chunk_provider = chunk_providers::RAMWorld::new(block_registry);
+        chunk_provider.add_generator(chunk_providers::AbstractFlatWorld::new()).unwrap();
+
+        let mut world: GridWorld<()> = GridWorld::new(chunk_provider);
+        for x in -1..=0 {
+            for y in -1..=0 {
+                for z in -1..=0 {
+                    world.load_chunk(ChunkCoordinate::new(x, y, z));
+                }
+            }
+        }
+
+        let ball =
+            RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_kinematic().translation(0.0, 5.0, 0.0).build());
+        ball.add_collider(ColliderBuilder::ball(1.0).build(), world.ecs_resources_mut());
+        let ball = world.ecs_world_mut().push((ball,));
+
+        world.update(Duration::from_millis(10));
+
+        (world, ball)
+    }
+
+    /// Rays hit entities and the terrain, whichever is closer, and can be told to skip either.
+    #[test]
+    fn raycast() {
+        let (world, ball) = world_with_ball();
+        let down = PhysicsVector::new(0.0, -1.0, 0.0);
+
+        let hit = world.cast_ray(PhysicsVector::new(0.0, 10.0, 0.0), down, 100.0, &QueryFilter::new()).unwrap();
+        assert_eq!(hit.target.entity(), Some(ball));
+        assert!((hit.distance - 4.0).abs() < 1.0e-4);
+        assert!((hit.normal - PhysicsVector::new(0.0, 1.0, 0.0)).norm() < 1.0e-4);
+
+        let hit = world.cast_ray(PhysicsVector::new(0.0, 10.0, 0.0), down, 100.0, &QueryFilter::new().excluding(ball)).unwrap();
+        assert!(
+            matches!(hit.target, QueryTarget::Terrain { location, .. } if location == GlobalBlockCoordinate::new(0, -1, 0))
+        );
+        assert!((hit.distance - 10.0).abs() < 1.0e-4);
+        assert_eq!(hit.normal, PhysicsVector::new(0.0, 1.0, 0.0));
+
+        // Off to the side, it misses the ball and goes straight to the ground.
+        let hit = world.cast_ray(PhysicsVector::new(3.5, 10.0, 0.5), down, 100.0, &QueryFilter::new()).unwrap();
+        assert!(matches!(hit.target, QueryTarget::Terrain { .. }));
+
+        // Too short to reach anything.
+        assert!(world.cast_ray(PhysicsVector::new(0.0, 10.0, 0.0), down, 3.0, &QueryFilter::new()).is_none());
+
+        // Sideways, without the terrain, there is nothing to hit.
+        let sideways = PhysicsVector::new(1.0, 0.0, 0.0);
+        let filter = QueryFilter::new().with_terrain(false);
+        assert!(world.cast_ray(PhysicsVector::new(-10.0, 0.5, 0.0), sideways, 100.0, &filter).is_none());
+        let hit = world.cast_ray(PhysicsVector::new(-10.0, 5.0, 0.0), sideways, 100.0, &filter).unwrap();
+        assert_eq!(hit.target.entity(), Some(ball));
+
+        // A diagonal ray walks through the terrain correctly.
+        let hit = world.cast_ray(
+            PhysicsVector::new(0.5, 3.0, 0.5),
+            PhysicsVector::new(1.0, -1.0, 0.0),
+            100.0,
+            &filter.with_terrain(true),
+        );
+        let hit = hit.unwrap();
+        assert!(
+            matches!(hit.target, QueryTarget::Terrain { location, .. } if location == GlobalBlockCoordinate::new(3, -1, 0))
+        );
+        assert!((hit.point.y - 0.0).abs() < 1.0e-4);
+    }
+
+    /// Rays that are allowed to go forever stop where the loaded chunks end.
+    #[test]
+    fn infinite_ray() {
+        let (world, _ball) = world_with_ball();
+        let sideways = PhysicsVector::new(1.0, 0.0, 0.0);
+        let filter = QueryFilter::new();
+
+        // There's nothing but air above the ground.
+        let up = PhysicsVector::new(0.0, 1.0, 0.0);
+        assert!(world.cast_ray(PhysicsVector::new(3.5, 10.0, 3.5), up, f32::INFINITY, &filter).is_none());
+        assert!(world.cast_ray(PhysicsVector::new(-10.0, 0.5, 0.5), sideways, f32::INFINITY, &filter).is_none());
+
+        // Starting past the loaded chunks, a ray pointed away from them has nothing to walk through.
+        assert!(world.cast_ray(PhysicsVector::new(100.0, -5.0, 0.5), sideways, f32::INFINITY, &filter).is_none());
+
+        // And one pointed toward them walks in from outside.
+        let hit = world.cast_ray(PhysicsVector::new(-100.0, -5.0, 0.5), sideways, f32::INFINITY, &filter).unwrap();
+        assert!(
+            matches!(hit.target, QueryTarget::Terrain { location, .. } if location == GlobalBlockCoordinate::new(-32, -5, 0))
+        );
+    }
+
+    /// Collision groups keep rays from hitting colliders outside of them.
+    #[test]
+    fn groups() {
+        let (mut world, ball) = world_with_ball();
+
+        let shy = RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_static().translation(0.0, 8.0, 0.0).build());
+        shy.add_collider(
+            ColliderBuilder::ball(0.5).collision_groups(InteractionGroups::new(0b10, 0b10)).build(),
+            world.ecs_resources_mut(),
+        );
+        world.ecs_world_mut().push((shy,));
+        world.update_query_pipeline();
+
+        let filter = QueryFilter::new().with_groups(InteractionGroups::new(0b1, 0b1));
+        let hit = world.cast_ray(PhysicsVector::new(0.0, 10.0, 0.0), PhysicsVector::new(0.0, -1.0, 0.0), 100.0, &filter);
+        assert_eq!(hit.unwrap().target.entity(), Some(ball));
+    }
+
+    /// Shape casts stop at the nearest thing in the way.
+    #[test]
+    fn shape_cast() {
+        let (world, ball) = world_with_ball();
+        let cube = SharedShape::cuboid(0.5, 0.5, 0.5);
+        let down = PhysicsVector::new(0.0, -1.0, 0.0);
+
+        let start = Isometry3::translation(0.0, 10.0, 0.0);
+        let hit = world.cast_shape(&*cube, &start, down, 100.0, &QueryFilter::new()).unwrap();
+        assert_eq!(hit.target.entity(), Some(ball));
+        assert!((hit.distance - 3.5).abs() < 1.0e-3);
+        assert!((hit.position.translation.y - 6.5).abs() < 1.0e-3);
+
+        let start = Isometry3::translation(4.0, 3.0, 0.0);
+        let hit = world.cast_shape(&*cube, &start, down, 10.0, &QueryFilter::new()).unwrap();
+        assert!(matches!(hit.target, QueryTarget::Terrain { .. }));
+        assert!((hit.distance - 2.5).abs() < 1.0e-3);
+    }
+
+    /// Overlap tests find every entity and block a shape touches.
+    #[test]
+    fn overlap() {
+        let (world, ball) = world_with_ball();
+
+        let targets = world.overlapping(&*SharedShape::ball(1.0), &Isometry3::translation(0.0, 5.5, 0.0), &QueryFilter::new());
+        assert_eq!(targets.iter().filter_map(QueryTarget::entity).collect::<Vec<_>>(), vec![ball]);
+        assert_eq!(targets.len(), 1);
+
+        // Sitting on the ground, a cube overlaps the four blocks under its corners.
+        let targets = world.overlapping(
+            &*SharedShape::cuboid(0.5, 0.5, 0.5),
+            &Isometry3::translation(0.0, 0.4, 0.0),
+            &QueryFilter::new(),
+        );
+        assert_eq!(targets.len(), 4);
+        assert!(targets.iter().all(|target| matches!(target, QueryTarget::Terrain { .. })));
+
+        let targets = world.overlapping(
+            &*SharedShape::cuboid(0.5, 0.5, 0.5),
+            &Isometry3::translation(0.0, 0.4, 0.0),
+            &QueryFilter::new().with_terrain(false),
+        );
+        assert!(targets.is_empty());
+    }
+}
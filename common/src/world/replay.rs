@@ -0,0 +1,703 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Recording everything done to a world from the outside, so that a run can be replayed exactly from a snapshot.
+//! Used to track down desyncs, and to review what players did.
+//!
+//! The simulation itself is deterministic, so the only things that need recording are the inputs to it, along with the
+//! tick they were applied on. A hash of the world's state is also recorded after every tick, so a replay can find the
+//! first tick where it stopped matching the original run.
+
+use super::{
+    components::RigidBody, BlockID, CharacterController, CharacterInput, CharacterSettings, ChunkCoordinate, Fnv1a,
+    GameCalendar, GlobalBlockCoordinate, GridWorld, PhysicsVector, SimulationSettings, SnapshotRegistry, WorldTime,
+};
+use anyhow::{ensure, Context, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use legion::{serialize::set_entity_serializer, serialize::Canon, Entity, IntoQuery};
+use nalgebra::Isometry3;
+use rapier3d::{
+    dynamics::RigidBodySet,
+    geometry::{Collider, SharedShape},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    io::{Read, Write},
+};
+
+/// The version of the recording format. Recordings of other versions can't be replayed.
+pub const RECORDING_VERSION: u32 = 1;
+
+/// Something done to a world from the outside.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum WorldInput {
+    /// Place a built block, as a player would.
+    PlaceBlock {
+        /// Where the block goes.
+        location: GlobalBlockCoordinate,
+
+        /// The block to place.
+        block: BlockID,
+    },
+
+    /// Break a block.
+    BreakBlock {
+        /// Where the block is.
+        location: GlobalBlockCoordinate,
+    },
+
+    /// Change what a character is trying to do.
+    SetCharacterInput {
+        /// The character.
+        character: Entity,
+
+        /// What the character is trying to do.
+        input: CharacterInput,
+    },
+
+    /// Spawn a character.
+    SpawnCharacter {
+        /// Where its feet are, in physics space.
+        feet: PhysicsVector,
+
+        /// How it moves.
+        settings: CharacterSettings,
+    },
+
+    /// Spawn a trigger volume.
+    SpawnTriggerVolume {
+        /// The shape of the volume.
+        shape: SharedShape,
+
+        /// Where it is, in physics space.
+        position: Isometry3<f32>,
+    },
+
+    /// Spawn an entity with a rigid body, such as a dropped item.
+    SpawnRigidBody {
+        /// The body, as it goes into the physics engine.
+        body: Box<rapier3d::dynamics::RigidBody>,
+
+        /// The body's colliders.
+        colliders: Vec<Collider>,
+    },
+
+    /// Load a chunk, generating it if it hasn't been yet.
+    LoadChunk {
+        /// The chunk.
+        index: ChunkCoordinate,
+    },
+
+    /// Unload a chunk, saving it if the world has chunk storage.
+    UnloadChunk {
+        /// The chunk.
+        index: ChunkCoordinate,
+    },
+
+    /// Schedule a tick for a block.
+    ScheduleTick {
+        /// Where the block is.
+        location: GlobalBlockCoordinate,
+
+        /// When the tick happens.
+        time: WorldTime,
+    },
+
+    /// Remove an entity.
+    RemoveEntity {
+        /// The entity to remove.
+        entity: Entity,
+    },
+
+    /// Change how fast the simulation runs. Usually done by an admin.
+    SetSimulationSettings(SimulationSettings),
+
+    /// Change the calendar. Usually done by an admin.
+    SetCalendar(GameCalendar),
+}
+
+impl WorldInput {
+    /// Swap the entities the input refers to, for entities that have a different ID in a replay.
+    fn map_entities(self, entities: &HashMap<Entity, Entity>) -> WorldInput {
+        let map = |entity: Entity| entities.get(&entity).copied().unwrap_or(entity);
+
+        match self {
+            WorldInput::SetCharacterInput { character, input } => {
+                WorldInput::SetCharacterInput { character: map(character), input }
+            }
+            WorldInput::RemoveEntity { entity } => WorldInput::RemoveEntity { entity: map(entity) },
+            input => input,
+        }
+    }
+}
+
+/// An input, and when it was applied.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecordedInput {
+    /// The number of steps the world had taken when the input was applied.
+    pub tick: u64,
+
+    /// The input.
+    pub input: WorldInput,
+
+    /// The entity the input spawned, if it spawned one.
+    pub spawned: Option<Entity>,
+
+    /// True if the input was applied part way through the step, while block ticks were running, such as by a mod's
+    /// tick handler. Replays apply these right after the block ticks of the step.
+    pub during_block_ticks: bool,
+}
+
+/// A hash of the world's state after each tick of a run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateHashes {
+    first_tick: u64,
+    hashes: Vec<u64>,
+}
+
+impl StateHashes {
+    /// Create an empty list of hashes, where the first one pushed will be for the given tick.
+    pub fn new(first_tick: u64) -> StateHashes {
+        StateHashes { first_tick, hashes: Vec::new() }
+    }
+
+    /// Add the hash for the next tick.
+    pub fn push(&mut self, hash: u64) {
+        self.hashes.push(hash);
+    }
+
+    /// Get the hash for a tick, or None if it isn't in this run.
+    pub fn get(&self, tick: u64) -> Option<u64> {
+        tick.checked_sub(self.first_tick).and_then(|index| self.hashes.get(index as usize)).copied()
+    }
+
+    /// The tick of the first hash.
+    pub fn first_tick(&self) -> u64 {
+        self.first_tick
+    }
+
+    /// The tick after the last hash.
+    pub fn end_tick(&self) -> u64 {
+        self.first_tick + self.hashes.len() as u64
+    }
+
+    /// The number of hashes.
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Check if there are no hashes.
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Compare two runs tick by tick, and find the first tick where their states differ. Only ticks that are in both
+    /// runs are compared, so None means the runs agree for as long as they overlap.
+    pub fn first_divergence(&self, other: &StateHashes) -> Option<u64> {
+        (self.first_tick.max(other.first_tick)..self.end_tick().min(other.end_tick()))
+            .find(|tick| self.get(*tick) != other.get(*tick))
+    }
+}
+
+/// Inputs recorded from a world, starting from a snapshot of it. Replay it with [GridWorld::replay].
+pub struct InputRecording {
+    // The snapshot is kept as written by GridWorld::write_snapshot, so that it can go straight into the recording's
+    // own stream.
+    snapshot: Vec<u8>,
+    canon: Canon,
+    inputs: Vec<RecordedInput>,
+    state_hashes: StateHashes,
+    end_tick: u64,
+}
+
+/// What a recording looks like when it's written, besides the snapshot.
+#[derive(Serialize, Deserialize)]
+struct RecordingHeader {
+    inputs: Vec<RecordedInput>,
+    state_hashes: StateHashes,
+    end_tick: u64,
+}
+
+impl InputRecording {
+    /// The inputs, in the order they were applied.
+    pub fn inputs(&self) -> &[RecordedInput] {
+        &self.inputs
+    }
+
+    /// The hash of the world's state after each tick of the recorded run.
+    pub fn state_hashes(&self) -> &StateHashes {
+        &self.state_hashes
+    }
+
+    /// The tick the recording starts on.
+    pub fn start_tick(&self) -> u64 {
+        self.state_hashes.first_tick()
+    }
+
+    /// The tick the recording was stopped on.
+    pub fn end_tick(&self) -> u64 {
+        self.end_tick
+    }
+
+    /// Write the recording out, compressed, such as to attach it to a bug report.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let header =
+            RecordingHeader { inputs: self.inputs.clone(), state_hashes: self.state_hashes.clone(), end_tick: self.end_tick };
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        serde_cbor::to_writer(&mut encoder, &RECORDING_VERSION).context("Error writing recording version.")?;
+        set_entity_serializer(&self.canon, || serde_cbor::to_writer(&mut encoder, &header))
+            .context("Error writing recorded inputs.")?;
+        encoder.write_all(&self.snapshot).context("Error writing recording snapshot.")?;
+
+        encoder.finish().context("Error compressing recording.")
+    }
+
+    /// Read a recording written by [InputRecording::to_bytes].
+    pub fn from_bytes(bytes: &[u8]) -> Result<InputRecording> {
+        let mut decompressed = Vec::new();
+        DeflateDecoder::new(bytes).read_to_end(&mut decompressed).context("Error decompressing recording.")?;
+        let mut deserializer = serde_cbor::Deserializer::from_slice(&decompressed);
+
+        let version = u32::deserialize(&mut deserializer).context("Error reading recording version.")?;
+        ensure!(
+            version == RECORDING_VERSION,
+            "Recording is version {}, but only version {} is supported.",
+            version,
+            RECORDING_VERSION
+        );
+
+        let canon = Canon::default();
+        let header: RecordingHeader = set_entity_serializer(&canon, || RecordingHeader::deserialize(&mut deserializer))
+            .context("Error reading recorded inputs.")?;
+
+        // The snapshot is whatever is left. It's checked when the recording is replayed.
+        let snapshot = decompressed[deserializer.byte_offset()..].to_vec();
+
+        Ok(InputRecording {
+            snapshot,
+            canon,
+            inputs: header.inputs,
+            state_hashes: header.state_hashes,
+            end_tick: header.end_tick,
+        })
+    }
+}
+
+/// A recording in progress.
+pub(super) struct InputRecorder {
+    recording: InputRecording,
+    in_block_ticks: bool,
+}
+
+impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
+    /// A hash of the state of the simulation: the time, random ticks, loaded terrain, rigid bodies and characters. Two
+    /// worlds that have run the same way have the same hash. Hashes don't change between builds, but can between
+    /// platforms with different endianness or pointer widths.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+        let hash_floats = |hasher: &mut Fnv1a, values: &[f32]| {
+            for value in values {
+                value.to_bits().hash(hasher);
+            }
+        };
+
+        self.time.hash(&mut hasher);
+        self.timestep.steps().hash(&mut hasher);
+        self.tick_scheduler.random_state().hash(&mut hasher);
+
+        let mut chunks: Vec<_> = self.terrain_chunks.values().collect();
+        chunks.sort_by_key(|chunk| {
+            let index = chunk.index();
+            (index.x, index.y, index.z)
+        });
+        for chunk in chunks {
+            chunk.index().as_slice().hash(&mut hasher);
+            chunk.data().get_data().hash(&mut hasher);
+        }
+
+        let rigid_bodies = self.ecs_resources.get::<RigidBodySet>().expect("Failed to find rigid body set.");
+        for (handle, rigid_body) in rigid_bodies.iter() {
+            handle.hash(&mut hasher);
+
+            let position = rigid_body.position();
+            hash_floats(&mut hasher, position.translation.vector.as_slice());
+            hash_floats(&mut hasher, position.rotation.coords.as_slice());
+            hash_floats(&mut hasher, rigid_body.linvel().as_slice());
+            hash_floats(&mut hasher, rigid_body.angvel().as_slice());
+        }
+
+        // Entities can be stored in any order, so characters are gone through in the order of their bodies.
+        let mut query = <(&RigidBody, &CharacterController)>::query();
+        let mut characters: Vec<_> = query.iter(&self.ecs_world).collect();
+        characters.sort_by_key(|(rigid_body, _)| rigid_body.handle().into_raw_parts());
+        for (rigid_body, controller) in characters {
+            rigid_body.handle().hash(&mut hasher);
+            hash_floats(&mut hasher, controller.velocity().as_slice());
+            (controller.is_grounded(), controller.is_crouching()).hash(&mut hasher);
+        }
+
+        self.ecs_world.len().hash(&mut hasher);
+
+        hasher.finish()
+    }
+
+    /// Apply an input to the world, and record it if a recording is in progress. Returns the entity the input spawned,
+    /// if it spawned one.
+    pub fn apply_input(&mut self, input: WorldInput) -> Option<Entity> {
+        let spawned = self.apply_input_unrecorded(input.clone());
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.recording.inputs.push(RecordedInput {
+                tick: self.timestep.steps(),
+                input,
+                spawned,
+                during_block_ticks: recorder.in_block_ticks,
+            });
+        }
+
+        spawned
+    }
+
+    fn apply_input_unrecorded(&mut self, input: WorldInput) -> Option<Entity> {
+        match input {
+            WorldInput::PlaceBlock { location, block } => {
+                self.place_built_block(location, block);
+                None
+            }
+            WorldInput::BreakBlock { location } => {
+                self.break_block(location);
+                None
+            }
+            WorldInput::SetCharacterInput { character, input } => {
+                if let Some(mut entry) = self.ecs_world.entry(character) {
+                    if let Ok(character_input) = entry.get_component_mut::<CharacterInput>() {
+                        *character_input = input;
+                    }
+                }
+                None
+            }
+            WorldInput::SpawnCharacter { feet, settings } => Some(self.spawn_character(feet, settings)),
+            WorldInput::SpawnTriggerVolume { shape, position } => Some(self.spawn_trigger_volume(shape, position)),
+            WorldInput::SpawnRigidBody { body, colliders } => Some(self.spawn_rigid_body(*body, colliders)),
+            WorldInput::LoadChunk { index } => {
+                if let Err(error) = self.try_load_chunk(index) {
+                    log::warn!("Failed to load chunk {:?}: {:?}", index, error);
+                }
+                None
+            }
+            WorldInput::UnloadChunk { index } => {
+                if let Err(error) = self.unload_chunk(index) {
+                    log::warn!("Failed to unload chunk {:?}: {:?}", index, error);
+                }
+                None
+            }
+            WorldInput::ScheduleTick { location, time } => {
+                if let Err(error) = self.schedule_tick(location, time) {
+                    log::warn!("Failed to schedule a tick at {:?}: {:?}", location, error);
+                }
+                None
+            }
+            WorldInput::RemoveEntity { entity } => {
+                self.remove_entity(entity);
+                None
+            }
+            WorldInput::SetSimulationSettings(settings) => {
+                self.set_simulation_settings(settings);
+                None
+            }
+            WorldInput::SetCalendar(calendar) => {
+                self.set_calendar(calendar);
+                None
+            }
+        }
+    }
+
+    /// Check if inputs are being recorded.
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Stop recording, and get what was recorded. Returns None if nothing was being recorded.
+    pub fn stop_recording(&mut self) -> Option<InputRecording> {
+        let steps = self.timestep.steps();
+
+        self.recorder.take().map(|recorder| InputRecording { end_tick: steps, ..recorder.recording })
+    }
+
+    /// Note whether block ticks are running, so that inputs applied while they do are marked as such.
+    pub(super) fn mark_block_ticks(&mut self, running: bool) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.in_block_ticks = running;
+        }
+    }
+
+    /// Apply a recorded input during a replay. Later inputs refer to spawned entities by the ID they had in the
+    /// recorded run, so those are mapped to the entities spawned in the replay.
+    fn replay_input(&mut self, recorded: &RecordedInput, spawned_entities: &mut HashMap<Entity, Entity>) {
+        let spawned = self.apply_input_unrecorded(recorded.input.clone().map_entities(spawned_entities));
+
+        if let (Some(recorded_entity), Some(entity)) = (recorded.spawned, spawned) {
+            spawned_entities.insert(recorded_entity, entity);
+        }
+    }
+
+    /// Record the state hash after a step, if a recording is in progress.
+    pub(super) fn record_step(&mut self) {
+        if self.recorder.is_some() {
+            let hash = self.state_hash();
+            if let Some(recorder) = self.recorder.as_mut() {
+                recorder.recording.state_hashes.push(hash);
+            }
+        }
+    }
+}
+
+impl<ChunkUserData: Default + Serialize + DeserializeOwned + Send + Sync + 'static> GridWorld<ChunkUserData> {
+    /// Start recording every input applied with [GridWorld::apply_input], starting from a snapshot of the world as it
+    /// is now. A recording already in progress is thrown away.
+    pub fn start_recording(&mut self, registry: &SnapshotRegistry<ChunkUserData>) -> Result<()> {
+        let canon = Canon::default();
+        let mut snapshot = Vec::new();
+        self.write_snapshot(&mut snapshot, registry, &canon)?;
+
+        let steps = self.timestep.steps();
+        self.recorder = Some(InputRecorder {
+            recording: InputRecording {
+                snapshot,
+                canon,
+                inputs: Vec::new(),
+                state_hashes: StateHashes::new(steps + 1),
+                end_tick: steps,
+            },
+            in_block_ticks: false,
+        });
+
+        Ok(())
+    }
+
+    /// Restore the snapshot a recording starts from, and run the world through the recorded ticks, applying the inputs
+    /// as they were applied the first time. Returns the state hashes of the replay, which can be compared with the
+    /// recording's own to find where the replay went differently.
+    pub fn replay(&mut self, recording: &InputRecording, registry: &SnapshotRegistry<ChunkUserData>) -> Result<StateHashes> {
+        ensure!(!self.is_recording(), "Can't replay into a world that is recording.");
+
+        let mut deserializer = serde_cbor::Deserializer::from_slice(&recording.snapshot);
+        let contents = Self::read_snapshot(&mut deserializer, registry, &recording.canon)?;
+        deserializer.end().context("Recording snapshot has trailing data.")?;
+        self.load_snapshot(contents);
+
+        let mut hashes = StateHashes::new(self.timestep.steps() + 1);
+        let mut spawned_entities = HashMap::new();
+        let mut inputs = recording.inputs.iter().peekable();
+
+        loop {
+            let tick = self.timestep.steps();
+            while let Some(recorded) = inputs.next_if(|recorded| recorded.tick <= tick) {
+                self.replay_input(recorded, &mut spawned_entities);
+            }
+
+            if tick >= recording.end_tick {
+                break;
+            }
+
+            self.step_with(|world| {
+                while let Some(recorded) = inputs.next_if(|recorded| recorded.during_block_ticks && recorded.tick <= tick + 1) {
+                    world.replay_input(recorded, &mut spawned_entities);
+                }
+            });
+            hashes.push(self.state_hash());
+        }
+
+        Ok(hashes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use nalgebra::Vector2;
+    use rapier3d::{dynamics::RigidBodyBuilder, geometry::ColliderBuilder};
+
+    /// Wears away the ground it's given to, a little below the surface so nothing standing on it notices.
+    struct Erosion;
+
+    impl BlockTickHandler<()> for Erosion {
+        fn random_tick(&self, world: &mut GridWorld<()>, location: GlobalBlockCoordinate) {
+            if location.y < -2 {
+                *world.get_block_mut(location).unwrap() = None;
+            }
+        }
+    }
+
+    /// Create a world with flat ground whose surface is at y = 0. The ground erodes when randomly ticked.
+    fn eroding_world() -> GridWorld<()> {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-1, -1, -1), ChunkCoordinate::new(2, 1, 2)));
+
+        let ground = world.get_block(GlobalBlockCoordinate::new(0, -1, 0)).unwrap().unwrap();
+        world.tick_scheduler_mut().register_handler(ground, Box::new(Erosion));
+
+        world
+    }
+
+    /// Count the blocks that have eroded away in a chunk under the surface.
+    fn eroded(world: &GridWorld<()>) -> usize {
+        GlobalBlockRange::from_end_points(GlobalBlockCoordinate::new(0, -32, 0), GlobalBlockCoordinate::new(31, -3, 31))
+            .iter_xyz(world)
+            .filter(|block| block.is_none())
+            .count()
+    }
+
+    /// Walking in some direction.
+    fn walking(x: f32, z: f32) -> CharacterInput {
+        CharacterInput { movement: Vector2::new(x, z), ..CharacterInput::default() }
+    }
+
+    /// Record a run with a bit of everything in it: characters that exist before and after the recording starts,
+    /// blocks being edited and randomly ticked, a trigger volume, a ball, chunks coming and going, and an admin slowing
+    /// time down.
+    fn record_run() -> (GridWorld<()>, InputRecording) {
+        let mut world = eroding_world();
+        let registry = SnapshotRegistry::new();
+        let ground = world.get_block(GlobalBlockCoordinate::new(0, -1, 0)).unwrap().unwrap();
+
+        let early = world.apply_input(WorldInput::SpawnCharacter {
+            feet: PhysicsVector::new(0.5, 0.5, 0.5),
+            settings: CharacterSettings::default(),
+        });
+        world.step();
+
+        world.start_recording(&registry).unwrap();
+        world.apply_input(WorldInput::SetCharacterInput { character: early.unwrap(), input: walking(1.0, 0.0) });
+        for _ in 0..20 {
+            world.step();
+        }
+
+        let late = world.apply_input(WorldInput::SpawnCharacter {
+            feet: PhysicsVector::new(-3.5, 0.5, -3.5),
+            settings: CharacterSettings::default(),
+        });
+        world.apply_input(WorldInput::SetCharacterInput { character: late.unwrap(), input: walking(0.0, 1.0) });
+        world.apply_input(WorldInput::PlaceBlock { location: GlobalBlockCoordinate::new(-4, 0, 1), block: ground });
+        world.apply_input(WorldInput::BreakBlock { location: GlobalBlockCoordinate::new(5, -1, 0) });
+        world.apply_input(WorldInput::SpawnTriggerVolume {
+            shape: SharedShape::cuboid(1.0, 1.0, 1.0),
+            position: Isometry3::translation(2.5, 1.0, 2.5),
+        });
+        world.apply_input(WorldInput::SpawnRigidBody {
+            body: Box::new(RigidBodyBuilder::new_dynamic().translation(2.5, 3.0, 2.5).build()),
+            colliders: vec![ColliderBuilder::ball(0.25).build()],
+        });
+        for _ in 0..30 {
+            world.step();
+        }
+
+        let mut settings = *world.simulation_settings();
+        settings.set_time_scale(0.5);
+        world.apply_input(WorldInput::SetSimulationSettings(settings));
+        world.apply_input(WorldInput::RemoveEntity { entity: early.unwrap() });
+        world.apply_input(WorldInput::LoadChunk { index: ChunkCoordinate::new(3, -1, 0) });
+        world.apply_input(WorldInput::UnloadChunk { index: ChunkCoordinate::new(-1, 1, -1) });
+        for _ in 0..20 {
+            world.step();
+        }
+
+        let recording = world.stop_recording().unwrap();
+        (world, recording)
+    }
+
+    /// Only the ticks two runs share are compared, and the first one that differs is found.
+    #[test]
+    fn divergence() {
+        let mut first = StateHashes::new(10);
+        let mut second = StateHashes::new(12);
+        for hash in [1, 2, 3, 4, 5].iter() {
+            first.push(*hash);
+        }
+        for hash in [3, 4, 9, 10].iter() {
+            second.push(*hash);
+        }
+
+        assert_eq!(first.end_tick(), 15);
+        assert_eq!(first.get(12), Some(3));
+        assert_eq!(first.get(9), None);
+        assert_eq!(first.first_divergence(&second), Some(14));
+
+        second.hashes[2] = 5;
+        assert_eq!(first.first_divergence(&second), None);
+        assert_eq!(second.first_divergence(&first), None);
+    }
+
+    /// Inputs are recorded with the tick they were applied on.
+    #[test]
+    fn record() {
+        let (world, recording) = record_run();
+
+        assert!(!world.is_recording());
+        assert_eq!(recording.start_tick(), 2);
+        assert_eq!(recording.end_tick(), 71);
+        assert_eq!(recording.state_hashes().len(), 70);
+        assert_eq!(recording.state_hashes().get(71), Some(world.state_hash()));
+
+        let ticks: Vec<u64> = recording.inputs().iter().map(|recorded| recorded.tick).collect();
+        assert_eq!(ticks, vec![1, 21, 21, 21, 21, 21, 21, 51, 51, 51, 51]);
+        assert!(recording.inputs()[1].spawned.is_some());
+        assert!(recording.inputs()[5].spawned.is_some());
+        assert!(recording.inputs()[0].spawned.is_none());
+        assert!(world.get_chunk(&ChunkCoordinate::new(3, -1, 0)).is_some());
+        assert!(world.get_chunk(&ChunkCoordinate::new(-1, 1, -1)).is_none());
+        assert!(eroded(&world) > 0);
+    }
+
+    /// Replaying a recording ends up in exactly the same state as the recorded run, whether it's replayed straight away
+    /// or after being written out and read back.
+    #[test]
+    fn replay() {
+        let (world, recording) = record_run();
+        let registry = SnapshotRegistry::new();
+
+        let mut replayed = eroding_world();
+        let hashes = replayed.replay(&recording, &registry).unwrap();
+        assert_eq!(recording.state_hashes().first_divergence(&hashes), None);
+        assert_eq!(&hashes, recording.state_hashes());
+        assert_eq!(replayed.state_hash(), world.state_hash());
+
+        let recording = InputRecording::from_bytes(&recording.to_bytes().unwrap()).unwrap();
+        let mut replayed = eroding_world();
+        let hashes = replayed.replay(&recording, &registry).unwrap();
+        assert_eq!(&hashes, recording.state_hashes());
+        assert_eq!(replayed.state_hash(), world.state_hash());
+        assert_eq!(recording.inputs().len(), 11);
+        assert_eq!(eroded(&replayed), eroded(&world));
+    }
+
+    /// Something done to the world behind the recording's back shows up as a divergence on the tick after it happened.
+    #[test]
+    fn desync() {
+        let mut world = eroding_world();
+        let registry = SnapshotRegistry::new();
+        let character = world.spawn_character(PhysicsVector::new(0.5, 0.5, 0.5), CharacterSettings::default());
+
+        world.start_recording(&registry).unwrap();
+        world.apply_input(WorldInput::SetCharacterInput { character, input: walking(1.0, 1.0) });
+        for _ in 0..10 {
+            world.step();
+        }
+
+        // Not applied as an input, so it doesn't get recorded.
+        *world.get_block_mut(GlobalBlockCoordinate::new(0, 5, 0)).unwrap() =
+            world.get_block(GlobalBlockCoordinate::new(0, -1, 0)).unwrap();
+        for _ in 0..10 {
+            world.step();
+        }
+        let recording = world.stop_recording().unwrap();
+
+        let mut replayed = eroding_world();
+        let hashes = replayed.replay(&recording, &registry).unwrap();
+        assert_eq!(recording.state_hashes().first_divergence(&hashes), Some(11));
+    }
+}
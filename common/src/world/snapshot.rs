@@ -0,0 +1,408 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Snapshots of the complete state of a world, for quicksaves, test fixtures, bug reports that need to be reproduced,
+//! and rolling a server back after something went wrong.
+//!
+//! A snapshot holds the time, every loaded chunk, the ECS entities and their components, and the physics engine.
+//! It doesn't hold what the world was set up with: the chunk provider, block and material registries, tick handlers,
+//! and ECS systems. A snapshot should be restored into a world set up the same way as the one it was taken from.
+
+use super::{
+    block_grid::BlockGrid,
+    components::{OriginAnchor, OwnedRigidBodies, RigidBody, Transform},
+    CharacterController, CharacterInput, Chunk, ChunkCoordinate, CollisionEvents, FixedTimestep, FloatingOrigin, GameCalendar,
+    GridWorld, PhysicsGlobalConstants, SimulationSettings, StructuralIntegrity, WorldTime,
+};
+use anyhow::{ensure, Context, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use legion::{
+    serialize::{set_entity_serializer, Canon},
+    storage::Component,
+    Registry, World,
+};
+use rapier3d::{
+    dynamics::{CCDSolver, JointSet, RigidBodySet},
+    geometry::{BroadPhase, ColliderSet, NarrowPhase},
+    pipeline::PhysicsPipeline,
+};
+use serde::{de::DeserializeOwned, de::DeserializeSeed, Deserialize, Serialize};
+use serde_cbor::de::Read;
+use std::{collections::HashMap, io::Write, marker::PhantomData};
+
+/// The version of the snapshot format. Snapshots of other versions can't be restored.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// The ECS components that can be put in a snapshot. Every component in the world must be registered, or taking a
+/// snapshot will fail. The components that come with the world are registered from the start, so only components added
+/// by the game need to be registered.
+pub struct SnapshotRegistry<ChunkUserData> {
+    registry: Registry<String>,
+    _chunk_user_data: PhantomData<ChunkUserData>,
+}
+
+impl<ChunkUserData: Serialize + DeserializeOwned + Send + Sync + 'static> SnapshotRegistry<ChunkUserData> {
+    /// Create a registry with the components that come with the world.
+    pub fn new() -> SnapshotRegistry<ChunkUserData> {
+        let mut registry = SnapshotRegistry { registry: Registry::new(), _chunk_user_data: PhantomData };
+
+        registry.register::<RigidBody>("rigid_body");
+        registry.register::<Transform>("transform");
+        registry.register::<OriginAnchor>("origin_anchor");
+        registry.register::<BlockGrid<ChunkUserData>>("block_grid");
+        registry.register::<CharacterController>("character_controller");
+        registry.register::<CharacterInput>("character_input");
+
+        registry
+    }
+
+    /// Register a component. The name is what the component is saved under, so it must be unique and must not change
+    /// between versions of the game.
+    pub fn register<C: Component + Serialize + DeserializeOwned>(&mut self, name: &str) {
+        self.registry.register::<C>(name.to_string());
+    }
+}
+
+impl<ChunkUserData: Serialize + DeserializeOwned + Send + Sync + 'static> Default for SnapshotRegistry<ChunkUserData> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Everything in a snapshot besides the ECS world, borrowed from the world for writing.
+#[derive(Serialize)]
+struct WorldStateRef<'a, ChunkUserData> {
+    time: WorldTime,
+    random_tick_state: u64,
+    timestep: &'a FixedTimestep,
+    simulation_settings: &'a SimulationSettings,
+    calendar: &'a GameCalendar,
+    terrain_chunks: &'a HashMap<ChunkCoordinate, Chunk<ChunkUserData>>,
+    staged_chunks: &'a HashMap<ChunkCoordinate, Chunk<ChunkUserData>>,
+    physics_constants: &'a PhysicsGlobalConstants,
+    broad_phase: &'a BroadPhase,
+    narrow_phase: &'a NarrowPhase,
+    rigid_bodies: &'a RigidBodySet,
+    colliders: &'a ColliderSet,
+    joints: &'a JointSet,
+    ccd_solver: &'a CCDSolver,
+    owned_rigid_bodies: &'a OwnedRigidBodies,
+    floating_origin: &'a FloatingOrigin,
+    structural_integrity: &'a StructuralIntegrity,
+    collision_events: &'a CollisionEvents,
+}
+
+/// The owned version of [WorldStateRef], for reading.
+#[derive(Deserialize)]
+struct WorldState<ChunkUserData> {
+    time: WorldTime,
+    random_tick_state: u64,
+    timestep: FixedTimestep,
+    simulation_settings: SimulationSettings,
+    calendar: GameCalendar,
+    terrain_chunks: HashMap<ChunkCoordinate, Chunk<ChunkUserData>>,
+    staged_chunks: HashMap<ChunkCoordinate, Chunk<ChunkUserData>>,
+    physics_constants: PhysicsGlobalConstants,
+    broad_phase: BroadPhase,
+    narrow_phase: NarrowPhase,
+    rigid_bodies: RigidBodySet,
+    colliders: ColliderSet,
+    joints: JointSet,
+    ccd_solver: CCDSolver,
+    owned_rigid_bodies: OwnedRigidBodies,
+    floating_origin: FloatingOrigin,
+    structural_integrity: StructuralIntegrity,
+    collision_events: CollisionEvents,
+}
+
+/// A snapshot that has been read, but not put into a world yet.
+pub(super) struct SnapshotContents<ChunkUserData> {
+    state: WorldState<ChunkUserData>,
+    ecs_world: World,
+}
+
+impl<ChunkUserData: Default + Serialize + DeserializeOwned + Send + Sync + 'static> GridWorld<ChunkUserData> {
+    /// Capture the complete state of the world. The snapshot is compressed, and can be restored with
+    /// [GridWorld::restore].
+    pub fn snapshot(&self, registry: &SnapshotRegistry<ChunkUserData>) -> Result<Vec<u8>> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+
+        serde_cbor::to_writer(&mut encoder, &SNAPSHOT_VERSION).context("Error writing snapshot version.")?;
+        self.write_snapshot(&mut encoder, registry, &Canon::default())?;
+
+        encoder.finish().context("Error compressing snapshot.")
+    }
+
+    /// Replace the state of the world with a snapshot from [GridWorld::snapshot]. If the snapshot can't be read, the
+    /// world is left as it was.
+    /// Entities are given new IDs, so any [legion::Entity] kept from before the restore is no longer valid.
+    pub fn restore(&mut self, snapshot: &[u8], registry: &SnapshotRegistry<ChunkUserData>) -> Result<()> {
+        let mut deserializer = serde_cbor::Deserializer::from_reader(DeflateDecoder::new(snapshot));
+
+        let version = u32::deserialize(&mut deserializer).context("Error reading snapshot version.")?;
+        ensure!(
+            version == SNAPSHOT_VERSION,
+            "Snapshot is version {}, but only version {} is supported.",
+            version,
+            SNAPSHOT_VERSION
+        );
+
+        let contents = Self::read_snapshot(&mut deserializer, registry, &Canon::default())?;
+        deserializer.end().context("Snapshot has trailing data.")?;
+        self.load_snapshot(contents);
+
+        Ok(())
+    }
+
+    /// Write the state of the world, uncompressed and without a version. Entities are written under the names the canon
+    /// gives them, so that other things written with the same canon can refer to them.
+    pub(super) fn write_snapshot(
+        &self, mut writer: impl Write, registry: &SnapshotRegistry<ChunkUserData>, canon: &Canon,
+    ) -> Result<()> {
+        let resources = &self.ecs_resources;
+        let physics_constants = resources.get::<PhysicsGlobalConstants>().expect("Failed to find physics constants.");
+        let broad_phase = resources.get::<BroadPhase>().expect("Failed to find broad phase.");
+        let narrow_phase = resources.get::<NarrowPhase>().expect("Failed to find narrow phase.");
+        let rigid_bodies = resources.get::<RigidBodySet>().expect("Failed to find rigid body set.");
+        let colliders = resources.get::<ColliderSet>().expect("Failed to find collider set.");
+        let joints = resources.get::<JointSet>().expect("Failed to find joint set.");
+        let ccd_solver = resources.get::<CCDSolver>().expect("Failed to find CCD solver.");
+        let owned_rigid_bodies = resources.get::<OwnedRigidBodies>().expect("Failed to find owned rigid bodies.");
+        let floating_origin = resources.get::<FloatingOrigin>().expect("Failed to find floating origin.");
+        let structural_integrity = resources.get::<StructuralIntegrity>().expect("Failed to find structural integrity solver.");
+        let collision_events = resources.get::<CollisionEvents>().expect("Failed to find collision events.");
+
+        let state = WorldStateRef {
+            time: self.time,
+            random_tick_state: self.tick_scheduler.random_state(),
+            timestep: &self.timestep,
+            simulation_settings: &self.simulation_settings,
+            calendar: &self.calendar,
+            terrain_chunks: &self.terrain_chunks,
+            staged_chunks: &self.staged_chunks,
+            physics_constants: &physics_constants,
+            broad_phase: &broad_phase,
+            narrow_phase: &narrow_phase,
+            rigid_bodies: &rigid_bodies,
+            colliders: &colliders,
+            joints: &joints,
+            ccd_solver: &ccd_solver,
+            owned_rigid_bodies: &owned_rigid_bodies,
+            floating_origin: &floating_origin,
+            structural_integrity: &structural_integrity,
+            collision_events: &collision_events,
+        };
+
+        // The same names have to be used for the ECS world and everything else that refers to entities.
+        set_entity_serializer(canon, || serde_cbor::to_writer(&mut writer, &state))
+            .context("Error writing world state to snapshot.")?;
+        serde_cbor::to_writer(&mut writer, &self.ecs_world.as_serializable(legion::any(), &registry.registry, canon))
+            .context("Error writing ECS world to snapshot.")?;
+
+        Ok(())
+    }
+
+    /// Read what [GridWorld::write_snapshot] wrote, without touching the world yet.
+    pub(super) fn read_snapshot<'de, R: Read<'de>>(
+        deserializer: &mut serde_cbor::Deserializer<R>, registry: &SnapshotRegistry<ChunkUserData>, canon: &Canon,
+    ) -> Result<SnapshotContents<ChunkUserData>> {
+        let state = set_entity_serializer(canon, || WorldState::deserialize(&mut *deserializer))
+            .context("Error reading world state from snapshot.")?;
+        let ecs_world = registry
+            .registry
+            .as_deserialize(canon)
+            .deserialize(&mut *deserializer)
+            .context("Error reading ECS world from snapshot.")?;
+
+        Ok(SnapshotContents { state, ecs_world })
+    }
+
+    /// Replace the state of the world with a snapshot that has been read.
+    pub(super) fn load_snapshot(&mut self, contents: SnapshotContents<ChunkUserData>) {
+        let SnapshotContents { state, ecs_world } = contents;
+
+        self.time = state.time;
+        self.tick_scheduler.set_random_state(state.random_tick_state);
+        self.timestep = state.timestep;
+        self.simulation_settings = state.simulation_settings;
+        self.calendar = state.calendar;
+        self.terrain_chunks = state.terrain_chunks;
+        self.staged_chunks = state.staged_chunks;
+        self.ecs_world = ecs_world;
+
+        let resources = &mut self.ecs_resources;
+        resources.insert(PhysicsPipeline::new());
+        resources.insert(state.physics_constants);
+        resources.insert(state.broad_phase);
+        resources.insert(state.narrow_phase);
+        resources.insert(state.rigid_bodies);
+        resources.insert(state.colliders);
+        resources.insert(state.joints);
+        resources.insert(state.ccd_solver);
+        resources.insert(state.owned_rigid_bodies);
+        resources.insert(state.floating_origin);
+        resources.insert(state.structural_integrity);
+        resources.insert(state.collision_events);
+        self.track_rigid_bodies();
+
+        // The query pipeline is just an acceleration structure, so it's rebuilt rather than saved.
+        self.update_query_pipeline();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use legion::{Entity, EntityStore, IntoQuery};
+    use nalgebra::{Isometry3, Vector2};
+    use rapier3d::{dynamics::RigidBodyBuilder, geometry::ColliderBuilder};
+    use std::time::Duration;
+
+    /// Fill a world with a bit of everything: a character walking, a ball bouncing, and a block placed in the terrain.
+    fn busy_world() -> (GridWorld<()>, Entity) {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-1, -1, -1), ChunkCoordinate::new(2, 1, 2)));
+
+        let ground = world.get_block(GlobalBlockCoordinate::new(0, -1, 0)).unwrap();
+        *world.get_block_mut(GlobalBlockCoordinate::new(3, 0, 3)).unwrap() = ground;
+
+        let character = world.spawn_character(PhysicsVector::new(0.5, 1.0, 0.5), CharacterSettings::default());
+        *world.ecs_world_mut().entry(character).unwrap().get_component_mut::<CharacterInput>().unwrap() =
+            CharacterInput { movement: Vector2::new(1.0, 1.0), ..CharacterInput::default() };
+
+        let ball = RigidBody::new(
+            world.ecs_resources_mut(),
+            RigidBodyBuilder::new_dynamic().translation(-5.0, 3.0, -5.0).linvel(1.0, 0.0, 0.5).build(),
+        );
+        ball.add_collider(ColliderBuilder::ball(0.5).restitution(0.8).build(), world.ecs_resources_mut());
+        let floor = RigidBody::new(world.ecs_resources_mut(), RigidBodyBuilder::new_static().build());
+        floor.add_collider(ColliderBuilder::cuboid(10.0, 0.5, 10.0).build(), world.ecs_resources_mut());
+        world.ecs_world_mut().push((ball, Transform::new(Isometry3::translation(-5.0, 3.0, -5.0))));
+        world.ecs_world_mut().push((floor,));
+
+        (world, character)
+    }
+
+    /// Where everything with a transform is, in an order that doesn't depend on entity IDs.
+    fn positions(world: &GridWorld<()>) -> Vec<[f32; 3]> {
+        let mut query = <&Transform>::query();
+        let mut positions: Vec<[f32; 3]> = query
+            .iter(world.ecs_world())
+            .map(|transform| {
+                let translation = transform.position().translation.vector;
+                [translation.x, translation.y, translation.z]
+            })
+            .collect();
+        positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        positions
+    }
+
+    /// Run the world for a while.
+    fn run(world: &mut GridWorld<()>, steps: u32) {
+        for _ in 0..steps {
+            world.update(Duration::from_millis(10));
+        }
+    }
+
+    /// A restored world picks up exactly where the original left off.
+    #[test]
+    fn round_trip() {
+        let (mut world, _character) = busy_world();
+        world.set_calendar(GameCalendar::new(Duration::from_secs(60), 12));
+        run(&mut world, 50);
+        world.update(Duration::from_millis(4));
+
+        let registry = SnapshotRegistry::new();
+        let snapshot = world.snapshot(&registry).unwrap();
+
+        let mut restored =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-1, -1, -1), ChunkCoordinate::new(2, 1, 2)));
+        restored.restore(&snapshot, &registry).unwrap();
+
+        assert_eq!(restored.time(), world.time());
+        assert_eq!(restored.timestep(), world.timestep());
+        assert_eq!(restored.calendar(), world.calendar());
+        assert_eq!(restored.tick_scheduler().random_state(), world.tick_scheduler().random_state());
+        assert_eq!(
+            restored.get_block(GlobalBlockCoordinate::new(3, 0, 3)),
+            world.get_block(GlobalBlockCoordinate::new(3, 0, 3))
+        );
+        assert_eq!(restored.ecs_world().len(), world.ecs_world().len());
+        assert_eq!(positions(&restored), positions(&world));
+
+        // The simulation carries on the same way in both.
+        run(&mut world, 100);
+        run(&mut restored, 100);
+        assert_eq!(restored.time(), world.time());
+        assert_eq!(positions(&restored), positions(&world));
+
+        let mut query = <&CharacterController>::query();
+        let controllers: Vec<_> = query.iter(world.ecs_world()).copied().collect();
+        let restored_controllers: Vec<_> = query.iter(restored.ecs_world()).copied().collect();
+        assert_eq!(restored_controllers, controllers);
+    }
+
+    /// Restoring a world into itself rolls back everything that happened since the snapshot.
+    #[test]
+    fn rollback() {
+        let (mut world, character) = busy_world();
+        run(&mut world, 20);
+
+        let registry = SnapshotRegistry::new();
+        let snapshot = world.snapshot(&registry).unwrap();
+        let time = world.time();
+        let before = positions(&world);
+
+        *world.get_block_mut(GlobalBlockCoordinate::new(0, -1, 0)).unwrap() = None;
+        world.remove_entity(character);
+        run(&mut world, 20);
+
+        world.restore(&snapshot, &registry).unwrap();
+        assert_eq!(world.time(), time);
+        assert!(world.get_block(GlobalBlockCoordinate::new(0, -1, 0)).unwrap().is_some());
+        assert_eq!(positions(&world), before);
+
+        // The character is back, under a new ID, and its rigid body came back with it.
+        let mut query = <(Entity, &RigidBody, &CharacterController)>::query();
+        let (character, rigid_body, _) = query.iter(world.ecs_world()).next().unwrap();
+        assert!(world.ecs_resources().get::<RigidBodySet>().unwrap().get(rigid_body.handle()).is_some());
+        let character = *character;
+        run(&mut world, 20);
+        assert!(world.ecs_world().entry_ref(character).is_ok());
+    }
+
+    /// Components the registry doesn't know about stop a snapshot from being taken, and a bad snapshot leaves the world
+    /// alone.
+    #[test]
+    fn failures() {
+        #[derive(Serialize, Deserialize)]
+        struct Health(u32);
+
+        let (mut world, _character) = busy_world();
+        world.ecs_world_mut().push((Health(10),));
+
+        let mut registry = SnapshotRegistry::new();
+        assert!(world.snapshot(&registry).is_err());
+
+        registry.register::<Health>("health");
+        let snapshot = world.snapshot(&registry).unwrap();
+
+        let time = world.time();
+        let entities = world.ecs_world().len();
+        run(&mut world, 10);
+        let later = world.time();
+
+        assert!(world.restore(&snapshot[..snapshot.len() / 2], &registry).is_err());
+        assert!(world.restore(b"not a snapshot", &registry).is_err());
+        assert_eq!(world.time(), later);
+
+        world.restore(&snapshot, &registry).unwrap();
+        assert_eq!(world.time(), time);
+        assert_eq!(world.ecs_world().len(), entities);
+
+        let mut query = <&Health>::query();
+        assert_eq!(query.iter(world.ecs_world()).map(|health| health.0).collect::<Vec<_>>(), vec![10]);
+    }
+}
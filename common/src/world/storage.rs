@@ -3,11 +3,16 @@
 
 //! Long term storage of the world on the local disk.
 
-use super::ChunkCoordinate;
-use anyhow::{Context, Result};
+use super::{
+    chunk_providers::{GenerationStage, WorldPreset},
+    BlockRegistry, Chunk, ChunkCoordinate, ChunkTickSchedule,
+};
+use anyhow::{anyhow, ensure, Context, Result};
 use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use fs::File;
+use serde::{Deserialize, Serialize};
 use std::{
+    convert::TryFrom,
     fs,
     io::{BufReader, BufWriter, Cursor, Read, Write},
     path::{Path, PathBuf},
@@ -27,7 +32,24 @@ pub const CHUNK_DIAMETER: usize = 1 << NUM_BLOCK_ADDRESS_BITS;
 // That makes the chunk 8Kb in length.
 const CHUNK_LENGTH: usize = CHUNK_DIAMETER * CHUNK_DIAMETER * CHUNK_DIAMETER * 2;
 
-create_strong_type!(ChunkKey, u64);
+create_strong_type!(ChunkKey, u128);
+
+/// The number of bits in a chunk key. Every axis of a chunk coordinate is 32 bits, and they're interleaved.
+const CHUNK_KEY_BITS: u32 = 3 * 32;
+const_assert!(CHUNK_KEY_BITS <= u128::BITS);
+
+/// Chunk files are named after their key in hexadecimal, padded out to fit the widest key.
+const CHUNK_FILE_NAME_DIGITS: usize = CHUNK_KEY_BITS.div_ceil(4) as usize;
+
+/// The version of the chunk file format. It's written at the start of every chunk file.
+/// Version 1 had 16 bit chunk coordinates, 12 digit file names, and no header at all.
+pub const CHUNK_FORMAT_VERSION: u32 = 2;
+
+/// Comes before the format version at the start of every chunk file, to tell it apart from version 1 files.
+const CHUNK_FILE_MAGIC: &[u8; 4] = b"CHNK";
+
+/// The number of hexadecimal digits in version 1 chunk file names.
+const LEGACY_CHUNK_FILE_NAME_DIGITS: usize = 12;
 
 /// The raw data for a chunk.
 pub struct ChunkData {
@@ -74,6 +96,34 @@ impl ChunkData {
     }
 }
 
+/// Everything about a chunk that's saved after its blocks.
+#[derive(Serialize)]
+struct SerializedChunkRecordRef<'a> {
+    generation_stage: GenerationStage,
+    scheduled_ticks: &'a ChunkTickSchedule,
+}
+
+/// The owned version of [SerializedChunkRecordRef], for loading.
+#[derive(Deserialize)]
+struct SerializedChunkRecord {
+    generation_stage: GenerationStage,
+    scheduled_ticks: ChunkTickSchedule,
+}
+
+impl Default for SerializedChunkRecord {
+    fn default() -> Self {
+        SerializedChunkRecord { generation_stage: GenerationStage::COMPLETE, scheduled_ticks: ChunkTickSchedule::default() }
+    }
+}
+
+/// A block in the table of block IDs saved with a world.
+#[derive(Serialize, Deserialize)]
+struct SavedBlock {
+    name: String,
+    id: u16,
+    display_text: String,
+}
+
 /// A struct that will store and fetch chunks. It will create new chunks if the
 /// chunk does not exist in the file, but it will not fill the chunk with
 /// content.
@@ -112,41 +162,85 @@ impl ChunkDiskStorage {
 
     /// Will load a chunk's terrain content. Search and fetch time is filesystem
     /// dependent. If the chunk does not exist, false will be returned.
-    /// Otherwise, true is returned.
+    /// Otherwise, true is returned. Chunks saved in the version 1 format are still found and read.
     pub fn load_chunk(&self, chunk: &mut ChunkData) -> Result<bool> {
-        let path = self.create_chunk_path(chunk.location.x, chunk.location.y, chunk.location.z);
+        Ok(self.read_chunk_file(chunk)?.is_some())
+    }
 
-        if path.exists() {
-            let file = File::open(path)?;
-            let mut file = BufReader::new(file);
-            let mut data = Vec::new();
-            file.read_to_end(&mut data).context("Error while reading chunk file.")?;
-            let data = Cursor::new(data);
-            let mut zip = DeflateDecoder::new(data);
-            {
-                // We need to view this as bytes. Don't worry about the endian. We'll fix that
-                // in a moment.
-                let block_data = unsafe { std::mem::transmute::<&mut [u16], &mut [u8]>(chunk.get_data_mut()) };
-                zip.read_exact(block_data).context("Failed to read bytes into chunk.")?;
-            }
+    /// Read a chunk's blocks, and everything saved after them. If the chunk does not exist, None will be returned.
+    fn read_chunk_file(&self, chunk: &mut ChunkData) -> Result<Option<SerializedChunkRecord>> {
+        let (path, has_header) = match self.find_chunk_path(chunk.location) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
 
-            // If we are a big endian machine, we have to flip all those bytes to our big
-            // endian format.
-            #[cfg(target_endian = "big")]
-            {
-                for block in chunk.get_data_mut() {
-                    *block = u16::from_le_bytes(block.to_ne_bytes());
-                }
+        let file = File::open(path)?;
+        let mut file = BufReader::new(file);
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).context("Error while reading chunk file.")?;
+
+        let (version, data) = if has_header {
+            let (version, data) = Self::read_header(&data)?;
+            ensure!(
+                version <= CHUNK_FORMAT_VERSION,
+                "Chunk was saved in format version {}, but only up to {} can be read.",
+                version,
+                CHUNK_FORMAT_VERSION
+            );
+            (version, data)
+        } else {
+            (1, &data[..])
+        };
+
+        let mut zip = DeflateDecoder::new(Cursor::new(data));
+        {
+            // We need to view this as bytes. Don't worry about the endian. We'll fix that
+            // in a moment. Every block is two bytes, so there are twice as many bytes as blocks.
+            let blocks = chunk.get_data_mut();
+            let block_data =
+                unsafe { std::slice::from_raw_parts_mut(blocks.as_mut_ptr() as *mut u8, std::mem::size_of_val(blocks)) };
+            zip.read_exact(block_data).context("Failed to read bytes into chunk.")?;
+        }
+
+        // If we are a big endian machine, we have to flip all those bytes to our big
+        // endian format.
+        #[cfg(target_endian = "big")]
+        {
+            for block in chunk.get_data_mut() {
+                *block = u16::from_le_bytes(block.to_ne_bytes());
             }
+        }
 
-            Ok(true)
+        let record = if version > 1 {
+            serde_cbor::from_reader(zip).context("Failed to read chunk record.")?
         } else {
-            Ok(false)
-        }
+            SerializedChunkRecord::default()
+        };
+
+        Ok(Some(record))
+    }
+
+    /// Split the header off of a chunk file, giving the format version and what comes after it.
+    fn read_header(data: &[u8]) -> Result<(u32, &[u8])> {
+        ensure!(data.len() >= 8 && &data[..4] == CHUNK_FILE_MAGIC, "Chunk file is missing its header.");
+        let version = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+
+        Ok((version, &data[8..]))
     }
 
-    /// Save the bytes of a chunk to a file.
+    /// Save the bytes of a chunk to a file. It's always saved in the newest format, replacing any version 1 file.
+    /// The chunk is saved as complete, with no scheduled ticks. Use [ChunkDiskStorage::save_terrain_chunk] to keep those.
     pub fn save_chunk(&self, chunk: &ChunkData) -> Result<()> {
+        let record = SerializedChunkRecordRef {
+            generation_stage: GenerationStage::COMPLETE,
+            scheduled_ticks: &ChunkTickSchedule::default(),
+        };
+
+        self.write_chunk_file(chunk, &record)
+    }
+
+    /// Write a chunk's blocks to a file, followed by everything else about the chunk.
+    fn write_chunk_file(&self, chunk: &ChunkData, record: &SerializedChunkRecordRef) -> Result<()> {
         let path = self.create_chunk_path(chunk.location.x, chunk.location.y, chunk.location.z);
         if path.exists() {
             // We are going to make a backup of the old version of this file.
@@ -165,35 +259,140 @@ impl ChunkDiskStorage {
 
         let file = File::create(path)?;
         let mut file = BufWriter::new(file); // Makes writing small bits of data a little more efficient.
-        let mut storage = Vec::new();
-        storage.reserve(CHUNK_LENGTH);
+        let storage = Vec::with_capacity(CHUNK_LENGTH);
         let mut compressor = DeflateEncoder::new(storage, self.compression_level);
 
         for block in chunk.get_data() {
             compressor.write(&block.to_le_bytes()).context("Error writing to compression buffer.")?;
         }
+        serde_cbor::to_writer(&mut compressor, record).context("Error writing chunk record to compression buffer.")?;
 
         let to_write = compressor.finish().context("Error compressing chunk")?;
+        file.write_all(CHUNK_FILE_MAGIC).context("Error writing chunk header to file.")?;
+        file.write_all(&CHUNK_FORMAT_VERSION.to_le_bytes()).context("Error writing chunk header to file.")?;
         file.write_all(&to_write).context("Error writing chunk data to file.")?;
 
+        // The old file would otherwise be found again if this one is ever deleted.
+        if let Some(legacy_path) = self.create_legacy_chunk_path(chunk.location) {
+            if legacy_path.exists() {
+                fs::remove_file(legacy_path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Save a chunk of terrain, along with how far along it is in being generated and the ticks scheduled for its
+    /// blocks. The chunk's user data isn't saved.
+    pub fn save_terrain_chunk<UserData>(&self, chunk: &Chunk<UserData>) -> Result<()> {
+        let record =
+            SerializedChunkRecordRef { generation_stage: chunk.generation_stage(), scheduled_ticks: chunk.scheduled_ticks() };
+
+        self.write_chunk_file(chunk.data(), &record)
+    }
+
+    /// Load a chunk of terrain, along with how far along it is in being generated and the ticks scheduled for its
+    /// blocks. If the chunk was never saved, None is returned. The chunk's user data starts out as its default.
+    pub fn load_terrain_chunk<UserData: Default>(&self, location: ChunkCoordinate) -> Result<Option<Chunk<UserData>>> {
+        let mut data = ChunkData::create(location);
+
+        match self.read_chunk_file(&mut data)? {
+            Some(record) => {
+                let mut chunk = Chunk::from_data(data, UserData::default());
+                chunk.set_generation_stage(record.generation_stage);
+                *chunk.scheduled_ticks_mut() = record.scheduled_ticks;
+
+                Ok(Some(chunk))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Save the preset the world is generated with. It's kept as RON in the root of the world's folder, so that it can
+    /// be read and tweaked by hand.
+    pub fn save_world_preset(&self, preset: &WorldPreset) -> Result<()> {
+        let text = preset.to_ron()?;
+        fs::write(self.root_folder.join("preset.ron"), text).context("Error writing world preset to file.")?;
+
         Ok(())
     }
 
+    /// Load the preset the world is generated with. If no preset was saved, None is returned.
+    pub fn load_world_preset(&self) -> Result<Option<WorldPreset>> {
+        let path = self.root_folder.join("preset.ron");
+
+        if path.exists() {
+            let text = fs::read_to_string(path).context("Error reading world preset from file.")?;
+            Ok(Some(WorldPreset::from_ron(&text)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Save the table of which block each ID in the world's chunks stands for. Like the preset, it's kept as RON in the
+    /// root of the world's folder. Which materials the blocks are made of isn't saved, since plugins set that up.
+    pub fn save_block_table(&self, registry: &BlockRegistry) -> Result<()> {
+        let table: Vec<SavedBlock> = registry
+            .iter_blocks()
+            .map(|block| SavedBlock {
+                name: String::from(block.name()),
+                id: block.id().index() as u16 + 1,
+                display_text: String::from(block.display_text()),
+            })
+            .collect();
+
+        let text =
+            ron::ser::to_string_pretty(&table, ron::ser::PrettyConfig::default()).context("Failed to write block table.")?;
+        fs::write(self.root_folder.join("blocks.ron"), text).context("Error writing block table to file.")?;
+
+        Ok(())
+    }
+
+    /// Load the table of which block each ID in the world's chunks stands for, as a block registry where every block
+    /// has the ID it was saved with. If no table was saved, None is returned.
+    pub fn load_block_table(&self) -> Result<Option<BlockRegistry>> {
+        let path = self.root_folder.join("blocks.ron");
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let text = fs::read_to_string(path).context("Error reading block table from file.")?;
+        let mut table: Vec<SavedBlock> = ron::de::from_str(&text).context("Failed to parse block table.")?;
+        table.sort_by_key(|block| block.id);
+
+        let mut registry = BlockRegistry::new();
+        for block in table {
+            // Block IDs are handed out in order, so the table can't skip any.
+            ensure!(
+                block.id == registry.num_block_types() + 1,
+                "Block \"{}\" has ID {}, but ID {} is missing from the block table.",
+                block.name,
+                block.id,
+                registry.num_block_types() + 1
+            );
+            registry
+                .add_block(block.name.clone(), block.display_text.clone())
+                .map_err(|_| anyhow!("Block \"{}\" is in the block table more than once.", block.name))?;
+        }
+
+        Ok(Some(registry))
+    }
+
     /// If you want to be able to fetch a chunk from the index, you first need a
     /// chunk key. This will generate it from a chunk index.
-    fn create_chunk_key(x: i16, y: i16, z: i16) -> ChunkKey {
+    fn create_chunk_key(x: i32, y: i32, z: i32) -> ChunkKey {
         // We group bits of the three axis together so that the more significant bits
         // are on the left and the less significant are on the right. This
         // improves our chances of physically close chunks are close in the binary tree,
         // improving our iteration speed when requesting a range.
-        fn spread_bits(input: i16) -> u64 {
-            let mut input = input as u64 & 0x000000000000FFFF;
+        fn spread_bits(input: i32) -> u128 {
+            let mut input = input as u32 as u128;
             let magic_numbers = [
-                (32, 0x00FF00000000FFFF),
-                (16, 0x00FF0000FF0000FF),
-                (8, 0xF00F00F00F00F00F),
-                (4, 0x30C30C30C30C30C3),
-                (2, 0x9249249249249249),
+                (32, 0x0000000000000000FFFF00000000FFFF),
+                (16, 0x000000000000FF0000FF0000FF0000FF),
+                (8, 0x0000000000F00F00F00F00F00F00F00F),
+                (4, 0x000000000C30C30C30C30C30C30C30C3),
+                (2, 0x00000000249249249249249249249249),
             ];
 
             // TODO should be loop unrolling on its own but I should check this.
@@ -213,14 +412,36 @@ impl ChunkDiskStorage {
     }
 
     fn create_chunk_file_name(key: ChunkKey) -> String {
-        format!("{:012X}", key.0)
+        format!("{:0width$X}", key.0, width = CHUNK_FILE_NAME_DIGITS)
     }
 
-    fn create_chunk_path(&self, x: i16, y: i16, z: i16) -> PathBuf {
+    fn create_chunk_path(&self, x: i32, y: i32, z: i32) -> PathBuf {
         let key = Self::create_chunk_key(x, y, z);
 
         self.root_folder.join(PathBuf::from(Self::create_chunk_file_name(key)))
     }
+
+    /// Version 1 chunk files were named after 16 bit chunk coordinates. Chunks outside of that range never had one.
+    fn create_legacy_chunk_file_name(location: ChunkCoordinate) -> Option<String> {
+        let narrow = |axis: i32| i16::try_from(axis).ok().map(|axis| axis as u16 as i32);
+        let key = Self::create_chunk_key(narrow(location.x)?, narrow(location.y)?, narrow(location.z)?);
+
+        Some(format!("{:0width$X}", key.0, width = LEGACY_CHUNK_FILE_NAME_DIGITS))
+    }
+
+    fn create_legacy_chunk_path(&self, location: ChunkCoordinate) -> Option<PathBuf> {
+        Self::create_legacy_chunk_file_name(location).map(|name| self.root_folder.join(PathBuf::from(name)))
+    }
+
+    /// Find the file a chunk was saved in, and whether it has a header. Files in the newest format are preferred.
+    fn find_chunk_path(&self, location: ChunkCoordinate) -> Option<(PathBuf, bool)> {
+        let path = self.create_chunk_path(location.x, location.y, location.z);
+        if path.exists() {
+            return Some((path, true));
+        }
+
+        self.create_legacy_chunk_path(location).filter(|path| path.exists()).map(|path| (path, false))
+    }
 }
 
 #[cfg(test)]
@@ -247,6 +468,132 @@ mod test_fileformate {
         assert!(storage.get_chunk(ChunkCoordinate::new(0, 0, 0)).unwrap().is_some());
     }
 
+    /// How far a chunk got in being generated and its scheduled ticks are saved with its blocks.
+    #[test]
+    fn save_and_load_terrain_chunk() {
+        use super::super::{LocalBlockCoordinate, WorldTime};
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ChunkDiskStorage::initialize(dir.path(), 9);
+        let location = ChunkCoordinate::new(1, -2, 3);
+
+        assert!(storage.load_terrain_chunk::<()>(location).unwrap().is_none());
+
+        let mut chunk = Chunk::new(location, ());
+        chunk.set_generation_stage(GenerationStage::Carving);
+        chunk.scheduled_ticks_mut().schedule(LocalBlockCoordinate::new(4, 5, 6), WorldTime::from_ms(1000));
+        chunk.scheduled_ticks_mut().schedule(LocalBlockCoordinate::new(7, 8, 9), WorldTime::from_ms(500));
+        storage.save_terrain_chunk(&chunk).unwrap();
+
+        // It's all in the one file.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let loaded = storage.load_terrain_chunk::<()>(location).unwrap().unwrap();
+        assert_eq!(loaded.generation_stage(), GenerationStage::Carving);
+        let ticks: Vec<_> = loaded.scheduled_ticks().iter().map(|tick| (tick.time(), tick.location())).collect();
+        assert_eq!(
+            ticks,
+            vec![
+                (WorldTime::from_ms(500), LocalBlockCoordinate::new(7, 8, 9)),
+                (WorldTime::from_ms(1000), LocalBlockCoordinate::new(4, 5, 6))
+            ]
+        );
+
+        // Chunks saved on their own are complete.
+        storage.save_chunk(chunk.data()).unwrap();
+        let loaded = storage.load_terrain_chunk::<()>(location).unwrap().unwrap();
+        assert!(loaded.generation_stage().is_complete());
+        assert!(loaded.scheduled_ticks().is_empty());
+    }
+
+    #[test]
+    fn save_and_load_world_preset() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ChunkDiskStorage::initialize(dir.path(), 9);
+
+        assert!(storage.load_world_preset().unwrap().is_none());
+
+        let preset = WorldPreset::superflat(42);
+        storage.save_world_preset(&preset).unwrap();
+        assert_eq!(storage.load_world_preset().unwrap(), Some(preset));
+    }
+
+    /// The block table gives every block back the ID it was saved with.
+    #[test]
+    fn save_and_load_block_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ChunkDiskStorage::initialize(dir.path(), 9);
+        assert!(storage.load_block_table().unwrap().is_none());
+
+        let mut registry = BlockRegistry::new();
+        for (name, display_text) in [("stone", "Stone"), ("soil", "Soil"), ("grass", "Grass")].iter() {
+            registry.add_block(String::from(*name), String::from(*display_text)).unwrap();
+        }
+        storage.save_block_table(&registry).unwrap();
+
+        let loaded = storage.load_block_table().unwrap().unwrap();
+        assert_eq!(loaded.num_block_types(), 3);
+        for block in registry.iter_blocks() {
+            let loaded = loaded.get_block_data_from_name(block.name()).unwrap();
+            assert_eq!(loaded.id(), block.id());
+            assert_eq!(loaded.display_text(), block.display_text());
+        }
+
+        // A table with a gap in it can't be loaded, since the blocks after the gap would get the wrong IDs.
+        fs::write(
+            dir.path().join("blocks.ron"),
+            r#"[(name: "stone", id: 1, display_text: "Stone"), (name: "soil", id: 3, display_text: "Soil")]"#,
+        )
+        .unwrap();
+        assert!(storage.load_block_table().is_err());
+    }
+
+    /// Chunks saved before chunk coordinates were widened can still be loaded, and are moved to the new format when
+    /// they're saved again.
+    #[test]
+    fn load_legacy_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ChunkDiskStorage::initialize(dir.path(), 9);
+        let location = ChunkCoordinate::new(-1, 2, -3);
+
+        // Version 1 files were nothing but the compressed blocks.
+        let mut chunk = ChunkData::create(location);
+        chunk.get_data_mut()[7] = 42;
+        let mut compressor = DeflateEncoder::new(Vec::new(), Compression::new(9));
+        for block in chunk.get_data() {
+            compressor.write_all(&block.to_le_bytes()).unwrap();
+        }
+        let legacy_name = ChunkDiskStorage::create_legacy_chunk_file_name(location).unwrap();
+        assert_eq!(legacy_name.len(), 12);
+        fs::write(dir.path().join(&legacy_name), compressor.finish().unwrap()).unwrap();
+
+        let loaded = storage.get_chunk(location).unwrap().unwrap();
+        assert_eq!(loaded.get_data()[7], 42);
+
+        storage.save_chunk(&loaded).unwrap();
+        assert!(!dir.path().join(&legacy_name).exists());
+        assert_eq!(storage.get_chunk(location).unwrap().unwrap().get_data()[7], 42);
+
+        // Chunks that didn't fit in 16 bits never had a version 1 file.
+        assert!(ChunkDiskStorage::create_legacy_chunk_file_name(ChunkCoordinate::new(0x8000, 0, 0)).is_none());
+    }
+
+    /// Chunks saved by a newer version of the game are refused rather than misread.
+    #[test]
+    fn newer_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ChunkDiskStorage::initialize(dir.path(), 9);
+        let location = ChunkCoordinate::new(0, 0, 0);
+        storage.save_chunk(&ChunkData::create(location)).unwrap();
+
+        let path = storage.create_chunk_path(0, 0, 0);
+        let mut data = fs::read(&path).unwrap();
+        data[4..8].copy_from_slice(&(CHUNK_FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, data).unwrap();
+
+        assert!(storage.get_chunk(location).is_err());
+    }
+
     #[test]
     #[allow(overflowing_literals)] // Makes it so we can ignore the overflow when writing hexadecimal.
     fn generate_chunk_file_names() {
@@ -254,27 +601,33 @@ mod test_fileformate {
         // doesn't go into the details of the keys.
         assert_eq!(
             ChunkDiskStorage::create_chunk_file_name(ChunkDiskStorage::create_chunk_key(0x0000, 0x0000, 0x0000)),
-            "000000000000"
+            "000000000000000000000000"
         );
 
         assert_eq!(
             ChunkDiskStorage::create_chunk_file_name(ChunkDiskStorage::create_chunk_key(0x8000, 0x8000, 0x8000)),
-            "E00000000000"
+            "000000000000E00000000000"
         );
 
         assert_eq!(
             ChunkDiskStorage::create_chunk_file_name(ChunkDiskStorage::create_chunk_key(0x0800, 0x0800, 0x0800)),
-            "000E00000000"
+            "000000000000000E00000000"
         );
 
         assert_eq!(
             ChunkDiskStorage::create_chunk_file_name(ChunkDiskStorage::create_chunk_key(0x0080, 0x0080, 0x0080)),
-            "000000E00000"
+            "000000000000000000E00000"
         );
 
         assert_eq!(
             ChunkDiskStorage::create_chunk_file_name(ChunkDiskStorage::create_chunk_key(0x0008, 0x0008, 0x0008)),
-            "000000000E00"
+            "000000000000000000000E00"
+        );
+
+        // The widest keys still fit.
+        assert_eq!(
+            ChunkDiskStorage::create_chunk_file_name(ChunkDiskStorage::create_chunk_key(-1, -1, -1)),
+            "FFFFFFFFFFFFFFFFFFFFFFFF"
         );
     }
 
@@ -348,5 +701,17 @@ mod test_fileformate {
         assert_eq!(ChunkDiskStorage::create_chunk_key(0x0001, 0x0000, 0x0000), ChunkKey(0x0000000000000004));
         assert_eq!(ChunkDiskStorage::create_chunk_key(0x0000, 0x0001, 0x0000), ChunkKey(0x0000000000000002));
         assert_eq!(ChunkDiskStorage::create_chunk_key(0x0000, 0x0000, 0x0001), ChunkKey(0x0000000000000001));
+
+        // Chunk coordinates are 32 bits wide, so the keys reach all the way up to the 96th bit.
+        assert_eq!(ChunkDiskStorage::create_chunk_key(0x10000, 0x0000, 0x0000), ChunkKey(0x0004000000000000));
+        assert_eq!(
+            ChunkDiskStorage::create_chunk_key(0x80000000, 0x0000, 0x0000),
+            ChunkKey(0x00000000800000000000000000000000)
+        );
+        assert_eq!(
+            ChunkDiskStorage::create_chunk_key(0x0000, 0x0000, 0x80000000),
+            ChunkKey(0x00000000200000000000000000000000)
+        );
+        assert_eq!(ChunkDiskStorage::create_chunk_key(-1, -1, -1), ChunkKey(0x00000000FFFFFFFFFFFFFFFFFFFFFFFF));
     }
 }
@@ -0,0 +1,715 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Structural integrity of things that have been built, so that factories and bridges need something to hold them up.
+//! Only blocks that were built are simulated. Anything else solid is natural terrain, which never breaks and anchors
+//! whatever is built on it.
+
+use super::{connectivity::FACE_NEIGHBORS, BlockID, GlobalBlockCoordinate, GridWorld};
+use legion::system;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// The default number of blocks the solver may visit each tick.
+pub const DEFAULT_BLOCK_VISIT_BUDGET: usize = 4096;
+
+/// Something that went wrong with a structure, which the world needs to act on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StructuralFailure {
+    /// A block is carrying more weight than it can handle and breaks.
+    Overstressed(GlobalBlockCoordinate),
+
+    /// A group of built blocks no longer connects to any natural terrain and falls.
+    Unsupported(Vec<GlobalBlockCoordinate>),
+}
+
+/// What the solver knows about a single built block.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct StructuralBlock {
+    weight: f32,
+    strength: f32,
+    grounded: bool,
+    load: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum SolvePhase {
+    Gather,
+    Distance,
+    Load,
+}
+
+/// The work of solving a single connected structure. It can be paused part way through and picked up on a later tick.
+#[derive(Serialize, Deserialize)]
+struct SolveJob {
+    seed: GlobalBlockCoordinate,
+    phase: SolvePhase,
+    members: Vec<GlobalBlockCoordinate>,
+    member_indexes: HashMap<GlobalBlockCoordinate, usize>,
+    cursor: usize,
+    distances: Vec<u32>,
+    distance_queue: VecDeque<usize>,
+    order: Vec<usize>,
+    loads: Vec<f32>,
+}
+
+impl SolveJob {
+    fn new(seed: GlobalBlockCoordinate) -> SolveJob {
+        let mut member_indexes = HashMap::new();
+        member_indexes.insert(seed, 0);
+
+        SolveJob {
+            seed,
+            phase: SolvePhase::Gather,
+            members: vec![seed],
+            member_indexes,
+            cursor: 0,
+            distances: Vec::new(),
+            distance_queue: VecDeque::new(),
+            order: Vec::new(),
+            loads: Vec::new(),
+        }
+    }
+}
+
+/// A solver for the load on built blocks. It lives in the ECS resources and does its work in an ECS system, a limited
+/// number of blocks at a time so that huge structures don't stall the game.
+///
+/// Load flows from each block towards the nearest natural terrain. A block passes everything it carries, plus its own
+/// weight, to the block below it if that block is on the way to the ground, and otherwise splits it between its
+/// neighbors that are. A block carrying more than its material's strength breaks.
+#[derive(Serialize, Deserialize)]
+pub struct StructuralIntegrity {
+    enabled: bool,
+    block_visit_budget: usize,
+    blocks: HashMap<GlobalBlockCoordinate, StructuralBlock>,
+    dirty: VecDeque<GlobalBlockCoordinate>,
+    dirty_set: HashSet<GlobalBlockCoordinate>,
+    job: Option<SolveJob>,
+    failures: Vec<StructuralFailure>,
+}
+
+impl StructuralIntegrity {
+    /// Create a new solver. It starts disabled.
+    pub fn new() -> StructuralIntegrity {
+        StructuralIntegrity {
+            enabled: false,
+            block_visit_budget: DEFAULT_BLOCK_VISIT_BUDGET,
+            blocks: HashMap::new(),
+            dirty: VecDeque::new(),
+            dirty_set: HashSet::new(),
+            job: None,
+            failures: Vec::new(),
+        }
+    }
+
+    /// True if the solver is tracking built blocks.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enable or disable the solver. Disabling it forgets every built block, so they'll be treated as natural terrain
+    /// if it is enabled again.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+
+        if !enabled {
+            self.blocks.clear();
+            self.dirty.clear();
+            self.dirty_set.clear();
+            self.job = None;
+            self.failures.clear();
+        }
+    }
+
+    /// The number of blocks the solver may visit each tick.
+    pub fn block_visit_budget(&self) -> usize {
+        self.block_visit_budget
+    }
+
+    /// Set the number of blocks the solver may visit each tick.
+    pub fn set_block_visit_budget(&mut self, block_visit_budget: usize) {
+        self.block_visit_budget = block_visit_budget.max(1);
+    }
+
+    /// True if a block is being tracked as a built block.
+    pub fn is_built(&self, location: GlobalBlockCoordinate) -> bool {
+        self.blocks.contains_key(&location)
+    }
+
+    /// The load on a built block, from the last time its structure was solved.
+    pub fn load(&self, location: GlobalBlockCoordinate) -> Option<f32> {
+        self.blocks.get(&location).map(|block| block.load)
+    }
+
+    /// True if there is no more work waiting to be done.
+    pub fn is_settled(&self) -> bool {
+        self.job.is_none() && self.dirty_set.is_empty()
+    }
+
+    /// Take the failures that have been found so far.
+    pub fn take_failures(&mut self) -> Vec<StructuralFailure> {
+        std::mem::take(&mut self.failures)
+    }
+
+    /// Start tracking a built block.
+    pub fn add_block(&mut self, location: GlobalBlockCoordinate, weight: f32, strength: f32, grounded: bool) {
+        self.blocks.insert(location, StructuralBlock { weight, strength, grounded, load: weight });
+        self.mark_dirty(location);
+    }
+
+    /// Stop tracking a built block.
+    pub fn remove_block(&mut self, location: GlobalBlockCoordinate) {
+        if self.blocks.remove(&location).is_some() {
+            // Removing a block can split its structure, so each side needs to be looked at on its own.
+            for offset in FACE_NEIGHBORS.iter() {
+                self.mark_dirty(location + offset);
+            }
+        }
+    }
+
+    /// Change whether a built block touches natural terrain.
+    pub fn set_grounded(&mut self, location: GlobalBlockCoordinate, grounded: bool) {
+        if let Some(block) = self.blocks.get_mut(&location) {
+            if block.grounded != grounded {
+                block.grounded = grounded;
+                self.mark_dirty(location);
+            }
+        }
+    }
+
+    fn mark_dirty(&mut self, location: GlobalBlockCoordinate) {
+        if self.blocks.contains_key(&location) && self.dirty_set.insert(location) {
+            self.dirty.push_back(location);
+        }
+
+        // If the structure we were working on has changed under us, it has to be started over. A change right next to
+        // it counts, since the block may have joined it.
+        let touches_job = self.job.as_ref().is_some_and(|job| {
+            job.member_indexes.contains_key(&location)
+                || FACE_NEIGHBORS.iter().any(|offset| job.member_indexes.contains_key(&(location + offset)))
+        });
+
+        if touches_job {
+            if let Some(job) = self.job.take() {
+                if self.dirty_set.insert(job.seed) {
+                    self.dirty.push_back(job.seed);
+                }
+            }
+        }
+    }
+
+    /// Do as much work as the budget allows.
+    pub fn run(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut budget = self.block_visit_budget;
+        while budget > 0 {
+            let mut job = match self.job.take() {
+                Some(job) => job,
+                None => match self.next_dirty() {
+                    Some(seed) => SolveJob::new(seed),
+                    None => return,
+                },
+            };
+
+            if self.step(&mut job, &mut budget) {
+                self.finish(job);
+            } else {
+                self.job = Some(job);
+            }
+        }
+    }
+
+    fn next_dirty(&mut self) -> Option<GlobalBlockCoordinate> {
+        while let Some(location) = self.dirty.pop_front() {
+            // Blocks are taken out of the set when a structure containing them gets solved, which may have happened
+            // after they were queued.
+            if self.dirty_set.remove(&location) && self.blocks.contains_key(&location) {
+                return Some(location);
+            }
+        }
+
+        None
+    }
+
+    /// Work on a job until it's done or we run out of budget. Returns true when the job is done.
+    fn step(&self, job: &mut SolveJob, budget: &mut usize) -> bool {
+        while *budget > 0 {
+            *budget -= 1;
+
+            match job.phase {
+                SolvePhase::Gather => {
+                    // Flood fill to find every block in the structure.
+                    let location = job.members[job.cursor];
+                    for offset in FACE_NEIGHBORS.iter() {
+                        let neighbor = location + offset;
+                        if self.blocks.contains_key(&neighbor) && !job.member_indexes.contains_key(&neighbor) {
+                            job.member_indexes.insert(neighbor, job.members.len());
+                            job.members.push(neighbor);
+                        }
+                    }
+
+                    job.cursor += 1;
+                    if job.cursor >= job.members.len() {
+                        job.distances = vec![u32::MAX; job.members.len()];
+                        for (index, location) in job.members.iter().enumerate() {
+                            if self.blocks[location].grounded {
+                                job.distances[index] = 0;
+                                job.distance_queue.push_back(index);
+                            }
+                        }
+
+                        job.phase = SolvePhase::Distance;
+                    }
+                }
+                SolvePhase::Distance => {
+                    // Find how far each block is from the ground, which tells us which way its load flows.
+                    if let Some(index) = job.distance_queue.pop_front() {
+                        let distance = job.distances[index] + 1;
+                        for offset in FACE_NEIGHBORS.iter() {
+                            if let Some(neighbor) = job.member_indexes.get(&(job.members[index] + offset)) {
+                                if job.distances[*neighbor] == u32::MAX {
+                                    job.distances[*neighbor] = distance;
+                                    job.distance_queue.push_back(*neighbor);
+                                }
+                            }
+                        }
+                    }
+
+                    if job.distance_queue.is_empty() {
+                        // Furthest from the ground goes first, so everything a block holds up is known before its turn.
+                        job.order = (0..job.members.len()).collect();
+                        let distances = &job.distances;
+                        job.order.sort_by_key(|index| std::cmp::Reverse(distances[*index]));
+                        job.loads = job.members.iter().map(|location| self.blocks[location].weight).collect();
+                        job.cursor = 0;
+                        job.phase = SolvePhase::Load;
+                    }
+                }
+                SolvePhase::Load => {
+                    if job.cursor >= job.order.len() {
+                        return true;
+                    }
+
+                    let index = job.order[job.cursor];
+                    job.cursor += 1;
+
+                    let distance = job.distances[index];
+                    if distance != 0 && distance != u32::MAX {
+                        let location = job.members[index];
+                        let supporters: Vec<usize> = FACE_NEIGHBORS
+                            .iter()
+                            .filter_map(|offset| job.member_indexes.get(&(location + offset)).copied())
+                            .filter(|neighbor| job.distances[*neighbor] == distance - 1)
+                            .collect();
+
+                        let below = job.member_indexes.get(&(location - GlobalBlockCoordinate::new(0, 1, 0))).copied();
+                        let load = job.loads[index];
+
+                        match below.filter(|below| supporters.contains(below)) {
+                            Some(below) => job.loads[below] += load,
+                            None => {
+                                let share = load / supporters.len() as f32;
+                                for supporter in supporters {
+                                    job.loads[supporter] += share;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        job.phase == SolvePhase::Load && job.cursor >= job.order.len()
+    }
+
+    /// Record the results of a finished job.
+    fn finish(&mut self, job: SolveJob) {
+        for location in job.members.iter() {
+            self.dirty_set.remove(location);
+        }
+
+        // Nothing reached the ground, so the whole thing falls.
+        if job.distances.iter().all(|distance| *distance == u32::MAX) {
+            for location in job.members.iter() {
+                self.blocks.remove(location);
+            }
+            self.failures.push(StructuralFailure::Unsupported(job.members));
+
+            return;
+        }
+
+        let mut worst: Option<(GlobalBlockCoordinate, f32)> = None;
+        for (location, load) in job.members.iter().zip(job.loads.iter()) {
+            if let Some(block) = self.blocks.get_mut(location) {
+                block.load = *load;
+
+                let stress = *load / block.strength;
+                if stress > 1.0 && worst.map(|(_location, worst)| stress > worst).unwrap_or(true) {
+                    worst = Some((*location, stress));
+                }
+            }
+        }
+
+        // Only the worst block breaks. The structure gets solved again afterwards, since the load will have shifted.
+        if let Some((location, _stress)) = worst {
+            self.failures.push(StructuralFailure::Overstressed(location));
+        }
+    }
+}
+
+impl Default for StructuralIntegrity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs the structural integrity solver.
+#[system]
+pub(super) fn ecs_structural_integrity(#[resource] structural_integrity: &mut StructuralIntegrity) {
+    structural_integrity.run();
+}
+
+impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
+    /// The weight and strength of a type of block, from the material it is made of.
+    /// Blocks without a material weigh nothing and never break.
+    fn block_structural_properties(&self, block: BlockID) -> (f32, f32) {
+        let material = self
+            .block_registry()
+            .get_block_data_from_id(block)
+            .and_then(|block| block.material())
+            .and_then(|material| self.material_registry.get_material_info(material));
+
+        match material {
+            Some(material) => (material.density() as f32, material.strength() as f32),
+            None => (0.0, f32::INFINITY),
+        }
+    }
+
+    /// True if there's natural terrain at a location.
+    fn is_natural_terrain(&self, location: GlobalBlockCoordinate, structural_integrity: &StructuralIntegrity) -> bool {
+        matches!(self.get_block(location), Some(Some(_))) && !structural_integrity.is_built(location)
+    }
+
+    /// Work out again whether the built blocks next to a location touch natural terrain, since it changed there.
+    fn refresh_grounded_around(&self, location: GlobalBlockCoordinate, structural_integrity: &mut StructuralIntegrity) {
+        for offset in FACE_NEIGHBORS.iter() {
+            let neighbor = location + offset;
+            if structural_integrity.is_built(neighbor) {
+                let grounded =
+                    FACE_NEIGHBORS.iter().any(|offset| self.is_natural_terrain(neighbor + offset, structural_integrity));
+                structural_integrity.set_grounded(neighbor, grounded);
+            }
+        }
+    }
+
+    /// Place a block that has been built, such as by a player. If structural integrity is enabled, the block will need
+    /// to be supported. Returns the block that was there before, or None if the chunk isn't loaded.
+    pub fn place_built_block(&mut self, location: GlobalBlockCoordinate, block: BlockID) -> Option<Option<BlockID>> {
+        let old_block = self.get_block_mut(location)?.replace(block);
+
+        let mut structural_integrity =
+            self.ecs_resources.get_mut::<StructuralIntegrity>().expect("Failed to find structural integrity solver.");
+        if structural_integrity.is_enabled() {
+            let was_natural = old_block.is_some() && !structural_integrity.is_built(location);
+            structural_integrity.remove_block(location);
+
+            let grounded =
+                FACE_NEIGHBORS.iter().any(|offset| self.is_natural_terrain(location + offset, &structural_integrity));
+            let (weight, strength) = self.block_structural_properties(block);
+            structural_integrity.add_block(location, weight, strength, grounded);
+
+            // Built over natural terrain, so whatever was standing on it may have lost its footing.
+            if was_natural {
+                self.refresh_grounded_around(location, &mut structural_integrity);
+            }
+        }
+
+        Some(old_block)
+    }
+
+    /// Place a block of natural terrain, such as from a world generator or a mod. It's never simulated, and anything
+    /// built next to it can stand on it. Returns the block that was there before, or None if the chunk isn't loaded.
+    pub fn place_natural_block(&mut self, location: GlobalBlockCoordinate, block: BlockID) -> Option<Option<BlockID>> {
+        let old_block = self.get_block_mut(location)?.replace(block);
+
+        let mut structural_integrity =
+            self.ecs_resources.get_mut::<StructuralIntegrity>().expect("Failed to find structural integrity solver.");
+        if structural_integrity.is_enabled() {
+            structural_integrity.remove_block(location);
+            self.refresh_grounded_around(location, &mut structural_integrity);
+        }
+
+        Some(old_block)
+    }
+
+    /// Remove a block, built or natural, and let the structural integrity solver know.
+    /// Returns the block that was there before, or None if the chunk isn't loaded.
+    pub fn break_block(&mut self, location: GlobalBlockCoordinate) -> Option<Option<BlockID>> {
+        let old_block = self.get_block_mut(location)?.take();
+
+        let mut structural_integrity =
+            self.ecs_resources.get_mut::<StructuralIntegrity>().expect("Failed to find structural integrity solver.");
+        if structural_integrity.is_enabled() {
+            if structural_integrity.is_built(location) {
+                structural_integrity.remove_block(location);
+            } else {
+                // Natural terrain was removed, so anything built on it may have lost its footing.
+                self.refresh_grounded_around(location, &mut structural_integrity);
+            }
+        }
+
+        Some(old_block)
+    }
+}
+
+impl<ChunkUserData: Default + Send + Sync + 'static> GridWorld<ChunkUserData> {
+    /// Act on anything the structural integrity solver found. Overstressed blocks break off on their own, and
+    /// unsupported structures fall, both as new block grids.
+    pub(super) fn apply_structural_failures(&mut self) {
+        let failures = self
+            .ecs_resources
+            .get_mut::<StructuralIntegrity>()
+            .expect("Failed to find structural integrity solver.")
+            .take_failures();
+
+        for failure in failures {
+            let blocks = match failure {
+                StructuralFailure::Overstressed(location) => {
+                    let mut structural_integrity = self
+                        .ecs_resources
+                        .get_mut::<StructuralIntegrity>()
+                        .expect("Failed to find structural integrity solver.");
+
+                    // It may have been removed since the solver looked at it.
+                    if !structural_integrity.is_built(location) {
+                        continue;
+                    }
+                    structural_integrity.remove_block(location);
+
+                    vec![location]
+                }
+                StructuralFailure::Unsupported(blocks) => blocks,
+            };
+
+            let blocks: Vec<GlobalBlockCoordinate> =
+                blocks.into_iter().filter(|location| matches!(self.get_block(*location), Some(Some(_)))).collect();
+            if !blocks.is_empty() {
+                self.blocks_to_grid(&blocks);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use legion::IntoQuery;
+    use std::time::Duration;
+
+    struct TestWorld {
+        world: GridWorld<()>,
+        strong_block: BlockID,
+        weak_block: BlockID,
+    }
+
+    /// A world with flat ground, a strong material that can hold 10 blocks, and a weak one that can hold 2.
+    fn create_world() -> TestWorld {
+        let mut block_registry = BlockRegistry::new();
+        chunk_providers::register_blocks(&mut block_registry, chunk_providers::AbstractFlatWorld::BLOCKS).unwrap();
+        let mut chunk_provider = chunk_providers::RAMWorld::new(block_registry);
+        chunk_provider.add_generator(chunk_providers::AbstractFlatWorld::new()).unwrap();
+
+        let mut world: GridWorld<()> = GridWorld::new(chunk_provider);
+        world.load_chunk_range(ChunkRange::from_end_points(ChunkCoordinate::new(-1, -1, -1), ChunkCoordinate::new(1, 1, 1)));
+
+        let strong = world.material_registry_mut().register_material(String::from("strong"), 100, 1000);
+        let weak = world.material_registry_mut().register_material(String::from("weak"), 100, 200);
+
+        let registry = world.block_registry_mut();
+        registry.add_block(String::from("strong_block"), String::from("Strong Block")).unwrap();
+        registry.add_block(String::from("weak_block"), String::from("Weak Block")).unwrap();
+        registry.set_block_material("strong_block", strong).unwrap();
+        registry.set_block_material("weak_block", weak).unwrap();
+        let strong_block = *registry.get_block_id_from_name("strong_block").unwrap();
+        let weak_block = *registry.get_block_id_from_name("weak_block").unwrap();
+
+        world.ecs_resources_mut().get_mut::<StructuralIntegrity>().unwrap().set_enabled(true);
+
+        TestWorld { world, strong_block, weak_block }
+    }
+
+    fn settle(world: &mut GridWorld<()>) {
+        for _ in 0..100 {
+            world.update(Duration::from_millis(10));
+            if world.ecs_resources().get::<StructuralIntegrity>().unwrap().is_settled() {
+                return;
+            }
+        }
+
+        panic!("Structures never settled.");
+    }
+
+    fn num_grids(world: &GridWorld<()>) -> usize {
+        <&BlockGrid<()>>::query().iter(world.ecs_world()).count()
+    }
+
+    /// A tower that is strong enough to hold itself up should stay up, with its base holding everything.
+    #[test]
+    fn stable_tower() {
+        let TestWorld { mut world, strong_block, .. } = create_world();
+
+        for y in 0..5 {
+            world.place_built_block(GlobalBlockCoordinate::new(0, y, 0), strong_block).unwrap();
+        }
+        settle(&mut world);
+
+        let structural_integrity = world.ecs_resources().get::<StructuralIntegrity>().unwrap();
+        assert_eq!(structural_integrity.load(GlobalBlockCoordinate::new(0, 0, 0)), Some(500.0));
+        assert_eq!(structural_integrity.load(GlobalBlockCoordinate::new(0, 4, 0)), Some(100.0));
+        drop(structural_integrity);
+
+        assert_eq!(num_grids(&world), 0);
+    }
+
+    /// A weak block at the base of a tower gets crushed, and the rest falls.
+    #[test]
+    fn crushed_base() {
+        let TestWorld { mut world, strong_block, weak_block } = create_world();
+
+        world.place_built_block(GlobalBlockCoordinate::new(0, 0, 0), weak_block).unwrap();
+        for y in 1..5 {
+            world.place_built_block(GlobalBlockCoordinate::new(0, y, 0), strong_block).unwrap();
+        }
+        settle(&mut world);
+
+        for y in 0..5 {
+            assert_eq!(world.get_block(GlobalBlockCoordinate::new(0, y, 0)), Some(None));
+        }
+
+        // The crushed block and the tower above it.
+        assert_eq!(num_grids(&world), 2);
+    }
+
+    /// Something built in the air with nothing holding it up falls.
+    #[test]
+    fn floating() {
+        let TestWorld { mut world, strong_block, .. } = create_world();
+
+        world.place_built_block(GlobalBlockCoordinate::new(0, 10, 0), strong_block).unwrap();
+        world.place_built_block(GlobalBlockCoordinate::new(1, 10, 0), strong_block).unwrap();
+        settle(&mut world);
+
+        assert_eq!(world.get_block(GlobalBlockCoordinate::new(0, 10, 0)), Some(None));
+        assert_eq!(num_grids(&world), 1);
+    }
+
+    /// Digging out the ground under a structure makes it fall.
+    #[test]
+    fn undermined() {
+        let TestWorld { mut world, strong_block, .. } = create_world();
+
+        world.place_built_block(GlobalBlockCoordinate::new(0, 0, 0), strong_block).unwrap();
+        world.place_built_block(GlobalBlockCoordinate::new(0, 1, 0), strong_block).unwrap();
+        settle(&mut world);
+        assert_eq!(num_grids(&world), 0);
+
+        world.break_block(GlobalBlockCoordinate::new(0, -1, 0)).unwrap();
+        settle(&mut world);
+        assert_eq!(num_grids(&world), 1);
+    }
+
+    /// Putting natural terrain under a structure gives it something to stand on.
+    #[test]
+    fn natural_support() {
+        let TestWorld { mut world, strong_block, weak_block } = create_world();
+
+        world.place_built_block(GlobalBlockCoordinate::new(0, 1, 0), strong_block).unwrap();
+        world.place_natural_block(GlobalBlockCoordinate::new(0, 0, 0), weak_block).unwrap();
+        settle(&mut world);
+
+        assert_eq!(world.get_block(GlobalBlockCoordinate::new(0, 1, 0)), Some(Some(strong_block)));
+        assert_eq!(num_grids(&world), 0);
+
+        // Building over that terrain takes the support away again.
+        world.place_built_block(GlobalBlockCoordinate::new(0, 0, 0), weak_block).unwrap();
+        world.break_block(GlobalBlockCoordinate::new(0, -1, 0)).unwrap();
+        settle(&mut world);
+        assert_eq!(num_grids(&world), 1);
+    }
+
+    /// Changes away from the structure being solved don't make the solver start it over.
+    #[test]
+    fn unrelated_changes() {
+        let mut structural_integrity = StructuralIntegrity::new();
+        structural_integrity.set_enabled(true);
+        structural_integrity.set_block_visit_budget(2);
+
+        for y in 0..8 {
+            structural_integrity.add_block(GlobalBlockCoordinate::new(0, y, 0), 1.0, 100.0, y == 0);
+        }
+        structural_integrity.run();
+        let cursor = structural_integrity.job.as_ref().unwrap().cursor;
+
+        structural_integrity.add_block(GlobalBlockCoordinate::new(10, 0, 0), 1.0, 100.0, true);
+        assert_eq!(structural_integrity.job.as_ref().unwrap().cursor, cursor);
+
+        // Right next to the structure, so the new block may be part of it.
+        structural_integrity.add_block(GlobalBlockCoordinate::new(1, 0, 0), 1.0, 100.0, true);
+        assert!(structural_integrity.job.is_none());
+    }
+
+    /// The load of a bridge is split between its two ends.
+    #[test]
+    fn bridge() {
+        let TestWorld { mut world, strong_block, .. } = create_world();
+
+        for y in 0..2 {
+            world.place_built_block(GlobalBlockCoordinate::new(0, y, 0), strong_block).unwrap();
+            world.place_built_block(GlobalBlockCoordinate::new(4, y, 0), strong_block).unwrap();
+        }
+        for x in 1..4 {
+            world.place_built_block(GlobalBlockCoordinate::new(x, 1, 0), strong_block).unwrap();
+        }
+        settle(&mut world);
+
+        let structural_integrity = world.ecs_resources().get::<StructuralIntegrity>().unwrap();
+        assert_eq!(structural_integrity.load(GlobalBlockCoordinate::new(0, 0, 0)), Some(350.0));
+        assert_eq!(structural_integrity.load(GlobalBlockCoordinate::new(4, 0, 0)), Some(350.0));
+    }
+
+    /// A small budget spreads the work over several ticks, but gets the same answer.
+    #[test]
+    fn budget() {
+        let TestWorld { mut world, strong_block, .. } = create_world();
+        world.ecs_resources_mut().get_mut::<StructuralIntegrity>().unwrap().set_block_visit_budget(4);
+
+        for y in 0..8 {
+            world.place_built_block(GlobalBlockCoordinate::new(0, y, 0), strong_block).unwrap();
+        }
+
+        world.update(Duration::from_millis(10));
+        assert!(!world.ecs_resources().get::<StructuralIntegrity>().unwrap().is_settled());
+
+        settle(&mut world);
+        let structural_integrity = world.ecs_resources().get::<StructuralIntegrity>().unwrap();
+        assert_eq!(structural_integrity.load(GlobalBlockCoordinate::new(0, 0, 0)), Some(800.0));
+    }
+
+    /// When disabled, nothing is tracked and nothing falls.
+    #[test]
+    fn disabled() {
+        let TestWorld { mut world, strong_block, .. } = create_world();
+        world.ecs_resources_mut().get_mut::<StructuralIntegrity>().unwrap().set_enabled(false);
+
+        world.place_built_block(GlobalBlockCoordinate::new(0, 10, 0), strong_block).unwrap();
+        settle(&mut world);
+
+        assert_eq!(world.get_block(GlobalBlockCoordinate::new(0, 10, 0)), Some(Some(strong_block)));
+        assert_eq!(num_grids(&world), 0);
+    }
+}
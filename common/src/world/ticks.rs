@@ -0,0 +1,357 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Block ticks, which let blocks do things over time such as grow, decay, or trigger other blocks.
+
+use super::{
+    block_index_to_local, chunk_providers::noise, storage, BlockID, ChunkCoordinate, GlobalBlockCoordinate, GridWorld,
+    LocalBlockCoordinate, LocalBlockCoordinateExt, WorldTime,
+};
+use derive_error::Error;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, rc::Rc};
+
+/// Error type for block ticks.
+#[derive(Debug, Error)]
+pub enum TickError {
+    /// Ticks can only be scheduled for blocks in chunks that are loaded.
+    ChunkNotLoaded,
+}
+
+/// A block tick error type.
+pub type TickResult<O> = std::result::Result<O, TickError>;
+
+/// A tick that has been scheduled to happen to a block at a specific time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledTick {
+    time: WorldTime,
+    location: LocalBlockCoordinate,
+}
+
+impl ScheduledTick {
+    /// The time this tick is to happen.
+    pub fn time(&self) -> WorldTime {
+        self.time
+    }
+
+    /// The location of the block to be ticked, within its chunk.
+    pub fn location(&self) -> LocalBlockCoordinate {
+        self.location
+    }
+}
+
+/// All of the ticks scheduled for blocks within a single chunk.
+/// This is stored with the chunk, so that saving the chunk saves its ticks too.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ChunkTickSchedule {
+    // Kept sorted by time. Ticks scheduled for the same time happen in the order they were scheduled.
+    ticks: Vec<ScheduledTick>,
+}
+
+impl ChunkTickSchedule {
+    /// Schedule a block within the chunk to be ticked at the specified time.
+    pub fn schedule(&mut self, location: LocalBlockCoordinate, time: WorldTime) {
+        let index = self.ticks.partition_point(|tick| tick.time <= time);
+        self.ticks.insert(index, ScheduledTick { time, location: location.validate() });
+    }
+
+    /// Remove and return all ticks that are due at or before the provided time.
+    pub fn take_due(&mut self, now: WorldTime) -> Vec<ScheduledTick> {
+        let num_due = self.ticks.partition_point(|tick| tick.time <= now);
+        self.ticks.drain(..num_due).collect()
+    }
+
+    /// Iterate the scheduled ticks in the order they will happen.
+    pub fn iter(&self) -> impl Iterator<Item = &ScheduledTick> {
+        self.ticks.iter()
+    }
+
+    /// The number of ticks waiting to happen.
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    /// True if there are no ticks waiting to happen.
+    pub fn is_empty(&self) -> bool {
+        self.ticks.is_empty()
+    }
+}
+
+/// Something that gives a type of block behavior over time.
+/// Both methods do nothing by default, so you only need to implement the ones your block cares about.
+pub trait BlockTickHandler<ChunkUserData> {
+    /// Called when a tick scheduled for a block of this type comes due.
+    fn scheduled_tick(&self, _world: &mut GridWorld<ChunkUserData>, _location: GlobalBlockCoordinate) {}
+
+    /// Called when a block of this type is randomly selected to be ticked.
+    fn random_tick(&self, _world: &mut GridWorld<ChunkUserData>, _location: GlobalBlockCoordinate) {}
+}
+
+/// Keeps track of which blocks respond to ticks and how often random ticks happen.
+pub struct TickScheduler<ChunkUserData> {
+    handlers: HashMap<BlockID, Rc<dyn BlockTickHandler<ChunkUserData>>>,
+    random_ticks_per_chunk: u32,
+    random_state: u64,
+}
+
+impl<ChunkUserData> TickScheduler<ChunkUserData> {
+    /// By default, this many blocks of each chunk are randomly ticked per update.
+    pub const DEFAULT_RANDOM_TICKS_PER_CHUNK: u32 = 3;
+
+    /// Create a new tick scheduler with no handlers.
+    pub fn new() -> TickScheduler<ChunkUserData> {
+        TickScheduler {
+            handlers: HashMap::new(),
+            random_ticks_per_chunk: Self::DEFAULT_RANDOM_TICKS_PER_CHUNK,
+            random_state: 0,
+        }
+    }
+
+    /// Set the handler to be used for a type of block. Replaces the old handler if there was one.
+    pub fn register_handler(&mut self, block: BlockID, handler: Box<dyn BlockTickHandler<ChunkUserData>>) {
+        self.handlers.insert(block, Rc::from(handler));
+    }
+
+    /// Stop using a handler for a type of block. Nothing happens if the block has a different handler by now. Returns
+    /// false if the handler wasn't the block's.
+    pub fn unregister_handler(&mut self, block: BlockID, handler: &Rc<dyn BlockTickHandler<ChunkUserData>>) -> bool {
+        let registered = self.handlers.get(&block).is_some_and(|registered| Rc::ptr_eq(registered, handler));
+        if registered {
+            self.handlers.remove(&block);
+        }
+
+        registered
+    }
+
+    /// Get the handler for a type of block.
+    pub fn get_handler(&self, block: BlockID) -> Option<Rc<dyn BlockTickHandler<ChunkUserData>>> {
+        self.handlers.get(&block).cloned()
+    }
+
+    /// The number of blocks randomly selected for a tick in each loaded chunk per update.
+    pub fn random_ticks_per_chunk(&self) -> u32 {
+        self.random_ticks_per_chunk
+    }
+
+    /// Set the number of blocks randomly selected for a tick in each loaded chunk per update.
+    pub fn set_random_ticks_per_chunk(&mut self, random_ticks_per_chunk: u32) {
+        self.random_ticks_per_chunk = random_ticks_per_chunk;
+    }
+
+    /// Where the random tick generator is in its sequence. Kept in snapshots so random ticks carry on the same way.
+    pub(super) fn random_state(&self) -> u64 {
+        self.random_state
+    }
+
+    /// Pick up the random tick generator's sequence from a snapshot.
+    pub(super) fn set_random_state(&mut self, random_state: u64) {
+        self.random_state = random_state;
+    }
+
+    /// Pick a random block index within a chunk.
+    /// This is a splitmix64 generator, which is small, fast, and gives the same results on every platform.
+    /// [noise::mix] steps the state forward itself before mixing it, so the state moves on by the same amount.
+    fn next_random_block_index(&mut self) -> usize {
+        let value = noise::mix(self.random_state);
+        self.random_state = self.random_state.wrapping_add(0x9E3779B97F4A7C15);
+
+        (value % (storage::CHUNK_DIAMETER * storage::CHUNK_DIAMETER * storage::CHUNK_DIAMETER) as u64) as usize
+    }
+}
+
+impl<ChunkUserData> Default for TickScheduler<ChunkUserData> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Chunks are stored in a hash map, which has no stable order. We sort them so that ticks happen in the same
+/// order every time.
+fn sorted_chunk_indexes<ChunkUserData>(world: &GridWorld<ChunkUserData>) -> Vec<ChunkCoordinate> {
+    let mut indexes: Vec<ChunkCoordinate> = world.terrain_chunks.keys().copied().collect();
+    indexes.sort_by_key(|index| (index.x, index.y, index.z));
+
+    indexes
+}
+
+/// Run all scheduled ticks that have come due, and then the random ticks for every loaded chunk.
+/// Ticks scheduled by a handler while this runs will not happen until the next call, even if they are already due.
+pub(super) fn run_block_ticks<ChunkUserData: Default>(world: &mut GridWorld<ChunkUserData>) {
+    let now = world.time();
+    let chunk_indexes = sorted_chunk_indexes(world);
+
+    let mut due_ticks = Vec::new();
+    for chunk_index in chunk_indexes.iter() {
+        if let Some(chunk) = world.terrain_chunks.get_mut(chunk_index) {
+            due_ticks.extend(
+                chunk
+                    .scheduled_ticks_mut()
+                    .take_due(now)
+                    .into_iter()
+                    .map(|tick| (tick.time, tick.location.to_global_block_coordinate(*chunk_index))),
+            );
+        }
+    }
+
+    // Stable, so ticks at the same time keep their chunk order.
+    due_ticks.sort_by_key(|(time, _location)| *time);
+
+    for (_time, location) in due_ticks {
+        // The block may have changed since the tick was scheduled, so we go with whatever is there now.
+        if let Some(Some(block)) = world.get_block(location) {
+            if let Some(handler) = world.tick_scheduler.get_handler(block) {
+                handler.scheduled_tick(world, location);
+            }
+        }
+    }
+
+    let mut random_ticks = Vec::new();
+    for chunk_index in chunk_indexes.iter() {
+        for _ in 0..world.tick_scheduler.random_ticks_per_chunk {
+            let block_index = world.tick_scheduler.next_random_block_index();
+
+            if let Some(chunk) = world.terrain_chunks.get(chunk_index) {
+                if let Ok(Some(block)) = chunk.direct_access(block_index) {
+                    if let Some(handler) = world.tick_scheduler.get_handler(block) {
+                        random_ticks
+                            .push((handler, block_index_to_local(block_index).to_global_block_coordinate(*chunk_index)));
+                    }
+                }
+            }
+        }
+    }
+
+    for (handler, location) in random_ticks {
+        handler.random_tick(world, location);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use std::{cell::RefCell, time::Duration};
+
+    /// Records every tick it gets.
+    struct RecordingHandler {
+        scheduled: Rc<RefCell<Vec<GlobalBlockCoordinate>>>,
+        random: Rc<RefCell<Vec<GlobalBlockCoordinate>>>,
+    }
+
+    impl BlockTickHandler<()> for RecordingHandler {
+        fn scheduled_tick(&self, _world: &mut GridWorld<()>, location: GlobalBlockCoordinate) {
+            self.scheduled.borrow_mut().push(location);
+        }
+
+        fn random_tick(&self, _world: &mut GridWorld<()>, location: GlobalBlockCoordinate) {
+            self.random.borrow_mut().push(location);
+        }
+    }
+
+    /// Ticks must come out in the order of their time, and in the order they were scheduled when the times match.
+    #[test]
+    fn schedule_order() {
+        let mut schedule = ChunkTickSchedule::default();
+        schedule.schedule(LocalBlockCoordinate::new(3, 0, 0), WorldTime::from_ms(300));
+        schedule.schedule(LocalBlockCoordinate::new(1, 0, 0), WorldTime::from_ms(100));
+        schedule.schedule(LocalBlockCoordinate::new(2, 0, 0), WorldTime::from_ms(100));
+
+        let due = schedule.take_due(WorldTime::from_ms(200));
+        assert_eq!(due.len(), 2);
+        assert_eq!(due[0].location(), LocalBlockCoordinate::new(1, 0, 0));
+        assert_eq!(due[1].location(), LocalBlockCoordinate::new(2, 0, 0));
+        assert_eq!(schedule.len(), 1);
+    }
+
+    /// A scheduled tick should only happen once its time has come.
+    #[test]
+    fn scheduled_tick() {
+        let mut world = flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::zeros(), ChunkCoordinate::zeros()));
+        world.load_chunk(ChunkCoordinate::new(0, -1, 0));
+        world.tick_scheduler_mut().set_random_ticks_per_chunk(0);
+
+        let block = *world.block_registry().get_block_id_from_name("abstract_block").unwrap();
+        let scheduled = Rc::new(RefCell::new(Vec::new()));
+        let random = Rc::new(RefCell::new(Vec::new()));
+        world
+            .tick_scheduler_mut()
+            .register_handler(block, Box::new(RecordingHandler { scheduled: scheduled.clone(), random: random.clone() }));
+
+        let location = GlobalBlockCoordinate::new(1, -2, 3);
+        world.schedule_tick(location, WorldTime::from_ms(50)).unwrap();
+
+        world.update(Duration::from_millis(10));
+        assert!(scheduled.borrow().is_empty());
+
+        world.update(Duration::from_millis(40));
+        assert_eq!(scheduled.borrow().as_slice(), &[location]);
+
+        // It should not happen a second time.
+        world.update(Duration::from_millis(10));
+        assert_eq!(scheduled.borrow().len(), 1);
+        assert!(random.borrow().is_empty());
+    }
+
+    /// A scheduled tick is saved with its chunk, and still happens once the chunk is loaded again.
+    #[test]
+    fn scheduled_tick_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut world = flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::zeros(), ChunkCoordinate::zeros()));
+        world.set_chunk_storage(Some(storage::ChunkDiskStorage::initialize(dir.path(), 9)));
+        world.load_chunk(ChunkCoordinate::new(0, -1, 0));
+        world.tick_scheduler_mut().set_random_ticks_per_chunk(0);
+
+        let block = *world.block_registry().get_block_id_from_name("abstract_block").unwrap();
+        let scheduled = Rc::new(RefCell::new(Vec::new()));
+        let random = Rc::new(RefCell::new(Vec::new()));
+        world.tick_scheduler_mut().register_handler(block, Box::new(RecordingHandler { scheduled: scheduled.clone(), random }));
+
+        let location = GlobalBlockCoordinate::new(1, -2, 3);
+        world.schedule_tick(location, WorldTime::from_ms(50)).unwrap();
+
+        world.unload_chunk(ChunkCoordinate::new(0, -1, 0)).unwrap();
+        assert!(world.get_chunk(&ChunkCoordinate::new(0, -1, 0)).is_none());
+
+        // Time passes without the chunk, so the tick is already due when it comes back.
+        world.update(Duration::from_millis(100));
+        assert!(scheduled.borrow().is_empty());
+
+        let chunk = world.load_chunk(ChunkCoordinate::new(0, -1, 0));
+        assert_eq!(chunk.scheduled_ticks().len(), 1);
+
+        world.update(Duration::from_millis(10));
+        assert_eq!(scheduled.borrow().as_slice(), &[location]);
+    }
+
+    /// You can't schedule ticks in chunks that don't exist.
+    #[test]
+    fn schedule_unloaded() {
+        let mut world = flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::zeros(), ChunkCoordinate::zeros()));
+        assert!(world.schedule_tick(GlobalBlockCoordinate::new(0, 0, 0), WorldTime::from_ms(0)).is_err());
+    }
+
+    /// Random ticks should only land on blocks that have handlers.
+    #[test]
+    fn random_ticks() {
+        let mut world = flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::zeros(), ChunkCoordinate::zeros()));
+        world.load_chunk(ChunkCoordinate::new(0, -1, 0));
+        world.load_chunk(ChunkCoordinate::new(0, 0, 0));
+        world.tick_scheduler_mut().set_random_ticks_per_chunk(10);
+
+        let block = *world.block_registry().get_block_id_from_name("abstract_block").unwrap();
+        let scheduled = Rc::new(RefCell::new(Vec::new()));
+        let random = Rc::new(RefCell::new(Vec::new()));
+        world
+            .tick_scheduler_mut()
+            .register_handler(block, Box::new(RecordingHandler { scheduled: scheduled.clone(), random: random.clone() }));
+
+        world.update(Duration::from_millis(10));
+
+        // Only the chunk below ground level has blocks in it.
+        let random = random.borrow();
+        assert_eq!(random.len(), 10);
+        for location in random.iter() {
+            assert_eq!(location.chunk_index(), ChunkCoordinate::new(0, -1, 0));
+        }
+    }
+}
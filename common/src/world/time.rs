@@ -3,14 +3,15 @@
 
 //! Utilities used for managing game time.
 
-use std::{ops, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{f64::consts::TAU, fmt, ops, time::Duration};
 
 /// Simulation time. Is tracked in milliseconds.
 /// Although you can operate on it using std::time::Duration, this struct only
 /// has precision in milliseconds. That means that if you set the microseconds
 /// or nanoseconds of the duration, they will be truncated from the final
 /// product.
-#[derive(Copy, Clone, PartialOrd, PartialEq, Ord, Eq)]
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq, Ord, Eq, Hash, Serialize, Deserialize)]
 pub struct WorldTime {
     time_ms: u64,
 }
@@ -20,9 +21,6 @@ impl WorldTime {
     pub fn from_ms(ms: u64) -> WorldTime {
         WorldTime { time_ms: ms }
     }
-
-    // TODO display formatters.
-    // TODO get delta by subtracting another
 }
 
 impl WorldTime {
@@ -30,13 +28,36 @@ impl WorldTime {
     pub fn as_millis(&self) -> u64 {
         self.time_ms
     }
+
+    /// How long after an earlier time this is. If the other time is actually later, the result is zero.
+    pub fn duration_since(&self, earlier: WorldTime) -> Duration {
+        Duration::from_millis(self.time_ms.saturating_sub(earlier.time_ms))
+    }
+
+    /// Move the time forward, or None if that would overflow.
+    pub fn checked_add(&self, duration: Duration) -> Option<WorldTime> {
+        self.time_ms.checked_add(duration.as_millis() as u64).map(Self::from_ms)
+    }
+
+    /// Move the time back, or None if that would go before the world was created.
+    pub fn checked_sub(&self, duration: Duration) -> Option<WorldTime> {
+        self.time_ms.checked_sub(duration.as_millis() as u64).map(Self::from_ms)
+    }
+}
+
+/// Shown as the time since the world was created, in hours, minutes, seconds and milliseconds.
+impl fmt::Display for WorldTime {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.time_ms / 1000;
+        write!(formatter, "{}:{:02}:{:02}.{:03}", seconds / 3600, seconds / 60 % 60, seconds % 60, self.time_ms % 1000)
+    }
 }
 
 impl ops::Add<Duration> for WorldTime {
     type Output = WorldTime;
 
     fn add(self, other: Duration) -> Self {
-        Self::from_ms(self.time_ms - other.as_millis() as u64)
+        Self::from_ms(self.time_ms + other.as_millis() as u64)
     }
 }
 
@@ -68,7 +89,204 @@ impl ops::SubAssign<Duration> for WorldTime {
     }
 }
 
+/// The parts of a day, as far as the light is concerned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DayPhase {
+    /// The sun is coming up.
+    Dawn,
+
+    /// The sun is up.
+    Day,
+
+    /// The sun is going down.
+    Dusk,
+
+    /// The sun is down.
+    Night,
+}
+
+/// A moment on the in-game calendar.
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq, Ord, Eq, Hash, Serialize, Deserialize)]
+pub struct GameDate {
+    /// The day, counting from zero.
+    pub day: u64,
+
+    /// The hour of the day, from 0 to 23.
+    pub hour: u8,
+
+    /// The minute of the hour, from 0 to 59.
+    pub minute: u8,
+}
+
+/// Shown as the day, counting from one like players would, and the time on a 24 hour clock.
+impl fmt::Display for GameDate {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "Day {}, {:02}:{:02}", self.day + 1, self.hour, self.minute)
+    }
+}
+
+/// Turns world time into in-game days, hours and minutes. An in-game day always has 24 hours, but how long it lasts in
+/// world time is up to you.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameCalendar {
+    day_length_ms: u64,
+    start_offset_ms: u64,
+}
+
+impl Default for GameCalendar {
+    fn default() -> Self {
+        // Twenty minute days, starting in the early morning.
+        Self::new(Duration::from_secs(20 * 60), 6)
+    }
+}
+
+impl GameCalendar {
+    /// The hour dawn starts.
+    pub const DAWN_HOUR: u8 = 5;
+
+    /// The hour the sun is fully up.
+    pub const DAY_HOUR: u8 = 7;
+
+    /// The hour dusk starts.
+    pub const DUSK_HOUR: u8 = 17;
+
+    /// The hour the sun is fully down.
+    pub const NIGHT_HOUR: u8 = 19;
+
+    /// Create a calendar where each in-game day lasts `day_length`, and the world starts at `start_hour` of the first
+    /// day. Days shorter than a millisecond are stretched to one.
+    pub fn new(day_length: Duration, start_hour: u8) -> GameCalendar {
+        let day_length_ms = (day_length.as_millis() as u64).max(1);
+        GameCalendar { day_length_ms, start_offset_ms: day_length_ms * (start_hour % 24) as u64 / 24 }
+    }
+
+    /// How long an in-game day lasts in world time.
+    pub fn day_length(&self) -> Duration {
+        Duration::from_millis(self.day_length_ms)
+    }
+
+    /// Milliseconds since midnight of the first day.
+    fn calendar_ms(&self, time: WorldTime) -> u64 {
+        time.as_millis() + self.start_offset_ms
+    }
+
+    /// Get the date and time on the calendar.
+    pub fn date(&self, time: WorldTime) -> GameDate {
+        let calendar_ms = self.calendar_ms(time);
+        let into_day = calendar_ms % self.day_length_ms;
+        let minute_of_day = (into_day as u128 * 24 * 60 / self.day_length_ms as u128) as u64;
+
+        GameDate { day: calendar_ms / self.day_length_ms, hour: (minute_of_day / 60) as u8, minute: (minute_of_day % 60) as u8 }
+    }
+
+    /// How far through the day it is, from 0 at midnight up to, but not including, 1 at the next midnight.
+    pub fn time_of_day(&self, time: WorldTime) -> f64 {
+        (self.calendar_ms(time) % self.day_length_ms) as f64 / self.day_length_ms as f64
+    }
+
+    /// The angle of the sun above the horizon in radians. It rises in the east at 0, is overhead at noon at π/2, sets
+    /// at π, and is directly under the world at midnight, at -π/2.
+    pub fn sun_angle(&self, time: WorldTime) -> f64 {
+        let angle = (self.time_of_day(time) - 0.25) * TAU;
+
+        if angle > std::f64::consts::PI {
+            angle - TAU
+        } else {
+            angle
+        }
+    }
+
+    /// Which part of the day it is.
+    pub fn day_phase(&self, time: WorldTime) -> DayPhase {
+        match self.date(time).hour {
+            hour if hour < Self::DAWN_HOUR => DayPhase::Night,
+            hour if hour < Self::DAY_HOUR => DayPhase::Dawn,
+            hour if hour < Self::DUSK_HOUR => DayPhase::Day,
+            hour if hour < Self::NIGHT_HOUR => DayPhase::Dusk,
+            _ => DayPhase::Night,
+        }
+    }
+
+    /// The first moment of a day, or None if it happened before the world was created.
+    pub fn start_of_day(&self, day: u64) -> Option<WorldTime> {
+        (day * self.day_length_ms).checked_sub(self.start_offset_ms).map(WorldTime::from_ms)
+    }
+}
+
 #[cfg(test)]
 mod testing {
-    // TODO test it.
+    use super::*;
+
+    /// Adding and subtracting durations moves time the right way, and only keeps whole milliseconds.
+    #[test]
+    fn arithmetic() {
+        let time = WorldTime::from_ms(1000);
+
+        assert_eq!(time + Duration::from_millis(250), WorldTime::from_ms(1250));
+        assert_eq!(time - Duration::from_millis(250), WorldTime::from_ms(750));
+        assert_eq!(time + Duration::from_micros(1999), WorldTime::from_ms(1001));
+
+        let mut moving = time;
+        moving += Duration::from_secs(2);
+        assert_eq!(moving, WorldTime::from_ms(3000));
+        moving -= Duration::from_millis(500);
+        assert_eq!(moving, WorldTime::from_ms(2500));
+
+        assert_eq!(moving - time, Duration::from_millis(1500));
+        assert_eq!(moving.duration_since(time), Duration::from_millis(1500));
+        assert_eq!(time.duration_since(moving), Duration::from_millis(0));
+
+        assert_eq!(time.checked_sub(Duration::from_millis(1001)), None);
+        assert_eq!(time.checked_sub(Duration::from_millis(1000)), Some(WorldTime::from_ms(0)));
+        assert_eq!(WorldTime::from_ms(u64::MAX).checked_add(Duration::from_millis(1)), None);
+    }
+
+    /// World time and dates display the way players would expect.
+    #[test]
+    fn display() {
+        assert_eq!(WorldTime::from_ms(0).to_string(), "0:00:00.000");
+        assert_eq!(WorldTime::from_ms(((26 * 60 + 3) * 60 + 4) * 1000 + 5).to_string(), "26:03:04.005");
+
+        let calendar = GameCalendar::new(Duration::from_secs(24 * 60), 0);
+        assert_eq!(calendar.date(WorldTime::from_ms(0)).to_string(), "Day 1, 00:00");
+        assert_eq!(calendar.date(WorldTime::from_ms((24 * 60 + 13 * 60 + 7) * 1000)).to_string(), "Day 2, 13:07");
+    }
+
+    /// Dates follow the day length and start hour.
+    #[test]
+    fn calendar() {
+        // An in-game minute is a real second.
+        let calendar = GameCalendar::new(Duration::from_secs(24 * 60), 6);
+        assert_eq!(calendar.day_length(), Duration::from_secs(24 * 60));
+
+        assert_eq!(calendar.date(WorldTime::from_ms(0)), GameDate { day: 0, hour: 6, minute: 0 });
+        assert_eq!(calendar.date(WorldTime::from_ms(999)), GameDate { day: 0, hour: 6, minute: 0 });
+        assert_eq!(calendar.date(WorldTime::from_ms(1_000)), GameDate { day: 0, hour: 6, minute: 1 });
+        assert_eq!(calendar.date(WorldTime::from_ms(18 * 60_000)), GameDate { day: 1, hour: 0, minute: 0 });
+
+        assert_eq!(calendar.start_of_day(0), None);
+        assert_eq!(calendar.start_of_day(1), Some(WorldTime::from_ms(18 * 60_000)));
+        assert_eq!(calendar.date(calendar.start_of_day(5).unwrap()), GameDate { day: 5, hour: 0, minute: 0 });
+
+        assert!((calendar.time_of_day(WorldTime::from_ms(0)) - 0.25).abs() < 1.0e-12);
+    }
+
+    /// The sun rises, peaks at noon and sets, and the phases of the day follow it.
+    #[test]
+    fn sun() {
+        let calendar = GameCalendar::new(Duration::from_secs(24 * 60), 0);
+        let at_hour = |hour: u64| WorldTime::from_ms(hour * 60_000);
+
+        assert!(calendar.sun_angle(at_hour(6)).abs() < 1.0e-12);
+        assert!((calendar.sun_angle(at_hour(12)) - std::f64::consts::FRAC_PI_2).abs() < 1.0e-12);
+        assert!((calendar.sun_angle(at_hour(18)) - std::f64::consts::PI).abs() < 1.0e-12);
+        assert!((calendar.sun_angle(at_hour(0)) + std::f64::consts::FRAC_PI_2).abs() < 1.0e-12);
+        assert!(calendar.sun_angle(at_hour(21)) < 0.0);
+
+        assert_eq!(calendar.day_phase(at_hour(2)), DayPhase::Night);
+        assert_eq!(calendar.day_phase(at_hour(6)), DayPhase::Dawn);
+        assert_eq!(calendar.day_phase(at_hour(12)), DayPhase::Day);
+        assert_eq!(calendar.day_phase(at_hour(18)), DayPhase::Dusk);
+        assert_eq!(calendar.day_phase(at_hour(23)), DayPhase::Night);
+    }
 }
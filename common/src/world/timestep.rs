@@ -0,0 +1,312 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Running the simulation in fixed steps, so that it behaves the same no matter how fast frames come in.
+
+use super::GridWorld;
+use serde::{Deserialize, Serialize};
+use std::{rc::Rc, time::Duration};
+
+/// How fast the world's simulation runs.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SimulationSettings {
+    tick_rate: u32,
+    time_scale: f64,
+    paused: bool,
+    max_steps_per_update: u32,
+}
+
+impl Default for SimulationSettings {
+    fn default() -> Self {
+        // A hundred ticks a second keeps every step a whole number of milliseconds.
+        SimulationSettings { tick_rate: 100, time_scale: 1.0, paused: false, max_steps_per_update: 10 }
+    }
+}
+
+impl SimulationSettings {
+    /// The most steps the simulation can take in a second. Any more and a step would be shorter than a nanosecond.
+    pub const MAX_TICK_RATE: u32 = 1_000_000_000;
+
+    /// The fastest world time can pass compared to real time.
+    pub const MAX_TIME_SCALE: f64 = 1000.0;
+
+    /// How many steps the simulation takes for each second of world time.
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    /// Set how many steps the simulation takes for each second of world time. Must be at least one, and no more than
+    /// [SimulationSettings::MAX_TICK_RATE].
+    pub fn set_tick_rate(&mut self, tick_rate: u32) {
+        self.tick_rate = tick_rate.clamp(1, Self::MAX_TICK_RATE);
+    }
+
+    /// How fast world time passes compared to real time.
+    pub fn time_scale(&self) -> f64 {
+        // Settings that were loaded instead of set could have any time scale at all.
+        Self::clamp_time_scale(self.time_scale)
+    }
+
+    /// Set how fast world time passes compared to real time. Zero pauses the world. Negative values and NaN are
+    /// treated as zero, and anything faster than [SimulationSettings::MAX_TIME_SCALE] is slowed down to it.
+    pub fn set_time_scale(&mut self, time_scale: f64) {
+        self.time_scale = Self::clamp_time_scale(time_scale);
+    }
+
+    /// Bring a time scale into the range the timestep can handle.
+    fn clamp_time_scale(time_scale: f64) -> f64 {
+        if time_scale > 0.0 {
+            time_scale.min(Self::MAX_TIME_SCALE)
+        } else {
+            0.0
+        }
+    }
+
+    /// Check if the world is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pause or unpause the world. Time stands still while paused, and picks up where it left off afterward, without
+    /// the time scale being lost.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// The most steps a single update will take. If the world falls further behind than that, the rest of the time is
+    /// dropped instead of trying to catch up, which would only make the next update take even longer.
+    pub fn max_steps_per_update(&self) -> u32 {
+        self.max_steps_per_update
+    }
+
+    /// Set the most steps a single update will take. Must be at least one.
+    pub fn set_max_steps_per_update(&mut self, max_steps_per_update: u32) {
+        self.max_steps_per_update = max_steps_per_update.max(1);
+    }
+
+    /// How much world time passes in each step.
+    pub fn step_duration(&self) -> Duration {
+        // Settings that were loaded instead of set could have any tick rate at all.
+        Duration::from_nanos(1_000_000_000 / self.tick_rate.clamp(1, Self::MAX_TICK_RATE) as u64)
+    }
+}
+
+/// Collects the time that passes between frames, and works out how many fixed steps the simulation needs to take to
+/// keep up with it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FixedTimestep {
+    accumulator: Duration,
+    sub_millisecond: Duration,
+    steps: u64,
+}
+
+impl FixedTimestep {
+    /// Create a timestep with no time built up.
+    pub fn new() -> FixedTimestep {
+        FixedTimestep::default()
+    }
+
+    /// Add real time that has passed, and get how many steps should be taken. Time that isn't enough for a whole step
+    /// is kept for next time.
+    pub fn advance(&mut self, elapsed: Duration, settings: &SimulationSettings) -> u32 {
+        if settings.is_paused() {
+            return 0;
+        }
+
+        let step = settings.step_duration();
+        let scaled = Duration::try_from_secs_f64(elapsed.as_secs_f64() * settings.time_scale()).unwrap_or(Duration::MAX);
+        self.accumulator = self.accumulator.saturating_add(scaled);
+
+        let mut steps = 0;
+        while self.accumulator >= step {
+            if steps == settings.max_steps_per_update() {
+                // Keep what's left of a step so that rendering still moves smoothly.
+                let remainder = Duration::from_nanos((self.accumulator.as_nanos() % step.as_nanos()) as u64);
+                log::warn!("Simulation can't keep up. Dropping {:?} of world time.", self.accumulator - remainder);
+
+                self.accumulator = remainder;
+                break;
+            }
+
+            self.accumulator -= step;
+            steps += 1;
+        }
+
+        steps
+    }
+
+    /// Take a step, and get how much to move world time forward for it. World time only counts whole milliseconds, so
+    /// the part of a step that's less than that is held on to until it adds up to one.
+    pub fn step_world_time(&mut self, settings: &SimulationSettings) -> Duration {
+        self.steps += 1;
+
+        let time = self.sub_millisecond + settings.step_duration();
+        let whole = Duration::from_millis(time.as_millis() as u64);
+        self.sub_millisecond = time - whole;
+
+        whole
+    }
+
+    /// How far between the last step and the next one the current moment is, from 0 to 1. Renderers can use this to
+    /// blend between the last two steps so that motion looks smooth at any frame rate.
+    pub fn interpolation_alpha(&self, settings: &SimulationSettings) -> f32 {
+        (self.accumulator.as_secs_f64() / settings.step_duration().as_secs_f64()).min(1.0) as f32
+    }
+
+    /// How many steps have been taken in total.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+}
+
+/// Something that runs at the end of every step of the world, however the world is stepped. Unlike an ECS system, it
+/// gets the whole world to work with, terrain and all.
+pub trait StepListener<ChunkUserData> {
+    /// Called once a step is done.
+    fn after_step(&self, world: &mut GridWorld<ChunkUserData>);
+}
+
+impl<ChunkUserData: Default> GridWorld<ChunkUserData> {
+    /// Run a listener at the end of every step. Listeners run in the order they were added.
+    pub fn add_step_listener(&mut self, listener: Rc<dyn StepListener<ChunkUserData>>) {
+        self.step_listeners.push(listener);
+    }
+
+    /// Stop running a listener. Returns false if it wasn't added.
+    pub fn remove_step_listener(&mut self, listener: &Rc<dyn StepListener<ChunkUserData>>) -> bool {
+        let count = self.step_listeners.len();
+        self.step_listeners.retain(|added| !Rc::ptr_eq(added, listener));
+
+        self.step_listeners.len() != count
+    }
+
+    /// Run the step listeners. They're run from a copy of the list, since they can add and remove listeners.
+    pub(super) fn run_step_listeners(&mut self) {
+        for listener in self.step_listeners.clone() {
+            listener.after_step(self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Steps come out the same no matter how the time is split up between frames.
+    #[test]
+    fn frame_rate_independent() {
+        let settings = SimulationSettings::default();
+
+        let mut smooth = FixedTimestep::new();
+        let smooth_steps: u32 = (0..60).map(|_| smooth.advance(Duration::from_micros(16_667), &settings)).sum();
+
+        let mut choppy = FixedTimestep::new();
+        let choppy_steps: u32 = (0..10).map(|_| choppy.advance(Duration::from_micros(100_002), &settings)).sum();
+
+        assert_eq!(smooth_steps, 100);
+        assert_eq!(choppy_steps, 100);
+    }
+
+    /// Time that doesn't make up a whole step is kept, and shows up in the interpolation alpha.
+    #[test]
+    fn leftover_time() {
+        let settings = SimulationSettings::default();
+        let mut timestep = FixedTimestep::new();
+
+        assert_eq!(timestep.advance(Duration::from_millis(4), &settings), 0);
+        assert!((timestep.interpolation_alpha(&settings) - 0.4).abs() < 1.0e-6);
+
+        assert_eq!(timestep.advance(Duration::from_millis(7), &settings), 1);
+        assert!((timestep.interpolation_alpha(&settings) - 0.1).abs() < 1.0e-6);
+    }
+
+    /// A long stall doesn't make the simulation try to catch up all at once.
+    #[test]
+    fn spiral_of_death() {
+        let mut settings = SimulationSettings::default();
+        settings.set_max_steps_per_update(5);
+        let mut timestep = FixedTimestep::new();
+
+        assert_eq!(timestep.advance(Duration::from_secs(10), &settings), 5);
+        assert_eq!(timestep.advance(Duration::from_millis(0), &settings), 0);
+    }
+
+    /// The time scale speeds up, slows down and pauses the world.
+    #[test]
+    fn time_scale() {
+        let mut settings = SimulationSettings::default();
+        let mut timestep = FixedTimestep::new();
+
+        settings.set_time_scale(2.0);
+        assert_eq!(timestep.advance(Duration::from_millis(30), &settings), 6);
+
+        settings.set_time_scale(0.5);
+        assert_eq!(timestep.advance(Duration::from_millis(40), &settings), 2);
+
+        settings.set_time_scale(0.0);
+        assert_eq!(timestep.advance(Duration::from_secs(1), &settings), 0);
+
+        // Pausing doesn't forget the time scale.
+        settings.set_time_scale(3.0);
+        settings.set_paused(true);
+        assert_eq!(timestep.advance(Duration::from_secs(1), &settings), 0);
+        settings.set_paused(false);
+        assert_eq!(timestep.advance(Duration::from_millis(10), &settings), 3);
+    }
+
+    /// Tick rates and time scales that can't be simulated are brought back into range instead of breaking the timestep.
+    #[test]
+    fn out_of_range_settings() {
+        let mut settings = SimulationSettings::default();
+        let mut timestep = FixedTimestep::new();
+
+        settings.set_tick_rate(u32::MAX);
+        assert_eq!(settings.tick_rate(), SimulationSettings::MAX_TICK_RATE);
+        assert_eq!(settings.step_duration(), Duration::from_nanos(1));
+        assert_eq!(timestep.advance(Duration::from_secs(1), &settings), settings.max_steps_per_update());
+
+        settings.set_tick_rate(0);
+        assert_eq!(settings.tick_rate(), 1);
+
+        settings.set_tick_rate(100);
+        settings.set_time_scale(f64::INFINITY);
+        assert_eq!(settings.time_scale(), SimulationSettings::MAX_TIME_SCALE);
+        assert_eq!(timestep.advance(Duration::from_secs(1), &settings), settings.max_steps_per_update());
+
+        settings.set_time_scale(f64::NAN);
+        assert_eq!(settings.time_scale(), 0.0);
+        assert_eq!(timestep.advance(Duration::from_secs(1), &settings), 0);
+
+        settings.set_time_scale(f64::NEG_INFINITY);
+        assert_eq!(settings.time_scale(), 0.0);
+
+        // Settings loaded from somewhere else don't go through the setters.
+        for (time_scale, expected) in [(-1.0, 0.0), (f64::NAN, 0.0), (1.0e300, SimulationSettings::MAX_TIME_SCALE)] {
+            let loaded: SimulationSettings = ron::from_str(&format!(
+                "(tick_rate: 100, time_scale: {:?}, paused: false, max_steps_per_update: 10)",
+                time_scale
+            ))
+            .unwrap();
+            assert_eq!(loaded.time_scale(), expected);
+            timestep.advance(Duration::from_secs(u64::MAX), &loaded);
+        }
+    }
+
+    /// World time keeps up with steps that aren't a whole number of milliseconds.
+    #[test]
+    fn uneven_steps() {
+        let mut settings = SimulationSettings::default();
+        settings.set_tick_rate(60);
+        let mut timestep = FixedTimestep::new();
+
+        // A step is rounded down to the nanosecond, so a second's worth of steps comes up just short of a second, and
+        // the last millisecond is held back.
+        let total: Duration = (0..60).map(|_| timestep.step_world_time(&settings)).sum();
+        assert_eq!(total, Duration::from_millis(999));
+
+        // Which comes through once there's enough to make it up.
+        settings.set_tick_rate(100);
+        let total: Duration = (0..100).map(|_| timestep.step_world_time(&settings)).sum();
+        assert_eq!(total, Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,1118 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Mods written in WebAssembly, run in a sandbox so that they can't crash or stall the world.
+//!
+//! Mods are run by a pure Rust interpreter. Every mod gets a limited amount of fuel for each step of the world, which
+//! it burns as it runs instructions, and a mod's memory and table can't grow past a cap. A mod that runs out of fuel,
+//! goes over its memory, or traps for any other reason is disabled and never called again. The world carries on
+//! without it.
+//!
+//! Mods can only touch the world through the host API, which they import from the `game` module:
+//!
+//! * `log(text: i32, length: i32)`: Log a UTF-8 message.
+//! * `world_time() -> i64`: The world time in milliseconds.
+//! * `block_id(name: i32, length: i32) -> i32`: The ID of a block by name, or 0 if there's no such block.
+//! * `get_block(x: i64, y: i64, z: i64) -> i32`: The ID of a block, 0 for air, or -1 if its chunk isn't loaded.
+//! * `set_block(x: i64, y: i64, z: i64, block: i32) -> i32`: Place a block, or break it with 0. Returns 1 if it was
+//!   changed, 0 if its chunk isn't loaded.
+//! * `schedule_tick(x: i64, y: i64, z: i64, delay: i64) -> i32`: Schedule a tick for a block this many milliseconds
+//!   from now. Returns 1 if it was scheduled, 0 if the chunk isn't loaded or the mod has too many ticks waiting.
+//! * `register_tick_handler(block: i32)`: Have this mod handle the ticks of a type of block. Only allowed in `init`.
+//! * `spawn_character(x: f32, y: f32, z: f32) -> i32`: Spawn a character with its feet at a position. Returns a handle
+//!   the mod can use to refer to it, or 0 if the mod has too many entities.
+//! * `remove_entity(handle: i32) -> i32`: Remove an entity the mod spawned. Returns 1 if it was removed.
+//!
+//! Mods can export any of these to react to the world:
+//!
+//! * `init()`: Called once when the mod is loaded.
+//! * `on_step()`: Called after every step of the world.
+//! * `on_random_tick(x: i64, y: i64, z: i64)`, `on_scheduled_tick(x: i64, y: i64, z: i64)`: Called when a block the
+//!   mod handles is ticked.
+//! * `on_collision(handle: i32, started: i32)`: Called when an entity the mod spawned starts or stops touching
+//!   something.
+//!
+//! The blocks a mod changes, the ticks it schedules and the entities it spawns and removes all go through
+//! [GridWorld::apply_input], so they're part of an input recording, and a recording replays the same way without the
+//! mod loaded. The entities a mod spawned are forgotten by a snapshot.
+
+use super::{
+    BlockID, BlockTickHandler, CharacterSettings, CollisionEvents, GlobalBlockCoordinate, GridWorld, PhysicsVector,
+    StepListener, WorldInput, WorldTime,
+};
+use anyhow::{anyhow, Context, Result};
+use legion::Entity;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    convert::TryFrom,
+    num::NonZeroU16,
+    rc::{Rc, Weak},
+    time::Duration,
+};
+use wasmi::{
+    core::{Trap, TrapCode, F32},
+    Caller, Config, Engine, Extern, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, WasmParams,
+};
+
+/// The module mods import the host API from.
+const HOST_MODULE: &str = "game";
+
+/// The longest string a mod can pass to the host, in bytes.
+const MAX_STRING_LENGTH: usize = 1024;
+
+/// How much resources a mod gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModLimits {
+    /// The fuel a mod gets for each step of the world, shared by every call into it during the step. Roughly one unit
+    /// is burned per instruction.
+    pub fuel_per_step: u64,
+
+    /// The fuel burned by each call the mod makes to the host API, on top of the instructions it takes to make it.
+    pub fuel_per_host_call: u64,
+
+    /// The most memory a mod can have, in bytes.
+    pub memory_bytes: usize,
+
+    /// The most elements a mod's table can have.
+    pub table_elements: u32,
+
+    /// The most entities a mod can have spawned at once.
+    pub entities: usize,
+
+    /// The most ticks a mod can have scheduled and waiting to happen at once.
+    pub scheduled_ticks: usize,
+}
+
+impl Default for ModLimits {
+    fn default() -> Self {
+        ModLimits {
+            fuel_per_step: 1_000_000,
+            fuel_per_host_call: 100,
+            memory_bytes: 16 * 1024 * 1024,
+            table_elements: 10_000,
+            entities: 256,
+            scheduled_ticks: 4096,
+        }
+    }
+}
+
+/// What parts of the host API a mod is allowed to use. A mod that calls something it isn't allowed to is disabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModPermissions {
+    /// Can look at blocks.
+    pub read_blocks: bool,
+
+    /// Can place and break blocks, and schedule ticks for them.
+    pub write_blocks: bool,
+
+    /// Can handle the ticks of blocks.
+    pub tick_handlers: bool,
+
+    /// Can spawn and remove entities.
+    pub spawn_entities: bool,
+}
+
+impl ModPermissions {
+    /// Allowed to use the whole host API.
+    pub fn all() -> ModPermissions {
+        ModPermissions { read_blocks: true, write_blocks: true, tick_handlers: true, spawn_entities: true }
+    }
+
+    /// Only allowed to log and check the time.
+    pub fn none() -> ModPermissions {
+        ModPermissions { read_blocks: false, write_blocks: false, tick_handlers: false, spawn_entities: false }
+    }
+}
+
+impl Default for ModPermissions {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+/// Identifies a mod loaded into a [ModHost].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ModID(usize);
+
+/// What the host API works with while a mod is running.
+struct ModState<ChunkUserData> {
+    name: String,
+    permissions: ModPermissions,
+    fuel_per_host_call: u64,
+    limits: StoreLimits,
+
+    /// The world the mod is being called for. Only set for the length of a call.
+    world: Option<*mut GridWorld<ChunkUserData>>,
+
+    /// Only true while `init` runs.
+    initializing: bool,
+
+    /// Blocks the mod asked to handle the ticks of, waiting for `init` to finish.
+    tick_handlers: Vec<BlockID>,
+
+    /// The entities the mod spawned, by the handles the mod knows them by.
+    entities: HashMap<i32, Entity>,
+
+    /// The handles of the entities the mod spawned.
+    handles: HashMap<Entity, i32>,
+
+    /// The handle the next entity gets. Handles aren't reused.
+    next_handle: i32,
+    max_entities: usize,
+
+    /// When the ticks the mod scheduled are due, for the ones that haven't happened yet.
+    scheduled_ticks: Vec<WorldTime>,
+    max_scheduled_ticks: usize,
+}
+
+impl<ChunkUserData> ModState<ChunkUserData> {
+    /// Trap unless the mod has a permission.
+    fn require(&self, allowed: bool, what: &str) -> Result<(), Trap> {
+        if allowed {
+            Ok(())
+        } else {
+            Err(Trap::new(format!("Mod \"{}\" isn't allowed to {}.", self.name, what)))
+        }
+    }
+
+    /// Get the world the mod is being called for.
+    fn world(&mut self) -> Result<&mut GridWorld<ChunkUserData>, Trap> {
+        let world = self.world.ok_or_else(|| Trap::new("The world isn't available outside of a call."))?;
+
+        // Yes, unsafe is needed here. The interpreter wants its state to live for as long as it does, but the world is
+        // only lent to us for one call. The pointer is set from that loan right before the call and cleared right after
+        // it, and nothing else touches the world in between.
+        Ok(unsafe { &mut *world })
+    }
+
+    /// Get the entity behind a handle.
+    fn entity(&self, handle: i32) -> Option<Entity> {
+        self.entities.get(&handle).copied()
+    }
+
+    /// Get the handle of an entity the mod spawned.
+    fn handle(&self, entity: Entity) -> Option<i32> {
+        self.handles.get(&entity).copied()
+    }
+
+    /// Stop keeping track of an entity the mod spawned.
+    fn forget(&mut self, handle: i32) {
+        if let Some(entity) = self.entities.remove(&handle) {
+            self.handles.remove(&entity);
+        }
+    }
+}
+
+impl<ChunkUserData: Default> ModState<ChunkUserData> {
+    /// Check if the mod can spawn another entity. Entities it spawned that were removed by something else are
+    /// forgotten first, so they don't count against it.
+    fn can_spawn(&mut self) -> Result<bool, Trap> {
+        if self.entities.len() < self.max_entities {
+            return Ok(true);
+        }
+
+        let owned: Vec<(i32, Entity)> = self.entities.iter().map(|(handle, entity)| (*handle, *entity)).collect();
+        let world = self.world()?;
+        let removed: Vec<i32> =
+            owned.into_iter().filter(|(_, entity)| !world.ecs_world().contains(*entity)).map(|(handle, _)| handle).collect();
+        for handle in removed {
+            self.forget(handle);
+        }
+
+        Ok(self.entities.len() < self.max_entities)
+    }
+}
+
+/// A loaded mod, with its own store for its memory and state.
+struct WasmMod<ChunkUserData> {
+    store: Store<ModState<ChunkUserData>>,
+    instance: Instance,
+    fuel_per_step: u64,
+
+    /// The step the mod was last given fuel for.
+    fueled_step: Option<u64>,
+    disabled: Option<String>,
+
+    /// What the mod has registered with the world, so it can be taken back out when the mod is disabled.
+    tick_handlers: Vec<(BlockID, Weak<dyn BlockTickHandler<ChunkUserData>>)>,
+    step_listener: Option<Weak<dyn StepListener<ChunkUserData>>>,
+}
+
+impl<ChunkUserData: Default + Send + Sync + 'static> WasmMod<ChunkUserData> {
+    /// Call one of the mod's exports with the world. Exports the mod doesn't have are skipped. If anything goes wrong,
+    /// the mod is disabled.
+    fn call<Params: WasmParams>(&mut self, world: &mut GridWorld<ChunkUserData>, export: &str, params: Params) {
+        if self.disabled.is_some() {
+            return;
+        }
+
+        let function = match self.instance.get_export(&self.store, export).and_then(Extern::into_func) {
+            Some(function) => function,
+            None => return,
+        };
+
+        let result = function
+            .typed::<Params, ()>(&self.store)
+            .map_err(|error| anyhow!("Export \"{}\" has the wrong signature: {}", export, error))
+            .and_then(|function| {
+                self.start_step(world)?;
+                self.store.data_mut().world = Some(world);
+                let result = function.call(&mut self.store, params);
+                self.store.data_mut().world = None;
+
+                result.with_context(|| format!("Mod trapped in \"{}\".", export))
+            });
+
+        if let Err(error) = result {
+            self.disable(world, error);
+        }
+    }
+
+    /// The first time the mod is called in a step, fill it back up to the fuel it gets for a step, and forget the
+    /// ticks it scheduled that have happened. Block ticks run first thing in a step, so by the time the mod is called,
+    /// every tick that's due has been taken.
+    fn start_step(&mut self, world: &GridWorld<ChunkUserData>) -> Result<()> {
+        let step = world.timestep().steps();
+        if self.fueled_step == Some(step) {
+            return Ok(());
+        }
+        self.fueled_step = Some(step);
+
+        let now = world.time();
+        self.store.data_mut().scheduled_ticks.retain(|time| *time > now);
+
+        let remaining = self.store.consume_fuel(0).map_err(|_| anyhow!("Fuel metering is disabled."))?;
+        self.store.add_fuel(self.fuel_per_step.saturating_sub(remaining)).map_err(|_| anyhow!("Fuel metering is disabled."))
+    }
+
+    /// Stop calling the mod, and take its tick handlers and step listener out of the world.
+    fn disable(&mut self, world: &mut GridWorld<ChunkUserData>, reason: anyhow::Error) {
+        let reason = format!("{:#}", reason);
+        log::warn!("Disabled mod \"{}\": {}", self.store.data().name, reason);
+        self.disabled = Some(reason);
+
+        for (block, handler) in self.tick_handlers.drain(..) {
+            if let Some(handler) = handler.upgrade() {
+                world.tick_scheduler_mut().unregister_handler(block, &handler);
+            }
+        }
+        if let Some(listener) = self.step_listener.take().and_then(|listener| listener.upgrade()) {
+            world.remove_step_listener(&listener);
+        }
+    }
+}
+
+/// Hands the ticks of a block to the mod that handles them.
+struct WasmTickHandler<ChunkUserData> {
+    wasm_mod: Rc<RefCell<WasmMod<ChunkUserData>>>,
+}
+
+impl<ChunkUserData: Default + Send + Sync + 'static> WasmTickHandler<ChunkUserData> {
+    fn call(&self, world: &mut GridWorld<ChunkUserData>, export: &str, location: GlobalBlockCoordinate) {
+        // The mod is already busy if it's what caused this tick. It gets skipped rather than being called into twice.
+        if let Ok(mut wasm_mod) = self.wasm_mod.try_borrow_mut() {
+            wasm_mod.call(world, export, (location.x, location.y, location.z));
+        }
+    }
+}
+
+impl<ChunkUserData: Default + Send + Sync + 'static> BlockTickHandler<ChunkUserData> for WasmTickHandler<ChunkUserData> {
+    fn scheduled_tick(&self, world: &mut GridWorld<ChunkUserData>, location: GlobalBlockCoordinate) {
+        self.call(world, "on_scheduled_tick", location);
+    }
+
+    fn random_tick(&self, world: &mut GridWorld<ChunkUserData>, location: GlobalBlockCoordinate) {
+        self.call(world, "on_random_tick", location);
+    }
+}
+
+/// Lets a mod react to the step that just happened, and to the collisions of the entities it spawned.
+struct WasmStepListener<ChunkUserData> {
+    wasm_mod: Rc<RefCell<WasmMod<ChunkUserData>>>,
+}
+
+impl<ChunkUserData: Default + Send + Sync + 'static> StepListener<ChunkUserData> for WasmStepListener<ChunkUserData> {
+    fn after_step(&self, world: &mut GridWorld<ChunkUserData>) {
+        let mut wasm_mod = self.wasm_mod.borrow_mut();
+
+        let collisions: Vec<(i32, i32)> = world
+            .ecs_resources
+            .get::<CollisionEvents>()
+            .map(|events| {
+                let state = wasm_mod.store.data();
+                events
+                    .iter()
+                    .flat_map(|event| [(event.entity1, event.started), (event.entity2, event.started)])
+                    .filter_map(|(entity, started)| state.handle(entity).map(|handle| (handle, started as i32)))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for collision in collisions {
+            wasm_mod.call(world, "on_collision", collision);
+        }
+
+        wasm_mod.call(world, "on_step", ());
+    }
+}
+
+/// Loads WebAssembly mods into a world. A mod's tick handlers and step listener are added to the world it's loaded
+/// into, so it runs however that world is stepped.
+pub struct ModHost<ChunkUserData> {
+    engine: Engine,
+    linker: Linker<ModState<ChunkUserData>>,
+    limits: ModLimits,
+    mods: Vec<Rc<RefCell<WasmMod<ChunkUserData>>>>,
+}
+
+impl<ChunkUserData: Default + Send + Sync + 'static> ModHost<ChunkUserData> {
+    /// Create a host with no mods. Every mod loaded into it gets the same limits.
+    pub fn new(limits: ModLimits) -> Result<ModHost<ChunkUserData>> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+
+        let mut linker = Linker::new(&engine);
+        Self::define_host_api(&mut linker).map_err(|error| anyhow!("Failed to define the host API: {}", error))?;
+
+        Ok(ModHost { engine, linker, limits, mods: Vec::new() })
+    }
+
+    /// Load a mod from a WebAssembly binary and call its `init`. Fails if the mod is invalid, imports something the
+    /// host doesn't have, asks for more memory than it's allowed from the start, or fails to initialize.
+    pub fn load_mod(
+        &mut self, world: &mut GridWorld<ChunkUserData>, name: &str, wasm: &[u8], permissions: ModPermissions,
+    ) -> Result<ModID> {
+        let module = Module::new(&self.engine, wasm).with_context(|| format!("Mod \"{}\" is invalid.", name))?;
+
+        let state = ModState {
+            name: name.to_string(),
+            permissions,
+            fuel_per_host_call: self.limits.fuel_per_host_call,
+            limits: StoreLimitsBuilder::new()
+                .memory_size(self.limits.memory_bytes)
+                .table_elements(self.limits.table_elements)
+                .instances(1)
+                .memories(1)
+                .tables(1)
+                .build(),
+            world: None,
+            initializing: true,
+            tick_handlers: Vec::new(),
+            entities: HashMap::new(),
+            handles: HashMap::new(),
+            next_handle: 1,
+            max_entities: self.limits.entities,
+            scheduled_ticks: Vec::new(),
+            max_scheduled_ticks: self.limits.scheduled_ticks,
+        };
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| &mut state.limits);
+        store.add_fuel(self.limits.fuel_per_step).map_err(|_| anyhow!("Fuel metering is disabled."))?;
+
+        // The start function runs as part of instantiation, so it needs the world too.
+        store.data_mut().world = Some(world);
+        let instance = self.linker.instantiate(&mut store, &module).and_then(|instance| instance.start(&mut store));
+        store.data_mut().world = None;
+        let instance = instance.with_context(|| format!("Mod \"{}\" could not be instantiated.", name))?;
+
+        let mut wasm_mod = WasmMod {
+            store,
+            instance,
+            fuel_per_step: self.limits.fuel_per_step,
+            fueled_step: None,
+            disabled: None,
+            tick_handlers: Vec::new(),
+            step_listener: None,
+        };
+        wasm_mod.call(world, "init", ());
+        if let Some(reason) = wasm_mod.disabled {
+            return Err(anyhow!("Mod \"{}\" failed to initialize: {}", name, reason));
+        }
+
+        // Loading doesn't count against the step the mod is loaded on.
+        wasm_mod.fueled_step = None;
+
+        let state = wasm_mod.store.data_mut();
+        state.initializing = false;
+        let tick_handlers = std::mem::take(&mut state.tick_handlers);
+
+        let wasm_mod = Rc::new(RefCell::new(wasm_mod));
+        for block in tick_handlers {
+            world.tick_scheduler_mut().register_handler(block, Box::new(WasmTickHandler { wasm_mod: wasm_mod.clone() }));
+            if let Some(handler) = world.tick_scheduler().get_handler(block) {
+                wasm_mod.borrow_mut().tick_handlers.push((block, Rc::downgrade(&handler)));
+            }
+        }
+
+        let listener: Rc<dyn StepListener<ChunkUserData>> = Rc::new(WasmStepListener { wasm_mod: wasm_mod.clone() });
+        wasm_mod.borrow_mut().step_listener = Some(Rc::downgrade(&listener));
+        world.add_step_listener(listener);
+
+        self.mods.push(wasm_mod);
+        Ok(ModID(self.mods.len() - 1))
+    }
+
+    /// The number of mods loaded, including the disabled ones.
+    pub fn len(&self) -> usize {
+        self.mods.len()
+    }
+
+    /// Check if no mods have been loaded.
+    pub fn is_empty(&self) -> bool {
+        self.mods.is_empty()
+    }
+
+    /// Check if a mod is still running.
+    pub fn is_enabled(&self, id: ModID) -> bool {
+        self.disabled_reason(id).is_none()
+    }
+
+    /// Why a mod was disabled, or None if it's still running.
+    pub fn disabled_reason(&self, id: ModID) -> Option<String> {
+        self.mods.get(id.0).and_then(|wasm_mod| wasm_mod.borrow().disabled.clone())
+    }
+
+    /// Add the host API to the linker.
+    fn define_host_api(linker: &mut Linker<ModState<ChunkUserData>>) -> Result<(), wasmi::errors::LinkerError> {
+        linker.func_wrap(HOST_MODULE, "log", |mut caller: Caller<ModState<ChunkUserData>>, text: i32, length: i32| {
+            charge(&mut caller)?;
+            let text = read_string(&caller, text, length)?;
+            log::info!("[{}] {}", caller.data().name, text);
+
+            Ok(())
+        })?;
+
+        linker.func_wrap(HOST_MODULE, "world_time", |mut caller: Caller<ModState<ChunkUserData>>| {
+            charge(&mut caller)?;
+            Ok(caller.data_mut().world()?.time().as_millis() as i64)
+        })?;
+
+        linker.func_wrap(HOST_MODULE, "block_id", |mut caller: Caller<ModState<ChunkUserData>>, name: i32, length: i32| {
+            charge(&mut caller)?;
+            let name = read_string(&caller, name, length)?;
+            let world = caller.data_mut().world()?;
+
+            Ok(world.block_registry().get_block_id_from_name(&name).map(block_to_id).unwrap_or(0))
+        })?;
+
+        linker.func_wrap(HOST_MODULE, "get_block", |mut caller: Caller<ModState<ChunkUserData>>, x: i64, y: i64, z: i64| {
+            charge(&mut caller)?;
+            let state = caller.data_mut();
+            state.require(state.permissions.read_blocks, "read blocks")?;
+
+            Ok(match state.world()?.get_block(GlobalBlockCoordinate::new(x, y, z)) {
+                Some(Some(block)) => block_to_id(&block),
+                Some(None) => 0,
+                None => -1,
+            })
+        })?;
+
+        linker.func_wrap(
+            HOST_MODULE,
+            "set_block",
+            |mut caller: Caller<ModState<ChunkUserData>>, x: i64, y: i64, z: i64, block: i32| {
+                charge(&mut caller)?;
+                let state = caller.data_mut();
+                state.require(state.permissions.write_blocks, "write blocks")?;
+
+                let world = state.world()?;
+                let location = GlobalBlockCoordinate::new(x, y, z);
+                if world.get_block(location).is_none() {
+                    return Ok(0);
+                }
+
+                let input = if block == 0 {
+                    WorldInput::BreakBlock { location }
+                } else {
+                    WorldInput::PlaceBlock { location, block: block_from_id(world, block)? }
+                };
+                world.apply_input(input);
+
+                Ok(1)
+            },
+        )?;
+
+        linker.func_wrap(
+            HOST_MODULE,
+            "schedule_tick",
+            |mut caller: Caller<ModState<ChunkUserData>>, x: i64, y: i64, z: i64, delay: i64| {
+                charge(&mut caller)?;
+                let state = caller.data_mut();
+                state.require(state.permissions.write_blocks, "schedule block ticks")?;
+                if state.scheduled_ticks.len() >= state.max_scheduled_ticks {
+                    return Ok(0);
+                }
+
+                let world = state.world()?;
+                let location = GlobalBlockCoordinate::new(x, y, z);
+                let time = world.time() + Duration::from_millis(delay.max(0) as u64);
+                if world.get_block(location).is_none() {
+                    return Ok(0);
+                }
+
+                world.apply_input(WorldInput::ScheduleTick { location, time });
+                state.scheduled_ticks.push(time);
+
+                Ok(1)
+            },
+        )?;
+
+        linker.func_wrap(HOST_MODULE, "register_tick_handler", |mut caller: Caller<ModState<ChunkUserData>>, block: i32| {
+            charge(&mut caller)?;
+            let state = caller.data_mut();
+            state.require(state.permissions.tick_handlers, "handle block ticks")?;
+            if !state.initializing {
+                return Err(Trap::new("Tick handlers can only be registered from init."));
+            }
+
+            let world = state.world()?;
+            let block = block_from_id(world, block)?;
+            if world.tick_scheduler().get_handler(block).is_some() || state.tick_handlers.contains(&block) {
+                return Err(Trap::new(format!("Block {} already has a tick handler.", block_to_id(&block))));
+            }
+            state.tick_handlers.push(block);
+
+            Ok(())
+        })?;
+
+        linker.func_wrap(
+            HOST_MODULE,
+            "spawn_character",
+            |mut caller: Caller<ModState<ChunkUserData>>, x: F32, y: F32, z: F32| {
+                charge(&mut caller)?;
+                let state = caller.data_mut();
+                state.require(state.permissions.spawn_entities, "spawn entities")?;
+
+                let feet = PhysicsVector::new(x.to_float(), y.to_float(), z.to_float());
+                if !feet.iter().all(|coordinate| coordinate.is_finite()) {
+                    return Err(Trap::new("Characters can only be spawned at finite positions."));
+                }
+
+                if !state.can_spawn()? {
+                    return Ok(0);
+                }
+                let handle = state.next_handle;
+                state.next_handle = handle.checked_add(1).ok_or_else(|| Trap::new("Ran out of entity handles."))?;
+
+                let entity = state
+                    .world()?
+                    .apply_input(WorldInput::SpawnCharacter { feet, settings: CharacterSettings::default() })
+                    .ok_or_else(|| Trap::new("The character wasn't spawned."))?;
+                state.entities.insert(handle, entity);
+                state.handles.insert(entity, handle);
+
+                Ok(handle)
+            },
+        )?;
+
+        linker.func_wrap(HOST_MODULE, "remove_entity", |mut caller: Caller<ModState<ChunkUserData>>, handle: i32| {
+            charge(&mut caller)?;
+            let state = caller.data_mut();
+            state.require(state.permissions.spawn_entities, "remove entities")?;
+
+            let entity = match state.entity(handle) {
+                Some(entity) => entity,
+                None => return Ok(0),
+            };
+            state.forget(handle);
+
+            let world = state.world()?;
+            if !world.ecs_world().contains(entity) {
+                return Ok(0);
+            }
+            world.apply_input(WorldInput::RemoveEntity { entity });
+
+            Ok(1)
+        })?;
+
+        Ok(())
+    }
+}
+
+/// Burn the fuel it costs to call the host API.
+fn charge<ChunkUserData>(caller: &mut Caller<ModState<ChunkUserData>>) -> Result<(), Trap> {
+    let cost = caller.data().fuel_per_host_call;
+    caller.consume_fuel(cost).map(|_| ()).map_err(|_| Trap::from(TrapCode::OutOfFuel))
+}
+
+/// Read a UTF-8 string out of the mod's memory.
+fn read_string<ChunkUserData>(caller: &Caller<ModState<ChunkUserData>>, pointer: i32, length: i32) -> Result<String, Trap> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Trap::new("Mods must export their memory to pass strings."))?;
+
+    let length = length as u32 as usize;
+    if length > MAX_STRING_LENGTH {
+        return Err(Trap::new(format!("Strings can't be longer than {} bytes.", MAX_STRING_LENGTH)));
+    }
+
+    let mut buffer = vec![0; length];
+    memory.read(caller, pointer as u32 as usize, &mut buffer).map_err(|_| Trap::from(TrapCode::MemoryOutOfBounds))?;
+
+    String::from_utf8(buffer).map_err(|_| Trap::new("Strings must be UTF-8."))
+}
+
+/// Turn a block into the ID mods know it by.
+fn block_to_id(block: &BlockID) -> i32 {
+    block.index() as i32 + 1
+}
+
+/// Turn a block ID from a mod into a block that exists.
+fn block_from_id<ChunkUserData: Default>(world: &GridWorld<ChunkUserData>, block: i32) -> Result<BlockID, Trap> {
+    u16::try_from(block)
+        .ok()
+        .filter(|&block| block > 0 && block <= world.block_registry().num_block_types())
+        .and_then(|block| NonZeroU16::new(block).map(BlockID::new))
+        .ok_or_else(|| Trap::new(format!("There is no block with ID {}.", block)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+
+    /// Create a world with flat ground whose surface is at y = 0, and a block for mods to handle.
+    fn sapling_world() -> GridWorld<()> {
+        let mut world =
+            flat_test_world(ChunkRange::from_end_points(ChunkCoordinate::new(-1, -1, -1), ChunkCoordinate::new(1, 1, 1)));
+        world.block_registry_mut().add_block("test:sapling".to_string(), "Sapling".to_string()).unwrap();
+
+        world
+    }
+
+    fn load(host: &mut ModHost<()>, world: &mut GridWorld<()>, source: &str, permissions: ModPermissions) -> Result<ModID> {
+        host.load_mod(world, "test", &wat::parse_str(source).unwrap(), permissions)
+    }
+
+    /// A mod that grows a tower out of a sapling, one block per scheduled tick, and counts its steps in a global.
+    const GROWING_MOD: &str = r#"
+        (module
+            (import "game" "block_id" (func $block_id (param i32 i32) (result i32)))
+            (import "game" "get_block" (func $get_block (param i64 i64 i64) (result i32)))
+            (import "game" "set_block" (func $set_block (param i64 i64 i64 i32) (result i32)))
+            (import "game" "schedule_tick" (func $schedule_tick (param i64 i64 i64 i64) (result i32)))
+            (import "game" "register_tick_handler" (func $register_tick_handler (param i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "test:sapling")
+            (global $sapling (mut i32) (i32.const 0))
+            (global $steps (export "steps") (mut i32) (i32.const 0))
+            (func (export "init")
+                (global.set $sapling (call $block_id (i32.const 0) (i32.const 12)))
+                (call $register_tick_handler (global.get $sapling)))
+            (func (export "on_scheduled_tick") (param $x i64) (param $y i64) (param $z i64)
+                (if (i32.eq (call $get_block (local.get $x) (i64.add (local.get $y) (i64.const 1)) (local.get $z)) (i32.const 0))
+                    (then
+                        (drop (call $set_block (local.get $x) (i64.add (local.get $y) (i64.const 1)) (local.get $z)
+                            (global.get $sapling)))
+                        (drop (call $schedule_tick (local.get $x) (i64.add (local.get $y) (i64.const 1)) (local.get $z)
+                            (i64.const 10))))))
+            (func (export "on_step")
+                (global.set $steps (i32.add (global.get $steps) (i32.const 1)))))
+    "#;
+
+    /// Mods can read and write blocks, handle ticks, and react to steps.
+    #[test]
+    fn host_api() {
+        let mut world = sapling_world();
+        let mut host = ModHost::new(ModLimits::default()).unwrap();
+        let id = load(&mut host, &mut world, GROWING_MOD, ModPermissions::all()).unwrap();
+
+        let sapling = *world.block_registry().get_block_id_from_name("test:sapling").unwrap();
+        let base = GlobalBlockCoordinate::new(2, 0, 2);
+        *world.get_block_mut(base).unwrap() = Some(sapling);
+        world.schedule_tick(base, world.time()).unwrap();
+
+        world.update(Duration::from_millis(50));
+
+        assert!(host.is_enabled(id));
+        assert_eq!(world.get_block(GlobalBlockCoordinate::new(2, 5, 2)), Some(Some(sapling)));
+
+        let wasm_mod = host.mods[id.0].borrow();
+        let steps = wasm_mod.instance.get_global(&wasm_mod.store, "steps").unwrap().get(&wasm_mod.store);
+        assert_eq!(steps.i32(), Some(5));
+    }
+
+    /// Mods can only use the parts of the host API they're allowed to.
+    #[test]
+    fn permissions() {
+        let mut world = sapling_world();
+        let mut host = ModHost::new(ModLimits::default()).unwrap();
+
+        let error = load(&mut host, &mut world, GROWING_MOD, ModPermissions { tick_handlers: false, ..ModPermissions::all() })
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("isn't allowed to handle block ticks"), "{:#}", error);
+        assert!(world
+            .tick_scheduler()
+            .get_handler(*world.block_registry().get_block_id_from_name("test:sapling").unwrap())
+            .is_none());
+
+        // Only the first mod gets to handle the block.
+        load(&mut host, &mut world, GROWING_MOD, ModPermissions::all()).unwrap();
+        assert!(load(&mut host, &mut world, GROWING_MOD, ModPermissions::all()).is_err());
+    }
+
+    /// A mod that runs forever is stopped when it runs out of fuel and disabled, and the world carries on.
+    #[test]
+    fn out_of_fuel() {
+        let mut world = sapling_world();
+        let mut host = ModHost::new(ModLimits { fuel_per_step: 10_000, ..ModLimits::default() }).unwrap();
+        let id = load(
+            &mut host,
+            &mut world,
+            r#"(module (func (export "on_step") (loop $forever (br $forever))))"#,
+            ModPermissions::none(),
+        )
+        .unwrap();
+
+        world.update(Duration::from_millis(30));
+
+        assert!(!host.is_enabled(id));
+        assert!(host.disabled_reason(id).unwrap().contains("on_step"));
+        assert_eq!(world.timestep().steps(), 3);
+    }
+
+    /// Mods can't have more memory than they're allowed.
+    #[test]
+    fn memory_cap() {
+        let mut world = sapling_world();
+        let limits = ModLimits { memory_bytes: 2 * 65536, ..ModLimits::default() };
+        let mut host = ModHost::new(limits).unwrap();
+
+        assert!(load(&mut host, &mut world, r#"(module (memory 3))"#, ModPermissions::none()).is_err());
+
+        let id = load(
+            &mut host,
+            &mut world,
+            r#"(module
+                (memory 1)
+                (global $grown (export "grown") (mut i32) (i32.const 0))
+                (func (export "on_step") (global.set $grown (memory.grow (i32.const 4)))))"#,
+            ModPermissions::none(),
+        )
+        .unwrap();
+
+        world.step();
+
+        let wasm_mod = host.mods[id.0].borrow();
+        assert_eq!(wasm_mod.instance.get_global(&wasm_mod.store, "grown").unwrap().get(&wasm_mod.store).i32(), Some(-1));
+    }
+
+    /// Mods can't have tables bigger than they're allowed, from the start or by growing them.
+    #[test]
+    fn table_cap() {
+        let mut world = sapling_world();
+        let limits = ModLimits { table_elements: 10, ..ModLimits::default() };
+        let mut host = ModHost::new(limits).unwrap();
+
+        assert!(load(&mut host, &mut world, r#"(module (table 11 funcref))"#, ModPermissions::none()).is_err());
+
+        let id = load(
+            &mut host,
+            &mut world,
+            r#"(module
+                (table 1 funcref)
+                (global $grown (export "grown") (mut i32) (i32.const 0))
+                (func (export "on_step") (global.set $grown (table.grow (ref.null func) (i32.const 10)))))"#,
+            ModPermissions::none(),
+        )
+        .unwrap();
+
+        world.step();
+
+        assert!(host.is_enabled(id));
+        let wasm_mod = host.mods[id.0].borrow();
+        assert_eq!(wasm_mod.instance.get_global(&wasm_mod.store, "grown").unwrap().get(&wasm_mod.store).i32(), Some(-1));
+    }
+
+    /// A mod's fuel is for the whole step, so a lot of cheap calls in one step run it out as surely as one long call.
+    #[test]
+    fn fuel_per_step() {
+        let mut world = sapling_world();
+        let mut host = ModHost::new(ModLimits { fuel_per_step: 20_000, ..ModLimits::default() }).unwrap();
+        let sapling = *world.block_registry().get_block_id_from_name("test:sapling").unwrap();
+        let id = load(
+            &mut host,
+            &mut world,
+            &format!(
+                r#"(module
+                    (import "game" "register_tick_handler" (func $register_tick_handler (param i32)))
+                    (func (export "init") (call $register_tick_handler (i32.const {})))
+                    (func (export "on_scheduled_tick") (param i64 i64 i64) (local $left i32)
+                        (local.set $left (i32.const 1000))
+                        (loop $spin
+                            (local.set $left (i32.sub (local.get $left) (i32.const 1)))
+                            (br_if $spin (local.get $left)))))"#,
+                block_to_id(&sapling)
+            ),
+            ModPermissions::all(),
+        )
+        .unwrap();
+
+        let tick = |world: &mut GridWorld<()>, blocks: i64| {
+            for x in 0..blocks {
+                let location = GlobalBlockCoordinate::new(x, 0, 0);
+                *world.get_block_mut(location).unwrap() = Some(sapling);
+                world.schedule_tick(location, world.time()).unwrap();
+            }
+            world.step();
+        };
+
+        // A couple of ticks fit in a step, and the fuel comes back every step.
+        for _ in 0..5 {
+            tick(&mut world, 2);
+        }
+        assert!(host.is_enabled(id));
+
+        tick(&mut world, 20);
+        assert!(!host.is_enabled(id));
+        assert!(host.disabled_reason(id).unwrap().contains("on_scheduled_tick"));
+    }
+
+    /// Mods can only have so many entities and scheduled ticks at once. Entities removed by something else and ticks
+    /// that have happened don't count.
+    #[test]
+    fn entity_and_tick_caps() {
+        let mut world = sapling_world();
+        let limits = ModLimits { entities: 2, scheduled_ticks: 3, ..ModLimits::default() };
+        let mut host = ModHost::new(limits).unwrap();
+        let id = load(
+            &mut host,
+            &mut world,
+            r#"(module
+                (import "game" "spawn_character" (func $spawn_character (param f32 f32 f32) (result i32)))
+                (import "game" "schedule_tick" (func $schedule_tick (param i64 i64 i64 i64) (result i32)))
+                (global $spawned (export "spawned") (mut i32) (i32.const 0))
+                (global $scheduled (export "scheduled") (mut i32) (i32.const 0))
+                (func $spawn
+                    (global.set $spawned (i32.add (global.get $spawned)
+                        (i32.ne (call $spawn_character (f32.const 0.5) (f32.const 1) (f32.const 0.5)) (i32.const 0)))))
+                (func $schedule
+                    (global.set $scheduled (i32.add (global.get $scheduled)
+                        (call $schedule_tick (i64.const 0) (i64.const 0) (i64.const 0) (i64.const 0)))))
+                (func (export "init")
+                    (call $spawn) (call $spawn) (call $spawn)
+                    (call $schedule) (call $schedule) (call $schedule) (call $schedule))
+                (func (export "on_step") (call $spawn) (call $schedule)))"#,
+            ModPermissions::all(),
+        )
+        .unwrap();
+
+        let counts = |host: &ModHost<()>| {
+            let wasm_mod = host.mods[id.0].borrow();
+            let global = |name| wasm_mod.instance.get_global(&wasm_mod.store, name).unwrap().get(&wasm_mod.store).i32();
+            (global("spawned"), global("scheduled"))
+        };
+        assert_eq!(counts(&host), (Some(2), Some(3)));
+
+        // The ticks from init have happened, but both entities are still around.
+        world.step();
+        assert_eq!(counts(&host), (Some(2), Some(4)));
+
+        let first = host.mods[id.0].borrow().store.data().entity(1).unwrap();
+        world.remove_entity(first);
+        world.step();
+        assert_eq!(counts(&host), (Some(3), Some(5)));
+        assert!(host.is_enabled(id));
+    }
+
+    /// What mods do goes into input recordings, so a run recorded with mods loaded replays the same way without them.
+    #[test]
+    fn replay() {
+        let mut world = sapling_world();
+        let mut host = ModHost::new(ModLimits::default()).unwrap();
+        load(&mut host, &mut world, GROWING_MOD, ModPermissions::all()).unwrap();
+        load(
+            &mut host,
+            &mut world,
+            r#"(module
+                (import "game" "spawn_character" (func $spawn_character (param f32 f32 f32) (result i32)))
+                (import "game" "remove_entity" (func $remove_entity (param i32) (result i32)))
+                (global $steps (mut i32) (i32.const 0))
+                (global $handle (mut i32) (i32.const 0))
+                (func (export "on_step")
+                    (global.set $steps (i32.add (global.get $steps) (i32.const 1)))
+                    (if (i32.eq (global.get $steps) (i32.const 3))
+                        (then
+                            (global.set $handle (call $spawn_character (f32.const 0.5) (f32.const 1) (f32.const 0.5)))
+                            (drop (call $spawn_character (f32.const 5.5) (f32.const 1) (f32.const 5.5)))))
+                    (if (i32.eq (global.get $steps) (i32.const 10))
+                        (then (drop (call $remove_entity (global.get $handle)))))))"#,
+            ModPermissions::all(),
+        )
+        .unwrap();
+
+        let registry = SnapshotRegistry::new();
+        let sapling = *world.block_registry().get_block_id_from_name("test:sapling").unwrap();
+        let base = GlobalBlockCoordinate::new(2, 0, 2);
+        world.start_recording(&registry).unwrap();
+        world.apply_input(WorldInput::PlaceBlock { location: base, block: sapling });
+        world.apply_input(WorldInput::ScheduleTick { location: base, time: world.time() });
+        for _ in 0..20 {
+            world.step();
+        }
+        let recording = world.stop_recording().unwrap();
+
+        assert_eq!(world.get_block(GlobalBlockCoordinate::new(2, 5, 2)), Some(Some(sapling)));
+        assert_eq!(world.ecs_world().len(), 1);
+        assert!(recording.inputs().iter().any(|recorded| recorded.during_block_ticks));
+
+        let mut replayed = sapling_world();
+        let hashes = replayed.replay(&recording, &registry).unwrap();
+        assert_eq!(&hashes, recording.state_hashes());
+        assert_eq!(replayed.state_hash(), world.state_hash());
+        assert_eq!(replayed.get_block(GlobalBlockCoordinate::new(2, 5, 2)), Some(Some(sapling)));
+    }
+
+    /// A mod that traps is disabled, and its tick handlers stop doing anything.
+    #[test]
+    fn trap() {
+        let mut world = sapling_world();
+        let mut host = ModHost::new(ModLimits::default()).unwrap();
+        let sapling = *world.block_registry().get_block_id_from_name("test:sapling").unwrap();
+        let id = load(
+            &mut host,
+            &mut world,
+            &format!(
+                r#"(module
+                    (import "game" "register_tick_handler" (func $register_tick_handler (param i32)))
+                    (func (export "init") (call $register_tick_handler (i32.const {})))
+                    (func (export "on_scheduled_tick") (param i64 i64 i64) unreachable))"#,
+                block_to_id(&sapling)
+            ),
+            ModPermissions::all(),
+        )
+        .unwrap();
+
+        for x in 0..2 {
+            let location = GlobalBlockCoordinate::new(x, 0, 0);
+            *world.get_block_mut(location).unwrap() = Some(sapling);
+            world.schedule_tick(location, world.time()).unwrap();
+        }
+
+        world.step();
+        world.step();
+
+        assert!(!host.is_enabled(id));
+        assert!(host.disabled_reason(id).unwrap().contains("on_scheduled_tick"));
+        assert_eq!(world.timestep().steps(), 2);
+    }
+
+    /// Does nothing with the ticks it gets.
+    struct IdleHandler;
+
+    impl BlockTickHandler<()> for IdleHandler {}
+
+    /// A disabled mod's tick handlers and step listener are taken out of the world, but handlers that have since
+    /// replaced its own are left alone.
+    #[test]
+    fn disabled_mod_unregistered() {
+        let mut world = sapling_world();
+        let mut host = ModHost::new(ModLimits::default()).unwrap();
+        let sapling = *world.block_registry().get_block_id_from_name("test:sapling").unwrap();
+        let ground = *world.block_registry().get_block_id_from_name("abstract_block").unwrap();
+        let id = load(
+            &mut host,
+            &mut world,
+            &format!(
+                r#"(module
+                    (import "game" "register_tick_handler" (func $register_tick_handler (param i32)))
+                    (func (export "init")
+                        (call $register_tick_handler (i32.const {}))
+                        (call $register_tick_handler (i32.const {})))
+                    (func (export "on_step") unreachable))"#,
+                block_to_id(&sapling),
+                block_to_id(&ground)
+            ),
+            ModPermissions::all(),
+        )
+        .unwrap();
+        assert!(world.tick_scheduler().get_handler(sapling).is_some());
+        assert_eq!(world.step_listeners.len(), 1);
+
+        world.tick_scheduler_mut().register_handler(ground, Box::new(IdleHandler));
+        let idle = world.tick_scheduler().get_handler(ground).unwrap();
+
+        world.step();
+        assert!(!host.is_enabled(id));
+        assert!(host.disabled_reason(id).unwrap().contains("on_step"));
+        assert!(world.tick_scheduler().get_handler(sapling).is_none());
+        assert!(Rc::ptr_eq(&world.tick_scheduler().get_handler(ground).unwrap(), &idle));
+        assert!(world.step_listeners.is_empty());
+    }
+
+    /// Mods only get to refer to the entities they spawned.
+    #[test]
+    fn entities() {
+        let mut world = sapling_world();
+        let mut host = ModHost::new(ModLimits::default()).unwrap();
+        let id = load(
+            &mut host,
+            &mut world,
+            r#"(module
+                (import "game" "spawn_character" (func $spawn_character (param f32 f32 f32) (result i32)))
+                (import "game" "remove_entity" (func $remove_entity (param i32) (result i32)))
+                (global $handle (mut i32) (i32.const 0))
+                (global $removed (export "removed") (mut i32) (i32.const 0))
+                (func (export "init")
+                    (global.set $handle (call $spawn_character (f32.const 0.5) (f32.const 1) (f32.const 0.5))))
+                (func (export "on_step")
+                    (global.set $removed (i32.add (global.get $removed)
+                        (i32.add (call $remove_entity (i32.const 7)) (call $remove_entity (global.get $handle)))))))"#,
+            ModPermissions::all(),
+        )
+        .unwrap();
+
+        let other = world.spawn_character(PhysicsVector::new(3.5, 1.0, 3.5), CharacterSettings::default());
+        assert_eq!(world.ecs_world().len(), 2);
+
+        world.step();
+        world.step();
+
+        assert!(host.is_enabled(id));
+        assert_eq!(world.ecs_world().len(), 1);
+        assert!(world.ecs_world().contains(other));
+
+        let wasm_mod = host.mods[id.0].borrow();
+        assert_eq!(wasm_mod.instance.get_global(&wasm_mod.store, "removed").unwrap().get(&wasm_mod.store).i32(), Some(1));
+    }
+
+    /// Mods hear about collisions of the entities they spawned.
+    #[test]
+    fn collisions() {
+        let mut world = sapling_world();
+        let mut host = ModHost::new(ModLimits::default()).unwrap();
+        let id = load(
+            &mut host,
+            &mut world,
+            r#"(module
+                (import "game" "spawn_character" (func $spawn_character (param f32 f32 f32) (result i32)))
+                (global $handle (mut i32) (i32.const 0))
+                (global $hits (export "hits") (mut i32) (i32.const 0))
+                (func (export "init")
+                    (global.set $handle (call $spawn_character (f32.const 0.5) (f32.const 0) (f32.const 0.5))))
+                (func (export "on_collision") (param $handle i32) (param $started i32)
+                    (if (i32.and (i32.eq (local.get $handle) (global.get $handle)) (local.get $started))
+                        (then (global.set $hits (i32.add (global.get $hits) (i32.const 1)))))))"#,
+            ModPermissions::all(),
+        )
+        .unwrap();
+
+        let ball = components::RigidBody::new(
+            world.ecs_resources_mut(),
+            rapier3d::dynamics::RigidBodyBuilder::new_dynamic().translation(0.5, 4.0, 0.5).build(),
+        );
+        ball.add_collider(rapier3d::geometry::ColliderBuilder::ball(0.25).build(), world.ecs_resources_mut());
+        world.ecs_world_mut().push((ball,));
+
+        for _ in 0..200 {
+            world.step();
+        }
+
+        assert!(host.is_enabled(id));
+        let wasm_mod = host.mods[id.0].borrow();
+        assert_eq!(wasm_mod.instance.get_global(&wasm_mod.store, "hits").unwrap().get(&wasm_mod.store).i32(), Some(1));
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright James Carl (C) 2020-2021
+// AGPL-3.0-or-later
+
+//! Renders a top down map of a region of the world to a PNG, either from a seed and preset or from a world save.
+//!
+//! ```text
+//! render_map [options] <output.png>
+//!   --seed <number>         The seed to generate from. Defaults to 0, or the preset file's seed.
+//!   --preset <name or file> "default", "superflat", or a RON preset file. Defaults to "default".
+//!   --save <folder>         Draw an existing world save instead of generating one.
+//!   --from <x,z>            One corner of the region, in chunks. Defaults to -8,-8.
+//!   --to <x,z>              The other corner of the region, in chunks. Defaults to 7,7.
+//!   --layers <bottom,top>   The layers of chunks to look for the ground in. Defaults to -4,3.
+//!   --no-biomes             Don't tint the map by biome.
+//!   --no-markers            Don't mark structures and vents.
+//!   --no-shading            Don't shade the map by height.
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use common::world::{
+    chunk_providers::WorldPreset,
+    map::{GeneratedMapSource, MapImage, MapRenderer, MapSource, SavedMapSource},
+};
+use std::{fs::File, io::BufWriter, path::Path};
+
+fn main() {
+    env_logger::init();
+
+    if let Err(error) = trampoline() {
+        log::error!("Failed to render map: {:?}", error);
+        std::process::exit(1);
+    }
+}
+
+fn trampoline() -> Result<()> {
+    let mut seed = None;
+    let mut preset_name = String::from("default");
+    let mut save = None;
+    let mut near = (-8, -8);
+    let mut far = (7, 7);
+    let mut layers = (-4, 3);
+    let mut biomes = true;
+    let mut markers = true;
+    let mut shading = true;
+    let mut output = None;
+
+    let mut arguments = std::env::args().skip(1);
+    while let Some(argument) = arguments.next() {
+        let mut value = || arguments.next().with_context(|| format!("{} needs a value.", argument));
+
+        match argument.as_str() {
+            "--seed" => seed = Some(value()?.parse().context("The seed must be a number.")?),
+            "--preset" => preset_name = value()?,
+            "--save" => save = Some(value()?),
+            "--from" => near = parse_pair(&value()?)?,
+            "--to" => far = parse_pair(&value()?)?,
+            "--layers" => layers = parse_pair(&value()?)?,
+            "--no-biomes" => biomes = false,
+            "--no-markers" => markers = false,
+            "--no-shading" => shading = false,
+            _ if argument.starts_with("--") => bail!("Unknown option {}.", argument),
+            _ => output = Some(argument),
+        }
+    }
+    let output = output.ok_or_else(|| anyhow!("An output file is needed."))?;
+
+    let (mut source, preset): (Box<dyn MapSource>, WorldPreset) = match save {
+        Some(save) => {
+            let source = SavedMapSource::open(Path::new(&save))?;
+            let preset = source.preset().clone();
+            (Box::new(source), preset)
+        }
+        None => {
+            let preset = load_preset(&preset_name, seed)?;
+            (Box::new(GeneratedMapSource::new(&preset)?), preset)
+        }
+    };
+
+    let mut renderer = MapRenderer::for_preset(&preset)?;
+    renderer.set_vertical_range(layers.0, layers.1);
+    renderer.set_height_shading(shading);
+    if !biomes {
+        renderer.set_biomes(None);
+    }
+    if !markers {
+        renderer.clear_markers();
+    }
+
+    log::info!("Rendering chunks {:?} to {:?} of \"{}\".", near, far, preset.name);
+    let image = renderer.render(source.as_mut(), near, far)?;
+    write_png(&image, Path::new(&output))?;
+    log::info!("Wrote {}x{} map to {}.", image.width(), image.height(), output);
+
+    Ok(())
+}
+
+fn load_preset(name: &str, seed: Option<u64>) -> Result<WorldPreset> {
+    match name {
+        "default" => Ok(WorldPreset::default_world(seed.unwrap_or(0))),
+        "superflat" => Ok(WorldPreset::superflat(seed.unwrap_or(0))),
+        path => {
+            let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read preset {}.", path))?;
+            let mut preset = WorldPreset::from_ron(&text)?;
+            preset.seed = seed.unwrap_or(preset.seed);
+
+            Ok(preset)
+        }
+    }
+}
+
+fn parse_pair(text: &str) -> Result<(i32, i32)> {
+    let mut parts = text.split(',').map(|part| part.trim().parse::<i32>());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(first)), Some(Ok(second)), None) => Ok((first, second)),
+        _ => Err(anyhow!("Expected two numbers separated by a comma, got \"{}\".", text)),
+    }
+}
+
+fn write_png(image: &MapImage, path: &Path) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}.", path.display()))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), image.width(), image.height());
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(image.as_rgba())?;
+
+    Ok(())
+}